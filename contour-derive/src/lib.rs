@@ -1,5 +1,6 @@
 #![recursion_limit="128"]
 extern crate proc_macro;
+#[cfg(feature = "regex")] extern crate regex;
 extern crate syn;
 #[macro_use] extern crate quote;
 
@@ -8,9 +9,23 @@ use std::hash::Hasher;
 
 use proc_macro::TokenStream;
 use syn::{
+    Attribute,
     Body,
+    Field,
+    FnArg,
+    FunctionRetTy,
     Ident,
+    ImplItemKind,
+    ItemKind,
+    Lit,
+    MetaItem,
+    MethodSig,
+    Mutability,
+    NestedMetaItem,
+    Pat,
+    Ty,
     VariantData,
+    Visibility,
 };
 use quote::{
     ToTokens,
@@ -20,32 +35,374 @@ use quote::{
 struct TupleField(usize);
 impl ToTokens for TupleField {
     fn to_tokens(&self, tokens: &mut Tokens) {
-        tokens.append(&format!("{}", self.0));
+        tokens.append(format!("{}", self.0));
     }
 }
 
-#[proc_macro_derive(Introspectable)]
+/// Returns whether `flag` appears as a bare word inside one of the
+/// `#[contour(...)]` attributes.
+fn has_flag(attrs: &[Attribute], flag: &str) -> bool {
+    attrs.iter().any(|attr| match attr.value {
+        MetaItem::List(ref name, ref items) if name == "contour" => {
+            items.iter().any(|item| match *item {
+                NestedMetaItem::MetaItem(MetaItem::Word(ref word)) => word == flag,
+                _ => false,
+            })
+        },
+        _ => false,
+    })
+}
+
+/// Source text that's spliced into the output as-is, for expressions given
+/// as strings in attributes.
+struct Raw(String);
+impl ToTokens for Raw {
+    fn to_tokens(&self, tokens: &mut Tokens) {
+        tokens.append(&self.0);
+    }
+}
+
+/// Returns the literal of a `key = value` item inside one of the
+/// `#[contour(...)]` attributes.
+fn attr_value<'a>(attrs: &'a [Attribute], key: &str) -> Option<&'a Lit> {
+    attrs.iter()
+        .filter_map(|attr| match attr.value {
+            MetaItem::List(ref name, ref items) if name == "contour" => Some(items),
+            _ => None,
+        })
+        .flat_map(|items| items.iter())
+        .filter_map(|item| match *item {
+            NestedMetaItem::MetaItem(MetaItem::NameValue(ref name, ref lit)) if name == key =>
+                Some(lit),
+            _ => None,
+        })
+        .next()
+}
+
+/// The integer type of an enum's discriminant: the one in its
+/// `#[repr(...)]`, or `isize`.
+fn discriminant_type(attrs: &[Attribute]) -> Ident {
+    attrs.iter()
+        .filter_map(|attr| match attr.value {
+            MetaItem::List(ref name, ref items) if name == "repr" => Some(items),
+            _ => None,
+        })
+        .flat_map(|items| items.iter())
+        .filter_map(|item| match *item {
+            NestedMetaItem::MetaItem(MetaItem::Word(ref word))
+                if word != "C" && word != "transparent" && word != "packed" =>
+                Some(word.clone()),
+            _ => None,
+        })
+        .next()
+        .unwrap_or_else(|| Ident::from("isize"))
+}
+
+/// Builds a `Repr` out of the type's `#[repr(...)]` attributes.
+fn repr(attrs: &[Attribute]) -> Tokens {
+    let (mut c, mut transparent, mut packed, mut align, mut int) =
+        (false, false, quote!(None), quote!(None), quote!(None));
+    let items = attrs.iter()
+        .filter_map(|attr| match attr.value {
+            MetaItem::List(ref name, ref items) if name == "repr" => Some(items),
+            _ => None,
+        })
+        .flat_map(|items| items.iter());
+    for item in items {
+        match *item {
+            NestedMetaItem::MetaItem(MetaItem::Word(ref word)) => match word.as_ref() {
+                "C" => c = true,
+                "transparent" => transparent = true,
+                "packed" => packed = quote!(Some(1)),
+                ty => int = quote!(Some(#ty)),
+            },
+            NestedMetaItem::MetaItem(MetaItem::List(ref name, ref args)) => {
+                let n = match args.first() {
+                    Some(&NestedMetaItem::Literal(Lit::Int(n, _))) => n as usize,
+                    _ => panic!("Expected an integer in #[repr({}(...))]", name),
+                };
+                match name.as_ref() {
+                    "packed" => packed = quote!(Some(#n)),
+                    "align" => align = quote!(Some(#n)),
+                    _ => panic!("Unknown repr {}", name),
+                }
+            },
+            _ => {},
+        }
+    }
+    quote! {
+        ::contour::Repr {
+            c: #c,
+            transparent: #transparent,
+            packed: #packed,
+            align: #align,
+            int: #int,
+        }
+    }
+}
+
+/// Builds `Hooks` for the type, adding the ones asked for with
+/// `#[contour(hooks(default, clone))]` to `drop`, which every type has.
+fn hooks(attrs: &[Attribute], ty: &Tokens) -> Tokens {
+    let items = attrs.iter()
+        .filter_map(|attr| match attr.value {
+            MetaItem::List(ref name, ref items) if name == "contour" => Some(items),
+            _ => None,
+        })
+        .flat_map(|items| items.iter())
+        .filter_map(|item| match *item {
+            NestedMetaItem::MetaItem(MetaItem::List(ref name, ref items)) if name == "hooks" =>
+                Some(items),
+            _ => None,
+        })
+        .flat_map(|items| items.iter());
+    let mut hooks = quote!(::contour::Hooks::new::<#ty>());
+    for item in items {
+        match *item {
+            NestedMetaItem::MetaItem(MetaItem::Word(ref word)) if word == "default" =>
+                hooks = quote!(#hooks.with_default::<#ty>()),
+            NestedMetaItem::MetaItem(MetaItem::Word(ref word)) if word == "clone" =>
+                hooks = quote!(#hooks.with_clone::<#ty>()),
+            _ => panic!("Expected `default` or `clone` in #[contour(hooks(...))]"),
+        }
+    }
+    hooks
+}
+
+/// Checks for `#[contour(assert_layout(size = N, align = N))]`, made where
+/// the contour is built since that's where the derive knows the layout.
+fn layout_checks(attrs: &[Attribute], name: &Ident, ty: &Tokens) -> Tokens {
+    let items = attrs.iter()
+        .filter_map(|attr| match attr.value {
+            MetaItem::List(ref name, ref items) if name == "contour" => Some(items),
+            _ => None,
+        })
+        .flat_map(|items| items.iter())
+        .filter_map(|item| match *item {
+            NestedMetaItem::MetaItem(MetaItem::List(ref name, ref items))
+                if name == "assert_layout" => Some(items),
+            _ => None,
+        })
+        .flat_map(|items| items.iter());
+    let mut checks = vec![];
+    for item in items {
+        let (key, n) = match *item {
+            NestedMetaItem::MetaItem(MetaItem::NameValue(ref key, Lit::Int(n, _))) => (key, n),
+            _ => panic!("Expected `size = N` or `align = N` in #[contour(assert_layout(...))]"),
+        };
+        let n = n as usize;
+        let actual = match key.as_ref() {
+            "size" => quote!(::std::mem::size_of::<#ty>()),
+            "align" => quote!(::std::mem::align_of::<#ty>()),
+            _ => panic!("Unknown layout property {}", key),
+        };
+        let key = key.as_ref();
+        checks.push(quote! {
+            let actual = #actual;
+            if actual != #n {
+                panic!("Layout of `{}` changed: {} is {}, expected {}",
+                       stringify!(#name), #key, actual, #n);
+            }
+        });
+    }
+    quote!(#(#checks)*)
+}
+
+/// Check for `#[contour(offset = N)]`, given the field's computed `offset`.
+fn offset_check(field: &Field, owner: &Tokens, fname: &Tokens) -> Tokens {
+    match attr_value(&field.attrs, "offset") {
+        Some(&Lit::Int(n, _)) => {
+            let n = n as usize;
+            quote! {
+                if offset != #n {
+                    panic!("Layout of `{}` changed: `{}` is at offset {}, expected {}",
+                           #owner, #fname, offset, #n);
+                }
+            }
+        },
+        Some(_) => panic!("Expected an integer in #[contour(offset = ...)]"),
+        None => quote!(),
+    }
+}
+
+/// The field's `#[contour(range(...))]`, `non_empty` and `regex = "..."`
+/// constraints.  With the `regex` feature, patterns are compiled here too, so
+/// that a bad one fails the build rather than every validation.
+fn constraints(field: &Field) -> Tokens {
+    let items = field.attrs.iter()
+        .filter_map(|attr| match attr.value {
+            MetaItem::List(ref name, ref items) if name == "contour" => Some(items),
+            _ => None,
+        })
+        .flat_map(|items| items.iter());
+    let mut constraints = vec![];
+    for item in items {
+        match *item {
+            NestedMetaItem::MetaItem(MetaItem::List(ref name, ref bounds)) if name == "range" => {
+                let (mut min, mut max) = (quote!(None), quote!(None));
+                for bound in bounds {
+                    let (key, lit) = match *bound {
+                        NestedMetaItem::MetaItem(MetaItem::NameValue(ref key, ref lit)) =>
+                            (key, lit),
+                        _ => panic!("Expected `min = N` or `max = N` in #[contour(range(...))]"),
+                    };
+                    // Literals in attributes can't be negative, so those are
+                    // written as strings.
+                    let text = match *lit {
+                        Lit::Int(n, _) => n.to_string(),
+                        Lit::Float(ref f, _) => f.clone(),
+                        Lit::Str(ref s, _) => s.clone(),
+                        _ => panic!("Expected a number in #[contour(range({} = ...))]", key),
+                    };
+                    let bound = if text.parse::<i128>().is_ok() {
+                        Raw(format!("Some(::contour::Bound::Int({}))", text))
+                    } else if text.parse::<f64>().is_ok() {
+                        Raw(format!("Some(::contour::Bound::Float({:?}))",
+                                        text.parse::<f64>().unwrap()))
+                    } else {
+                        panic!("Expected a number in #[contour(range({} = ...))]", key);
+                    };
+                    match key.as_ref() {
+                        "min" => min = quote!(#bound),
+                        "max" => max = quote!(#bound),
+                        _ => panic!("Unknown range bound {}", key),
+                    }
+                }
+                constraints.push(quote!(::contour::Constraint::Range { min: #min, max: #max }));
+            },
+            NestedMetaItem::MetaItem(MetaItem::Word(ref word)) if word == "non_empty" =>
+                constraints.push(quote!(::contour::Constraint::NonEmpty)),
+            NestedMetaItem::MetaItem(MetaItem::NameValue(ref key, ref lit)) if key == "regex" => {
+                match *lit {
+                    Lit::Str(ref pattern, _) => {
+                        check_pattern(pattern);
+                        constraints.push(quote!(::contour::Constraint::Regex(#lit)));
+                    },
+                    _ => panic!("Expected a string in #[contour(regex = ...)]"),
+                }
+            },
+            _ => {},
+        }
+    }
+    quote!(vec![#(#constraints),*])
+}
+
+/// Fails the build on a pattern that `validate` couldn't compile.  Without
+/// the `regex` feature, `validate` can't check patterns at all, so neither
+/// does this.
+#[cfg(feature = "regex")]
+fn check_pattern(pattern: &str) {
+    if let Err(e) = regex::Regex::new(&format!("^(?:{})$", pattern)) {
+        panic!("Invalid pattern in #[contour(regex = {:?})]: {}", pattern, e);
+    }
+}
+
+#[cfg(not(feature = "regex"))]
+fn check_pattern(_pattern: &str) {}
+
+/// The field's `#[contour(bits(...))]`: `name = N` for a flag at bit `N`,
+/// or `name(offset = N, width = W)` for a packed value.  Returns the bit
+/// fields and a check that they fit in the field's type.
+fn bit_fields(field: &Field, owner: &Tokens, fname: &Tokens) -> (Tokens, Tokens) {
+    let items = field.attrs.iter()
+        .filter_map(|attr| match attr.value {
+            MetaItem::List(ref name, ref items) if name == "contour" => Some(items),
+            _ => None,
+        })
+        .flat_map(|items| items.iter())
+        .filter_map(|item| match *item {
+            NestedMetaItem::MetaItem(MetaItem::List(ref name, ref items)) if name == "bits" =>
+                Some(items),
+            _ => None,
+        })
+        .flat_map(|items| items.iter());
+    let mut fields = vec![];
+    let mut end = 0;
+    for item in items {
+        let (name, offset, width) = match *item {
+            NestedMetaItem::MetaItem(MetaItem::NameValue(ref name, Lit::Int(n, _))) =>
+                (name, n, 1),
+            NestedMetaItem::MetaItem(MetaItem::List(ref name, ref params)) => {
+                let (mut offset, mut width) = (None, None);
+                for param in params {
+                    match *param {
+                        NestedMetaItem::MetaItem(MetaItem::NameValue(ref key, Lit::Int(n, _))) =>
+                            match key.as_ref() {
+                                "offset" => offset = Some(n),
+                                "width" => width = Some(n),
+                                _ => panic!("Unknown bit field parameter {}", key),
+                            },
+                        _ => panic!("Expected `offset = N` or `width = N` in \
+                                     #[contour(bits({}(...)))]", name),
+                    }
+                }
+                let offset = offset.unwrap_or_else(|| {
+                    panic!("Bit field {} needs an offset", name)
+                });
+                (name, offset, width.unwrap_or(1))
+            },
+            _ => panic!("Expected `name = N` or `name(offset = N, width = W)` in \
+                         #[contour(bits(...))]"),
+        };
+        // Words are read as `u64`s, so nothing past bit 64 can be shown.
+        if width == 0 || offset.checked_add(width).is_none_or(|end| end > 64) {
+            panic!("Bit field {} is out of range; bit fields must fit in 64 bits", name);
+        }
+        let (offset, width) = (offset as u32, width as u32);
+        end = ::std::cmp::max(end, offset + width);
+        let name = name.as_ref();
+        fields.push(quote!(::contour::BitField { name: #name, offset: #offset, width: #width }));
+    }
+    let ty = &field.ty;
+    let check = if fields.is_empty() {
+        quote!()
+    } else {
+        let end = end as usize;
+        quote! {
+            if 8 * ::std::mem::size_of::<#ty>() < #end {
+                panic!("Bit fields of `{}`.`{}` don't fit in `{}`",
+                       #owner, #fname, stringify!(#ty));
+            }
+        }
+    };
+    (quote!(vec![#(#fields),*]), check)
+}
+
+/// `#[contour(skip)]` fields are left out of the contour entirely, so their
+/// types don't need to be `Introspectable`.
+fn charted(field: &&Field) -> bool {
+    !has_flag(&field.attrs, "skip")
+}
+
+#[proc_macro_derive(Introspectable, attributes(contour))]
 pub fn introspectable(input: TokenStream) -> TokenStream {
     let s = input.to_string();
     let ast = syn::parse_derive_input(&s).unwrap();
     let name = &ast.ident;
     let (impl_g, ty_g, where_g) = ast.generics.split_for_impl();
+    let repr = repr(&ast.attrs);
+    let layout_checks = layout_checks(&ast.attrs, name, &quote!(#name #ty_g));
+    let hooks = hooks(&ast.attrs, &quote!(#name #ty_g));
     let chart_children = match ast.body {
         Body::Struct(VariantData::Struct(ref fields)) => fields.iter()
-            .map(|f| {let ty = &f.ty; quote!({#ty::chart(map);})})
+            .filter(charted)
+            .map(|f| {let ty = &f.ty; quote!({<#ty as Introspectable>::chart(map);})})
             .collect(),
         Body::Struct(VariantData::Tuple(ref fields)) => fields.iter()
-            .map(|f| {let ty = &f.ty; quote!({#ty::chart(map);})})
+            .filter(charted)
+            .map(|f| {let ty = &f.ty; quote!({<#ty as Introspectable>::chart(map);})})
             .collect(),
         Body::Struct(VariantData::Unit) => vec![],
         Body::Enum(ref variants) => {
             variants.iter()
                 .flat_map(|variant| match variant.data {
                     VariantData::Struct(ref fields) => fields.iter()
-                        .map(|f| {let ty = &f.ty; quote!({#ty::chart(map);})})
+                        .filter(charted)
+                        .map(|f| {let ty = &f.ty; quote!({<#ty as Introspectable>::chart(map);})})
                         .collect(),
                     VariantData::Tuple(ref fields) => fields.iter()
-                        .map(|f| {let ty = &f.ty; quote!({#ty::chart(map);})})
+                        .filter(charted)
+                        .map(|f| {let ty = &f.ty; quote!({<#ty as Introspectable>::chart(map);})})
                         .collect(),
                     VariantData::Unit => vec![],
                 })
@@ -55,27 +412,40 @@ pub fn introspectable(input: TokenStream) -> TokenStream {
 
     let gen = match ast.body {
         Body::Struct(VariantData::Struct(ref fields)) => {
+            let skipped = fields.iter().any(|f| !charted(&f));
             let fields: Vec<_> = fields.iter()
+                .filter(charted)
                 .map(|f| {
                     let ident = f.ident.as_ref().expect("Unnamed struct field?");
                     let ty = &f.ty;
+                    let redacted = has_flag(&f.attrs, "redact");
+                    let constraints = constraints(f);
+                    let (bits, bits_check) = bit_fields(f, &quote!(stringify!(#name)),
+                                             &quote!(stringify!(#ident)));
+                    let check = offset_check(f, &quote!(stringify!(#name)),
+                                             &quote!(stringify!(#ident)));
                     quote! {{
                         let _bomb: #name #ty_g = unsafe {::std::mem::uninitialized()};
                         let _base = &_bomb as *const _ as *const u8;
                         let _us = &_bomb.#ident as *const _ as *const u8;
                         let offset = _base.offset_to(_us).unwrap() as usize;
                         ::std::mem::forget(_bomb);
+                        #check
+                        #bits_check
                         StructField {
                             name: stringify!(#ident),
                             type_id: ::std::any::TypeId::of::<#ty>(),
                             offset: offset,
+                            redacted: #redacted,
+                            constraints: #constraints,
+                            bits: #bits,
                         }
                     }}
                 })
                 .collect();
 
             quote! {
-                impl #impl_g Introspectable for #name #ty_g #where_g {
+                unsafe impl #impl_g Introspectable for #name #ty_g #where_g {
                     fn chart<CM: ContourMap>(map: &CM) {
                         let contour = Self::contour();
                         if map.register(contour) {
@@ -84,38 +454,55 @@ pub fn introspectable(input: TokenStream) -> TokenStream {
                         #(#chart_children)*
                     }
                     fn contour() -> Contour {
+                        #layout_checks
                         Contour::Struct {
                             name: stringify!(#name),
+                            module_path: module_path!(),
                             size: ::std::mem::size_of::<#name #ty_g>(),
+                            align: ::std::mem::align_of::<#name #ty_g>(),
                             type_id: ::std::any::TypeId::of::<#name #ty_g>(),
                             fields: vec![#(#fields),*],
+                            skipped: #skipped,
+                            repr: #repr,
+                            hooks: #hooks,
                         }
                     }
                 }
             }
         },
         Body::Struct(VariantData::Tuple(ref fields)) => {
+            let skipped = fields.iter().any(|f| !charted(&f));
             let fields: Vec<_> = fields.iter()
                 .enumerate()
+                .filter(|(_, f)| charted(f))
                 .map(|(i, f)| {
                     let field = TupleField(i);
                     let ty = &f.ty;
+                    let redacted = has_flag(&f.attrs, "redact");
+                    let constraints = constraints(f);
+                    let (bits, bits_check) = bit_fields(f, &quote!(stringify!(#name)), &quote!(#i));
+                    let check = offset_check(f, &quote!(stringify!(#name)), &quote!(#i));
                     quote! {{
                         let _bomb: #name #ty_g = unsafe {::std::mem::uninitialized()};
                         let _base = &_bomb as *const _ as *const u8;
                         let _us = &_bomb.#field as *const _ as *const u8;
                         let offset = _base.offset_to(_us).unwrap() as usize;
                         ::std::mem::forget(_bomb);
+                        #check
+                        #bits_check
                         TupleField {
                             ix: #i,
                             type_id: ::std::any::TypeId::of::<#ty>(),
                             offset: offset,
+                            redacted: #redacted,
+                            constraints: #constraints,
+                            bits: #bits,
                         }
                     }}
                 })
                 .collect();
             quote! {
-                impl #impl_g Introspectable for #name #ty_g #where_g {
+                unsafe impl #impl_g Introspectable for #name #ty_g #where_g {
                     fn chart<CM: ContourMap>(map: &CM) {
                         let contour = Self::contour();
                         if map.register(contour) {
@@ -125,11 +512,17 @@ pub fn introspectable(input: TokenStream) -> TokenStream {
                     }
 
                     fn contour() -> Contour {
+                        #layout_checks
                         Contour::Tuple {
                             name: stringify!(#name),
+                            module_path: module_path!(),
                             size: ::std::mem::size_of::<#name #ty_g>(),
+                            align: ::std::mem::align_of::<#name #ty_g>(),
                             type_id: ::std::any::TypeId::of::<#name #ty_g>(),
                             fields: vec![#(#fields),*],
+                            skipped: #skipped,
+                            repr: #repr,
+                            hooks: #hooks,
                         }
                     }
                 }
@@ -137,7 +530,7 @@ pub fn introspectable(input: TokenStream) -> TokenStream {
         },
         Body::Struct(VariantData::Unit) => {
             quote! {
-                impl Introspectable for #name {
+                unsafe impl Introspectable for #name {
                     fn chart<CM: ContourMap>(map: &CM) {
                         let contour = Self::contour();
                         if map.register(contour) {
@@ -147,32 +540,63 @@ pub fn introspectable(input: TokenStream) -> TokenStream {
                     }
 
                     fn contour() -> Contour {
+                        #layout_checks
                         Contour::Unit {
                             name: stringify!(#name),
+                            module_path: module_path!(),
                             type_id: ::std::any::TypeId::of::<#name>(),
+                            hooks: #hooks,
                         }
                     }
                 }
             }
         },
         Body::Enum(ref variants) => {
+            // Variants without an explicit discriminant count up from the
+            // one before.
+            let int = discriminant_type(&ast.attrs);
+            let mut discriminants = vec![];
+            let mut base = (quote!(0), 0);
+            for variant in variants {
+                if let Some(ref expr) = variant.discriminant {
+                    base = (quote!(#expr), 0);
+                }
+                let (ref expr, k) = base;
+                discriminants.push(quote!((#expr) as #int as i128 + #k as i128));
+                base.1 += 1;
+            }
             let variant_fields: Vec<_> = variants.iter()
-                .map(|variant| {
+                .zip(discriminants)
+                .map(|(variant, discriminant)| {
                     let vname = &variant.ident;
                     match variant.data {
                         VariantData::Struct(ref fields) => {
+                            let skipped = fields.iter().any(|f| !charted(&f));
                             let initializer: Vec<_> = fields.iter()
                                 .map(|field| field.ident.as_ref().unwrap())
                                 .map(|f| quote! {#f: unsafe {::std::mem::uninitialized()}})
                                 .collect();
 
                             let fields: Vec<_> = fields.iter()
+                                .filter(charted)
                                 .map(|field| {
                                     let fname = field.ident.as_ref().unwrap();
                                     let ty = &field.ty;
+                                    let redacted = has_flag(&field.attrs, "redact");
+                                    let constraints = constraints(field);
+                                    let (bits, bits_check) = bit_fields(
+                                        field,
+                                        &quote!(concat!(stringify!(#name), "::",
+                                                        stringify!(#vname))),
+                                        &quote!(stringify!(#fname)));
+                                    let check = offset_check(
+                                        field,
+                                        &quote!(concat!(stringify!(#name), "::",
+                                                        stringify!(#vname))),
+                                        &quote!(stringify!(#fname)));
                                     let _initializer = initializer.clone();
                                     quote! {{
-                                        let _bomb = #name::#vname {
+                                        let _bomb: #name #ty_g = #name::#vname {
                                             #(#_initializer),*
                                         };
                                         let _base = &_bomb as *const _ as *const u8;
@@ -183,10 +607,15 @@ pub fn introspectable(input: TokenStream) -> TokenStream {
                                         };
                                         let offset = _base.offset_to(_us).unwrap() as usize;
                                         ::std::mem::forget(_bomb);
+                                        #check
+                                        #bits_check
                                         StructField {
                                             name: stringify!(#fname),
                                             type_id: ::std::any::TypeId::of::<#ty>(),
                                             offset: offset,
+                                            redacted: #redacted,
+                                            constraints: #constraints,
+                                            bits: #bits,
                                         }
                                     }}
                                 })
@@ -194,11 +623,14 @@ pub fn introspectable(input: TokenStream) -> TokenStream {
                             quote! {
                                 Variant {
                                     name: stringify!(#vname),
-                                    fields: VariantFields::Struct(vec![#(#fields),*])
+                                    discriminant: #discriminant,
+                                    fields: VariantFields::Struct(vec![#(#fields),*]),
+                                    skipped: #skipped,
                                 }
                             }
                         },
                         VariantData::Tuple(ref fields) => {
+                            let skipped = fields.iter().any(|f| !charted(&f));
                             let n = fields.len();
                             let initializer: Vec<_> = (0..n)
                                 .map(|_| quote! {unsafe {::std::mem::uninitialized()}})
@@ -206,9 +638,22 @@ pub fn introspectable(input: TokenStream) -> TokenStream {
 
                             let fields: Vec<_> = fields.iter()
                                 .enumerate()
+                                .filter(|(_, f)| charted(f))
                                 .map(|(i, field)| {
                                     let _initializer = initializer.clone();
                                     let ty = &field.ty;
+                                    let redacted = has_flag(&field.attrs, "redact");
+                                    let constraints = constraints(field);
+                                    let (bits, bits_check) = bit_fields(
+                                        field,
+                                        &quote!(concat!(stringify!(#name), "::",
+                                                        stringify!(#vname))),
+                                        &quote!(#i));
+                                    let check = offset_check(
+                                        field,
+                                        &quote!(concat!(stringify!(#name), "::",
+                                                        stringify!(#vname))),
+                                        &quote!(#i));
 
                                     let mut pat = vec![];
                                     pat.extend((0..i).map(|_| quote!(_)));
@@ -216,7 +661,7 @@ pub fn introspectable(input: TokenStream) -> TokenStream {
                                     pat.extend(((i+1)..n).map(|_| quote!(_)));
 
                                     quote! {{
-                                        let _bomb = #name::#vname(#(#_initializer),*);
+                                        let _bomb: #name #ty_g = #name::#vname(#(#_initializer),*);
                                         let _base = &_bomb as *const _ as *const u8;
                                         let _us = match _bomb {
                                             #name::#vname( #(#pat),* ) =>
@@ -225,10 +670,15 @@ pub fn introspectable(input: TokenStream) -> TokenStream {
                                         };
                                         let offset = _base.offset_to(_us).unwrap() as usize;
                                         ::std::mem::forget(_bomb);
+                                        #check
+                                        #bits_check
                                         TupleField {
                                             ix: #i,
                                             type_id: ::std::any::TypeId::of::<#ty>(),
                                             offset: offset,
+                                            redacted: #redacted,
+                                            constraints: #constraints,
+                                            bits: #bits,
                                         }
                                     }}
                                 })
@@ -236,14 +686,18 @@ pub fn introspectable(input: TokenStream) -> TokenStream {
                             quote! {
                                 Variant {
                                     name: stringify!(#vname),
+                                    discriminant: #discriminant,
                                     fields: VariantFields::Tuple(vec![#(#fields),*]),
+                                    skipped: #skipped,
                                 }
                             }
                         },
                         VariantData::Unit => quote! {
                             Variant {
                                 name: stringify!(#vname),
+                                discriminant: #discriminant,
                                 fields: VariantFields::Unit,
+                                skipped: false,
                             }
                         }
                     }
@@ -254,6 +708,7 @@ pub fn introspectable(input: TokenStream) -> TokenStream {
             let mut hasher = DefaultHasher::new();
             hasher.write(format!("{:?}", ast).as_bytes());
             let fn_name = Ident::from(format!("gettag_{:x}", hasher.finish()));
+            let turbofish = ty_g.as_turbofish();
             let enum_variants: Vec<_> = variants.iter()
                 .enumerate()
                 .map(|(i, v)| {
@@ -266,11 +721,11 @@ pub fn introspectable(input: TokenStream) -> TokenStream {
                 })
                 .collect();
             quote! {
-                unsafe extern "C" fn #fn_name(_self: *const u8) -> usize {
+                unsafe extern "C" fn #fn_name #impl_g (_self: *const u8) -> usize #where_g {
                     let s = _self as *const #name #ty_g;
                     match *s { #(#enum_variants),* }
                 }
-                impl #impl_g Introspectable for #name #ty_g #where_g {
+                unsafe impl #impl_g Introspectable for #name #ty_g #where_g {
                     fn chart<CM: ContourMap>(map: &CM) {
                         let contour = Self::contour();
                         if map.register(contour) {
@@ -280,19 +735,394 @@ pub fn introspectable(input: TokenStream) -> TokenStream {
                     }
 
                     fn contour() -> Contour {
+                        #layout_checks
                         Contour::Enum {
                             name: stringify!(#name),
-                            size: ::std::mem::size_of::<#name>(),
-                            type_id: ::std::any::TypeId::of::<#name>(),
+                            module_path: module_path!(),
+                            size: ::std::mem::size_of::<#name #ty_g>(),
+                            align: ::std::mem::align_of::<#name #ty_g>(),
+                            type_id: ::std::any::TypeId::of::<#name #ty_g>(),
                             variants: vec![#(#variant_fields),*],
-                            tag: #fn_name,
+                            tag: #fn_name #turbofish,
+                            repr: #repr,
+                            hooks: #hooks,
+                        }
+                    }
+                }
+            }
+        },
+    };
+
+
+    gen.parse().unwrap()
+}
+
+/// The value a field takes when the snapshot doesn't have it: the literal in
+/// `#[contour(default = ...)]`, with strings converted with `From` so they
+/// can fill in `String`s, the expression in `#[contour(default_expr = "...")]`,
+/// or `Default`.
+fn field_default(field: &Field) -> Tokens {
+    match attr_value(&field.attrs, "default") {
+        Some(lit @ &Lit::Str(..)) => return quote!(::std::convert::From::from(#lit)),
+        Some(lit) => return quote!(#lit),
+        None => {},
+    }
+    match attr_value(&field.attrs, "default_expr") {
+        Some(Lit::Str(expr, _)) => {
+            let expr = Raw(expr.clone());
+            quote!(#expr)
+        },
+        Some(_) => panic!("Expected a string in #[contour(default_expr = ...)]"),
+        None => quote!(::std::default::Default::default()),
+    }
+}
+
+/// Expressions loading each of `fields` out of the `FieldValues` bound to
+/// `fields`, followed by a statement recording any fields the type lacks as
+/// dropped.
+fn load_fields(fields: &[Field]) -> (Tokens, Vec<Tokens>) {
+    let names: Vec<_> = fields.iter()
+        .enumerate()
+        .filter(|(_, f)| charted(f))
+        .map(|(i, f)| match f.ident {
+            Some(ref ident) => ident.to_string(),
+            None => i.to_string(),
+        })
+        .collect();
+    let unknown = quote!(cx.unknown(fields, &[#(#names),*]););
+    let loads = fields.iter()
+        .enumerate()
+        .map(|(i, f)| {
+            let default = field_default(f);
+            if !charted(&f) {
+                return default;
+            }
+            let name = match f.ident {
+                Some(ref ident) => ident.to_string(),
+                None => i.to_string(),
+            };
+            quote! {
+                match cx.field(fields, #name)? {
+                    Some(v) => v,
+                    None => #default,
+                }
+            }
+        })
+        .collect();
+    (unknown, loads)
+}
+
+#[proc_macro_derive(Load, attributes(contour))]
+pub fn load(input: TokenStream) -> TokenStream {
+    let s = input.to_string();
+    let ast = syn::parse_derive_input(&s).unwrap();
+    let name = &ast.ident;
+    let (impl_g, ty_g, where_g) = ast.generics.split_for_impl();
+
+    let body = match ast.body {
+        Body::Struct(VariantData::Struct(ref fields)) => {
+            let idents: Vec<_> = fields.iter().map(|f| f.ident.as_ref().unwrap()).collect();
+            let (unknown, loads) = load_fields(fields);
+            quote! {
+                let fields = cx.fields(value)?;
+                #unknown
+                Ok(#name { #(#idents: #loads),* })
+            }
+        },
+        Body::Struct(VariantData::Tuple(ref fields)) => {
+            let (unknown, loads) = load_fields(fields);
+            quote! {
+                let fields = cx.fields(value)?;
+                #unknown
+                Ok(#name(#(#loads),*))
+            }
+        },
+        Body::Struct(VariantData::Unit) => quote! {
+            let fields = cx.fields(value)?;
+            cx.unknown(fields, &[]);
+            Ok(#name)
+        },
+        Body::Enum(ref variants) => {
+            let arms: Vec<_> = variants.iter()
+                .map(|variant| {
+                    let vname = &variant.ident;
+                    let vstr = vname.to_string();
+                    let build = match variant.data {
+                        VariantData::Struct(ref fields) => {
+                            let idents: Vec<_> = fields.iter()
+                                .map(|f| f.ident.as_ref().unwrap())
+                                .collect();
+                            let (unknown, loads) = load_fields(fields);
+                            quote! {
+                                #unknown
+                                Ok(#name::#vname { #(#idents: #loads),* })
+                            }
+                        },
+                        VariantData::Tuple(ref fields) => {
+                            let (unknown, loads) = load_fields(fields);
+                            quote! {
+                                #unknown
+                                Ok(#name::#vname(#(#loads),*))
+                            }
+                        },
+                        VariantData::Unit => quote! {
+                            cx.unknown(fields, &[]);
+                            Ok(#name::#vname)
+                        },
+                    };
+                    quote! {
+                        #vstr => cx.in_variant(#vstr, |cx| { #build })
+                    }
+                })
+                .collect();
+            quote! {
+                let (variant, fields) = cx.variant(value)?;
+                match variant {
+                    #(#arms,)*
+                    _ => cx.unknown_variant(variant),
+                }
+            }
+        },
+    };
+
+    let gen = quote! {
+        impl #impl_g ::contour::Load for #name #ty_g #where_g {
+            fn load(value: &::contour::Value, cx: &mut ::contour::Loader)
+                -> Result<Self, ::contour::LoadError>
+            {
+                #body
+            }
+        }
+    };
+    gen.parse().unwrap()
+}
+
+/// A `FieldValues` of the charted fields in `data`, where `access` gives a
+/// reference to the field at an index.
+fn direct_fields<F>(data: &VariantData, access: F) -> Tokens
+    where F: Fn(usize, &Field) -> Tokens
+{
+    let values: Vec<_> = data.fields().iter()
+        .enumerate()
+        .filter(|(_, f)| charted(f))
+        .map(|(i, f)| {
+            let access = access(i, f);
+            let value = quote!(::contour::testing::Direct::direct(#access));
+            match f.ident {
+                Some(ref ident) => quote!((stringify!(#ident).to_owned(), #value)),
+                None => value,
+            }
+        })
+        .collect();
+    match *data {
+        VariantData::Struct(_) => quote!(::contour::value::FieldValues::Named(vec![#(#values),*])),
+        VariantData::Tuple(_) => quote!(::contour::value::FieldValues::Tuple(vec![#(#values),*])),
+        VariantData::Unit => quote!(::contour::value::FieldValues::Unit),
+    }
+}
+
+#[proc_macro_derive(Direct, attributes(contour))]
+pub fn direct(input: TokenStream) -> TokenStream {
+    let s = input.to_string();
+    let ast = syn::parse_derive_input(&s).unwrap();
+    let name = &ast.ident;
+    let (impl_g, ty_g, where_g) = ast.generics.split_for_impl();
+
+    let body = match ast.body {
+        Body::Struct(ref data) => {
+            let fields = direct_fields(data, |i, f| match f.ident {
+                Some(ref ident) => quote!(&self.#ident),
+                None => {
+                    let field = TupleField(i);
+                    quote!(&self.#field)
+                },
+            });
+            quote! {
+                ::contour::Value::Struct {
+                    name: stringify!(#name).to_owned(),
+                    fields: #fields,
+                }
+            }
+        },
+        Body::Enum(ref variants) => {
+            let binding = |i: usize| Ident::from(format!("_field{}", i));
+            let arms: Vec<_> = variants.iter()
+                .map(|variant| {
+                    let vname = &variant.ident;
+                    let pattern = match variant.data {
+                        VariantData::Struct(ref fields) => {
+                            let idents: Vec<_> = fields.iter()
+                                .filter(charted)
+                                .map(|f| f.ident.as_ref().unwrap())
+                                .collect();
+                            quote!(#name::#vname { #(ref #idents,)* .. })
+                        },
+                        VariantData::Tuple(ref fields) => {
+                            let bindings: Vec<_> = fields.iter()
+                                .enumerate()
+                                .map(|(i, f)| if charted(&f) {
+                                    let binding = binding(i);
+                                    quote!(ref #binding)
+                                } else {
+                                    quote!(_)
+                                })
+                                .collect();
+                            quote!(#name::#vname(#(#bindings),*))
+                        },
+                        VariantData::Unit => quote!(#name::#vname),
+                    };
+                    let fields = direct_fields(&variant.data, |i, f| match f.ident {
+                        Some(ref ident) => quote!(#ident),
+                        None => {
+                            let binding = binding(i);
+                            quote!(#binding)
+                        },
+                    });
+                    quote! {
+                        #pattern => ::contour::Value::Enum {
+                            name: stringify!(#name).to_owned(),
+                            variant: stringify!(#vname).to_owned(),
+                            fields: #fields,
                         }
                     }
+                })
+                .collect();
+            quote! {
+                match *self {
+                    #(#arms,)*
                 }
             }
         },
     };
 
+    let gen = quote! {
+        impl #impl_g ::contour::testing::Direct for #name #ty_g #where_g {
+            fn direct(&self) -> ::contour::Value {
+                #body
+            }
+        }
+    };
+    gen.parse().unwrap()
+}
+
+/// Describes one method for `#[introspect]`, with a thunk that unpacks its
+/// arguments from `Boxed`es and boxes up what it returns.
+fn method(name: &Ident, sig: &MethodSig) -> Tokens {
+    if !sig.generics.ty_params.is_empty() {
+        panic!("Generic method `{}` can't be introspected; mark it #[contour(skip)]", name);
+    }
+    let mut inputs = sig.decl.inputs.iter().peekable();
+    let (receiver, call) = match inputs.peek() {
+        Some(&&FnArg::SelfRef(_, Mutability::Immutable)) =>
+            (quote!(::contour::Receiver::Ref), quote!((&*(_receiver as *const Self)).#name)),
+        Some(&&FnArg::SelfRef(_, Mutability::Mutable)) =>
+            (quote!(::contour::Receiver::Mut), quote!((&mut *(_receiver as *mut Self)).#name)),
+        Some(&&FnArg::SelfValue(_)) =>
+            panic!("Method `{}` takes `self` by value, so it can't be introspected; \
+                    mark it #[contour(skip)]", name),
+        _ => (quote!(::contour::Receiver::None), quote!(Self::#name)),
+    };
+    if let Some(&&FnArg::SelfRef(..)) = inputs.peek() {
+        inputs.next();
+    }
+
+    let (mut args, mut unpack, mut idents) = (vec![], vec![], vec![]);
+    for (i, input) in inputs.enumerate() {
+        let (arg_name, ty) = match *input {
+            FnArg::Captured(Pat::Ident(_, ref ident, _), ref ty) => (ident.to_string(), ty),
+            FnArg::Captured(_, ref ty) | FnArg::Ignored(ref ty) => ("_".to_owned(), ty),
+            _ => unreachable!(),
+        };
+        if let Ty::Rptr(..) = *ty {
+            panic!("Method `{}` takes a reference in `{}`, which can't be boxed up; \
+                    mark it #[contour(skip)]", name, arg_name);
+        }
+        let ident = Ident::from(format!("_arg{}", i));
+        args.push(quote! {
+            ::contour::Arg {
+                name: #arg_name,
+                ty: stringify!(#ty),
+                type_id: ::std::any::TypeId::of::<#ty>(),
+            }
+        });
+        unpack.push(quote! {
+            let #ident: #ty = *_args.next().unwrap().downcast::<#ty>().ok()
+                .expect("argument types are checked by `Method::call`");
+        });
+        idents.push(ident);
+    }
+
+    let returns = match sig.decl.output {
+        FunctionRetTy::Default => None,
+        FunctionRetTy::Ty(Ty::Tup(ref tys)) if tys.is_empty() => None,
+        FunctionRetTy::Ty(ref ty) => Some(ty),
+    };
+    if let Some(&Ty::Rptr(..)) = returns {
+        panic!("Method `{}` returns a reference, which can't be boxed up; \
+                mark it #[contour(skip)]", name);
+    }
+    let (ret, wrap) = match returns {
+        Some(ty) => (quote!(Some(::std::any::TypeId::of::<#ty>())),
+                     quote!(Some(::contour::Boxed::new::<#ty>(#call(#(#idents),*))))),
+        None => (quote!(None), quote!({ #call(#(#idents),*); None })),
+    };
+    quote! {
+        ::contour::Method {
+            name: stringify!(#name),
+            receiver: #receiver,
+            args: vec![#(#args),*],
+            ret: #ret,
+            thunk: {
+                let thunk: unsafe fn(*mut u8, Vec<::contour::Boxed>)
+                                     -> Option<::contour::Boxed> =
+                    |_receiver, _args| unsafe {
+                        let mut _args = _args.into_iter();
+                        #(#unpack)*
+                        #wrap
+                    };
+                thunk
+            },
+        }
+    }
+}
+
+/// Records the `pub` methods of an inherent `impl` block in an impl of
+/// `Methods`, so tools can call them on live values.  Methods marked
+/// `#[contour(skip)]` are left out, and have to be if they're generic, take
+/// `self` by value, or take or return references.  Only one block per type
+/// can be introspected.
+#[proc_macro_attribute]
+pub fn introspect(_args: TokenStream, input: TokenStream) -> TokenStream {
+    let s = input.to_string();
+    let mut item = syn::parse_item(&s).unwrap();
+    let methods: Vec<_> = match item.node {
+        ItemKind::Impl(_, _, _, None, _, ref mut items) => items.iter_mut()
+            .filter_map(|item| {
+                let skip = has_flag(&item.attrs, "skip");
+                item.attrs.retain(|attr| attr.name() != "contour");
+                match item.node {
+                    ImplItemKind::Method(ref sig, _) if !skip && item.vis == Visibility::Public =>
+                        Some(method(&item.ident, sig)),
+                    _ => None,
+                }
+            })
+            .collect(),
+        _ => panic!("#[introspect] only applies to inherent impl blocks"),
+    };
+    let gen = match item.node {
+        ItemKind::Impl(_, _, ref generics, _, ref self_ty, _) => {
+            let (impl_g, _, where_g) = generics.split_for_impl();
+            quote! {
+                #item
 
+                impl #impl_g ::contour::Methods for #self_ty #where_g {
+                    fn methods() -> Vec<::contour::Method> {
+                        vec![#(#methods),*]
+                    }
+                }
+            }
+        },
+        _ => unreachable!(),
+    };
     gen.parse().unwrap()
 }
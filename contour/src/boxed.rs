@@ -0,0 +1,208 @@
+//! Values owned through their contours, for tools like REPLs and fuzzers
+//! that only know a type by its `TypeId`.
+
+use std::alloc::{
+    self,
+    Layout,
+};
+use std::any::TypeId;
+use std::fmt;
+use std::mem::ManuallyDrop;
+use std::ptr;
+
+use {
+    Contour,
+    ContourMap,
+    Hooks,
+    Introspectable,
+};
+
+/// A heap allocation holding a value of a charted type, created, cloned and
+/// dropped through the type's `Hooks`.
+///
+/// The contour is always either `T::contour()` or one the caller of an
+/// unsafe constructor vouched for, which is what lets `try_clone`, `drop`
+/// and `downcast` trust its hooks, layout and `TypeId`.
+pub struct Boxed {
+    /// Boxed itself, so that a `Boxed` handed back by `downcast` is small.
+    contour: Box<Contour>,
+    ptr: *mut u8,
+    drop: Option<unsafe fn(*mut u8)>,
+}
+
+impl Boxed {
+    /// Boxes up a value whose type is known statically.  It can be dropped
+    /// even if the type doesn't record hooks in its contour.
+    pub fn new<T: Introspectable>(value: T) -> Self {
+        Boxed {
+            contour: Box::new(T::contour()),
+            ptr: Box::into_raw(Box::new(value)) as *mut u8,
+            drop: Hooks::new::<T>().drop,
+        }
+    }
+
+    /// A fresh `Default::default()` of `type_id`, or `None` if `map` doesn't
+    /// know how to make one.
+    ///
+    /// # Safety
+    ///
+    /// `map`'s contour for `type_id` must be accurate, as contours charted
+    /// from `Introspectable` impls are, since its size, alignment and hooks
+    /// are used as they are.
+    pub unsafe fn new_default(map: &dyn ContourMap, type_id: TypeId) -> Option<Self> {
+        let contour = map.lookup(type_id)?;
+        let default = contour.hooks()?.default?;
+        let drop = contour.hooks()?.drop?;
+        let ptr = allocate(&contour);
+        default(ptr);
+        Some(Boxed { contour: Box::new(contour), ptr, drop: Some(drop) })
+    }
+
+    /// A clone of the live value of type `type_id` at `src`, or `None` if
+    /// `map` doesn't know how to clone one.
+    ///
+    /// # Safety
+    ///
+    /// `src` must point to a live value of type `type_id`, and `map` must be
+    /// accurate, as for `new_default`.
+    pub unsafe fn clone_from(map: &dyn ContourMap, type_id: TypeId, src: *const u8)
+        -> Option<Self>
+    {
+        let contour = map.lookup(type_id)?;
+        let clone = contour.hooks()?.clone?;
+        let drop = contour.hooks()?.drop?;
+        let ptr = allocate(&contour);
+        clone(src, ptr);
+        Some(Boxed { contour: Box::new(contour), ptr, drop: Some(drop) })
+    }
+
+    pub fn try_clone(&self) -> Option<Self> {
+        let clone = self.contour.hooks()?.clone?;
+        unsafe {
+            let ptr = allocate(&self.contour);
+            clone(self.ptr, ptr);
+            Some(Boxed { contour: self.contour.clone(), ptr, drop: self.drop })
+        }
+    }
+
+    pub fn contour(&self) -> &Contour {
+        &self.contour
+    }
+
+    pub fn type_id(&self) -> TypeId {
+        self.contour.type_id()
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// Hands the value over as an ordinary `Box`, if it's a `T`.
+    pub fn downcast<T: 'static>(self) -> Result<Box<T>, Self> {
+        if self.type_id() != TypeId::of::<T>() {
+            return Err(self);
+        }
+        // We allocated with `T`'s layout, same as `Box` would have.
+        let this = ManuallyDrop::new(self);
+        unsafe {
+            drop(ptr::read(&this.contour));
+            Ok(Box::from_raw(this.ptr as *mut T))
+        }
+    }
+}
+
+impl Drop for Boxed {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(drop) = self.drop {
+                drop(self.ptr);
+            }
+            if self.contour.size() > 0 {
+                alloc::dealloc(self.ptr, layout(&self.contour));
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Boxed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Boxed({} @ {:p})", self.contour.name(), self.ptr)
+    }
+}
+
+fn layout(contour: &Contour) -> Layout {
+    Layout::from_size_align(contour.size(), contour.align()).expect("Contour has a bad layout")
+}
+
+/// Uninitialized memory for a value of `contour`'s type.  Zero-sized types
+/// get a dangling pointer, like `Box` gives them.
+unsafe fn allocate(contour: &Contour) -> *mut u8 {
+    if contour.size() == 0 {
+        return contour.align() as *mut u8;
+    }
+    let layout = layout(contour);
+    let ptr = alloc::alloc(layout);
+    if ptr.is_null() {
+        alloc::handle_alloc_error(layout);
+    }
+    ptr
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::rc::Rc;
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        Registry,
+        StructField,
+    };
+    use super::*;
+
+    #[derive(Clone, Debug, Default, Introspectable, PartialEq)]
+    #[contour(hooks(default, clone))]
+    struct Session {
+        user: String,
+        ids: Vec<u32>,
+        shared: Rc<u8>,
+    }
+
+    #[derive(Introspectable)]
+    struct Handle {
+        fd: i32,
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let registry = Registry::of::<Session>();
+        let type_id = TypeId::of::<Session>();
+        let fresh = unsafe { Boxed::new_default(&registry, type_id) }.unwrap();
+        assert_eq!(*fresh.downcast::<Session>().unwrap(), Session::default());
+
+        let session = Session { user: "ann".to_owned(), ids: vec![3, 4], shared: Rc::new(7) };
+        let copy = unsafe {
+            Boxed::clone_from(&registry, type_id, &session as *const Session as *const u8)
+        }.unwrap();
+        let copy2 = copy.try_clone().unwrap();
+        assert_eq!(Rc::strong_count(&session.shared), 3);
+        drop(copy);
+        assert_eq!(Rc::strong_count(&session.shared), 2);
+        let copy2 = copy2.downcast::<u8>().unwrap_err().downcast::<Session>().unwrap();
+        assert_eq!(*copy2, session);
+    }
+
+    #[test]
+    fn test_missing_hooks() {
+        let registry = Registry::of::<Handle>();
+        assert!(unsafe { Boxed::new_default(&registry, TypeId::of::<Handle>()) }.is_none());
+        let n = unsafe { Boxed::new_default(&Registry::of::<u64>(), TypeId::of::<u64>()) };
+        let n = n.unwrap();
+        assert_eq!(*n.downcast::<u64>().unwrap(), 0);
+    }
+}
@@ -0,0 +1,423 @@
+//! C declarations for types whose layout C can share.
+//!
+//! `#[repr(C)]` structs become C structs with the same field names, with
+//! every byte of padding spelled out as a `_padN` member and `static_assert`s
+//! on the size and each offset, so a header that's drifted from the Rust
+//! definitions fails to compile instead of misreading memory.
+//! `#[repr(transparent)]` structs become typedefs of their field, and
+//! fieldless enums with an integer repr become typedefs of that integer,
+//! without declaring the variants themselves.  Raw pointers become C
+//! pointers, to `void` if what they point to has no C equivalent.  Anything
+//! else is an error, as are two types with the same name, like two
+//! instantiations of a generic struct.
+
+use std::any::TypeId;
+use std::collections::{
+    HashMap,
+    HashSet,
+};
+use std::error;
+use std::fmt;
+use std::fmt::Write;
+
+use {
+    Contour,
+    ContourMap,
+    Introspectable,
+    Primitive,
+    Registry,
+    VariantFields,
+};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HeaderError {
+    /// The type or field, like `Frame.payload`, that can't be declared.
+    pub path: String,
+    pub msg: String,
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at `{}`: {}", self.path, self.msg)
+    }
+}
+
+impl error::Error for HeaderError {}
+
+/// A header declaring `T` and everything it needs, wrapped in an include
+/// guard named `guard`.
+pub fn header<T: Introspectable>(guard: &str) -> Result<String, HeaderError> {
+    let registry = Registry::of::<T>();
+    header_for(&registry, &[T::contour().type_id()], guard)
+}
+
+pub fn header_for(map: &dyn ContourMap, roots: &[TypeId], guard: &str)
+    -> Result<String, HeaderError>
+{
+    let mut gen = Gen {
+        map,
+        seen: HashSet::new(),
+        names: HashMap::new(),
+        decls: vec![],
+        structs: vec![],
+        pointees: vec![],
+    };
+    for &root in roots {
+        let name = gen.contour(root, "?")?.name();
+        gen.declare(root, name)?;
+        while let Some((pointee, path)) = gen.pointees.pop() {
+            gen.declare(pointee, &path)?;
+        }
+    }
+
+    let mut out = String::new();
+    out.push_str("/* Generated from Rust type definitions by contour.  Do not edit. */\n");
+    writeln!(out, "#ifndef {}\n#define {}\n", guard, guard).unwrap();
+    out.push_str("#include <assert.h>\n");
+    out.push_str("#include <stdbool.h>\n");
+    out.push_str("#include <stddef.h>\n");
+    out.push_str("#include <stdint.h>\n\n");
+    for name in &gen.structs {
+        writeln!(out, "typedef struct {} {};", name, name).unwrap();
+    }
+    if !gen.structs.is_empty() {
+        out.push('\n');
+    }
+    for decl in &gen.decls {
+        out.push_str(decl);
+        out.push('\n');
+    }
+    writeln!(out, "#endif /* {} */", guard).unwrap();
+    Ok(out)
+}
+
+struct Gen<'a> {
+    map: &'a dyn ContourMap,
+    seen: HashSet<TypeId>,
+    /// Which type each name declared so far belongs to.
+    names: HashMap<&'static str, TypeId>,
+    /// Definitions, each after everything it contains by value.
+    decls: Vec<String>,
+    /// Struct names, for forward declarations.
+    structs: Vec<&'static str>,
+    /// Structs reached through pointers, still to be defined.  The forward
+    /// declarations are enough for the pointers themselves, and defining
+    /// them on the spot could put them ahead of a struct they contain.
+    pointees: Vec<(TypeId, String)>,
+}
+
+impl<'a> Gen<'a> {
+    fn contour(&self, type_id: TypeId, path: &str) -> Result<Contour, HeaderError> {
+        self.map.lookup(type_id).ok_or_else(|| error(path, "type hasn't been charted"))
+    }
+
+    /// The C spelling of a field of type `type_id`.  Doesn't check that
+    /// anything it names can actually be declared; `declare` does that.
+    fn c_type(&self, type_id: TypeId, path: &str) -> Result<String, HeaderError> {
+        let contour = self.contour(type_id, path)?;
+        match contour {
+            Contour::Primitive { ref variant, .. } => match *variant {
+                Primitive::u8 => Ok("uint8_t"),
+                Primitive::u16 => Ok("uint16_t"),
+                Primitive::u32 => Ok("uint32_t"),
+                Primitive::u64 => Ok("uint64_t"),
+                Primitive::usize => Ok("uintptr_t"),
+                Primitive::i8 => Ok("int8_t"),
+                Primitive::i16 => Ok("int16_t"),
+                Primitive::i32 => Ok("int32_t"),
+                Primitive::i64 => Ok("int64_t"),
+                Primitive::isize => Ok("intptr_t"),
+                Primitive::f32 => Ok("float"),
+                Primitive::f64 => Ok("double"),
+                Primitive::bool => Ok("bool"),
+                // A Unicode scalar value, not a C `char`.
+                Primitive::char => Ok("uint32_t"),
+                Primitive::String => Err(error(path, "`String` has no C equivalent")),
+            }.map(|s| s.to_owned()),
+            Contour::Struct { name, ref repr, .. } |
+            Contour::Tuple { name, ref repr, .. } if repr.c || repr.transparent =>
+                Ok(name.to_owned()),
+            Contour::Enum { name, ref variants, ref repr, .. }
+                if (repr.c || repr.int.is_some()) &&
+                   variants.iter().all(|v| v.fields == VariantFields::Unit) =>
+                Ok(name.to_owned()),
+            Contour::Struct { name, .. } | Contour::Tuple { name, .. } =>
+                Err(error(path, &format!("`{}` isn't #[repr(C)]", name))),
+            Contour::Enum { name, ref repr, .. } if repr.c || repr.int.is_some() =>
+                Err(error(path, &format!("`{}` has variants with fields", name))),
+            Contour::Enum { name, .. } =>
+                Err(error(path, &format!("`{}` needs a #[repr] for its discriminant", name))),
+            Contour::Unit { name, .. } =>
+                Err(error(path, &format!("`{}` is zero-sized", name))),
+            Contour::Pointer { name, pointee, deref: None, .. } => {
+                let target = self.c_type(pointee, path).unwrap_or_else(|_| "void".to_owned());
+                if name == "*const" {
+                    Ok(format!("const {} *", target))
+                } else {
+                    Ok(format!("{} *", target))
+                }
+            },
+            ref c => Err(error(path, &format!("`{}` has no C equivalent", c.name()))),
+        }
+    }
+
+    /// Adds the definitions of `type_id` and whatever it depends on.
+    fn declare(&mut self, type_id: TypeId, path: &str) -> Result<(), HeaderError> {
+        if !self.seen.insert(type_id) {
+            return Ok(());
+        }
+        let contour = self.contour(type_id, path)?;
+        // Checks that the type can be spelled at all.
+        self.c_type(type_id, path)?;
+        match contour {
+            Contour::Struct { name, .. } | Contour::Tuple { name, .. } | Contour::Enum { name, .. }
+                if self.names.insert(name, type_id).is_some() =>
+                return Err(error(path, &format!("another type is also named `{}`", name))),
+            _ => {},
+        }
+        match contour {
+            Contour::Struct { name, size, ref fields, ref repr, .. } => {
+                let fields: Vec<_> = fields.iter()
+                    .map(|f| (f.name.to_owned(), f.type_id, f.offset))
+                    .collect();
+                self.declare_struct(name, size, repr.transparent, repr.packed, repr.align, fields)
+            },
+            Contour::Tuple { name, size, ref fields, ref repr, .. } => {
+                let fields: Vec<_> = fields.iter()
+                    .map(|f| (format!("_{}", f.ix), f.type_id, f.offset))
+                    .collect();
+                self.declare_struct(name, size, repr.transparent, repr.packed, repr.align, fields)
+            },
+            Contour::Enum { name, size, ref repr, .. } => {
+                let int = match repr.int {
+                    Some("usize") => "uintptr_t".to_owned(),
+                    Some("isize") => "intptr_t".to_owned(),
+                    Some(int) if int.starts_with('u') => format!("uint{}_t", 8 * size),
+                    Some(_) => format!("int{}_t", 8 * size),
+                    // A `repr(C)` enum is a C enum, which is an `int` in practice.
+                    None => "int".to_owned(),
+                };
+                self.decls.push(format!("typedef {} {};\n", int, name));
+                Ok(())
+            },
+            Contour::Pointer { pointee, .. } => {
+                // Pointees that can't be declared are left as `void`.
+                if self.c_type(pointee, path).is_err() {
+                    return Ok(());
+                }
+                match self.contour(pointee, path)? {
+                    Contour::Struct { ref repr, .. } |
+                    Contour::Tuple { ref repr, .. } if !repr.transparent =>
+                        self.pointees.push((pointee, path.to_owned())),
+                    _ => self.declare(pointee, path)?,
+                }
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn declare_struct(&mut self,
+                      name: &'static str,
+                      size: usize,
+                      transparent: bool,
+                      packed: Option<usize>,
+                      align: Option<usize>,
+                      fields: Vec<(String, TypeId, usize)>)
+        -> Result<(), HeaderError>
+    {
+        let mut members = vec![];
+        for &(ref fname, type_id, offset) in &fields {
+            let path = format!("{}.{}", name, fname);
+            self.declare(type_id, &path)?;
+            let c_type = self.c_type(type_id, &path)?;
+            let fsize = self.contour(type_id, &path)?.size();
+            members.push((fname.clone(), c_type, offset, fsize));
+        }
+
+        if transparent {
+            let inner = members.iter()
+                .find(|m| m.3 == size)
+                .ok_or_else(|| error(name, "no field as large as the struct"))?;
+            self.decls.push(format!("typedef {} {};\n", inner.1, name));
+            return Ok(());
+        }
+
+        members.sort_by_key(|m| m.2);
+        let mut def = String::new();
+        if let Some(n) = packed {
+            writeln!(def, "#pragma pack(push, {})", n).unwrap();
+        }
+        writeln!(def, "struct {} {{", name).unwrap();
+        let mut end = 0;
+        let mut pads = 0;
+        let mut pad = |def: &mut String, from: usize, to: usize| if to > from {
+            writeln!(def, "    uint8_t _pad{}[{}];", pads, to - from).unwrap();
+            pads += 1;
+        };
+        for &(ref fname, ref c_type, offset, fsize) in &members {
+            if offset < end {
+                return Err(error(&format!("{}.{}", name, fname), "overlaps the field before it"));
+            }
+            pad(&mut def, end, offset);
+            if c_type.ends_with('*') {
+                writeln!(def, "    {}{};", c_type, fname).unwrap();
+            } else {
+                writeln!(def, "    {} {};", c_type, fname).unwrap();
+            }
+            end = offset + fsize;
+        }
+        pad(&mut def, end, size);
+        match align {
+            Some(n) => writeln!(def, "}} __attribute__((aligned({})));", n).unwrap(),
+            None => def.push_str("};\n"),
+        }
+        if packed.is_some() {
+            def.push_str("#pragma pack(pop)\n");
+        }
+        writeln!(def, "static_assert(sizeof(struct {}) == {}, \"size of {}\");",
+                 name, size, name).unwrap();
+        for &(ref fname, _, offset, _) in &members {
+            writeln!(def, "static_assert(offsetof(struct {}, {}) == {}, \"offset of {}.{}\");",
+                     name, fname, offset, name, fname).unwrap();
+        }
+
+        self.structs.push(name);
+        self.decls.push(def);
+        Ok(())
+    }
+}
+
+fn error(path: &str, msg: &str) -> HeaderError {
+    HeaderError { path: path.to_owned(), msg: msg.to_owned() }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        StructField,
+        TupleField,
+        Variant,
+        VariantFields,
+    };
+    use super::*;
+
+    #[derive(Introspectable)]
+    #[repr(u8)]
+    enum Kind {
+        Data,
+        Ack,
+    }
+
+    #[derive(Introspectable)]
+    #[repr(transparent)]
+    struct Seq(u32);
+
+    #[derive(Introspectable)]
+    #[repr(C)]
+    struct Frame {
+        kind: Kind,
+        seq: Seq,
+        len: u64,
+        next: *mut Frame,
+        owner: *const String,
+        last: bool,
+    }
+
+    #[derive(Introspectable)]
+    #[repr(C)]
+    struct Bad {
+        frame: Frame,
+        name: String,
+    }
+
+    #[test]
+    fn test_header() {
+        let header = header::<Frame>("FRAME_H").unwrap();
+        let expected = "\
+/* Generated from Rust type definitions by contour.  Do not edit. */
+#ifndef FRAME_H
+#define FRAME_H
+
+#include <assert.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+typedef struct Frame Frame;
+
+typedef uint8_t Kind;
+
+typedef uint32_t Seq;
+
+struct Frame {
+    Kind kind;
+    uint8_t _pad0[3];
+    Seq seq;
+    uint64_t len;
+    Frame *next;
+    const void *owner;
+    bool last;
+    uint8_t _pad1[7];
+};
+static_assert(sizeof(struct Frame) == 40, \"size of Frame\");
+static_assert(offsetof(struct Frame, kind) == 0, \"offset of Frame.kind\");
+static_assert(offsetof(struct Frame, seq) == 4, \"offset of Frame.seq\");
+static_assert(offsetof(struct Frame, len) == 8, \"offset of Frame.len\");
+static_assert(offsetof(struct Frame, next) == 16, \"offset of Frame.next\");
+static_assert(offsetof(struct Frame, owner) == 24, \"offset of Frame.owner\");
+static_assert(offsetof(struct Frame, last) == 32, \"offset of Frame.last\");
+
+#endif /* FRAME_H */
+";
+        assert_eq!(header, expected);
+
+        let err = super::header::<Bad>("BAD_H").unwrap_err();
+        assert_eq!(err.to_string(), "at `Bad.name`: `String` has no C equivalent");
+    }
+
+    #[derive(Introspectable)]
+    #[repr(C)]
+    struct Node {
+        next: *mut Link,
+    }
+
+    #[derive(Introspectable)]
+    #[repr(C)]
+    struct Link {
+        node: Node,
+    }
+
+    #[derive(Introspectable)]
+    #[repr(C)]
+    struct Pair<T: Introspectable + 'static> {
+        a: T,
+        b: T,
+    }
+
+    #[derive(Introspectable)]
+    #[repr(C)]
+    struct Pairs {
+        small: Pair<u8>,
+        large: Pair<u32>,
+    }
+
+    #[test]
+    fn test_name_collision() {
+        let err = header::<Pairs>("PAIRS_H").unwrap_err();
+        assert_eq!(err.to_string(), "at `Pairs.large`: another type is also named `Pair`");
+    }
+
+    #[test]
+    fn test_pointee_order() {
+        let header = header::<Node>("NODE_H").unwrap();
+        assert!(header.contains("typedef struct Node Node;\ntypedef struct Link Link;\n"));
+        let node = header.find("struct Node {").unwrap();
+        let link = header.find("struct Link {").unwrap();
+        assert!(node < link, "{}", header);
+    }
+}
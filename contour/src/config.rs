@@ -0,0 +1,473 @@
+//! Building values out of flat key/value configuration.
+//!
+//! Keys are dotted paths through the type, like `server.port`, and every
+//! value starts out as a string that's parsed according to the type at its
+//! key:
+//!
+//! * Primitives parse the obvious way, with `bool`s also accepting
+//!   `yes`/`no`, `on`/`off` and `1`/`0`.
+//! * Enums are set to a variant by name (`server.mode=Tls`), and that
+//!   variant's fields then sit under the same key (`server.mode.cert=...`).
+//! * Sequences of leaf values are comma-separated.
+//! * Maps take their keys from the next path segment (`limits.conn=10`).
+//!
+//! The resulting value is loaded through `Load`, so anything that isn't set
+//! gets its default.  Every problem in the source is reported, not just the
+//! first one.
+
+use std::env;
+use std::error;
+use std::fmt;
+
+use load::{
+    load_value,
+    Load,
+    LoadError,
+};
+use schema::{
+    Fields,
+    Kind,
+    Schema,
+};
+use value::{
+    FieldValues,
+    Value,
+};
+use {
+    Introspectable,
+    Primitive,
+    PrimitiveValue,
+};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConfigError {
+    /// The key the problem is with, or empty for syntax errors in a source.
+    pub key: String,
+    pub msg: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "{}", self.msg)
+        } else {
+            write!(f, "at `{}`: {}", self.key, self.msg)
+        }
+    }
+}
+
+impl error::Error for ConfigError {}
+
+impl From<LoadError> for ConfigError {
+    fn from(e: LoadError) -> Self {
+        let (key, msg) = match e {
+            LoadError::Type { path, expected, found } =>
+                (path, format!("expected {}, found {}", expected, found)),
+            LoadError::Variant { path, name } => (path, format!("no variant named `{}`", name)),
+            LoadError::Pointer { path } => (path, "can't load a null pointer".to_owned()),
+            LoadError::Decode(e) => (String::new(), e.to_string()),
+        };
+        ConfigError { key, msg }
+    }
+}
+
+/// Builds a `T` out of `source`'s pairs.  Later pairs override earlier ones
+/// for the same key, so sources can be chained from least to most specific.
+pub fn populate<T, I, K, V>(source: I) -> Result<T, Vec<ConfigError>>
+    where T: Introspectable + Load,
+          I: IntoIterator<Item = (K, V)>,
+          K: AsRef<str>,
+          V: AsRef<str>
+{
+    let schema = Schema::of::<T>();
+    let mut root = Node::default();
+    for (key, raw) in source {
+        root.insert(key.as_ref(), raw.as_ref());
+    }
+    let mut builder = Builder { schema: &schema, errors: vec![] };
+    let value = builder.value(schema.root, &root, "");
+    match value {
+        Some(ref value) if builder.errors.is_empty() =>
+            load_value::<T>(value).map(|loaded| loaded.value).map_err(|e| vec![e.into()]),
+        _ => Err(builder.errors),
+    }
+}
+
+/// Pairs from the environment variables starting with `prefix`.  The rest of
+/// each name is lowercased, with `__` separating path segments, so
+/// `APP_SERVER__MAX_CONNS` with prefix `APP_` sets `server.max_conns`.
+/// Variables whose names aren't valid UTF-8 are skipped, and it's an error
+/// for a value to be invalid.
+pub fn env(prefix: &str) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut pairs = vec![];
+    for (name, raw) in env::vars_os() {
+        let name = match name.into_string() {
+            Ok(ref name) if name.starts_with(prefix) && name.len() > prefix.len() =>
+                name[prefix.len()..].to_lowercase().replace("__", "."),
+            _ => continue,
+        };
+        match raw.into_string() {
+            Ok(raw) => pairs.push((name, raw)),
+            Err(_) => return Err(ConfigError { key: name, msg: "isn't valid UTF-8".to_owned() }),
+        }
+    }
+    Ok(pairs)
+}
+
+/// Pairs from an INI file, or a TOML file that only uses tables and scalar
+/// values.  `[section]` headers prefix the keys after them, and values may be
+/// quoted.
+pub fn ini(text: &str) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut section = String::new();
+    let mut pairs = vec![];
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line[1..line.len() - 1].trim().to_owned();
+            continue;
+        }
+        let (key, raw) = match line.find('=') {
+            Some(eq) => (line[..eq].trim(), line[eq + 1..].trim()),
+            None => return Err(ConfigError {
+                key: String::new(),
+                msg: format!("line {}: expected `key = value`", n + 1),
+            }),
+        };
+        let raw = if raw.len() >= 2 && (raw.starts_with('"') && raw.ends_with('"') ||
+                                        raw.starts_with('\'') && raw.ends_with('\'')) {
+            &raw[1..raw.len() - 1]
+        } else {
+            raw
+        };
+        let key = if section.is_empty() { key.to_owned() } else { format!("{}.{}", section, key) };
+        pairs.push((key, raw.to_owned()));
+    }
+    Ok(pairs)
+}
+
+/// Pairs from `--set key=value` and `--set=key=value` command-line flags.
+/// Other arguments are left for the caller to deal with.
+pub fn args<I: IntoIterator<Item = String>>(args: I) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut pairs = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let set = if arg == "--set" {
+            args.next()
+        } else if let Some(set) = arg.strip_prefix("--set=") {
+            Some(set.to_owned())
+        } else {
+            continue;
+        };
+        match set.as_ref().and_then(|s| s.find('=').map(|eq| (&s[..eq], &s[eq + 1..]))) {
+            Some((key, raw)) => pairs.push((key.to_owned(), raw.to_owned())),
+            None => return Err(ConfigError {
+                key: String::new(),
+                msg: "`--set` takes `key=value`".to_owned(),
+            }),
+        }
+    }
+    Ok(pairs)
+}
+
+/// The keys in a source, arranged by path segment.
+#[derive(Default)]
+struct Node {
+    raw: Option<String>,
+    children: Vec<(String, Node)>,
+}
+
+impl Node {
+    fn insert(&mut self, key: &str, raw: &str) {
+        let mut node = self;
+        for segment in key.split('.') {
+            let ix = match node.children.iter().position(|c| c.0 == segment) {
+                Some(ix) => ix,
+                None => {
+                    node.children.push((segment.to_owned(), Node::default()));
+                    node.children.len() - 1
+                },
+            };
+            node = &mut {node}.children[ix].1;
+        }
+        node.raw = Some(raw.to_owned());
+    }
+}
+
+struct Builder<'a> {
+    schema: &'a Schema,
+    errors: Vec<ConfigError>,
+}
+
+impl<'a> Builder<'a> {
+    fn error<T>(&mut self, key: &str, msg: String) -> Option<T> {
+        self.errors.push(ConfigError { key: key.to_owned(), msg });
+        None
+    }
+
+    /// The value of type `ty` that `node`, at `key`, describes.
+    fn value(&mut self, ty: usize, node: &Node, key: &str) -> Option<Value> {
+        let schema = self.schema;
+        let desc = &schema.types[ty];
+        match desc.kind {
+            Kind::Struct(ref fields) => {
+                if node.raw.is_some() {
+                    return self.error(key, format!("`{}` can't be set directly, only its fields",
+                                                   desc.name));
+                }
+                let fields = self.fields(fields, node, key)?;
+                Some(Value::Struct { name: desc.name.clone(), fields })
+            },
+            Kind::Enum(ref variants) => {
+                let raw = match node.raw {
+                    Some(ref raw) => raw,
+                    None => return self.error(key, "set this to pick a variant".to_owned()),
+                };
+                let variant = match variants.iter().find(|v| v.name == *raw) {
+                    Some(variant) => variant,
+                    None => {
+                        let names: Vec<_> = variants.iter().map(|v| &v.name[..]).collect();
+                        return self.error(key, format!("no variant named `{}`, expected one of {}",
+                                                       raw, names.join(", ")));
+                    },
+                };
+                let fields = self.fields(&variant.fields, node, key)?;
+                Some(Value::Enum {
+                    name: desc.name.clone(),
+                    variant: variant.name.clone(),
+                    fields,
+                })
+            },
+            Kind::Pointer { pointee, .. } => self.value(pointee, node, key),
+            Kind::Map { key: k, value: v } => {
+                if node.raw.is_some() {
+                    return self.error(key, "maps can't be set directly, only their entries"
+                                          .to_owned());
+                }
+                let mut entries = vec![];
+                let mut ok = true;
+                for (segment, child) in &node.children {
+                    let child_key = join(key, segment);
+                    match (self.leaf(k, segment, &child_key), self.value(v, child, &child_key)) {
+                        (Some(k), Some(v)) => entries.push((k, v)),
+                        _ => ok = false,
+                    }
+                }
+                if ok { Some(Value::Map(entries)) } else { None }
+            },
+            Kind::Primitive(..) | Kind::Seq { .. } => {
+                if let Some((segment, _)) = node.children.first() {
+                    return self.error(&join(key, segment), format!("`{}` has no fields", key));
+                }
+                match node.raw {
+                    Some(ref raw) => self.leaf(ty, raw, key),
+                    // Only the root can be left unset, since other keys
+                    // only exist if something sets them.
+                    None => self.error(key, format!("nothing sets this `{}`", desc.name)),
+                }
+            },
+        }
+    }
+
+    /// Only the fields that `node` sets; `Load` defaults the rest.  Tuple
+    /// fields are named by index, which `FieldValues::get` also finds.
+    fn fields(&mut self, fields: &Fields, node: &Node, key: &str) -> Option<FieldValues> {
+        if let Fields::Unit = *fields {
+            if let Some((segment, _)) = node.children.first() {
+                return self.error(&join(key, segment), format!("`{}` has no fields", key));
+            }
+            return Some(FieldValues::Unit);
+        }
+        let mut values = vec![];
+        let mut ok = true;
+        for (segment, child) in &node.children {
+            let child_key = join(key, segment);
+            match fields.iter().find(|f| f.name == *segment) {
+                Some(field) => match self.value(field.ty, child, &child_key) {
+                    Some(value) => values.push((field.name.clone(), value)),
+                    None => ok = false,
+                },
+                None => {
+                    self.error::<()>(&child_key, format!("no field named `{}`", segment));
+                    ok = false;
+                },
+            }
+        }
+        if ok { Some(FieldValues::Named(values)) } else { None }
+    }
+
+    /// Parses a value of type `ty` out of a single string.
+    fn leaf(&mut self, ty: usize, raw: &str, key: &str) -> Option<Value> {
+        let schema = self.schema;
+        let desc = &schema.types[ty];
+        match desc.kind {
+            Kind::Primitive(ref p) => match parse(p, raw) {
+                Ok(v) => Some(Value::Primitive(v)),
+                Err(msg) => self.error(key, msg),
+            },
+            Kind::Seq { element } => {
+                let items: Vec<_> = raw.split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(|item| self.leaf(element, item, key))
+                    .collect();
+                items.into_iter().collect::<Option<_>>().map(Value::Seq)
+            },
+            Kind::Pointer { pointee, .. } => self.leaf(pointee, raw, key),
+            Kind::Enum(..) => self.value(ty, &Node { raw: Some(raw.to_owned()), children: vec![] },
+                                         key),
+            _ => self.error(key, format!("`{}` can't be set from a string", desc.name)),
+        }
+    }
+}
+
+fn join(key: &str, segment: &str) -> String {
+    if key.is_empty() { segment.to_owned() } else { format!("{}.{}", key, segment) }
+}
+
+macro_rules! parse_primitive {
+    ($kind:expr, $raw:expr, $($n:ident),*) => {
+        match *$kind {
+            $(Primitive::$n => $raw.parse::<$n>()
+                .map(PrimitiveValue::$n)
+                .map_err(|_| format!("`{}` isn't a valid {}", $raw, stringify!($n))),)*
+            Primitive::bool => match $raw {
+                "true" | "yes" | "on" | "1" => Ok(PrimitiveValue::bool(true)),
+                "false" | "no" | "off" | "0" => Ok(PrimitiveValue::bool(false)),
+                _ => Err(format!("`{}` isn't a valid bool", $raw)),
+            },
+            Primitive::char => {
+                let mut chars = $raw.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Ok(PrimitiveValue::char(c)),
+                    _ => Err(format!("`{}` isn't a single char", $raw)),
+                }
+            },
+            Primitive::String => Ok(PrimitiveValue::String($raw.to_owned())),
+        }
+    };
+}
+
+fn parse(kind: &Primitive, raw: &str) -> Result<PrimitiveValue, String> {
+    parse_primitive!(kind, raw, u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, f32, f64)
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::collections::BTreeMap;
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        StructField,
+        Variant,
+        VariantFields,
+    };
+    use super::*;
+
+    #[derive(Debug, Introspectable, Load, PartialEq)]
+    enum Mode {
+        Plain,
+        Tls { cert: String, verify: bool },
+    }
+
+    impl Default for Mode {
+        fn default() -> Self {
+            Mode::Plain
+        }
+    }
+
+    #[derive(Debug, Default, Introspectable, Load, PartialEq)]
+    struct Server {
+        host: String,
+        #[contour(default = 80)]
+        port: u16,
+        mode: Mode,
+        tags: Vec<String>,
+        limits: BTreeMap<String, u32>,
+    }
+
+    #[derive(Debug, Introspectable, Load, PartialEq)]
+    struct Settings {
+        server: Server,
+        workers: Box<u8>,
+        ratio: f64,
+    }
+
+    #[test]
+    fn test_populate() {
+        let mut pairs = ini("# defaults\nratio = 0.5\n\n[server]\nhost = \"example.com\"\n\
+                             port = 8080\ntags = a, b\n").unwrap();
+        pairs.extend(args(vec!["-v", "--set", "server.mode=Tls", "--set=server.mode.cert=c.pem",
+                               "--set", "server.limits.conn=10"]
+                          .into_iter().map(String::from)).unwrap());
+        let settings: Settings = populate(pairs).unwrap();
+
+        let mut limits = BTreeMap::new();
+        limits.insert("conn".to_owned(), 10);
+        assert_eq!(settings, Settings {
+            server: Server {
+                host: "example.com".to_owned(),
+                port: 8080,
+                mode: Mode::Tls { cert: "c.pem".to_owned(), verify: false },
+                tags: vec!["a".to_owned(), "b".to_owned()],
+                limits: limits,
+            },
+            workers: Box::new(0),
+            ratio: 0.5,
+        });
+        let settings: Settings = populate(vec![("server.host", "example.com")]).unwrap();
+        assert_eq!((settings.server.port, settings.server.mode), (80, Mode::Plain));
+    }
+
+    #[test]
+    fn test_errors() {
+        let errors = populate::<Settings, _, _, _>(vec![
+            ("server.port", "99999"),
+            ("server.mode", "Quic"),
+            ("server.hots", "x"),
+            ("workers", "many"),
+            ("ratio.x", "1"),
+        ]).unwrap_err();
+        let errors: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors, vec![
+            "at `server.port`: `99999` isn't a valid u16",
+            "at `server.mode`: no variant named `Quic`, expected one of Plain, Tls",
+            "at `server.hots`: no field named `hots`",
+            "at `workers`: `many` isn't a valid u8",
+            "at `ratio.x`: `ratio` has no fields",
+        ]);
+        assert_eq!(ini("[server]\nport").unwrap_err().to_string(),
+                   "line 2: expected `key = value`");
+        let errors = populate::<u32, _, &str, &str>(vec![]).unwrap_err();
+        assert_eq!(errors, vec![ConfigError {
+            key: String::new(),
+            msg: "nothing sets this `u32`".to_owned(),
+        }]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_env() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let host = OsStr::new("CONTOUR_TEST_SERVER__HOST");
+        let port = OsStr::new("CONTOUR_TEST_SERVER__PORT");
+        let bad = OsStr::from_bytes(b"CONTOUR_TEST_\xff");
+        env::set_var(host, "example.com");
+        env::set_var(bad, "x");
+        let valid = super::env("CONTOUR_TEST_");
+        env::set_var(port, OsStr::from_bytes(b"80\xff"));
+        let invalid = super::env("CONTOUR_TEST_");
+        for name in &[host, port, bad] {
+            env::remove_var(name);
+        }
+
+        assert_eq!(valid.unwrap(), vec![("server.host".to_owned(), "example.com".to_owned())]);
+        assert_eq!(invalid.unwrap_err().to_string(), "at `server.port`: isn't valid UTF-8");
+    }
+}
@@ -0,0 +1,426 @@
+//! Structural comparison of two values of the same type.
+
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::fmt;
+
+use path::{
+    Key,
+    Path,
+    Segment,
+};
+use {
+    json,
+    raw_address,
+    Contour,
+    ContourMap,
+    Introspectable,
+    PrimitiveValue,
+    Registry,
+    StructField,
+    TupleField,
+    VariantFields,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    /// A primitive leaf differs.
+    Value { path: Path, before: PrimitiveValue, after: PrimitiveValue },
+    /// An enum switched variants.  Nothing underneath it is compared.
+    Variant { path: Path, before: &'static str, after: &'static str },
+    /// A sequence or map changed size.  Elements they have in common are
+    /// still compared.
+    Length { path: Path, before: usize, after: usize },
+    /// A map entry or pointer target only exists afterwards.
+    Added { path: Path },
+    /// A map entry or pointer target only existed before.
+    Removed { path: Path },
+}
+
+impl Change {
+    pub fn path(&self) -> &Path {
+        match *self {
+            Change::Value { ref path, .. } => path,
+            Change::Variant { ref path, .. } => path,
+            Change::Length { ref path, .. } => path,
+            Change::Added { ref path } => path,
+            Change::Removed { ref path } => path,
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Change::Value { ref path, ref before, ref after } =>
+                write!(f, "{}: {} -> {}", Root(path), before, after),
+            Change::Variant { ref path, before, after } =>
+                write!(f, "{}: {} -> {}", Root(path), before, after),
+            Change::Length { ref path, before, after } =>
+                write!(f, "{}: len {} -> {}", Root(path), before, after),
+            Change::Added { ref path } => write!(f, "{}: added", Root(path)),
+            Change::Removed { ref path } => write!(f, "{}: removed", Root(path)),
+        }
+    }
+}
+
+/// Shows the empty path as `.` so it's still visible in a listing.
+struct Root<'a>(&'a Path);
+
+impl<'a> fmt::Display for Root<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(f, ".")
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+/// The changes between two values, in the order they were found.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Diff {
+    pub changes: Vec<Change>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Renders the changes as a JSON array of objects, each with an `op` of
+    /// `value`, `variant`, `length`, `added` or `removed`, a `path`, and
+    /// `before` and `after` where they apply.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("[");
+        for (i, change) in self.changes.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let op = match *change {
+                Change::Value {..} => "value",
+                Change::Variant {..} => "variant",
+                Change::Length {..} => "length",
+                Change::Added {..} => "added",
+                Change::Removed {..} => "removed",
+            };
+            out.push_str("{\"op\":");
+            json::string(op, &mut out);
+            out.push_str(",\"path\":");
+            json::string(&change.path().to_string(), &mut out);
+            match *change {
+                Change::Value { ref before, ref after, .. } => {
+                    out.push_str(",\"before\":");
+                    json::primitive(before, &mut out);
+                    out.push_str(",\"after\":");
+                    json::primitive(after, &mut out);
+                },
+                Change::Variant { before, after, .. } => {
+                    out.push_str(",\"before\":");
+                    json::string(before, &mut out);
+                    out.push_str(",\"after\":");
+                    json::string(after, &mut out);
+                },
+                Change::Length { before, after, .. } => {
+                    out.push_str(&format!(",\"before\":{},\"after\":{}", before, after));
+                },
+                Change::Added {..} | Change::Removed {..} => (),
+            }
+            out.push('}');
+        }
+        out.push(']');
+        out
+    }
+}
+
+/// One change per line.
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for change in &self.changes {
+            writeln!(f, "{}", change)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares `a` against `b`, treating `a` as "before".
+pub fn diff_values<T: Introspectable>(a: &T, b: &T) -> Diff {
+    let registry = Registry::of::<T>();
+    let type_id = T::contour().type_id();
+    unsafe {
+        diff_ptrs(&registry,
+                  type_id,
+                  a as *const T as *const u8,
+                  b as *const T as *const u8)
+    }
+}
+
+/// Compares two values of type `type_id`, which must already be charted into
+/// `map`.
+///
+/// # Safety
+///
+/// See `Walker::walk` for the requirements on `a` and `b`.
+pub unsafe fn diff_ptrs(map: &dyn ContourMap, type_id: TypeId, a: *const u8, b: *const u8) -> Diff {
+    let mut differ = Differ {
+        map,
+        path: Path::root(),
+        active: vec![],
+        diff: Diff::default(),
+    };
+    differ.value(type_id, a, b);
+    differ.diff
+}
+
+struct Differ<'a> {
+    map: &'a dyn ContourMap,
+    path: Path,
+    /// Pointer targets we're underneath, so cyclic graphs terminate.
+    active: Vec<(TypeId, *const u8, *const u8)>,
+    diff: Diff,
+}
+
+impl<'a> Differ<'a> {
+    fn push(&mut self, change: Change) {
+        self.diff.changes.push(change);
+    }
+
+    unsafe fn at(&mut self, segment: Segment, type_id: TypeId, a: *const u8, b: *const u8) {
+        self.path.push(segment);
+        self.value(type_id, a, b);
+        self.path.pop();
+    }
+
+    unsafe fn value(&mut self, type_id: TypeId, a: *const u8, b: *const u8) {
+        if a == b {
+            return;
+        }
+        let contour = match self.map.lookup(type_id) {
+            Some(contour) => contour,
+            None => panic!("No contour for {:?}", type_id),
+        };
+        match contour {
+            Contour::Struct { ref fields, .. } => self.struct_fields(fields, a, b),
+            Contour::Tuple { ref fields, .. } => self.tuple_fields(fields, a, b),
+            Contour::Unit {..} => (),
+            Contour::Enum { ref variants, tag, .. } => {
+                let (va, vb) = (&variants[tag(a)], &variants[tag(b)]);
+                if va.name != vb.name {
+                    let path = self.path.clone();
+                    self.push(Change::Variant { path, before: va.name, after: vb.name });
+                    return;
+                }
+                self.path.push(Segment::Variant(va.name.to_owned()));
+                match va.fields {
+                    VariantFields::Struct(ref fields) => self.struct_fields(fields, a, b),
+                    VariantFields::Tuple(ref fields) => self.tuple_fields(fields, a, b),
+                    VariantFields::Unit => (),
+                }
+                self.path.pop();
+            },
+            Contour::Primitive { ref variant, atomic, .. } => {
+                let (before, after) = (variant.load(a, atomic), variant.load(b, atomic));
+                if !same(&before, &after) {
+                    let path = self.path.clone();
+                    self.push(Change::Value { path, before, after });
+                }
+            },
+            Contour::Seq { element, len, item, .. } => {
+                let (na, nb) = (len(a), len(b));
+                if na != nb {
+                    let path = self.path.clone();
+                    self.push(Change::Length { path, before: na, after: nb });
+                }
+                for ix in 0..na.min(nb) {
+                    let segment = Segment::Index(Key::Int(ix as i64));
+                    self.at(segment, element, item(a, ix), item(b, ix));
+                }
+            },
+            // Raw pointers aren't followed, so only their addresses are
+            // compared.
+            Contour::Pointer { pointee, deref, .. } => {
+                let (ta, tb) = match deref {
+                    Some(deref) => (deref(a), deref(b)),
+                    None => (raw_address(a), raw_address(b)),
+                };
+                let path = self.path.clone();
+                match (ta.is_null(), tb.is_null()) {
+                    (true, true) => (),
+                    (true, false) => self.push(Change::Added { path }),
+                    (false, true) => self.push(Change::Removed { path }),
+                    (false, false) if deref.is_none() => if ta != tb {
+                        let before = PrimitiveValue::usize(ta as usize);
+                        let after = PrimitiveValue::usize(tb as usize);
+                        self.push(Change::Value { path, before, after });
+                    },
+                    (false, false) => {
+                        let key = (pointee, ta, tb);
+                        if !self.active.contains(&key) {
+                            self.active.push(key);
+                            self.value(pointee, ta, tb);
+                            self.active.pop();
+                        }
+                    },
+                }
+            },
+            Contour::Map { key, value, len, entries, .. } => {
+                let (na, nb) = (len(a), len(b));
+                if na != nb {
+                    let path = self.path.clone();
+                    self.push(Change::Length { path, before: na, after: nb });
+                }
+                let (ea, eb) = match (self.entries(key, entries, a), self.entries(key, entries, b)) {
+                    (Some(ea), Some(eb)) => (ea, eb),
+                    // Without primitive keys there's no telling which entries
+                    // correspond, so the length is all we can report.
+                    _ => return,
+                };
+
+                let mut keys: Vec<&Key> = ea.keys().chain(eb.keys().filter(|k| !ea.contains_key(k)))
+                    .collect();
+                keys.sort_by_key(|k| k.to_string());
+                for k in keys {
+                    let path = self.path.child(Segment::Index(k.clone()));
+                    match (ea.get(k), eb.get(k)) {
+                        (Some(&va), Some(&vb)) => self.at(Segment::Index(k.clone()), value, va, vb),
+                        (None, Some(_)) => self.push(Change::Added { path }),
+                        (Some(_), None) => self.push(Change::Removed { path }),
+                        (None, None) => unreachable!(),
+                    }
+                }
+            },
+        }
+    }
+
+    unsafe fn struct_fields(&mut self, fields: &[StructField], a: *const u8, b: *const u8) {
+        for field in fields {
+            let offset = field.offset as isize;
+            let segment = Segment::Field(field.name.to_owned());
+            self.at(segment, field.type_id, a.offset(offset), b.offset(offset));
+        }
+    }
+
+    unsafe fn tuple_fields(&mut self, fields: &[TupleField], a: *const u8, b: *const u8) {
+        for field in fields {
+            let offset = field.offset as isize;
+            self.at(Segment::Tuple(field.ix), field.type_id, a.offset(offset), b.offset(offset));
+        }
+    }
+
+    /// Indexes a map's values by key, if its keys are primitives that `Key`
+    /// can represent.
+    unsafe fn entries(&self,
+                      key: TypeId,
+                      entries: unsafe fn(*const u8, &mut dyn FnMut(*const u8, *const u8)),
+                      ptr: *const u8)
+        -> Option<HashMap<Key, *const u8>>
+    {
+        let kind = match self.map.lookup(key) {
+            Some(Contour::Primitive { variant, .. }) => variant,
+            _ => return None,
+        };
+        let mut out = HashMap::new();
+        let mut ok = true;
+        entries(ptr, &mut |k, v| match Key::from_value(&kind.read(k)) {
+            Some(k) => {
+                out.insert(k, v);
+            },
+            None => ok = false,
+        });
+        if ok { Some(out) } else { None }
+    }
+}
+
+/// Like `==`, except that NaNs equal themselves so they don't show up as
+/// spurious changes.
+fn same(a: &PrimitiveValue, b: &PrimitiveValue) -> bool {
+    match (a, b) {
+        (&PrimitiveValue::f32(x), &PrimitiveValue::f32(y)) => x == y || (x.is_nan() && y.is_nan()),
+        (&PrimitiveValue::f64(x), &PrimitiveValue::f64(y)) => x == y || (x.is_nan() && y.is_nan()),
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::collections::BTreeMap;
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        StructField,
+        Variant,
+        VariantFields,
+    };
+    use super::*;
+
+    #[derive(Introspectable)]
+    enum State {
+        Idle,
+        Running { pid: u32 },
+    }
+
+    #[derive(Introspectable)]
+    struct Conn {
+        port: u16,
+        state: State,
+    }
+
+    #[derive(Introspectable)]
+    struct Server {
+        name: String,
+        load: f64,
+        conns: Vec<Conn>,
+        limits: BTreeMap<String, u32>,
+    }
+
+    fn server() -> Server {
+        let mut limits = BTreeMap::new();
+        limits.insert("cpu".to_owned(), 4);
+        limits.insert("mem".to_owned(), 1024);
+        Server {
+            name: "db".to_owned(),
+            load: f64::NAN,
+            conns: vec![
+                Conn { port: 1, state: State::Idle },
+                Conn { port: 2, state: State::Running { pid: 10 } },
+            ],
+            limits: limits,
+        }
+    }
+
+    #[test]
+    fn test_identical() {
+        assert!(diff_values(&server(), &server()).is_empty());
+    }
+
+    #[test]
+    fn test_changes() {
+        let before = server();
+        let mut after = server();
+        after.name = "db2".to_owned();
+        after.conns[0].state = State::Running { pid: 3 };
+        after.conns[1].state = State::Running { pid: 11 };
+        after.conns.push(Conn { port: 3, state: State::Idle });
+        after.limits.remove("cpu");
+        after.limits.insert("disk".to_owned(), 7);
+
+        let diff = diff_values(&before, &after);
+        assert_eq!(diff.to_string(), "\
+name: \"db\" -> \"db2\"
+conns: len 2 -> 3
+conns[0].state: Idle -> Running
+conns[1].state::Running.pid: 10 -> 11
+limits[\"cpu\"]: removed
+limits[\"disk\"]: added
+");
+        assert_eq!(diff.changes[0].path().to_string(), "name");
+        assert_eq!(
+            Diff { changes: diff.changes[..3].to_vec() }.to_json(),
+            "[{\"op\":\"value\",\"path\":\"name\",\"before\":\"db\",\"after\":\"db2\"},\
+             {\"op\":\"length\",\"path\":\"conns\",\"before\":2,\"after\":3},\
+             {\"op\":\"variant\",\"path\":\"conns[0].state\",\"before\":\"Idle\",\"after\":\"Running\"}]"
+        );
+    }
+}
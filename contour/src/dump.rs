@@ -0,0 +1,237 @@
+//! Memory images of dead processes, for reading values out of with
+//! `remote::read_anchor`.
+//!
+//! The addresses in an `Anchor` the process published while it was alive
+//! are still good in its core file, since a core records the process's
+//! address space as it was.
+
+use std::cmp;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use remote::Memory;
+use schema::DecodeError;
+
+/// An ELF core file, as written by the kernel or `gcore`.
+pub struct CoreDump {
+    data: Vec<u8>,
+    segments: Vec<Segment>,
+}
+
+/// A `PT_LOAD` segment: `len` bytes at `vaddr`, stored at `offset`.
+struct Segment {
+    vaddr: usize,
+    offset: usize,
+    len: usize,
+}
+
+const PT_LOAD: u64 = 1;
+const ET_CORE: u64 = 4;
+
+impl CoreDump {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        CoreDump::parse(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Only 64-bit little-endian cores are understood.  Segments that run
+    /// past the end of a truncated file are cut short rather than rejected,
+    /// so what did get written can still be read.
+    pub fn parse(data: Vec<u8>) -> Result<Self, DecodeError> {
+        if data.len() < 0x40 || &data[..4] != b"\x7fELF" {
+            return error(0, "not an ELF file");
+        }
+        if data[4] != 2 || data[5] != 1 {
+            return error(4, "not a 64-bit little-endian ELF file");
+        }
+        if read(&data, 0x10, 2)? != ET_CORE {
+            return error(0x10, "not a core file");
+        }
+        let phoff = read(&data, 0x20, 8)? as usize;
+        let phentsize = read(&data, 0x36, 2)? as usize;
+        let phnum = read(&data, 0x38, 2)? as usize;
+        if phnum == 0xffff {
+            return error(0x38, "too many segments");
+        }
+        let mut segments = vec![];
+        for i in 0..phnum {
+            let ph = match i.checked_mul(phentsize).and_then(|o| o.checked_add(phoff)) {
+                Some(ph) => ph,
+                None => return error(0x20, "segment table runs past the end of memory"),
+            };
+            if read(&data, ph, 4)? != PT_LOAD {
+                continue;
+            }
+            let offset = read(&data, ph + 8, 8)? as usize;
+            let len = read(&data, ph + 32, 8)? as usize;
+            segments.push(Segment {
+                vaddr: read(&data, ph + 16, 8)? as usize,
+                offset,
+                len: cmp::min(len, data.len().saturating_sub(offset)),
+            });
+        }
+        Ok(CoreDump { data, segments })
+    }
+}
+
+impl Memory for CoreDump {
+    fn read(&self, addr: usize, buf: &mut [u8]) -> io::Result<()> {
+        // A read can straddle adjacent segments.
+        let mut done = 0;
+        while done < buf.len() {
+            let at = addr.checked_add(done).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound,
+                               format!("{:#x} runs past the end of memory", addr))
+            })?;
+            let found = self.segments.iter().find(|s| s.vaddr <= at && at - s.vaddr < s.len);
+            let segment = match found {
+                Some(segment) => segment,
+                None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                                  format!("{:#x} isn't in the core", at))),
+            };
+            let start = segment.offset + (at - segment.vaddr);
+            let n = cmp::min(buf.len() - done, segment.len - (at - segment.vaddr));
+            buf[done..done + n].copy_from_slice(&self.data[start..start + n]);
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+/// A single range of memory that was copied out starting at `base`.
+pub struct RawDump {
+    pub base: usize,
+    pub bytes: Vec<u8>,
+}
+
+impl RawDump {
+    pub fn new(base: usize, bytes: Vec<u8>) -> Self {
+        RawDump { base, bytes }
+    }
+}
+
+impl Memory for RawDump {
+    fn read(&self, addr: usize, buf: &mut [u8]) -> io::Result<()> {
+        let start = addr.wrapping_sub(self.base);
+        let end = start.checked_add(buf.len());
+        if addr < self.base || end.is_none_or(|end| end > self.bytes.len()) {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("{:#x} isn't in the dump", addr)));
+        }
+        buf.copy_from_slice(&self.bytes[start..start + buf.len()]);
+        Ok(())
+    }
+}
+
+fn error<T>(offset: usize, msg: &str) -> Result<T, DecodeError> {
+    Err(DecodeError { offset, msg: msg.to_owned() })
+}
+
+/// Reads a little-endian integer of `width` bytes at `offset`.
+fn read(data: &[u8], offset: usize, width: usize) -> Result<u64, DecodeError> {
+    match offset.checked_add(width).and_then(|end| data.get(offset..end)) {
+        Some(bytes) => Ok(bytes.iter().rev().fold(0, |v, b| v << 8 | *b as u64)),
+        None => error(offset, "unexpected end of file"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::slice;
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        PrimitiveValue,
+        StructField,
+    };
+    use remote::{
+        read_anchor,
+        Anchor,
+    };
+    use snapshot::{
+        snapshot,
+        Snapshot,
+    };
+    use value::Value;
+    use super::*;
+
+    #[derive(Introspectable)]
+    struct ServerState {
+        requests: u64,
+        motd: String,
+    }
+
+    fn bytes_of<T>(value: &T) -> Vec<u8> {
+        let ptr = value as *const T as *const u8;
+        unsafe { slice::from_raw_parts(ptr, ::std::mem::size_of::<T>()) }.to_vec()
+    }
+
+    /// Writes a core holding each `(vaddr, bytes)` as a segment.
+    fn core(segments: &[(usize, Vec<u8>)]) -> Vec<u8> {
+        fn put(out: &mut Vec<u8>, v: u64, width: usize) {
+            for i in 0..width {
+                out.push((v >> (8 * i)) as u8);
+            }
+        }
+        let mut out = b"\x7fELF\x02\x01\x01".to_vec();
+        out.resize(0x10, 0);
+        put(&mut out, ET_CORE, 2);
+        out.resize(0x20, 0);
+        put(&mut out, 0x40, 8);
+        out.resize(0x36, 0);
+        put(&mut out, 56, 2);
+        put(&mut out, segments.len() as u64, 2);
+        out.resize(0x40, 0);
+        let mut offset = 0x40 + 56 * segments.len();
+        for &(vaddr, ref bytes) in segments {
+            put(&mut out, PT_LOAD, 4);
+            put(&mut out, 0, 4);
+            put(&mut out, offset as u64, 8);
+            put(&mut out, vaddr as u64, 8);
+            put(&mut out, 0, 8);
+            put(&mut out, bytes.len() as u64, 8);
+            put(&mut out, bytes.len() as u64, 8);
+            put(&mut out, 1, 8);
+            offset += bytes.len();
+        }
+        for &(_, ref bytes) in segments {
+            out.extend_from_slice(bytes);
+        }
+        out
+    }
+
+    #[test]
+    fn test_core_dump() {
+        let state = ServerState { requests: 1234, motd: "welcome".to_owned() };
+        let anchor = Anchor::of(&state);
+        let dump = CoreDump::parse(core(&[
+            (anchor.addr, bytes_of(&state)),
+            (state.motd.as_ptr() as usize, state.motd.as_bytes().to_vec()),
+        ])).unwrap();
+        assert_eq!(read_anchor(&dump, &anchor).unwrap(),
+                   Snapshot::decode(&snapshot(&state)).unwrap().value);
+
+        let dump = CoreDump::parse(core(&[(anchor.addr, bytes_of(&state))])).unwrap();
+        let err = read_anchor(&dump, &anchor).unwrap_err().to_string();
+        assert!(err.starts_with("at `motd`: couldn't read"), "{}", err);
+        assert!(CoreDump::parse(b"\x7fELF".to_vec()).is_err());
+
+        let mut data = core(&[(anchor.addr, bytes_of(&state)), (0, vec![])]);
+        data[0x20..0x28].copy_from_slice(&[0xff; 8]);
+        assert!(CoreDump::parse(data).is_err());
+    }
+
+    #[test]
+    fn test_raw_dump() {
+        let requests = [5u32, 6, 7];
+        let dump = RawDump::new(requests.as_ptr() as usize, bytes_of(&requests));
+        let anchor = Anchor::of(&requests[1]);
+        assert_eq!(read_anchor(&dump, &anchor).unwrap(),
+                   Value::Primitive(PrimitiveValue::u32(6)));
+
+        assert!(dump.read(usize::max_value(), &mut [0; 4]).is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use std::any::TypeId;
+use std::collections::HashMap;
+
+use {
+    Contour,
+    ContourMap,
+    StructField,
+    TupleField,
+    VariantFields,
+};
+
+/// Computes a 64-bit digest of `type_id`'s layout: its name, module path,
+/// size, alignment, field names and offsets, and (recursively) the
+/// fingerprints of its field types.
+///
+/// `TypeId`s aren't stable across builds, so they never feed into the hash,
+/// and we use FNV-1a rather than `DefaultHasher` since the latter's algorithm
+/// isn't guaranteed to stay put.  Two builds that agree on a fingerprint agree
+/// on how to interpret the bytes of a value.
+///
+/// Returns `None` if `type_id` or one of its descendants hasn't been charted
+/// into `map`.
+pub fn fingerprint<CM: ContourMap>(type_id: TypeId, map: &CM) -> Option<u64> {
+    let mut f = Fingerprinter {
+        map,
+        done: HashMap::new(),
+        stack: vec![],
+    };
+    f.visit(type_id).map(|(fp, _)| fp)
+}
+
+const TAG_STRUCT: u8 = 1;
+const TAG_TUPLE: u8 = 2;
+const TAG_UNIT: u8 = 3;
+const TAG_ENUM: u8 = 4;
+const TAG_PRIMITIVE: u8 = 5;
+const TAG_BACKREF: u8 = 6;
+const TAG_SEQ: u8 = 7;
+const TAG_POINTER: u8 = 8;
+const TAG_MAP: u8 = 9;
+
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Fnv(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn u8(&mut self, v: u8) {
+        self.write(&[v]);
+    }
+
+    fn u64(&mut self, v: u64) {
+        let mut buf = [0u8; 8];
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = (v >> (8 * i)) as u8;
+        }
+        self.write(&buf);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u64(s.len() as u64);
+        self.write(s.as_bytes());
+    }
+}
+
+struct Fingerprinter<'a, CM: 'a> {
+    map: &'a CM,
+    done: HashMap<TypeId, u64>,
+    stack: Vec<TypeId>,
+}
+
+impl<'a, CM: ContourMap> Fingerprinter<'a, CM> {
+    /// Returns the fingerprint along with the shallowest stack depth that a
+    /// back-reference in this subtree pointed at (`usize::MAX` if none).
+    fn visit(&mut self, type_id: TypeId) -> Option<(u64, usize)> {
+        if let Some(&fp) = self.done.get(&type_id) {
+            return Some((fp, usize::MAX));
+        }
+        // Recursive types refer back to something on the stack.  Hash the
+        // distance rather than the `TypeId`, so the result is position
+        // independent.
+        if let Some(depth) = self.stack.iter().position(|t| *t == type_id) {
+            let mut h = Fnv::new();
+            h.u8(TAG_BACKREF);
+            h.u64((self.stack.len() - depth) as u64);
+            return Some((h.0, depth));
+        }
+        let contour = self.map.lookup(type_id)?;
+
+        self.stack.push(type_id);
+        let result = self.hash(&contour);
+        self.stack.pop();
+
+        let (fp, low) = result?;
+        // A fingerprint that depends on something further up the stack is only
+        // valid in this context, so don't memoize it.
+        if low >= self.stack.len() {
+            self.done.insert(type_id, fp);
+        }
+        Some((fp, low))
+    }
+
+    fn hash(&mut self, contour: &Contour) -> Option<(u64, usize)> {
+        let mut h = Fnv::new();
+        let mut low = usize::MAX;
+        match *contour {
+            Contour::Struct { name, module_path, size, align, ref fields, .. } => {
+                h.u8(TAG_STRUCT);
+                h.str(module_path);
+                h.str(name);
+                h.u64(size as u64);
+                h.u64(align as u64);
+                low = self.struct_fields(&mut h, fields)?;
+            },
+            Contour::Tuple { name, module_path, size, align, ref fields, .. } => {
+                h.u8(TAG_TUPLE);
+                h.str(module_path);
+                h.str(name);
+                h.u64(size as u64);
+                h.u64(align as u64);
+                low = self.tuple_fields(&mut h, fields)?;
+            },
+            Contour::Unit { name, module_path, .. } => {
+                h.u8(TAG_UNIT);
+                h.str(module_path);
+                h.str(name);
+            },
+            Contour::Enum { name, module_path, size, align, ref variants, .. } => {
+                h.u8(TAG_ENUM);
+                h.str(module_path);
+                h.str(name);
+                h.u64(size as u64);
+                h.u64(align as u64);
+                h.u64(variants.len() as u64);
+                for variant in variants {
+                    h.str(variant.name);
+                    let l = match variant.fields {
+                        VariantFields::Struct(ref fields) => {
+                            h.u8(TAG_STRUCT);
+                            self.struct_fields(&mut h, fields)?
+                        },
+                        VariantFields::Tuple(ref fields) => {
+                            h.u8(TAG_TUPLE);
+                            self.tuple_fields(&mut h, fields)?
+                        },
+                        VariantFields::Unit => {
+                            h.u8(TAG_UNIT);
+                            usize::MAX
+                        },
+                    };
+                    low = low.min(l);
+                }
+            },
+            Contour::Primitive { name, size, align, .. } => {
+                h.u8(TAG_PRIMITIVE);
+                h.str(name);
+                h.u64(size as u64);
+                h.u64(align as u64);
+            },
+            Contour::Seq { name, size, align, element, .. } => {
+                h.u8(TAG_SEQ);
+                h.str(name);
+                h.u64(size as u64);
+                h.u64(align as u64);
+                let (fp, l) = self.visit(element)?;
+                h.u64(fp);
+                low = l;
+            },
+            Contour::Pointer { name, size, align, pointee, .. } => {
+                h.u8(TAG_POINTER);
+                h.str(name);
+                h.u64(size as u64);
+                h.u64(align as u64);
+                let (fp, l) = self.visit(pointee)?;
+                h.u64(fp);
+                low = l;
+            },
+            Contour::Map { name, size, align, key, value, .. } => {
+                h.u8(TAG_MAP);
+                h.str(name);
+                h.u64(size as u64);
+                h.u64(align as u64);
+                let (key_fp, key_low) = self.visit(key)?;
+                let (value_fp, value_low) = self.visit(value)?;
+                h.u64(key_fp);
+                h.u64(value_fp);
+                low = key_low.min(value_low);
+            },
+        }
+        Some((h.0, low))
+    }
+
+    fn struct_fields(&mut self, h: &mut Fnv, fields: &[StructField]) -> Option<usize> {
+        let mut low = usize::MAX;
+        h.u64(fields.len() as u64);
+        for field in fields {
+            let (fp, l) = self.visit(field.type_id)?;
+            h.str(field.name);
+            h.u64(field.offset as u64);
+            h.u64(fp);
+            low = low.min(l);
+        }
+        Some(low)
+    }
+
+    fn tuple_fields(&mut self, h: &mut Fnv, fields: &[TupleField]) -> Option<usize> {
+        let mut low = usize::MAX;
+        h.u64(fields.len() as u64);
+        for field in fields {
+            let (fp, l) = self.visit(field.type_id)?;
+            h.u64(field.ix as u64);
+            h.u64(field.offset as u64);
+            h.u64(fp);
+            low = low.min(l);
+        }
+        Some(low)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::any::TypeId;
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        Registry,
+        StructField,
+    };
+    use super::fingerprint;
+
+    mod v1 {
+        use {Contour, ContourMap, Introspectable, StructField};
+        #[derive(Introspectable)]
+        pub struct Header {
+            pub magic: u32,
+            pub len: u64,
+        }
+    }
+
+    mod v2 {
+        use {Contour, ContourMap, Introspectable, StructField};
+        #[derive(Introspectable)]
+        pub struct Header {
+            pub magic: u32,
+            pub length: u64,
+        }
+    }
+
+    #[derive(Introspectable)]
+    struct Outer {
+        header: v1::Header,
+        flag: bool,
+    }
+
+    #[derive(Introspectable)]
+    struct Wrapper<T: Introspectable + 'static> {
+        inner: T,
+    }
+
+    #[test]
+    fn test_stable() {
+        assert_eq!(Outer::fingerprint(), Outer::fingerprint());
+        let registry = Registry::of::<Outer>();
+        assert_eq!(fingerprint(TypeId::of::<Outer>(), &registry),
+                   Some(Outer::fingerprint()));
+    }
+
+    #[test]
+    fn test_layout_changes() {
+        assert!(v1::Header::fingerprint() != v2::Header::fingerprint());
+        assert!(Wrapper::<u32>::fingerprint() != Wrapper::<u64>::fingerprint());
+        assert!(Wrapper::<v1::Header>::fingerprint() != Wrapper::<v2::Header>::fingerprint());
+    }
+
+    #[test]
+    fn test_uncharted() {
+        let registry = Registry::new();
+        registry.register(Outer::contour());
+        assert_eq!(fingerprint(TypeId::of::<Outer>(), &registry), None);
+    }
+}
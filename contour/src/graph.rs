@@ -0,0 +1,307 @@
+//! Diagrams of the type graph in a registry, in GraphViz and Mermaid syntax.
+//!
+//! Structs and enums become nodes, listing each field's name, type, offset
+//! and size, with enums split into one sub-record per variant.  Primitives
+//! and containers don't get nodes of their own: a field of type
+//! `Vec<Box<Pool>>` is shown with that type and gets an edge straight to
+//! `Pool`.
+
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use {
+    Contour,
+    Registry,
+    StructField,
+    TupleField,
+    Variant,
+    VariantFields,
+};
+
+/// Renders `registry` as a GraphViz digraph of record-shaped nodes.
+pub fn to_dot(registry: &Registry) -> String {
+    let graph = Graph::new(registry);
+    let mut out = String::new();
+    out.push_str("digraph contours {\n");
+    out.push_str("    node [shape=record, fontname=\"monospace\"];\n");
+    let mut edges = String::new();
+    for (id, contour) in graph.nodes.iter().enumerate() {
+        let mut label = format!("{{{}", dot_escape(&graph.title(contour)));
+        match *contour {
+            Contour::Enum { ref variants, .. } => for (v, variant) in variants.iter().enumerate() {
+                label.push_str("|{");
+                label.push_str(&dot_escape(variant.name));
+                for (f, row) in graph.variant_rows(variant).iter().enumerate() {
+                    write!(label, "|<v{}f{}> {}", v, f, dot_escape(&row.text)).unwrap();
+                    for target in &row.targets {
+                        writeln!(edges, "    t{}:v{}f{} -> t{};", id, v, f, target).unwrap();
+                    }
+                }
+                label.push('}');
+            },
+            _ => for (f, row) in graph.rows(contour).iter().enumerate() {
+                write!(label, "|<f{}> {}", f, dot_escape(&row.text)).unwrap();
+                for target in &row.targets {
+                    writeln!(edges, "    t{}:f{} -> t{};", id, f, target).unwrap();
+                }
+            },
+        }
+        label.push('}');
+        writeln!(out, "    t{} [label=\"{}\"];", id, label).unwrap();
+    }
+    out.push_str(&edges);
+    out.push_str("}\n");
+    out
+}
+
+/// Renders `registry` as a Mermaid class diagram.  Enum variants are listed
+/// as members, with their fields in parentheses.
+pub fn to_mermaid(registry: &Registry) -> String {
+    let graph = Graph::new(registry);
+    let mut out = String::new();
+    out.push_str("classDiagram\n");
+    let mut edges = String::new();
+    for (id, contour) in graph.nodes.iter().enumerate() {
+        writeln!(out, "    class t{}[\"{}\"]", id, mermaid_escape(contour.name())).unwrap();
+        let mut edge = |name: &str, targets: &[usize]| for target in targets {
+            writeln!(edges, "    t{} --> t{} : {}", id, target, mermaid_escape(name)).unwrap();
+        };
+        match *contour {
+            Contour::Enum { ref variants, .. } => {
+                writeln!(out, "    <<enumeration>> t{}", id).unwrap();
+                for variant in variants {
+                    let rows = graph.variant_rows(variant);
+                    let member = match variant.fields {
+                        VariantFields::Unit => variant.name.to_owned(),
+                        _ => {
+                            let fields: Vec<_> = rows.iter().map(|r| &r.text[..]).collect();
+                            format!("{}({})", variant.name, fields.join(", "))
+                        },
+                    };
+                    writeln!(out, "    t{} : {}", id, mermaid_escape(&member)).unwrap();
+                    for row in &rows {
+                        edge(&format!("{}::{}", variant.name, row.name), &row.targets);
+                    }
+                }
+            },
+            _ => for row in graph.rows(contour) {
+                writeln!(out, "    t{} : {}", id, mermaid_escape(&row.text)).unwrap();
+                edge(&row.name, &row.targets);
+            },
+        }
+    }
+    out.push_str(&edges);
+    out
+}
+
+/// A field, as shown in a node.
+struct Row {
+    name: String,
+    /// `name: Type @offset (size)`.
+    text: String,
+    /// Nodes that the field's type reaches without passing through another
+    /// node.
+    targets: Vec<usize>,
+}
+
+struct Graph {
+    contours: HashMap<TypeId, Contour>,
+    /// Structs and enums, sorted by module path and name so the output is
+    /// stable across runs.
+    nodes: Vec<Contour>,
+    ids: HashMap<TypeId, usize>,
+}
+
+impl Graph {
+    fn new(registry: &Registry) -> Self {
+        let contours: HashMap<_, _> = registry.contours()
+            .into_iter()
+            .map(|c| (c.type_id(), c))
+            .collect();
+        let mut nodes: Vec<_> = contours.values()
+            .filter(|c| matches!(**c, Contour::Struct {..} | Contour::Tuple {..} |
+                                      Contour::Unit {..} | Contour::Enum {..}))
+            .cloned()
+            .collect();
+        nodes.sort_by(|a, b| {
+            (module_path(a), a.name(), a.type_id()).cmp(&(module_path(b), b.name(), b.type_id()))
+        });
+        let ids = nodes.iter().enumerate().map(|(i, c)| (c.type_id(), i)).collect();
+        Graph { contours, nodes, ids }
+    }
+
+    fn title(&self, contour: &Contour) -> String {
+        format!("{} ({})", contour.name(), contour.size())
+    }
+
+    /// Spells out containers' type parameters, since their names alone don't
+    /// say much.
+    fn type_name(&self, type_id: TypeId) -> String {
+        match self.contours.get(&type_id) {
+            Some(&Contour::Seq { name, element, .. }) =>
+                format!("{}<{}>", name, self.type_name(element)),
+            Some(&Contour::Pointer { name, pointee, deref: None, .. }) =>
+                format!("{} {}", name, self.type_name(pointee)),
+            Some(&Contour::Pointer { name, pointee, .. }) =>
+                format!("{}<{}>", name, self.type_name(pointee)),
+            Some(&Contour::Map { name, key, value, .. }) =>
+                format!("{}<{}, {}>", name, self.type_name(key), self.type_name(value)),
+            Some(contour) => contour.name().to_owned(),
+            None => "?".to_owned(),
+        }
+    }
+
+    fn targets(&self, type_id: TypeId, out: &mut Vec<usize>) {
+        if let Some(&id) = self.ids.get(&type_id) {
+            if !out.contains(&id) {
+                out.push(id);
+            }
+            return;
+        }
+        match self.contours.get(&type_id) {
+            Some(&Contour::Seq { element, .. }) => self.targets(element, out),
+            Some(&Contour::Pointer { pointee, .. }) => self.targets(pointee, out),
+            Some(&Contour::Map { key, value, .. }) => {
+                self.targets(key, out);
+                self.targets(value, out);
+            },
+            _ => {},
+        }
+    }
+
+    fn row(&self, name: String, type_id: TypeId, offset: usize) -> Row {
+        let size = match self.contours.get(&type_id) {
+            Some(contour) => contour.size().to_string(),
+            None => "?".to_owned(),
+        };
+        let mut targets = vec![];
+        self.targets(type_id, &mut targets);
+        Row {
+            text: format!("{}: {} @{} ({})", name, self.type_name(type_id), offset, size),
+            name,
+            targets,
+        }
+    }
+
+    fn struct_rows(&self, fields: &[StructField]) -> Vec<Row> {
+        fields.iter().map(|f| self.row(f.name.to_owned(), f.type_id, f.offset)).collect()
+    }
+
+    fn tuple_rows(&self, fields: &[TupleField]) -> Vec<Row> {
+        fields.iter().map(|f| self.row(f.ix.to_string(), f.type_id, f.offset)).collect()
+    }
+
+    fn rows(&self, contour: &Contour) -> Vec<Row> {
+        match *contour {
+            Contour::Struct { ref fields, .. } => self.struct_rows(fields),
+            Contour::Tuple { ref fields, .. } => self.tuple_rows(fields),
+            _ => vec![],
+        }
+    }
+
+    fn variant_rows(&self, variant: &Variant) -> Vec<Row> {
+        match variant.fields {
+            VariantFields::Struct(ref fields) => self.struct_rows(fields),
+            VariantFields::Tuple(ref fields) => self.tuple_rows(fields),
+            VariantFields::Unit => vec![],
+        }
+    }
+}
+
+fn module_path(contour: &Contour) -> &'static str {
+    match *contour {
+        Contour::Struct { module_path, .. } |
+        Contour::Tuple { module_path, .. } |
+        Contour::Unit { module_path, .. } |
+        Contour::Enum { module_path, .. } => module_path,
+        _ => "",
+    }
+}
+
+/// Escapes the characters that are special inside a quoted record label.
+fn dot_escape(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        if "{}|<>\"\\".contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Mermaid writes generics with tildes, and chokes on angle brackets and
+/// quotes.
+fn mermaid_escape(s: &str) -> String {
+    s.replace(['<', '>'], "~").replace('"', "'")
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::collections::HashMap;
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        StructField,
+        TupleField,
+        Variant,
+        VariantFields,
+    };
+    use super::*;
+
+    #[derive(Introspectable)]
+    enum Health {
+        Up,
+        Degraded(u8),
+    }
+
+    #[derive(Introspectable)]
+    struct Pool {
+        size: u32,
+        health: Health,
+    }
+
+    #[derive(Introspectable)]
+    struct Cluster {
+        pools: Vec<Box<Pool>>,
+        by_name: HashMap<String, Pool>,
+    }
+
+    #[test]
+    fn test_dot() {
+        let dot = to_dot(&Registry::of::<Cluster>());
+        let lines: Vec<_> = dot.lines().collect();
+        assert_eq!(lines[0], "digraph contours {");
+        assert!(lines[2].starts_with(r#"    t0 [label="{Cluster ("#));
+        assert!(lines[2].contains(r"|<f0> pools: Vec\<Box\<Pool\>\> @"));
+        assert!(lines[3].starts_with(r#"    t1 [label="{Health ("#));
+        assert!(lines[3].ends_with(r#"|{Up}|{Degraded|<v1f0> 0: u8 @1 (1)}}"];"#));
+        assert!(lines[4].starts_with(r#"    t2 [label="{Pool ("#));
+        assert_eq!(&lines[5..], &[
+            "    t0:f0 -> t2;",
+            "    t0:f1 -> t2;",
+            "    t2:f1 -> t1;",
+            "}",
+        ]);
+    }
+
+    #[test]
+    fn test_mermaid() {
+        let mermaid = to_mermaid(&Registry::of::<Pool>());
+        let lines: Vec<_> = mermaid.lines().collect();
+        assert_eq!(lines, vec![
+            "classDiagram",
+            "    class t0[\"Health\"]",
+            "    <<enumeration>> t0",
+            "    t0 : Up",
+            "    t0 : Degraded(0: u8 @1 (1))",
+            "    class t1[\"Pool\"]",
+            "    t1 : size: u32 @0 (4)",
+            "    t1 : health: Health @4 (2)",
+            "    t1 --> t0 : health",
+        ]);
+    }
+}
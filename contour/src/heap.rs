@@ -0,0 +1,272 @@
+//! Deep memory accounting: how much memory a value takes up, including
+//! everything it owns on the heap.
+//!
+//! Figures for containers come from their contours' `heap_size`, so they're
+//! only as accurate as those are.  Allocations behind `Rc` and `Arc` are
+//! counted the first time they're reached, and raw pointers aren't followed
+//! at all, since they don't own what they point to.
+
+use std::any::TypeId;
+use std::collections::HashSet;
+
+use path::{
+    Key,
+    Path,
+    Segment,
+};
+use visit::{
+    walk_map,
+    walk_pointer,
+    walk_seq,
+    walk_value,
+    walk_variant,
+    FieldName,
+    Visitor,
+    Walker,
+};
+use {
+    Contour,
+    ContourMap,
+    Introspectable,
+    Primitive,
+    Registry,
+    Variant,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SizeReport {
+    /// `size_of` the root, plus all of the heap memory reachable from it.
+    pub total: usize,
+    /// Heap bytes under each path that owns any, largest first.  Each figure
+    /// includes the path's descendants, and descendants more than `max_depth`
+    /// segments deep are only counted towards their ancestors.
+    pub by_path: Vec<(Path, usize)>,
+}
+
+/// `size_of_val(value)` plus everything it owns on the heap.
+pub fn deep_size<T: Introspectable>(value: &T) -> usize {
+    size_report(value, 0).total
+}
+
+pub fn size_report<T: Introspectable>(value: &T, max_depth: usize) -> SizeReport {
+    let registry = Registry::of::<T>();
+    let type_id = T::contour().type_id();
+    unsafe { size_report_ptr(&registry, type_id, value as *const T as *const u8, max_depth) }
+}
+
+/// # Safety
+///
+/// See `Walker::walk` for the requirements on `ptr`.
+pub unsafe fn size_report_ptr(map: &dyn ContourMap,
+                              type_id: TypeId,
+                              ptr: *const u8,
+                              max_depth: usize)
+    -> SizeReport
+{
+    let mut sizer = Sizer {
+        path: Path::root(),
+        max_depth,
+        totals: vec![0],
+        by_path: vec![],
+        seen: HashSet::new(),
+    };
+    let mut walker = Walker::new(map);
+    walker.walk(&mut sizer, type_id, ptr);
+
+    let heap = sizer.totals[0];
+    if heap > 0 {
+        sizer.by_path.push((Path::root(), heap));
+    }
+    sizer.by_path.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.to_string().cmp(&b.0.to_string())));
+    SizeReport {
+        total: walker.contour(type_id).size() + heap,
+        by_path: sizer.by_path,
+    }
+}
+
+struct Sizer {
+    path: Path,
+    max_depth: usize,
+    /// Running heap totals for each node we're underneath, root first.
+    totals: Vec<usize>,
+    by_path: Vec<(Path, usize)>,
+    /// Targets of owning pointers we've already counted.
+    seen: HashSet<*const u8>,
+}
+
+impl Sizer {
+    fn add(&mut self, bytes: usize) {
+        *self.totals.last_mut().unwrap() += bytes;
+    }
+
+    fn enter(&mut self, segment: Segment) {
+        self.path.push(segment);
+        self.totals.push(0);
+    }
+
+    fn leave(&mut self) {
+        let total = self.totals.pop().unwrap();
+        if total > 0 && self.path.segments().len() <= self.max_depth {
+            self.by_path.push((self.path.clone(), total));
+        }
+        self.path.pop();
+        self.add(total);
+    }
+}
+
+impl Visitor for Sizer {
+    unsafe fn visit_field(&mut self,
+                          cx: &mut Walker,
+                          name: FieldName,
+                          type_id: TypeId,
+                          ptr: *const u8) {
+        self.enter(match name {
+            FieldName::Named(name) => Segment::Field(name.to_owned()),
+            FieldName::Index(ix) => Segment::Tuple(ix),
+        });
+        walk_value(self, cx, type_id, ptr);
+        self.leave();
+    }
+
+    unsafe fn visit_variant(&mut self, cx: &mut Walker, variant: &Variant, ptr: *const u8) {
+        self.path.push(Segment::Variant(variant.name.to_owned()));
+        walk_variant(self, cx, variant, ptr);
+        self.path.pop();
+    }
+
+    unsafe fn visit_primitive(&mut self,
+                              _cx: &mut Walker,
+                              kind: &Primitive,
+                              _atomic: bool,
+                              ptr: *const u8) {
+        if *kind == Primitive::String {
+            let s = unsafe { &*(ptr as *const String) };
+            self.add(s.capacity());
+        }
+    }
+
+    unsafe fn visit_seq(&mut self, cx: &mut Walker, contour: &Contour, ptr: *const u8) {
+        if let Contour::Seq { heap_size, .. } = *contour {
+            self.add(unsafe { heap_size(ptr) });
+        }
+        walk_seq(self, cx, contour, ptr)
+    }
+
+    unsafe fn visit_element(&mut self,
+                            cx: &mut Walker,
+                            ix: usize,
+                            type_id: TypeId,
+                            ptr: *const u8) {
+        self.enter(Segment::Index(Key::Int(ix as i64)));
+        walk_value(self, cx, type_id, ptr);
+        self.leave();
+    }
+
+    unsafe fn visit_map(&mut self, cx: &mut Walker, contour: &Contour, ptr: *const u8) {
+        if let Contour::Map { heap_size, .. } = *contour {
+            self.add(unsafe { heap_size(ptr) });
+        }
+        walk_map(self, cx, contour, ptr)
+    }
+
+    unsafe fn visit_entry(&mut self,
+                          cx: &mut Walker,
+                          key_type: TypeId,
+                          key: *const u8,
+                          value_type: TypeId,
+                          value: *const u8) {
+        // Entries with keys a path can't express are charged to the map.
+        let segment = match cx.contour(key_type) {
+            Contour::Primitive { variant, .. } =>
+                Key::from_value(&unsafe { variant.read(key) }).map(Segment::Index),
+            _ => None,
+        };
+        let framed = segment.is_some();
+        if let Some(segment) = segment {
+            self.enter(segment);
+        }
+        self.visit_value(cx, key_type, key);
+        self.visit_value(cx, value_type, value);
+        if framed {
+            self.leave();
+        }
+    }
+
+    unsafe fn visit_pointer(&mut self, cx: &mut Walker, contour: &Contour, ptr: *const u8) {
+        let (deref, heap_size) = match *contour {
+            Contour::Pointer { deref: Some(deref), heap_size: Some(heap_size), .. } =>
+                (deref, heap_size),
+            _ => return,
+        };
+        let target = unsafe { deref(ptr) };
+        if target.is_null() || !self.seen.insert(target) {
+            return;
+        }
+        self.add(unsafe { heap_size(ptr) });
+        walk_pointer(self, cx, contour, ptr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::collections::BTreeMap;
+    use std::mem;
+    use std::rc::Rc;
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        StructField,
+    };
+    use super::*;
+
+    #[derive(Introspectable)]
+    struct Entry {
+        key: String,
+        blob: Rc<Vec<u8>>,
+    }
+
+    #[derive(Introspectable)]
+    struct Cache {
+        entries: Vec<Entry>,
+        index: BTreeMap<u32, u64>,
+        total: Box<u64>,
+        parent: *const Cache,
+    }
+
+    #[test]
+    fn test_deep_size() {
+        let blob = Rc::new(Vec::with_capacity(100));
+        let mut entries = Vec::with_capacity(4);
+        entries.push(Entry { key: String::with_capacity(10), blob: blob.clone() });
+        entries.push(Entry { key: String::with_capacity(20), blob: blob.clone() });
+        let mut index = BTreeMap::new();
+        index.insert(1, 2);
+        let cache = Cache {
+            entries: entries,
+            index: index,
+            total: Box::new(0),
+            parent: ::std::ptr::null(),
+        };
+
+        let rc = 2 * mem::size_of::<usize>() + mem::size_of::<Vec<u8>>();
+        let entries = 4 * mem::size_of::<Entry>() + 10 + 20 + rc + 100;
+        let index = mem::size_of::<(u32, u64)>();
+        let heap = entries + index + 8;
+        assert_eq!(deep_size(&cache), mem::size_of::<Cache>() + heap);
+
+        let report = size_report(&cache, 2);
+        let by_path: Vec<_> = report.by_path.iter()
+            .map(|&(ref path, size)| (path.to_string(), size))
+            .collect();
+        assert_eq!(by_path, vec![
+            ("".to_owned(), heap),
+            ("entries".to_owned(), entries),
+            ("entries[0]".to_owned(), 10 + rc + 100),
+            ("entries[1]".to_owned(), 20),
+            ("index".to_owned(), index),
+            ("total".to_owned(), 8),
+        ]);
+    }
+}
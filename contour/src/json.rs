@@ -0,0 +1,391 @@
+//! Just enough JSON for the machine-readable formats in this crate, and for
+//! reading `server` requests.
+
+use std::fmt::Write;
+
+use schema::{
+    Fields,
+    Kind,
+    Schema,
+};
+use value::{
+    FieldValues,
+    Value,
+};
+use PrimitiveValue;
+
+pub fn string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Chars come out as one-character strings, and non-finite floats as `null`
+/// since JSON can't represent them.
+pub fn primitive(value: &PrimitiveValue, out: &mut String) {
+    match *value {
+        PrimitiveValue::f32(v) if !v.is_finite() => out.push_str("null"),
+        PrimitiveValue::f64(v) if !v.is_finite() => out.push_str("null"),
+        PrimitiveValue::char(c) => {
+            let mut buf = [0u8; 4];
+            string(c.encode_utf8(&mut buf), out);
+        },
+        PrimitiveValue::String(ref s) => string(s, out),
+        ref v => write!(out, "{}", v).unwrap(),
+    }
+}
+
+/// Structs come out as objects, tuple structs as arrays and unit structs as
+/// `null`.  Enums are `"Variant"` or `{"Variant": fields}`, maps are arrays
+/// of `[key, value]` pairs, and null pointers and cycles are `null`.
+pub fn value(value: &Value, out: &mut String) {
+    match *value {
+        Value::Struct { ref fields, .. } => field_values(fields, out),
+        Value::Enum { ref variant, ref fields, .. } => match *fields {
+            FieldValues::Unit => string(variant, out),
+            _ => {
+                out.push('{');
+                string(variant, out);
+                out.push(':');
+                field_values(fields, out);
+                out.push('}');
+            },
+        },
+        Value::Primitive(ref v) => primitive(v, out),
+        Value::Seq(ref items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                self::value(item, out);
+            }
+            out.push(']');
+        },
+        Value::Map(ref entries) => {
+            out.push('[');
+            for (i, (k, v)) in entries.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push('[');
+                self::value(k, out);
+                out.push(',');
+                self::value(v, out);
+                out.push(']');
+            }
+            out.push(']');
+        },
+        Value::Pointer(Some(ref target)) => self::value(target, out),
+        Value::Pointer(None) | Value::Cycle => out.push_str("null"),
+        Value::Address(address) => out.push_str(&address.to_string()),
+    }
+}
+
+fn field_values(fields: &FieldValues, out: &mut String) {
+    match *fields {
+        FieldValues::Named(ref fields) => {
+            out.push('{');
+            for (i, (name, v)) in fields.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                string(name, out);
+                out.push(':');
+                value(v, out);
+            }
+            out.push('}');
+        },
+        FieldValues::Tuple(ref fields) => {
+            out.push('[');
+            for (i, v) in fields.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                value(v, out);
+            }
+            out.push(']');
+        },
+        FieldValues::Unit => out.push_str("null"),
+    }
+}
+
+/// Types are objects with a `kind` of `struct`, `tuple`, `unit`, `enum`,
+/// `primitive`, `seq`, `pointer` or `map`, referring to each other by
+/// index into `types`.
+pub fn schema(schema: &Schema, out: &mut String) {
+    write!(out, "{{\"root\":{},\"types\":[", schema.root).unwrap();
+    for (i, ty) in schema.types.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str("{\"name\":");
+        string(&ty.name, out);
+        out.push_str(",\"module_path\":");
+        string(&ty.module_path, out);
+        write!(out, ",\"size\":{},\"align\":{},\"kind\":", ty.size, ty.align).unwrap();
+        match ty.kind {
+            Kind::Struct(ref fields) => {
+                string(fields_kind(fields), out);
+                out.push_str(",\"fields\":");
+                field_descs(fields, out);
+            },
+            Kind::Enum(ref variants) => {
+                out.push_str("\"enum\",\"variants\":[");
+                for (i, variant) in variants.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    out.push_str("{\"name\":");
+                    string(&variant.name, out);
+                    out.push_str(",\"kind\":");
+                    string(fields_kind(&variant.fields), out);
+                    out.push_str(",\"fields\":");
+                    field_descs(&variant.fields, out);
+                    out.push('}');
+                }
+                out.push(']');
+            },
+            Kind::Primitive(ref p) => write!(out, "\"primitive\",\"type\":\"{:?}\"", p).unwrap(),
+            Kind::Seq { element } => write!(out, "\"seq\",\"element\":{}", element).unwrap(),
+            Kind::Pointer { pointee, owned } =>
+                write!(out, "\"pointer\",\"pointee\":{},\"owned\":{}", pointee, owned).unwrap(),
+            Kind::Map { key, value } =>
+                write!(out, "\"map\",\"key\":{},\"value\":{}", key, value).unwrap(),
+        }
+        out.push('}');
+    }
+    out.push_str("]}");
+}
+
+fn fields_kind(fields: &Fields) -> &'static str {
+    match *fields {
+        Fields::Named(_) => "struct",
+        Fields::Tuple(_) => "tuple",
+        Fields::Unit => "unit",
+    }
+}
+
+fn field_descs(fields: &Fields, out: &mut String) {
+    out.push('[');
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str("{\"name\":");
+        string(&field.name, out);
+        write!(out, ",\"type\":{},\"offset\":{}}}", field.ty, field.offset).unwrap();
+    }
+    out.push(']');
+}
+
+/// A parsed document.  Numbers keep their text, so integers too big for an
+/// `f64` survive until they're parsed as what they're meant to be.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref members) => members.iter().find(|m| m.0 == key).map(|m| &m.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse(s: &str) -> Result<Json, String> {
+    let mut parser = Parser { s, pos: 0 };
+    let json = parser.value(0)?;
+    parser.whitespace();
+    if parser.pos < s.len() {
+        return parser.error("trailing characters");
+    }
+    Ok(json)
+}
+
+/// Deeper documents than this are rejected rather than risking the stack.
+const MAX_DEPTH: usize = 128;
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, msg: &str) -> Result<T, String> {
+        Err(format!("at byte {}: {}", self.pos, msg))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        if self.s[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn whitespace(&mut self) {
+        while self.peek().is_some_and(|c| c == ' ' || c == '\t' || c == '\n' || c == '\r') {
+            self.bump();
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, String> {
+        if depth > MAX_DEPTH {
+            return self.error("nested too deeply");
+        }
+        self.whitespace();
+        match self.peek() {
+            Some('n') if self.eat("null") => Ok(Json::Null),
+            Some('t') if self.eat("true") => Ok(Json::Bool(true)),
+            Some('f') if self.eat("false") => Ok(Json::Bool(false)),
+            Some('"') => self.string().map(Json::String),
+            Some('[') => {
+                self.bump();
+                let mut items = vec![];
+                self.whitespace();
+                if self.eat("]") {
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value(depth + 1)?);
+                    self.whitespace();
+                    if self.eat("]") {
+                        return Ok(Json::Array(items));
+                    }
+                    if !self.eat(",") {
+                        return self.error("expected `,` or `]`");
+                    }
+                }
+            },
+            Some('{') => {
+                self.bump();
+                let mut members = vec![];
+                self.whitespace();
+                if self.eat("}") {
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.whitespace();
+                    if self.peek() != Some('"') {
+                        return self.error("expected a key");
+                    }
+                    let key = self.string()?;
+                    self.whitespace();
+                    if !self.eat(":") {
+                        return self.error("expected `:`");
+                    }
+                    members.push((key, self.value(depth + 1)?));
+                    self.whitespace();
+                    if self.eat("}") {
+                        return Ok(Json::Object(members));
+                    }
+                    if !self.eat(",") {
+                        return self.error("expected `,` or `}`");
+                    }
+                }
+            },
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| "+-.eE".contains(c) || c.is_ascii_digit()) {
+                    self.bump();
+                }
+                let text = &self.s[start..self.pos];
+                if text.parse::<f64>().is_err() {
+                    return self.error("bad number");
+                }
+                Ok(Json::Number(text.to_owned()))
+            },
+            _ => self.error("expected a value"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.bump();
+        let mut out = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(out),
+                Some('\\') => {
+                    let c = match self.bump() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => self.escape()?,
+                        _ => return self.error("unknown escape"),
+                    };
+                    out.push(c);
+                },
+                Some(c) if (c as u32) < 0x20 => return self.error("control character in string"),
+                Some(c) => out.push(c),
+                None => return self.error("unterminated string"),
+            }
+        }
+    }
+
+    /// The rest of a `\u` escape, which may be the first half of a
+    /// surrogate pair.
+    fn escape(&mut self) -> Result<char, String> {
+        let hex = |p: &mut Parser| -> Result<u32, String> {
+            match p.s.get(p.pos..p.pos + 4).and_then(|h| u32::from_str_radix(h, 16).ok()) {
+                Some(n) => {
+                    p.pos += 4;
+                    Ok(n)
+                },
+                None => p.error("bad unicode escape"),
+            }
+        };
+        let mut n = hex(self)?;
+        if (0xd800..0xdc00).contains(&n) && self.eat("\\u") {
+            let low = hex(self)?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return self.error("bad surrogate pair");
+            }
+            n = 0x10000 + ((n - 0xd800) << 10) + (low - 0xdc00);
+        }
+        match ::std::char::from_u32(n) {
+            Some(c) => Ok(c),
+            None => self.error("bad unicode escape"),
+        }
+    }
+}
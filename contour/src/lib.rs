@@ -1,43 +1,255 @@
 #![cfg_attr(test, feature(offset_to))]
 #![allow(non_camel_case_types)]
 #[cfg(test)] #[macro_use] extern crate contour_derive;
+// Derived code names this crate's types as `::contour::...`.
+#[cfg(test)] extern crate self as contour;
+#[cfg(feature = "regex")] extern crate regex;
+#[cfg(feature = "serde")] extern crate serde;
 extern crate syn;
 
 use std::any::TypeId;
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+pub mod boxed;
+pub mod c_header;
+pub mod config;
+pub mod diff;
+pub mod dump;
+mod fingerprint;
+pub mod graph;
+pub mod heap;
+mod json;
+pub mod load;
+pub mod mapped;
+pub mod methods;
+pub mod metrics;
+pub mod path;
+pub mod pretty;
+mod registry;
+pub mod remote;
+mod root;
+pub mod schema;
+pub mod server;
+#[cfg(feature = "serde")] mod serialize;
+pub mod snapshot;
+pub mod structural;
+pub mod testing;
+pub mod validate;
+pub mod value;
+pub mod visit;
+pub mod watch;
+
+pub use boxed::Boxed;
+pub use diff::{
+    diff_values,
+    Diff,
+};
+pub use fingerprint::fingerprint;
+pub use heap::deep_size;
+pub use load::{
+    load,
+    Load,
+    LoadError,
+    Loaded,
+    Loader,
+};
+pub use methods::{
+    Arg,
+    CallError,
+    Method,
+    Methods,
+    Receiver,
+};
+pub use pretty::{
+    pretty,
+    Pretty,
+    PrettyOptions,
+};
+pub use registry::Registry;
+pub use schema::Schema;
+#[cfg(feature = "serde")] pub use serialize::Serializable;
+pub use snapshot::{
+    snapshot,
+    Snapshot,
+};
+pub use structural::{
+    dyn_eq,
+    dyn_hash,
+};
+pub use validate::{
+    validate,
+    Violation,
+};
+pub use value::Value;
+pub use visit::{
+    FieldName,
+    Visitor,
+    Walker,
+};
+
+#[derive(Clone, Debug)]
 pub enum Contour {
     Struct {
         name: &'static str,
+        module_path: &'static str,
         size: usize,
+        align: usize,
         type_id: TypeId,
         fields: Vec<StructField>,
+        /// Whether any fields were left out with `#[contour(skip)]`, so
+        /// that `fields` doesn't account for everything in the value.
+        skipped: bool,
+        repr: Repr,
+        hooks: Hooks,
     },
     Tuple {
         name: &'static str,
+        module_path: &'static str,
         size: usize,
+        align: usize,
         type_id: TypeId,
         fields: Vec<TupleField>,
+        skipped: bool,
+        repr: Repr,
+        hooks: Hooks,
     },
     Unit {
         name: &'static str,
+        module_path: &'static str,
         type_id: TypeId,
+        hooks: Hooks,
     },
     Enum {
         name: &'static str,
+        module_path: &'static str,
         size: usize,
+        align: usize,
         type_id: TypeId,
         variants: Vec<Variant>,
         tag: unsafe extern "C" fn(*const u8) -> usize,
+        repr: Repr,
+        hooks: Hooks,
     },
     Primitive {
         name: &'static str,
         type_id: TypeId,
         size: usize,
+        align: usize,
         variant: Primitive,
+        /// Whether this is one of the `std::sync::atomic` types, which have
+        /// to be read with atomic loads (see `Primitive::load`).
+        atomic: bool,
+        hooks: Hooks,
+    },
+    /// A contiguous run of `element`s, like `Vec<T>`.
+    ///
+    /// For this and the other containers, `heap_size` is the size of the
+    /// allocation the container owns directly, not counting anything its
+    /// elements own in turn.
+    Seq {
+        name: &'static str,
+        type_id: TypeId,
+        size: usize,
+        align: usize,
+        element: TypeId,
+        len: unsafe extern "C" fn(*const u8) -> usize,
+        item: unsafe extern "C" fn(*const u8, usize) -> *const u8,
+        heap_size: unsafe extern "C" fn(*const u8) -> usize,
+    },
+    /// Anything that points at a single `pointee`, owned or not.  `heap_size`
+    /// is `None` for pointers that don't own their target.
+    ///
+    /// Raw pointers have no `deref`: nothing says they point at a live value,
+    /// so tools show their address (see `raw_address`) instead of following
+    /// them.
+    Pointer {
+        name: &'static str,
+        type_id: TypeId,
+        size: usize,
+        align: usize,
+        pointee: TypeId,
+        deref: Option<unsafe extern "C" fn(*const u8) -> *const u8>,
+        heap_size: Option<unsafe extern "C" fn(*const u8) -> usize>,
+    },
+    /// An associative container.  `entries` calls back with pointers to each
+    /// key and value, in whatever order the container iterates in.
+    Map {
+        name: &'static str,
+        type_id: TypeId,
+        size: usize,
+        align: usize,
+        key: TypeId,
+        value: TypeId,
+        len: unsafe extern "C" fn(*const u8) -> usize,
+        entries: unsafe fn(*const u8, &mut dyn FnMut(*const u8, *const u8)),
+        heap_size: unsafe extern "C" fn(*const u8) -> usize,
     },
 }
 
+/// Contours are compared by everything but their function pointers, which
+/// are fixed by the type anyway and aren't guaranteed to be unique.
+impl PartialEq for Contour {
+    fn eq(&self, other: &Contour) -> bool {
+        match (self, other) {
+            (&Contour::Struct { name, module_path, size, align, type_id,
+                                ref fields, skipped, ref repr, hooks },
+             &Contour::Struct { name: name2, module_path: module_path2, size: size2,
+                                align: align2, type_id: type_id2, fields: ref fields2,
+                                skipped: skipped2, repr: ref repr2, hooks: hooks2 }) =>
+                (name, module_path, size, align, type_id, fields, skipped, repr, hooks) ==
+                (name2, module_path2, size2, align2, type_id2, fields2, skipped2, repr2, hooks2),
+            (&Contour::Tuple { name, module_path, size, align, type_id,
+                               ref fields, skipped, ref repr, hooks },
+             &Contour::Tuple { name: name2, module_path: module_path2, size: size2,
+                               align: align2, type_id: type_id2, fields: ref fields2,
+                               skipped: skipped2, repr: ref repr2, hooks: hooks2 }) =>
+                (name, module_path, size, align, type_id, fields, skipped, repr, hooks) ==
+                (name2, module_path2, size2, align2, type_id2, fields2, skipped2, repr2, hooks2),
+            (&Contour::Unit { name, module_path, type_id, hooks },
+             &Contour::Unit { name: name2, module_path: module_path2, type_id: type_id2,
+                              hooks: hooks2 }) =>
+                (name, module_path, type_id, hooks) == (name2, module_path2, type_id2, hooks2),
+            (&Contour::Enum { name, module_path, size, align, type_id,
+                              ref variants, ref repr, hooks, .. },
+             &Contour::Enum { name: name2, module_path: module_path2, size: size2,
+                              align: align2, type_id: type_id2, variants: ref variants2,
+                              repr: ref repr2, hooks: hooks2, .. }) =>
+                (name, module_path, size, align, type_id, variants, repr, hooks) ==
+                (name2, module_path2, size2, align2, type_id2, variants2, repr2, hooks2),
+            (&Contour::Primitive { name, type_id, size, align, ref variant, atomic, hooks },
+             &Contour::Primitive { name: name2, type_id: type_id2, size: size2, align: align2,
+                                   variant: ref variant2, atomic: atomic2, hooks: hooks2 }) =>
+                (name, type_id, size, align, variant, atomic, hooks) ==
+                (name2, type_id2, size2, align2, variant2, atomic2, hooks2),
+            (&Contour::Seq { name, type_id, size, align, element, .. },
+             &Contour::Seq { name: name2, type_id: type_id2, size: size2, align: align2,
+                             element: element2, .. }) =>
+                (name, type_id, size, align, element) ==
+                (name2, type_id2, size2, align2, element2),
+            (&Contour::Pointer { name, type_id, size, align, pointee, deref, heap_size },
+             &Contour::Pointer { name: name2, type_id: type_id2, size: size2, align: align2,
+                                 pointee: pointee2, deref: deref2, heap_size: heap_size2 }) =>
+                (name, type_id, size, align, pointee, deref.is_some(), heap_size.is_some()) ==
+                (name2, type_id2, size2, align2, pointee2, deref2.is_some(),
+                 heap_size2.is_some()),
+            (&Contour::Map { name, type_id, size, align, key, value, .. },
+             &Contour::Map { name: name2, type_id: type_id2, size: size2, align: align2,
+                             key: key2, value: value2, .. }) =>
+                (name, type_id, size, align, key, value) ==
+                (name2, type_id2, size2, align2, key2, value2),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Contour {}
+
 /// These types are "primitive" from contour's perspective in that they're
 /// defined elsewhere (and don't have a `#[derive(Introspectable)]`), yet we
 /// still want to be able to introspect them.
@@ -61,15 +273,114 @@ pub enum Primitive {
     String,
 }
 
+/// An owned copy of a primitive read out of a live value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PrimitiveValue {
+    u8(u8),
+    u16(u16),
+    u32(u32),
+    u64(u64),
+    usize(usize),
+    i8(i8),
+    i16(i16),
+    i32(i32),
+    i64(i64),
+    f32(f32),
+    f64(f64),
+    isize(isize),
+    bool(bool),
+    char(char),
+
+    String(String),
+}
+
+macro_rules! read_primitive {
+    ($kind:expr, $ptr:expr, $($n:ident),*) => {
+        match *$kind {
+            $(Primitive::$n => PrimitiveValue::$n(*($ptr as *const $n)),)*
+            Primitive::String => PrimitiveValue::String((*($ptr as *const String)).clone()),
+        }
+    };
+}
+
+macro_rules! load_atomic {
+    ($kind:expr, $ptr:expr, $($n:ident: $t:ident),*) => {
+        match *$kind {
+            $(Primitive::$n => PrimitiveValue::$n(
+                (*($ptr as *const ::std::sync::atomic::$t))
+                    .load(::std::sync::atomic::Ordering::Relaxed)),)*
+            _ => $kind.read($ptr),
+        }
+    };
+}
+
+impl Primitive {
+    /// Copies the value out of `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a live primitive of this kind.
+    pub unsafe fn read(&self, ptr: *const u8) -> PrimitiveValue {
+        read_primitive!(self, ptr,
+                        u8, u16, u32, u64, usize, i8, i16, i32, i64, f32, f64, isize,
+                        bool, char)
+    }
+
+    /// Like `read`, but if `atomic` is set, `ptr` points to the atomic
+    /// version of this kind, which is loaded with `Ordering::Relaxed` so that
+    /// other threads can go on updating it.  This is how tools read the
+    /// values of `Contour::Primitive`s.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a live primitive of this kind, or of its atomic
+    /// version if `atomic` is set.
+    pub unsafe fn load(&self, ptr: *const u8, atomic: bool) -> PrimitiveValue {
+        if !atomic {
+            return self.read(ptr);
+        }
+        load_atomic!(self, ptr,
+                     u8: AtomicU8, u16: AtomicU16, u32: AtomicU32, u64: AtomicU64,
+                     usize: AtomicUsize, i8: AtomicI8, i16: AtomicI16, i32: AtomicI32,
+                     i64: AtomicI64, isize: AtomicIsize, bool: AtomicBool)
+    }
+}
+
+/// Formats the value the way it'd be written as a Rust literal.
+impl fmt::Display for PrimitiveValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PrimitiveValue::u8(v) => write!(f, "{}", v),
+            PrimitiveValue::u16(v) => write!(f, "{}", v),
+            PrimitiveValue::u32(v) => write!(f, "{}", v),
+            PrimitiveValue::u64(v) => write!(f, "{}", v),
+            PrimitiveValue::usize(v) => write!(f, "{}", v),
+            PrimitiveValue::i8(v) => write!(f, "{}", v),
+            PrimitiveValue::i16(v) => write!(f, "{}", v),
+            PrimitiveValue::i32(v) => write!(f, "{}", v),
+            PrimitiveValue::i64(v) => write!(f, "{}", v),
+            PrimitiveValue::f32(v) => write!(f, "{:?}", v),
+            PrimitiveValue::f64(v) => write!(f, "{:?}", v),
+            PrimitiveValue::isize(v) => write!(f, "{}", v),
+            PrimitiveValue::bool(v) => write!(f, "{}", v),
+            PrimitiveValue::char(v) => write!(f, "{:?}", v),
+            PrimitiveValue::String(ref v) => write!(f, "{:?}", v),
+        }
+    }
+}
+
 macro_rules! prim_impl {
     ($t:ty, $n:ident) => {
-        impl Introspectable for $t {
+        unsafe impl Introspectable for $t {
             fn contour() -> Contour {
                 Contour::Primitive {
                     name: stringify!($t),
                     type_id: ::std::any::TypeId::of::<$t>(),
                     size: ::std::mem::size_of::<$t>(),
+                    align: ::std::mem::align_of::<$t>(),
                     variant: Primitive::$n,
+                    atomic: false,
+                    hooks: Hooks::new::<$t>().with_default::<$t>().with_clone::<$t>(),
                 }
             }
 
@@ -95,6 +406,200 @@ prim_impl!(bool, bool);
 prim_impl!(char, char);
 prim_impl!(String, String);
 
+/// Atomics have the same in-memory representation as the integers they
+/// wrap, so they're charted as those, but flagged so that tools read them
+/// with atomic loads.
+macro_rules! atomic_impl {
+    ($t:ident, $n:ident) => {
+        unsafe impl Introspectable for ::std::sync::atomic::$t {
+            fn contour() -> Contour {
+                Contour::Primitive {
+                    name: stringify!($t),
+                    type_id: ::std::any::TypeId::of::<Self>(),
+                    size: ::std::mem::size_of::<Self>(),
+                    align: ::std::mem::align_of::<Self>(),
+                    variant: Primitive::$n,
+                    atomic: true,
+                    hooks: Hooks::new::<Self>().with_default::<Self>(),
+                }
+            }
+
+            fn chart<CM: ContourMap>(map: &CM) {
+                map.register(Self::contour());
+            }
+        }
+    };
+}
+atomic_impl!(AtomicU8, u8);
+atomic_impl!(AtomicU16, u16);
+atomic_impl!(AtomicU32, u32);
+atomic_impl!(AtomicU64, u64);
+atomic_impl!(AtomicUsize, usize);
+atomic_impl!(AtomicI8, i8);
+atomic_impl!(AtomicI16, i16);
+atomic_impl!(AtomicI32, i32);
+atomic_impl!(AtomicI64, i64);
+atomic_impl!(AtomicIsize, isize);
+atomic_impl!(AtomicBool, bool);
+
+unsafe extern "C" fn vec_len<T>(p: *const u8) -> usize {
+    let v = &*(p as *const Vec<T>);
+    v.len()
+}
+
+unsafe extern "C" fn vec_item<T>(p: *const u8, ix: usize) -> *const u8 {
+    let v = &*(p as *const Vec<T>);
+    &v[ix] as *const T as *const u8
+}
+
+unsafe extern "C" fn vec_heap_size<T>(p: *const u8) -> usize {
+    let v = &*(p as *const Vec<T>);
+    v.capacity() * ::std::mem::size_of::<T>()
+}
+
+unsafe impl<T: Introspectable + 'static> Introspectable for Vec<T> {
+    fn contour() -> Contour {
+        Contour::Seq {
+            name: "Vec",
+            type_id: TypeId::of::<Vec<T>>(),
+            size: ::std::mem::size_of::<Vec<T>>(),
+            align: ::std::mem::align_of::<Vec<T>>(),
+            element: TypeId::of::<T>(),
+            len: vec_len::<T>,
+            item: vec_item::<T>,
+            heap_size: vec_heap_size::<T>,
+        }
+    }
+
+    fn chart<CM: ContourMap>(map: &CM) {
+        if map.register(Self::contour()) {
+            return;
+        }
+        T::chart(map);
+    }
+}
+
+unsafe extern "C" fn box_heap_size<T>(_: *const u8) -> usize {
+    ::std::mem::size_of::<T>()
+}
+
+/// `Rc` and `Arc` keep their strong and weak counts in front of the value.
+unsafe extern "C" fn rc_heap_size<T>(_: *const u8) -> usize {
+    let align = ::std::mem::align_of::<T>().max(::std::mem::align_of::<usize>());
+    let round = |n: usize| n.div_ceil(align) * align;
+    round(2 * ::std::mem::size_of::<usize>()) + round(::std::mem::size_of::<T>())
+}
+
+macro_rules! pointer_impl {
+    ($t:ty, $n:expr, $deref:expr, $heap_size:expr) => {
+        unsafe impl<T: Introspectable + 'static> Introspectable for $t {
+            fn contour() -> Contour {
+                Contour::Pointer {
+                    name: $n,
+                    type_id: TypeId::of::<$t>(),
+                    size: ::std::mem::size_of::<$t>(),
+                    align: ::std::mem::align_of::<$t>(),
+                    pointee: TypeId::of::<T>(),
+                    deref: $deref,
+                    heap_size: $heap_size,
+                }
+            }
+
+            fn chart<CM: ContourMap>(map: &CM) {
+                if map.register(Self::contour()) {
+                    return;
+                }
+                T::chart(map);
+            }
+        }
+    };
+}
+
+macro_rules! deref_impl {
+    ($t:ty, $deref:ident) => {
+        unsafe extern "C" fn $deref<T>(p: *const u8) -> *const u8 {
+            &**(p as *const $t) as *const T as *const u8
+        }
+    };
+}
+deref_impl!(Box<T>, box_deref);
+deref_impl!(Rc<T>, rc_deref);
+deref_impl!(Arc<T>, arc_deref);
+
+pointer_impl!(Box<T>, "Box", Some(box_deref::<T>), Some(box_heap_size::<T>));
+pointer_impl!(Rc<T>, "Rc", Some(rc_deref::<T>), Some(rc_heap_size::<T>));
+pointer_impl!(Arc<T>, "Arc", Some(arc_deref::<T>), Some(rc_heap_size::<T>));
+pointer_impl!(*const T, "*const", None, None);
+pointer_impl!(*mut T, "*mut", None, None);
+
+/// Reads the address out of the raw pointer at `ptr`.
+///
+/// # Safety
+///
+/// `ptr` must point to a live `*const T` or `*mut T` for some sized `T`.
+pub unsafe fn raw_address(ptr: *const u8) -> *const u8 {
+    *(ptr as *const *const u8)
+}
+
+macro_rules! map_impl {
+    ($t:ident, $n:expr, $len:ident, $entries:ident, $heap_size:ident, |$m:ident| $heap_body:expr
+     $(, $extra:ident)*) => {
+        unsafe extern "C" fn $len<K, V $(, $extra)*>(p: *const u8) -> usize {
+            let m = &*(p as *const $t<K, V $(, $extra)*>);
+            m.len()
+        }
+
+        unsafe fn $entries<K, V $(, $extra)*>(p: *const u8,
+                                              f: &mut dyn FnMut(*const u8, *const u8)) {
+            let m = &*(p as *const $t<K, V $(, $extra)*>);
+            for (k, v) in m.iter() {
+                f(k as *const K as *const u8, v as *const V as *const u8);
+            }
+        }
+
+        unsafe extern "C" fn $heap_size<K, V $(, $extra)*>(p: *const u8) -> usize {
+            let $m = &*(p as *const $t<K, V $(, $extra)*>);
+            $heap_body
+        }
+
+        unsafe impl<K, V $(, $extra)*> Introspectable for $t<K, V $(, $extra)*>
+            where K: Introspectable + 'static,
+                  V: Introspectable + 'static,
+                  $($extra: 'static),*
+        {
+            fn contour() -> Contour {
+                Contour::Map {
+                    name: $n,
+                    type_id: TypeId::of::<Self>(),
+                    size: ::std::mem::size_of::<Self>(),
+                    align: ::std::mem::align_of::<Self>(),
+                    key: TypeId::of::<K>(),
+                    value: TypeId::of::<V>(),
+                    len: $len::<K, V $(, $extra)*>,
+                    entries: $entries::<K, V $(, $extra)*>,
+                    heap_size: $heap_size::<K, V $(, $extra)*>,
+                }
+            }
+
+            fn chart<CM: ContourMap>(map: &CM) {
+                if map.register(Self::contour()) {
+                    return;
+                }
+                K::chart(map);
+                V::chart(map);
+            }
+        }
+    };
+}
+// Neither map exposes its real footprint, so these are estimates: a hash
+// table's slots plus a control byte each, and a B-tree's entries without the
+// per-node overhead.
+map_impl!(HashMap, "HashMap", hash_map_len, hash_map_entries, hash_map_heap_size,
+          |m| m.capacity() * (::std::mem::size_of::<(K, V)>() + 1),
+          S);
+map_impl!(BTreeMap, "BTreeMap", btree_map_len, btree_map_entries, btree_map_heap_size,
+          |m| m.len() * ::std::mem::size_of::<(K, V)>());
+
 impl Contour {
     pub fn name(&self) -> &'static str {
         match *self {
@@ -103,6 +608,61 @@ impl Contour {
             Contour::Unit {name, ..} => name,
             Contour::Enum {name, ..} => name,
             Contour::Primitive {name, ..} => name,
+            Contour::Seq {name, ..} => name,
+            Contour::Pointer {name, ..} => name,
+            Contour::Map {name, ..} => name,
+        }
+    }
+
+    /// `size_of` the type.  Unit structs are always zero-sized.
+    pub fn size(&self) -> usize {
+        match *self {
+            Contour::Struct {size, ..} => size,
+            Contour::Tuple {size, ..} => size,
+            Contour::Unit {..} => 0,
+            Contour::Enum {size, ..} => size,
+            Contour::Primitive {size, ..} => size,
+            Contour::Seq {size, ..} => size,
+            Contour::Pointer {size, ..} => size,
+            Contour::Map {size, ..} => size,
+        }
+    }
+
+    /// `align_of` the type.  Unit structs are always 1-aligned.
+    pub fn align(&self) -> usize {
+        match *self {
+            Contour::Struct {align, ..} => align,
+            Contour::Tuple {align, ..} => align,
+            Contour::Unit {..} => 1,
+            Contour::Enum {align, ..} => align,
+            Contour::Primitive {align, ..} => align,
+            Contour::Seq {align, ..} => align,
+            Contour::Pointer {align, ..} => align,
+            Contour::Map {align, ..} => align,
+        }
+    }
+
+    /// The `#[repr(...)]` of derived types.  Unit structs never have one
+    /// that matters, since they're zero-sized.
+    pub fn repr(&self) -> Option<&Repr> {
+        match *self {
+            Contour::Struct {ref repr, ..} => Some(repr),
+            Contour::Tuple {ref repr, ..} => Some(repr),
+            Contour::Enum {ref repr, ..} => Some(repr),
+            _ => None,
+        }
+    }
+
+    /// How to create, copy and destroy values of the type, for derived types
+    /// and primitives.
+    pub fn hooks(&self) -> Option<&Hooks> {
+        match *self {
+            Contour::Struct {ref hooks, ..} => Some(hooks),
+            Contour::Tuple {ref hooks, ..} => Some(hooks),
+            Contour::Unit {ref hooks, ..} => Some(hooks),
+            Contour::Enum {ref hooks, ..} => Some(hooks),
+            Contour::Primitive {ref hooks, ..} => Some(hooks),
+            _ => None,
         }
     }
 
@@ -113,15 +673,92 @@ impl Contour {
             Contour::Unit {type_id, ..} => type_id,
             Contour::Enum {type_id, ..} => type_id,
             Contour::Primitive {type_id, ..} => type_id,
+            Contour::Seq {type_id, ..} => type_id,
+            Contour::Pointer {type_id, ..} => type_id,
+            Contour::Map {type_id, ..} => type_id,
         }
     }
 }
 
+/// A type's `#[repr(...)]` attributes, which decide how much of its layout
+/// can be relied on outside of Rust.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Repr {
+    pub c: bool,
+    pub transparent: bool,
+    /// `Some(1)` for `packed`, or `Some(n)` for `packed(n)`.
+    pub packed: Option<usize>,
+    pub align: Option<usize>,
+    /// An enum's discriminant type, as in `repr(u8)`.
+    pub int: Option<&'static str>,
+}
+
+/// Type-erased lifecycle functions for a type.  Derived types always get
+/// `drop`, and get `default` and `clone` when they ask for them with
+/// `#[contour(hooks(default, clone))]`.  See `Boxed` for a safe wrapper.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Hooks {
+    /// Writes `Default::default()` to the uninitialized memory at its
+    /// argument.
+    pub default: Option<unsafe fn(*mut u8)>,
+    /// Writes a clone of the value at its first argument to the uninitialized
+    /// memory at its second.
+    pub clone: Option<unsafe fn(*const u8, *mut u8)>,
+    /// Drops the value at its argument in place.
+    pub drop: Option<unsafe fn(*mut u8)>,
+}
+
+/// Hooks are compared by which of them are present, like the function
+/// pointers in `Contour`.
+impl PartialEq for Hooks {
+    fn eq(&self, other: &Hooks) -> bool {
+        (self.default.is_some(), self.clone.is_some(), self.drop.is_some()) ==
+            (other.default.is_some(), other.clone.is_some(), other.drop.is_some())
+    }
+}
+
+impl Eq for Hooks {}
+
+impl Hooks {
+    pub fn new<T>() -> Self {
+        Hooks { default: None, clone: None, drop: Some(drop_hook::<T>) }
+    }
+
+    pub fn with_default<T: Default>(mut self) -> Self {
+        self.default = Some(default_hook::<T>);
+        self
+    }
+
+    pub fn with_clone<T: Clone>(mut self) -> Self {
+        self.clone = Some(clone_hook::<T>);
+        self
+    }
+}
+
+unsafe fn drop_hook<T>(ptr: *mut u8) {
+    ::std::ptr::drop_in_place(ptr as *mut T);
+}
+
+unsafe fn default_hook<T: Default>(ptr: *mut u8) {
+    ::std::ptr::write(ptr as *mut T, T::default());
+}
+
+unsafe fn clone_hook<T: Clone>(src: *const u8, dst: *mut u8) {
+    ::std::ptr::write(dst as *mut T, (*(src as *const T)).clone());
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct StructField {
     pub name: &'static str,
     pub type_id: TypeId,
     pub offset: usize,
+    /// Set by `#[contour(redact)]`: the field is charted as usual, but tools
+    /// that display values should hide it.
+    pub redacted: bool,
+    /// Rules the field's value has to follow, checked by `validate`.
+    pub constraints: Vec<Constraint>,
+    /// Named bits within an integer field, for displaying it symbolically.
+    pub bits: Vec<BitField>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -129,12 +766,96 @@ pub struct TupleField {
     pub ix: usize,
     pub type_id: TypeId,
     pub offset: usize,
+    pub redacted: bool,
+    pub constraints: Vec<Constraint>,
+    pub bits: Vec<BitField>,
+}
+
+/// Declared on fields with `#[contour(range(min = 1, max = 65535))]`,
+/// `#[contour(non_empty)]` and `#[contour(regex = "...")]`.  Constraints
+/// apply through pointers, so a `Box<String>` can be `non_empty`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Constraint {
+    /// Inclusive bounds on a number.  Either may be left off.
+    Range { min: Option<Bound>, max: Option<Bound> },
+    /// A string, sequence or map with something in it.
+    NonEmpty,
+    /// A string that matches the pattern in its entirety.  Checking these
+    /// needs the `regex` feature.
+    Regex(&'static str),
+}
+
+/// A limit in a `Constraint::Range`, kept as an integer where it was written
+/// as one so large `u64`s and `i64`s compare exactly.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bound {
+    Int(i128),
+    Float(f64),
+}
+
+/// Bounds come from literals, which can't be NaN.
+impl Eq for Bound {}
+
+/// A named run of bits within an integer field, declared with
+/// `#[contour(bits(ready = 0, mode(offset = 1, width = 3)))]`.  Fields one
+/// bit wide are flags; wider ones hold packed values.  Fields are read as
+/// `u64`s, so bit fields can't reach past the 64th bit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BitField {
+    pub name: &'static str,
+    /// Counted from the least significant bit.
+    pub offset: u32,
+    pub width: u32,
+}
+
+impl BitField {
+    pub fn mask(&self) -> u64 {
+        let ones: u64 = if self.width >= 64 { !0 } else { (1 << self.width) - 1 };
+        ones.checked_shl(self.offset).unwrap_or(0)
+    }
+
+    pub fn extract(&self, word: u64) -> u64 {
+        (word & self.mask()) >> self.offset
+    }
+
+    /// Renders `word` as its set flags and non-zero packed values, e.g.
+    /// `ready | mode=3`, followed by any set bits that no field covers.
+    pub fn describe(fields: &[BitField], word: u64) -> String {
+        let mut parts = vec![];
+        let mut rest = word;
+        for field in fields {
+            let value = field.extract(word);
+            rest &= !field.mask();
+            if value == 0 {
+                continue;
+            }
+            parts.push(if field.width == 1 {
+                field.name.to_owned()
+            } else {
+                format!("{}={}", field.name, value)
+            });
+        }
+        if rest != 0 {
+            parts.push(format!("{:#x}", rest));
+        }
+        if parts.is_empty() {
+            "0".to_owned()
+        } else {
+            parts.join(" | ")
+        }
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Variant {
     pub name: &'static str,
+    /// The variant's discriminant value, in the enum's `repr(int)` type, or
+    /// `isize` if it doesn't have one.
+    pub discriminant: i128,
     pub fields: VariantFields,
+    /// Whether any fields were left out with `#[contour(skip)]`, as for
+    /// `Contour::Struct`.
+    pub skipped: bool,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -144,18 +865,45 @@ pub enum VariantFields {
     Unit,
 }
 
-pub trait Introspectable {
+/// A type that can describe its own layout.
+///
+/// # Safety
+///
+/// Tools read values through raw pointers wherever the contour says to, so
+/// it has to describe the type exactly: `type_id`, `size` and `align` are the
+/// type's own, every field's `offset` and `type_id` match a field that's
+/// really there, and the field types' contours are charted.  The functions in
+/// enum, sequence, map and pointer contours have to return tags, lengths,
+/// elements and targets that are valid for as long as the value is borrowed.
+/// `#[derive(Introspectable)]` upholds all of this.
+pub unsafe trait Introspectable {
     fn contour() -> Contour;
 
     /// The type is responsible for charting its descendants and *not* recursing
     /// if it's already been charted.
     fn chart<CM: ContourMap>(map: &CM);
+
+    /// Stable 64-bit digest of this type's layout and the layouts of
+    /// everything it reaches.  See `contour::fingerprint`.
+    fn fingerprint() -> u64 {
+        let registry = Registry::new();
+        Self::chart(&registry);
+        fingerprint(Self::contour().type_id(), &registry)
+            .expect("chart didn't register all descendants")
+    }
 }
 
 pub trait ContourMap {
     /// Returns `true` if `type_id` exists and `contour` matches.
     /// Panics if `type_id` exists and `contour` doesn't match.
     fn register(&self, contour: Contour) -> bool;
+
+    /// Returns the contour registered for `type_id`, if any.  Maps that only
+    /// collect contours can leave this out, but tools that walk values need
+    /// to look their descendants up, so they won't work with those.
+    fn lookup(&self, _type_id: TypeId) -> Option<Contour> {
+        None
+    }
 }
 
 #[cfg(test)]
@@ -183,6 +931,10 @@ mod tests {
             map.insert(type_id, contour);
             false
         }
+
+        fn lookup(&self, type_id: TypeId) -> Option<Contour> {
+            self.map.borrow().get(&type_id).cloned()
+        }
     }
 
     #[derive(Introspectable)]
@@ -259,9 +1011,95 @@ mod tests {
         assert_eq!(unsafe {e2t(&SecondEnum::Mark as *const _ as *const u8)}, 3);
     }
 
+    #[derive(Introspectable)]
+    struct Cached {
+        key: u32,
+        #[contour(skip)]
+        hits: u64,
+    }
+
+    #[derive(Introspectable)]
+    enum Slot {
+        Empty,
+        Full(u32, #[contour(skip)] u64),
+    }
+
+    #[test]
+    fn test_skipped() {
+        match Cached::contour() {
+            Contour::Struct { ref fields, skipped, .. } => {
+                assert_eq!(fields.len(), 1);
+                assert!(skipped);
+            },
+            c => panic!("Wrong contour {:?}", c),
+        }
+        match Slot::contour() {
+            Contour::Enum { ref variants, .. } =>
+                assert_eq!(variants.iter().map(|v| v.skipped).collect::<Vec<_>>(),
+                           vec![false, true]),
+            c => panic!("Wrong contour {:?}", c),
+        }
+    }
+
+    #[derive(Introspectable)]
+    #[repr(C)]
+    #[contour(assert_layout(size = 16, align = 8))]
+    struct WireHeader {
+        #[contour(offset = 0)]
+        magic: u32,
+        #[contour(offset = 8)]
+        len: u64,
+    }
+
+    #[derive(Introspectable)]
+    #[repr(C)]
+    struct MovedField(u8, #[contour(offset = 1)] u32);
+
+    #[derive(Introspectable)]
+    #[contour(assert_layout(size = 2))]
+    enum Grown {
+        A(u8),
+        B(u32),
+    }
+
+    #[test]
+    fn test_layout_assertions() {
+        WireHeader::contour();
+    }
+
+    #[test]
+    #[should_panic(expected = "Layout of `MovedField` changed: `1` is at offset 4, expected 1")]
+    fn test_offset_assertion() {
+        MovedField::contour();
+    }
+
+    #[test]
+    #[should_panic(expected = "Layout of `Grown` changed: size is 8, expected 2")]
+    fn test_size_assertion() {
+        Grown::contour();
+    }
+
+    #[derive(Introspectable)]
+    enum GenericEnum<A: Introspectable + 'static> {
+        Empty,
+        Full(u8, A),
+    }
+
     #[test]
     fn test_generic() {
         println!("{:#?}", GenericTest::<u64>::contour());
+
+        let contour = GenericEnum::<u64>::contour();
+        assert_eq!(contour.size(), ::std::mem::size_of::<GenericEnum<u64>>());
+        assert_eq!(contour.align(), ::std::mem::align_of::<GenericEnum<u64>>());
+        assert_eq!(contour.type_id(), TypeId::of::<GenericEnum<u64>>());
+        let tag = match contour {
+            Contour::Enum { tag, .. } => tag,
+            _ => panic!("Wrong variant!"),
+        };
+        let full = GenericEnum::Full(1, 2u64);
+        assert_eq!(unsafe {tag(&full as *const _ as *const u8)}, 1);
+        assert_eq!(unsafe {tag(&GenericEnum::<u64>::Empty as *const _ as *const u8)}, 0);
     }
 
     #[test]
@@ -288,4 +1126,27 @@ mod tests {
         A::chart(&mut sm);
         assert_eq!(sm.map.borrow().len(), 5);
     }
+
+    #[test]
+    fn test_atomics() {
+        use std::sync::atomic::{
+            AtomicBool,
+            AtomicI16,
+        };
+        let (variant, atomic) = match AtomicI16::contour() {
+            Contour::Primitive { variant, atomic, .. } => (variant, atomic),
+            c => panic!("unexpected contour {:?}", c),
+        };
+        assert_eq!((variant.clone(), atomic), (Primitive::i16, true));
+        let n = AtomicI16::new(-7);
+        let ptr = &n as *const AtomicI16 as *const u8;
+        assert_eq!(unsafe { variant.load(ptr, atomic) }, PrimitiveValue::i16(-7));
+        let flag = AtomicBool::new(true);
+        let ptr = &flag as *const AtomicBool as *const u8;
+        assert_eq!(unsafe { Primitive::bool.load(ptr, true) }, PrimitiveValue::bool(true));
+        match i16::contour() {
+            Contour::Primitive { atomic, .. } => assert!(!atomic),
+            c => panic!("unexpected contour {:?}", c),
+        }
+    }
 }
@@ -0,0 +1,425 @@
+//! Rebuilding typed values from snapshots, possibly taken by an older build.
+//!
+//! Types opt in with `#[derive(Load)]`.  Struct fields are matched by name
+//! rather than position, and enum variants by name rather than index, so
+//! fields can be added, removed and reordered between the build that took a
+//! snapshot and the one loading it:
+//!
+//! * Fields the snapshot has but the type doesn't are dropped.
+//! * Fields the type has but the snapshot doesn't are filled in from
+//!   `#[contour(default = literal)]` or `#[contour(default_expr = "expr")]`
+//!   if there is one, or `Default` otherwise.
+//!   `#[contour(skip)]` fields, which snapshots never have, are always filled
+//!   in this way, and aren't reported.
+//! * Integers load into any integer type they fit in, and `f32`s into `f64`s.
+//! * `Box`, `Rc` and `Arc` load from either a pointer or a bare value, so a
+//!   field can move behind a pointer (but not back out from behind one).
+//!
+//! Both kinds of difference are reported by path in `Loaded`.  Anything else,
+//! like a field whose type changed incompatibly or a variant that's since been
+//! removed, is a `LoadError`.
+
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::hash::{
+    BuildHasher,
+    Hash,
+};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use path::{
+    Key,
+    Path,
+    Segment,
+};
+use schema::DecodeError;
+use snapshot::Snapshot;
+use value::{
+    FieldValues,
+    Value,
+};
+use PrimitiveValue;
+
+pub trait Load: Sized {
+    fn load(value: &Value, cx: &mut Loader) -> Result<Self, LoadError>;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Loaded<T> {
+    pub value: T,
+    /// Fields in the snapshot that `T` no longer has.
+    pub dropped: Vec<Path>,
+    /// Fields of `T` that the snapshot didn't have.
+    pub defaulted: Vec<Path>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadError {
+    Decode(DecodeError),
+    /// The snapshot has a `found` at `path` where the type wants `expected`.
+    Type { path: String, expected: &'static str, found: String },
+    /// The snapshot's enum at `path` is in a variant the type doesn't have.
+    Variant { path: String, name: String },
+    /// The snapshot has a null or cyclic pointer at `path`, which can't be
+    /// rebuilt as an owned value.
+    Pointer { path: String },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Decode(ref e) => write!(f, "corrupt snapshot {}", e),
+            LoadError::Type { ref path, expected, ref found } =>
+                write!(f, "at `{}`: expected {}, found {}", path, expected, found),
+            LoadError::Variant { ref path, ref name } =>
+                write!(f, "at `{}`: no variant named `{}`", path, name),
+            LoadError::Pointer { ref path } =>
+                write!(f, "at `{}`: can't load a null or cyclic pointer", path),
+        }
+    }
+}
+
+impl error::Error for LoadError {}
+
+impl From<DecodeError> for LoadError {
+    fn from(e: DecodeError) -> Self {
+        LoadError::Decode(e)
+    }
+}
+
+/// Decodes a snapshot and loads it as a `T`.
+pub fn load<T: Load>(bytes: &[u8]) -> Result<Loaded<T>, LoadError> {
+    load_value(&Snapshot::decode(bytes)?.value)
+}
+
+pub fn load_value<T: Load>(value: &Value) -> Result<Loaded<T>, LoadError> {
+    let mut cx = Loader { path: Path::root(), dropped: vec![], defaulted: vec![] };
+    let value = T::load(value, &mut cx)?;
+    Ok(Loaded { value, dropped: cx.dropped, defaulted: cx.defaulted })
+}
+
+/// Tracks where in the snapshot loading has got to, and what's been dropped
+/// or defaulted along the way.  Derived `Load` impls go through it for
+/// everything but leaf values.
+pub struct Loader {
+    path: Path,
+    dropped: Vec<Path>,
+    defaulted: Vec<Path>,
+}
+
+impl Loader {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn load<T: Load>(&mut self, segment: Segment, value: &Value) -> Result<T, LoadError> {
+        self.path.push(segment);
+        let result = T::load(value, self);
+        self.path.pop();
+        result
+    }
+
+    pub fn type_error<T>(&self, expected: &'static str, found: &Value) -> Result<T, LoadError> {
+        Err(LoadError::Type {
+            path: self.path.to_string(),
+            expected,
+            found: describe(found),
+        })
+    }
+
+    /// The fields of a struct.  Tuple structs' fields are named by index.
+    pub fn fields<'v>(&self, value: &'v Value) -> Result<&'v FieldValues, LoadError> {
+        match *value {
+            Value::Struct { ref fields, .. } => Ok(fields),
+            ref v => self.type_error("struct", v),
+        }
+    }
+
+    /// The name and fields of an enum's variant.
+    pub fn variant<'v>(&self, value: &'v Value) -> Result<(&'v str, &'v FieldValues), LoadError> {
+        match *value {
+            Value::Enum { ref variant, ref fields, .. } => Ok((variant, fields)),
+            ref v => self.type_error("enum", v),
+        }
+    }
+
+    /// Runs `f` with the path inside `variant`.
+    pub fn in_variant<T, F>(&mut self, variant: &str, f: F) -> Result<T, LoadError>
+        where F: FnOnce(&mut Self) -> Result<T, LoadError>
+    {
+        self.path.push(Segment::Variant(variant.to_owned()));
+        let result = f(self);
+        self.path.pop();
+        result
+    }
+
+    pub fn unknown_variant<T>(&self, variant: &str) -> Result<T, LoadError> {
+        Err(LoadError::Variant { path: self.path.to_string(), name: variant.to_owned() })
+    }
+
+    /// Loads the field called `name`, or returns `None` and records it as
+    /// defaulted if the snapshot doesn't have it.
+    pub fn field<T: Load>(&mut self, fields: &FieldValues, name: &str)
+        -> Result<Option<T>, LoadError>
+    {
+        let segment = field_segment(name);
+        match fields.get(name) {
+            Some(value) => self.load(segment, value).map(Some),
+            None => {
+                self.defaulted.push(self.path.child(segment));
+                Ok(None)
+            },
+        }
+    }
+
+    /// Records the fields not among `known` as dropped.
+    pub fn unknown(&mut self, fields: &FieldValues, known: &[&str]) {
+        let names: Vec<String> = match *fields {
+            FieldValues::Named(ref fields) => fields.iter().map(|f| f.0.clone()).collect(),
+            FieldValues::Tuple(ref fields) => (0..fields.len()).map(|i| i.to_string()).collect(),
+            FieldValues::Unit => vec![],
+        };
+        for name in names {
+            if !known.contains(&&name[..]) {
+                self.dropped.push(self.path.child(field_segment(&name)));
+            }
+        }
+    }
+}
+
+fn field_segment(name: &str) -> Segment {
+    match name.parse() {
+        Ok(ix) => Segment::Tuple(ix),
+        Err(_) => Segment::Field(name.to_owned()),
+    }
+}
+
+fn describe(value: &Value) -> String {
+    match *value {
+        Value::Struct { ref name, .. } => format!("struct `{}`", name),
+        Value::Enum { ref name, .. } => format!("enum `{}`", name),
+        Value::Primitive(ref v) => format!("`{}`", v),
+        Value::Seq(..) => "sequence".to_owned(),
+        Value::Map(..) => "map".to_owned(),
+        Value::Pointer(..) => "pointer".to_owned(),
+        Value::Address(..) => "raw pointer".to_owned(),
+        Value::Cycle => "cycle".to_owned(),
+    }
+}
+
+fn int_value(value: &PrimitiveValue) -> Option<i128> {
+    match *value {
+        PrimitiveValue::u8(v) => Some(v as i128),
+        PrimitiveValue::u16(v) => Some(v as i128),
+        PrimitiveValue::u32(v) => Some(v as i128),
+        PrimitiveValue::u64(v) => Some(v as i128),
+        PrimitiveValue::usize(v) => Some(v as i128),
+        PrimitiveValue::i8(v) => Some(v as i128),
+        PrimitiveValue::i16(v) => Some(v as i128),
+        PrimitiveValue::i32(v) => Some(v as i128),
+        PrimitiveValue::i64(v) => Some(v as i128),
+        PrimitiveValue::isize(v) => Some(v as i128),
+        _ => None,
+    }
+}
+
+macro_rules! int_load {
+    ($($t:ident),*) => {
+        $(impl Load for $t {
+            fn load(value: &Value, cx: &mut Loader) -> Result<Self, LoadError> {
+                match *value {
+                    Value::Primitive(ref p) => match int_value(p).map($t::try_from) {
+                        Some(Ok(v)) => Ok(v),
+                        _ => cx.type_error(stringify!($t), value),
+                    },
+                    _ => cx.type_error(stringify!($t), value),
+                }
+            }
+        })*
+    };
+}
+int_load!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+macro_rules! exact_load {
+    ($t:ident, $($pat:pat => $e:expr),*) => {
+        impl Load for $t {
+            fn load(value: &Value, cx: &mut Loader) -> Result<Self, LoadError> {
+                match *value {
+                    $(Value::Primitive($pat) => Ok($e),)*
+                    _ => cx.type_error(stringify!($t), value),
+                }
+            }
+        }
+    };
+}
+exact_load!(f32, PrimitiveValue::f32(v) => v);
+exact_load!(f64, PrimitiveValue::f32(v) => v as f64, PrimitiveValue::f64(v) => v);
+exact_load!(bool, PrimitiveValue::bool(v) => v);
+exact_load!(char, PrimitiveValue::char(v) => v);
+exact_load!(String, PrimitiveValue::String(ref v) => v.clone());
+
+impl<T: Load> Load for Vec<T> {
+    fn load(value: &Value, cx: &mut Loader) -> Result<Self, LoadError> {
+        match *value {
+            Value::Seq(ref items) => items.iter()
+                .enumerate()
+                .map(|(i, item)| cx.load(Segment::Index(Key::Int(i as i64)), item))
+                .collect(),
+            _ => cx.type_error("sequence", value),
+        }
+    }
+}
+
+macro_rules! pointer_load {
+    ($p:ident) => {
+        impl<T: Load> Load for $p<T> {
+            fn load(value: &Value, cx: &mut Loader) -> Result<Self, LoadError> {
+                match *value {
+                    Value::Pointer(Some(ref target)) => T::load(target, cx).map($p::new),
+                    Value::Pointer(None) | Value::Cycle =>
+                        Err(LoadError::Pointer { path: cx.path.to_string() }),
+                    ref v => T::load(v, cx).map($p::new),
+                }
+            }
+        }
+    };
+}
+pointer_load!(Box);
+pointer_load!(Rc);
+pointer_load!(Arc);
+
+/// Entries are addressed by key where the key is a primitive, and by
+/// position otherwise.
+fn load_entries<K: Load, V: Load>(value: &Value, cx: &mut Loader) -> Result<Vec<(K, V)>, LoadError> {
+    match *value {
+        Value::Map(ref entries) => entries.iter()
+            .enumerate()
+            .map(|(i, (k, v))| {
+                let key = match *k {
+                    Value::Primitive(ref p) => Key::from_value(p),
+                    _ => None,
+                };
+                let segment = Segment::Index(key.unwrap_or(Key::Int(i as i64)));
+                Ok((cx.load(segment.clone(), k)?, cx.load(segment, v)?))
+            })
+            .collect(),
+        _ => cx.type_error("map", value),
+    }
+}
+
+impl<K, V, S> Load for HashMap<K, V, S>
+    where K: Load + Eq + Hash, V: Load, S: BuildHasher + Default
+{
+    fn load(value: &Value, cx: &mut Loader) -> Result<Self, LoadError> {
+        Ok(load_entries(value, cx)?.into_iter().collect())
+    }
+}
+
+impl<K: Load + Ord, V: Load> Load for BTreeMap<K, V> {
+    fn load(value: &Value, cx: &mut Loader) -> Result<Self, LoadError> {
+        Ok(load_entries(value, cx)?.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        StructField,
+        TupleField,
+        Variant,
+        VariantFields,
+    };
+    use snapshot::snapshot;
+    use super::*;
+
+    mod v1 {
+        use super::*;
+
+        #[derive(Introspectable)]
+        pub enum Mode {
+            Off,
+            Fixed(u8),
+            Legacy { level: u32 },
+        }
+
+        #[derive(Introspectable)]
+        pub struct Settings {
+            pub name: String,
+            pub retries: u16,
+            pub modes: Vec<Mode>,
+            pub obsolete: bool,
+        }
+    }
+
+    #[derive(Debug, Introspectable, Load, PartialEq)]
+    enum Mode {
+        Off,
+        Fixed(u64),
+        Scaled { factor: f64 },
+    }
+
+    #[derive(Debug, Introspectable, Load, PartialEq)]
+    struct Settings {
+        retries: i64,
+        name: Box<String>,
+        modes: Vec<Mode>,
+        #[contour(default_expr = "vec![\"local\".to_owned()]")]
+        zones: Vec<String>,
+        #[contour(default = "localhost")]
+        host: String,
+        timeout: u32,
+        #[contour(skip, default = 7)]
+        cache: u8,
+    }
+
+    #[test]
+    fn test_evolve() {
+        let old = v1::Settings {
+            name: "primary".to_owned(),
+            retries: 3,
+            modes: vec![v1::Mode::Off, v1::Mode::Fixed(2)],
+            obsolete: true,
+        };
+        let loaded = load::<Settings>(&snapshot(&old)).unwrap();
+        assert_eq!(loaded.value, Settings {
+            retries: 3,
+            name: Box::new("primary".to_owned()),
+            modes: vec![Mode::Off, Mode::Fixed(2)],
+            zones: vec!["local".to_owned()],
+            host: "localhost".to_owned(),
+            timeout: 0,
+            cache: 7,
+        });
+        let paths = |ps: &[Path]| ps.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert_eq!(paths(&loaded.dropped), vec!["obsolete"]);
+        assert_eq!(paths(&loaded.defaulted), vec!["zones", "host", "timeout"]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let old = v1::Settings {
+            name: "primary".to_owned(),
+            retries: 3,
+            modes: vec![v1::Mode::Off, v1::Mode::Legacy { level: 1 }],
+            obsolete: false,
+        };
+        let err = load::<Settings>(&snapshot(&old)).unwrap_err();
+        assert_eq!(err.to_string(), "at `modes[1]`: no variant named `Legacy`");
+
+        let err = load::<Vec<u8>>(&snapshot(&vec![1u32, 300])).unwrap_err();
+        assert_eq!(err.to_string(), "at `[1]`: expected u8, found `300`");
+
+        let err = load::<Settings>(&snapshot(&7u8)).unwrap_err();
+        assert_eq!(err.to_string(), "at ``: expected struct, found `7`");
+    }
+}
@@ -0,0 +1,331 @@
+//! Zero-copy views of values that another process wrote into a file or
+//! shared memory.
+//!
+//! A region starts with a header carrying the layout fingerprint of the type
+//! it holds, followed by the value itself, aligned for its type.  `encode`
+//! writes one; a `View` checks the fingerprint and every byte that could make
+//! the value invalid before handing out a reference into the region.
+//!
+//! Only plain data can be viewed in place: structs and tuples of numbers,
+//! `bool`s and `char`s, whose types opt in by implementing `Plain`.
+//! Anything with pointers would point into the writer's address space, and
+//! enum tags aren't checked.  Structs with skipped fields are refused because
+//! those fields' bytes go unchecked, and atomics because a shared `&` would
+//! let readers write into the region.  The fingerprint covers sizes and
+//! offsets but not byte order, so writers and readers must share an
+//! architecture, and the region mustn't change while it's viewed.
+
+use std::any::TypeId;
+use std::char;
+use std::error;
+use std::fmt;
+use std::mem;
+use std::ops::Deref;
+
+use path::{
+    Path,
+    Segment,
+};
+use snapshot::{
+    snapshot,
+    Snapshot,
+};
+use value::Value;
+use {
+    Contour,
+    ContourMap,
+    Introspectable,
+    Primitive,
+    Registry,
+};
+
+const MAGIC: &[u8] = b"CNTM";
+const VERSION: u8 = 1;
+
+/// Magic, version, three reserved bytes and the fingerprint.
+const HEADER_LEN: usize = 16;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ViewError {
+    /// The region isn't a well-formed, suitably aligned view.
+    Malformed(String),
+    /// The region was written for a different layout.
+    Fingerprint { expected: u64, found: u64 },
+    /// The type at `path` isn't plain data.
+    NotPlain { path: String, name: String },
+    /// The bytes at `path` aren't a valid value of its type.
+    Invalid { path: String, msg: String },
+}
+
+impl fmt::Display for ViewError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ViewError::Malformed(ref msg) => write!(f, "{}", msg),
+            ViewError::Fingerprint { expected, found } =>
+                write!(f, "region has layout {:016x}, expected {:016x}", found, expected),
+            ViewError::NotPlain { ref path, ref name } =>
+                write!(f, "at `{}`: `{}` can't be viewed in place", path, name),
+            ViewError::Invalid { ref path, ref msg } => write!(f, "at `{}`: {}", path, msg),
+        }
+    }
+}
+
+impl error::Error for ViewError {}
+
+/// Types that any bytes passing `View::new`'s checks are a valid value of.
+///
+/// # Safety
+///
+/// The type mustn't rely on invariants between its fields, like `len <= cap`,
+/// or on anything else the contour doesn't describe, since a region can hold
+/// whatever its writer likes.
+pub unsafe trait Plain: Introspectable {}
+
+/// A read-only `T` that lives in someone else's bytes.
+pub struct View<'a, T: 'a> {
+    value: &'a T,
+}
+
+impl<'a, T: Plain> View<'a, T> {
+    /// Views `region`, which should be mapped at least 16-byte aligned so
+    /// that the value inside is aligned for `T`.
+    pub fn new(region: &'a [u8]) -> Result<Self, ViewError> {
+        let malformed = |msg: &str| Err(ViewError::Malformed(msg.to_owned()));
+        if region.len() < HEADER_LEN || &region[..MAGIC.len()] != MAGIC {
+            return malformed("not a mapped value");
+        }
+        if region[MAGIC.len()] != VERSION {
+            return malformed("unsupported version");
+        }
+        let mut found = [0; 8];
+        found.copy_from_slice(&region[8..HEADER_LEN]);
+        let found = u64::from_le_bytes(found);
+        let expected = T::fingerprint();
+        if found != expected {
+            return Err(ViewError::Fingerprint { expected, found });
+        }
+
+        let leaves = leaves::<T>()?;
+        let start = start::<T>();
+        if region.len() < start + mem::size_of::<T>() {
+            return malformed("region is too short");
+        }
+        let data = &region[start..];
+        if !(data.as_ptr() as usize).is_multiple_of(mem::align_of::<T>()) {
+            return malformed("value isn't aligned");
+        }
+        for leaf in &leaves {
+            let bytes = &data[leaf.offset..leaf.offset + leaf.size];
+            let msg = match leaf.kind {
+                Primitive::bool if bytes[0] > 1 => format!("{} isn't a bool", bytes[0]),
+                Primitive::char => {
+                    let mut raw = [0; 4];
+                    raw.copy_from_slice(bytes);
+                    let raw = u32::from_ne_bytes(raw);
+                    match char::from_u32(raw) {
+                        Some(_) => continue,
+                        None => format!("{:#x} isn't a char", raw),
+                    }
+                },
+                _ => continue,
+            };
+            return Err(ViewError::Invalid { path: leaf.path.to_string(), msg });
+        }
+        Ok(View { value: unsafe { &*(data.as_ptr() as *const T) } })
+    }
+
+    pub fn get(&self) -> &'a T {
+        self.value
+    }
+
+    /// A copy of the value for dynamic tools.
+    pub fn value(&self) -> Value {
+        Snapshot::decode(&snapshot(self.value)).expect("snapshot didn't round-trip").value
+    }
+}
+
+impl<'a, T> Deref for View<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+/// Writes `value` out as a region that `View::<T>::new` accepts.  Padding
+/// is zeroed.
+pub fn encode<T: Introspectable>(value: &T) -> Result<Vec<u8>, ViewError> {
+    let leaves = leaves::<T>()?;
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    out.resize(8, 0);
+    out.extend_from_slice(&T::fingerprint().to_le_bytes());
+    let start = start::<T>();
+    out.resize(start + mem::size_of::<T>(), 0);
+    let ptr = value as *const T as *const u8;
+    for leaf in &leaves {
+        for i in 0..leaf.size {
+            out[start + leaf.offset + i] = unsafe { *ptr.add(leaf.offset + i) };
+        }
+    }
+    Ok(out)
+}
+
+/// Where the value starts in a region.
+fn start<T>() -> usize {
+    let align = mem::align_of::<T>();
+    HEADER_LEN.div_ceil(align) * align
+}
+
+/// A primitive somewhere inside a plain value.
+struct Leaf {
+    path: Path,
+    offset: usize,
+    size: usize,
+    kind: Primitive,
+}
+
+fn leaves<T: Introspectable>() -> Result<Vec<Leaf>, ViewError> {
+    let registry = Registry::of::<T>();
+    let mut out = vec![];
+    collect(&registry, T::contour().type_id(), 0, &mut Path::root(), &mut out)?;
+    Ok(out)
+}
+
+fn collect(map: &Registry, type_id: TypeId, offset: usize, path: &mut Path, out: &mut Vec<Leaf>)
+    -> Result<(), ViewError>
+{
+    let contour = map.lookup(type_id).expect("chart didn't register all descendants");
+    match contour {
+        Contour::Struct { ref fields, skipped: false, .. } => for field in fields {
+            path.push(Segment::Field(field.name.to_owned()));
+            collect(map, field.type_id, offset + field.offset, path, out)?;
+            path.pop();
+        },
+        Contour::Tuple { ref fields, skipped: false, .. } => for field in fields {
+            path.push(Segment::Tuple(field.ix));
+            collect(map, field.type_id, offset + field.offset, path, out)?;
+            path.pop();
+        },
+        Contour::Unit { .. } => {},
+        Contour::Primitive { size, ref variant, atomic: false, .. }
+            if *variant != Primitive::String =>
+        {
+            out.push(Leaf {
+                path: path.clone(),
+                offset,
+                size,
+                kind: variant.clone(),
+            });
+        },
+        _ => return Err(ViewError::NotPlain {
+            path: path.to_string(),
+            name: contour.name().to_owned(),
+        }),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        StructField,
+        TupleField,
+    };
+    use std::sync::atomic::AtomicU32;
+
+    use super::*;
+
+    #[derive(Introspectable)]
+    struct Point(i16, i16);
+
+    unsafe impl Plain for Point {}
+
+    #[derive(Introspectable)]
+    struct Stats {
+        hits: u64,
+        ratio: f32,
+        live: bool,
+        grade: char,
+        origin: Point,
+    }
+
+    unsafe impl Plain for Stats {}
+
+    #[derive(Introspectable)]
+    struct Named {
+        hits: u64,
+        name: String,
+    }
+
+    #[derive(Introspectable)]
+    struct Flagged {
+        hits: u64,
+        #[contour(skip)]
+        live: bool,
+    }
+
+    #[derive(Introspectable)]
+    struct Counter {
+        hits: AtomicU32,
+    }
+
+    /// Copies `bytes` somewhere 16-byte aligned, like a mapping would be.
+    fn mapped(bytes: &[u8]) -> Vec<u128> {
+        let mut out = vec![0u128; (bytes.len() + 15) / 16];
+        let ptr = out.as_mut_ptr() as *mut u8;
+        for (i, b) in bytes.iter().enumerate() {
+            unsafe { *ptr.offset(i as isize) = *b };
+        }
+        out
+    }
+
+    fn region(words: &[u128], len: usize) -> &[u8] {
+        unsafe { ::std::slice::from_raw_parts(words.as_ptr() as *const u8, len) }
+    }
+
+    #[test]
+    fn test_view() {
+        let stats = Stats { hits: 42, ratio: 0.5, live: true, grade: 'B', origin: Point(-3, 4) };
+        let bytes = encode(&stats).unwrap();
+        let words = mapped(&bytes);
+        let view = View::<Stats>::new(region(&words, bytes.len())).unwrap();
+        assert_eq!((view.hits, view.grade, view.origin.0), (42, 'B', -3));
+        assert_eq!(view.value(), Snapshot::decode(&snapshot(&stats)).unwrap().value);
+
+        let err = View::<Point>::new(region(&words, bytes.len())).err().unwrap();
+        assert_eq!(err, ViewError::Fingerprint {
+            expected: Point::fingerprint(),
+            found: Stats::fingerprint(),
+        });
+        let err = View::<Stats>::new(region(&words, bytes.len() - 1)).err().unwrap();
+        assert_eq!(err.to_string(), "region is too short");
+    }
+
+    #[test]
+    fn test_invalid() {
+        let stats = Stats { hits: 1, ratio: 1.0, live: false, grade: 'x', origin: Point(0, 0) };
+        let mut bytes = encode(&stats).unwrap();
+        let live = start::<Stats>() +
+                   (&stats.live as *const bool as usize - &stats as *const Stats as usize);
+        bytes[live] = 2;
+        let words = mapped(&bytes);
+        let err = View::<Stats>::new(region(&words, bytes.len())).err().unwrap();
+        assert_eq!(err.to_string(), "at `live`: 2 isn't a bool");
+
+        let named = Named { hits: 0, name: String::new() };
+        assert_eq!(encode(&named).unwrap_err().to_string(),
+                   "at `name`: `String` can't be viewed in place");
+
+        let flagged = Flagged { hits: 0, live: false };
+        assert_eq!(encode(&flagged).unwrap_err().to_string(),
+                   "at ``: `Flagged` can't be viewed in place");
+        let counter = Counter { hits: AtomicU32::new(0) };
+        assert_eq!(encode(&counter).unwrap_err().to_string(),
+                   "at `hits`: `AtomicU32` can't be viewed in place");
+    }
+}
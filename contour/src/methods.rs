@@ -0,0 +1,207 @@
+//! Methods recorded by `#[introspect]`, callable on values that are only
+//! known by their `TypeId`.
+
+use std::any::TypeId;
+use std::error;
+use std::fmt;
+
+use Boxed;
+
+/// Implemented by `#[introspect]` on an inherent `impl` block.
+pub trait Methods {
+    fn methods() -> Vec<Method>;
+}
+
+/// How a method takes `self`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Receiver {
+    /// An associated function with no `self`.
+    None,
+    Ref,
+    Mut,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Arg {
+    pub name: &'static str,
+    /// The type as written in the signature.
+    pub ty: &'static str,
+    pub type_id: TypeId,
+}
+
+#[derive(Clone, Debug)]
+pub struct Method {
+    pub name: &'static str,
+    pub receiver: Receiver,
+    pub args: Vec<Arg>,
+    /// `None` for methods returning `()`.
+    pub ret: Option<TypeId>,
+    /// Calls the method on the receiver, if any, with arguments already
+    /// checked against `args`.
+    pub thunk: unsafe fn(*mut u8, Vec<Boxed>) -> Option<Boxed>,
+}
+
+/// Methods are compared by signature, not by `thunk`, whose address isn't
+/// guaranteed to be unique.
+impl PartialEq for Method {
+    fn eq(&self, other: &Method) -> bool {
+        (self.name, &self.receiver, &self.args, self.ret) ==
+            (other.name, &other.receiver, &other.args, other.ret)
+    }
+}
+
+impl Eq for Method {}
+
+impl Method {
+    /// Calls the method after checking the arguments' types.
+    ///
+    /// # Safety
+    ///
+    /// `receiver` must point to a live value of the type the method was
+    /// recorded for, not borrowed elsewhere if the method takes `&mut self`;
+    /// it's ignored for associated functions.
+    pub unsafe fn call(&self, receiver: *mut u8, args: Vec<Boxed>)
+        -> Result<Option<Boxed>, CallError>
+    {
+        if args.len() != self.args.len() {
+            return Err(CallError::Arity {
+                method: self.name,
+                expected: self.args.len(),
+                found: args.len(),
+            });
+        }
+        for (arg, boxed) in self.args.iter().zip(&args) {
+            if arg.type_id != boxed.type_id() {
+                return Err(CallError::Type {
+                    method: self.name,
+                    arg: arg.name,
+                    expected: arg.ty,
+                    found: boxed.contour().name().to_owned(),
+                });
+            }
+        }
+        Ok((self.thunk)(receiver, args))
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CallError {
+    Arity { method: &'static str, expected: usize, found: usize },
+    Type { method: &'static str, arg: &'static str, expected: &'static str, found: String },
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CallError::Arity { method, expected, found } =>
+                write!(f, "`{}` takes {} argument{}, got {}",
+                       method, expected, if expected == 1 { "" } else { "s" }, found),
+            CallError::Type { method, arg, expected, ref found } =>
+                write!(f, "argument `{}` of `{}` should be `{}`, got `{}`",
+                       arg, method, expected, found),
+        }
+    }
+}
+
+impl error::Error for CallError {}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::any::TypeId;
+    use {
+        Arg,
+        Boxed,
+        Contour,
+        ContourMap,
+        Introspectable,
+        Methods,
+        Receiver,
+        Registry,
+        StructField,
+    };
+
+    #[derive(Debug, Introspectable, PartialEq)]
+    struct Pool {
+        size: usize,
+        name: String,
+    }
+
+    #[introspect]
+    impl Pool {
+        pub fn new(name: String) -> Pool {
+            Pool { size: 1, name: name }
+        }
+
+        /// Returns whether the pool grew.
+        pub fn resize(&mut self, size: usize) -> bool {
+            let grew = size > self.size;
+            self.size = size;
+            grew
+        }
+
+        pub fn label(&self, prefix: String, sep: char) -> String {
+            format!("{}{}{}", prefix, sep, self.name)
+        }
+
+        pub fn reset(&mut self) {
+            self.size = 0;
+        }
+
+        #[contour(skip)]
+        pub fn with<T>(&self, f: T) -> T {
+            f
+        }
+
+        #[contour(skip)]
+        pub fn name(&self) -> &String {
+            &self.name
+        }
+
+        fn private(&self) {}
+    }
+
+    #[test]
+    fn test_methods() {
+        let methods = Pool::methods();
+        let names: Vec<_> = methods.iter().map(|m| m.name).collect();
+        assert_eq!(names, vec!["new", "resize", "label", "reset"]);
+        assert_eq!(methods[0].receiver, Receiver::None);
+        assert_eq!(methods[1].args, vec![
+            Arg { name: "size", ty: "usize", type_id: TypeId::of::<usize>() },
+        ]);
+        assert_eq!(methods[1].ret, Some(TypeId::of::<bool>()));
+        assert_eq!((methods[2].receiver, methods[3].ret), (Receiver::Ref, None));
+    }
+
+    #[test]
+    fn test_call() {
+        let registry = Registry::new();
+        registry.register_methods::<Pool>();
+        let type_id = TypeId::of::<Pool>();
+        assert_eq!(registry.methods(type_id).len(), 4);
+        assert!(registry.lookup(type_id).is_some());
+
+        let new = registry.method(type_id, "new").unwrap();
+        let pool = unsafe { new.call(::std::ptr::null_mut(), vec![Boxed::new("db".to_owned())]) };
+        let mut pool = pool.unwrap().unwrap();
+        let resize = registry.method(type_id, "resize").unwrap();
+        let grew = unsafe { resize.call(pool.as_mut_ptr(), vec![Boxed::new(8usize)]) };
+        assert_eq!(*grew.unwrap().unwrap().downcast::<bool>().unwrap(), true);
+
+        let label = registry.method(type_id, "label").unwrap();
+        let args = vec![Boxed::new("pool".to_owned()), Boxed::new(':')];
+        let s = unsafe { label.call(pool.as_mut_ptr(), args) }.unwrap().unwrap();
+        assert_eq!(*s.downcast::<String>().unwrap(), "pool:db");
+
+        let err = unsafe { resize.call(pool.as_mut_ptr(), vec![Boxed::new(8u32)]) };
+        assert_eq!(err.unwrap_err().to_string(),
+                   "argument `size` of `resize` should be `usize`, got `u32`");
+        let err = unsafe { resize.call(pool.as_mut_ptr(), vec![]) };
+        assert_eq!(err.unwrap_err().to_string(), "`resize` takes 1 argument, got 0");
+
+        let reset = registry.method(type_id, "reset").unwrap();
+        assert!(unsafe { reset.call(pool.as_mut_ptr(), vec![]) }.unwrap().is_none());
+        assert_eq!(*pool.downcast::<Pool>().unwrap(), Pool { size: 0, name: "db".to_owned() });
+    }
+}
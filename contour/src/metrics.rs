@@ -0,0 +1,306 @@
+//! Prometheus text exposition of the numbers inside a value.
+//!
+//! Every integer, float, bool and atomic becomes a sample named after its
+//! path from the root, e.g. `app_cache_hits`, with bools as 1 or 0.  Map
+//! entries get a `key` label and sequence elements an `index` label.  An
+//! enum adds a `variant` label to everything beneath it, along with a sample
+//! of 1 for the enum itself so that unit variants show up too.  Labels from
+//! nested maps, sequences and enums are numbered, as in `key_1`.
+//!
+//! Strings, chars and redacted fields are left out.  No `# TYPE` lines are
+//! written, since a contour can't tell a counter from a gauge.
+
+use std::any::TypeId;
+use std::collections::HashMap;
+
+use visit::{
+    walk_value,
+    Visitor,
+    Walker,
+};
+use {
+    Contour,
+    ContourMap,
+    Introspectable,
+    Primitive,
+    PrimitiveValue,
+    Registry,
+    StructField,
+    TupleField,
+    Variant,
+    VariantFields,
+};
+
+/// Renders every numeric leaf of `value` as a sample named `prefix_...`.
+pub fn export<T: Introspectable>(value: &T, prefix: &str) -> String {
+    let registry = Registry::of::<T>();
+    let type_id = T::contour().type_id();
+    unsafe { export_ptr(&registry, type_id, value as *const T as *const u8, prefix) }
+}
+
+/// # Safety
+///
+/// See `Walker::walk` for the requirements on `ptr`.
+pub unsafe fn export_ptr(map: &dyn ContourMap, type_id: TypeId, ptr: *const u8, prefix: &str)
+    -> String
+{
+    let mut exporter = Exporter {
+        name: vec![prefix.to_owned()],
+        labels: vec![],
+        metrics: vec![],
+        index: HashMap::new(),
+    };
+    Walker::new(map).walk(&mut exporter, type_id, ptr);
+
+    let mut out = String::new();
+    for (_, samples) in exporter.metrics {
+        for sample in samples {
+            out.push_str(&sample);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+struct Exporter {
+    name: Vec<String>,
+    labels: Vec<(String, String)>,
+    /// Samples grouped by metric, since the format wants each metric's
+    /// samples together, in the order the metrics were first seen.
+    metrics: Vec<(String, Vec<String>)>,
+    index: HashMap<String, usize>,
+}
+
+impl Exporter {
+    fn sample(&mut self, value: String) {
+        let name = metric_name(&self.name);
+        let line = if self.labels.is_empty() {
+            format!("{} {}", name, value)
+        } else {
+            let labels: Vec<_> = self.labels.iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                .collect();
+            format!("{}{{{}}} {}", name, labels.join(","), value)
+        };
+        let ix = match self.index.get(&name) {
+            Some(&ix) => ix,
+            None => {
+                self.index.insert(name.clone(), self.metrics.len());
+                self.metrics.push((name, vec![]));
+                self.metrics.len() - 1
+            },
+        };
+        self.metrics[ix].1.push(line);
+    }
+
+    /// Runs `f` with a `kind` label, numbered if there's one already.
+    fn labelled<F: FnOnce(&mut Self)>(&mut self, kind: &str, value: String, f: F) {
+        let n = self.labels.iter().filter(|&(k, _)| k.split('_').next() == Some(kind)).count();
+        let key = if n == 0 { kind.to_owned() } else { format!("{}_{}", kind, n) };
+        self.labels.push((key, value));
+        f(self);
+        self.labels.pop();
+    }
+
+    unsafe fn field(&mut self, cx: &mut Walker, name: String, type_id: TypeId, ptr: *const u8) {
+        self.name.push(name);
+        walk_value(self, cx, type_id, ptr);
+        self.name.pop();
+    }
+
+    unsafe fn struct_fields(&mut self, cx: &mut Walker, fields: &[StructField], ptr: *const u8) {
+        for f in fields.iter().filter(|f| !f.redacted) {
+            let subptr = unsafe { ptr.add(f.offset) };
+            self.field(cx, f.name.to_owned(), f.type_id, subptr);
+        }
+    }
+
+    unsafe fn tuple_fields(&mut self, cx: &mut Walker, fields: &[TupleField], ptr: *const u8) {
+        for f in fields.iter().filter(|f| !f.redacted) {
+            let subptr = unsafe { ptr.add(f.offset) };
+            self.field(cx, f.ix.to_string(), f.type_id, subptr);
+        }
+    }
+}
+
+impl Visitor for Exporter {
+    unsafe fn visit_struct(&mut self, cx: &mut Walker, contour: &Contour, ptr: *const u8) {
+        match *contour {
+            Contour::Struct { ref fields, .. } => self.struct_fields(cx, fields, ptr),
+            Contour::Tuple { ref fields, .. } => self.tuple_fields(cx, fields, ptr),
+            _ => {},
+        }
+    }
+
+    unsafe fn visit_variant(&mut self, cx: &mut Walker, variant: &Variant, ptr: *const u8) {
+        self.labelled("variant", variant.name.to_owned(), |e| {
+            e.sample("1".to_owned());
+            match variant.fields {
+                VariantFields::Struct(ref fields) => e.struct_fields(cx, fields, ptr),
+                VariantFields::Tuple(ref fields) => e.tuple_fields(cx, fields, ptr),
+                VariantFields::Unit => {},
+            }
+        });
+    }
+
+    unsafe fn visit_primitive(&mut self,
+                              _cx: &mut Walker,
+                              kind: &Primitive,
+                              atomic: bool,
+                              ptr: *const u8) {
+        if let Some(value) = number(&unsafe { kind.load(ptr, atomic) }) {
+            self.sample(value);
+        }
+    }
+
+    unsafe fn visit_element(&mut self,
+                            cx: &mut Walker,
+                            ix: usize,
+                            type_id: TypeId,
+                            ptr: *const u8) {
+        self.labelled("index", ix.to_string(), |e| walk_value(e, cx, type_id, ptr));
+    }
+
+    /// Entries are labelled by key where the key is a primitive, and by
+    /// position otherwise.
+    unsafe fn visit_map(&mut self, cx: &mut Walker, contour: &Contour, ptr: *const u8) {
+        if let Contour::Map { key, value, entries, .. } = *contour {
+            let key_contour = cx.contour(key);
+            let mut i = 0;
+            unsafe {
+                entries(ptr, &mut |k, v| {
+                    let label = match key_contour {
+                        Contour::Primitive { ref variant, .. } => match variant.read(k) {
+                            PrimitiveValue::String(s) => s,
+                            PrimitiveValue::char(c) => c.to_string(),
+                            value => value.to_string(),
+                        },
+                        _ => i.to_string(),
+                    };
+                    self.labelled("key", label, |e| walk_value(e, cx, value, v));
+                    i += 1;
+                });
+            }
+        }
+    }
+}
+
+/// The value as the exposition format writes it, if it's a number.
+fn number(value: &PrimitiveValue) -> Option<String> {
+    let float = |v: f64| if v.is_nan() {
+        "NaN".to_owned()
+    } else if v.is_infinite() {
+        if v > 0.0 { "+Inf".to_owned() } else { "-Inf".to_owned() }
+    } else {
+        v.to_string()
+    };
+    Some(match *value {
+        PrimitiveValue::f32(v) => float(v as f64),
+        PrimitiveValue::f64(v) => float(v),
+        PrimitiveValue::bool(v) => (v as u8).to_string(),
+        PrimitiveValue::char(_) | PrimitiveValue::String(_) => return None,
+        ref v => v.to_string(),
+    })
+}
+
+/// Joins the path with underscores, replacing anything a metric name can't
+/// contain.
+fn metric_name(parts: &[String]) -> String {
+    let joined = parts.iter().filter(|p| !p.is_empty()).cloned().collect::<Vec<_>>().join("_");
+    let mut name: String = joined.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == ':' { c } else { '_' })
+        .collect();
+    if name.chars().next().is_none_or(|c| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{
+        AtomicU64,
+        Ordering,
+    };
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        StructField,
+        Variant,
+        VariantFields,
+    };
+    use super::*;
+
+    #[derive(Introspectable)]
+    enum Role {
+        Primary,
+        Replica { lag_ms: u64 },
+    }
+
+    #[derive(Introspectable)]
+    struct Shard {
+        role: Role,
+        queries: AtomicU64,
+    }
+
+    #[derive(Introspectable)]
+    struct Stats {
+        requests: u64,
+        ratio: f64,
+        healthy: bool,
+        name: String,
+        #[contour(redact)]
+        secret: u32,
+        by_route: BTreeMap<String, u32>,
+        shards: Vec<Shard>,
+    }
+
+    #[test]
+    fn test_export() {
+        let mut by_route = BTreeMap::new();
+        by_route.insert("/x".to_owned(), 4);
+        by_route.insert("/a\"b".to_owned(), 3);
+        let stats = Stats {
+            requests: 12,
+            ratio: 0.25,
+            healthy: true,
+            name: "edge".to_owned(),
+            secret: 99,
+            by_route: by_route,
+            shards: vec![
+                Shard { role: Role::Primary, queries: AtomicU64::new(7) },
+                Shard { role: Role::Replica { lag_ms: 30 }, queries: AtomicU64::new(0) },
+            ],
+        };
+        stats.shards[1].queries.fetch_add(8, Ordering::SeqCst);
+        assert_eq!(export(&stats, "app"), "\
+app_requests 12
+app_ratio 0.25
+app_healthy 1
+app_by_route{key=\"/a\\\"b\"} 3
+app_by_route{key=\"/x\"} 4
+app_shards_role{index=\"0\",variant=\"Primary\"} 1
+app_shards_role{index=\"1\",variant=\"Replica\"} 1
+app_shards_queries{index=\"0\"} 7
+app_shards_queries{index=\"1\"} 8
+app_shards_role_lag_ms{index=\"1\",variant=\"Replica\"} 30
+");
+    }
+
+    #[test]
+    fn test_names() {
+        let mut nested = BTreeMap::new();
+        nested.insert(3u8, vec![f64::INFINITY]);
+        let mut outer = BTreeMap::new();
+        outer.insert('q', nested);
+        assert_eq!(export(&outer, "cache-1"),
+                   "cache_1{key=\"q\",key_1=\"3\",index=\"0\"} +Inf\n");
+    }
+}
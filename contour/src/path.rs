@@ -0,0 +1,618 @@
+//! Path expressions that address a value nested inside another, e.g.
+//! `server.pools[2].conns.active` or `state::Running.pid`.
+//!
+//! A path is a sequence of segments, applied left to right starting at the
+//! root value:
+//!
+//! * `.name` (or just `name` at the start) picks a named field of a struct,
+//!   or of the enum variant that's currently active.
+//! * `.0` picks a positional field of a tuple struct or tuple variant.
+//! * `[2]` picks an element of a sequence; `[2]`, `["key"]`, `['c']` and
+//!   `[true]` pick the entry of a map whose (primitive) key is equal.
+//! * `::Name` doesn't move anywhere, but fails unless the enum's active
+//!   variant is `Name`.
+//!
+//! Pointers are followed transparently before applying each segment, except
+//! for raw pointers, which nothing says are valid.
+
+use std::any::TypeId;
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+use {
+    Contour,
+    ContourMap,
+    Introspectable,
+    PrimitiveValue,
+    Registry,
+    VariantFields,
+};
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Segment {
+    Field(String),
+    Tuple(usize),
+    Index(Key),
+    Variant(String),
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Key {
+    Int(i64),
+    Str(String),
+    Char(char),
+    Bool(bool),
+}
+
+impl Key {
+    /// Whether this key selects the map entry whose key is `value`.
+    pub fn matches(&self, value: &PrimitiveValue) -> bool {
+        match (self, value) {
+            (&Key::Int(i), _) => int_value(value) == Some(i),
+            (Key::Str(s), PrimitiveValue::String(v)) => s == v,
+            (&Key::Char(c), &PrimitiveValue::char(v)) => c == v,
+            (&Key::Bool(b), &PrimitiveValue::bool(v)) => b == v,
+            _ => false,
+        }
+    }
+
+    /// The key that `matches` `value`, if it's of a type keys can express.
+    pub fn from_value(value: &PrimitiveValue) -> Option<Key> {
+        match *value {
+            PrimitiveValue::String(ref v) => Some(Key::Str(v.clone())),
+            PrimitiveValue::char(v) => Some(Key::Char(v)),
+            PrimitiveValue::bool(v) => Some(Key::Bool(v)),
+            _ => int_value(value).map(Key::Int),
+        }
+    }
+}
+
+fn int_value(value: &PrimitiveValue) -> Option<i64> {
+    match *value {
+        PrimitiveValue::u8(v) => Some(v as i64),
+        PrimitiveValue::u16(v) => Some(v as i64),
+        PrimitiveValue::u32(v) => Some(v as i64),
+        PrimitiveValue::u64(v) if v <= i64::MAX as u64 => Some(v as i64),
+        PrimitiveValue::usize(v) if v as u64 <= i64::MAX as u64 => Some(v as i64),
+        PrimitiveValue::i8(v) => Some(v as i64),
+        PrimitiveValue::i16(v) => Some(v as i64),
+        PrimitiveValue::i32(v) => Some(v as i64),
+        PrimitiveValue::i64(v) => Some(v),
+        PrimitiveValue::isize(v) => Some(v as i64),
+        _ => None,
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Key::Int(i) => write!(f, "{}", i),
+            Key::Str(ref s) => write!(f, "{:?}", s),
+            Key::Char(c) => write!(f, "{:?}", c),
+            Key::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Path {
+    segments: Vec<Segment>,
+}
+
+impl Path {
+    /// The empty path, which resolves to the root value itself.
+    pub fn root() -> Self {
+        Path { segments: vec![] }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, PathError> {
+        Parser { s, pos: 0 }.path()
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn push(&mut self, segment: Segment) {
+        self.segments.push(segment);
+    }
+
+    pub fn pop(&mut self) -> Option<Segment> {
+        self.segments.pop()
+    }
+
+    /// Returns a copy of this path with `segment` appended.
+    pub fn child(&self, segment: Segment) -> Self {
+        let mut path = self.clone();
+        path.push(segment);
+        path
+    }
+
+    fn prefix(&self, n: usize) -> Self {
+        Path { segments: self.segments[..n].to_vec() }
+    }
+}
+
+impl FromStr for Path {
+    type Err = PathError;
+
+    fn from_str(s: &str) -> Result<Self, PathError> {
+        Path::parse(s)
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, segment) in self.segments.iter().enumerate() {
+            let dot = if i == 0 { "" } else { "." };
+            match *segment {
+                Segment::Field(ref name) => write!(f, "{}{}", dot, name)?,
+                Segment::Tuple(ix) => write!(f, "{}{}", dot, ix)?,
+                Segment::Index(ref key) => write!(f, "[{}]", key)?,
+                Segment::Variant(ref name) => write!(f, "::{}", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PathError {
+    /// The expression itself is malformed at byte offset `pos`.
+    Syntax { pos: usize, msg: String },
+    /// The last segment of `path` couldn't be applied to the value that the
+    /// segments before it resolved to.
+    Segment { path: String, msg: String },
+    /// The path resolved to a `found` where a `expected` was wanted.
+    Type { path: String, expected: &'static str, found: &'static str },
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PathError::Syntax { pos, ref msg } => write!(f, "syntax error at {}: {}", pos, msg),
+            PathError::Segment { ref path, ref msg } => write!(f, "at `{}`: {}", path, msg),
+            PathError::Type { ref path, expected, found } =>
+                write!(f, "at `{}`: expected {}, found {}", path, expected, found),
+        }
+    }
+}
+
+impl error::Error for PathError {}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek();
+        if let Some(c) = c {
+            self.pos += c.len_utf8();
+        }
+        c
+    }
+
+    fn eat(&mut self, prefix: &str) -> bool {
+        if self.s[self.pos..].starts_with(prefix) {
+            self.pos += prefix.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error<T>(&self, msg: &str) -> Result<T, PathError> {
+        Err(PathError::Syntax { pos: self.pos, msg: msg.to_owned() })
+    }
+
+    fn path(&mut self) -> Result<Path, PathError> {
+        let mut path = Path::root();
+        while let Some(c) = self.peek() {
+            let segment = if self.eat("::") {
+                Segment::Variant(self.ident()?)
+            } else if self.eat("[") {
+                let key = self.key()?;
+                if !self.eat("]") {
+                    return self.error("expected `]`");
+                }
+                Segment::Index(key)
+            } else if path.is_empty() || c == '.' {
+                if !path.is_empty() {
+                    self.bump();
+                }
+                match self.peek() {
+                    Some(c) if c.is_ascii_digit() => Segment::Tuple(self.number()? as usize),
+                    _ => Segment::Field(self.ident()?),
+                }
+            } else {
+                return self.error("expected `.`, `[` or `::`");
+            };
+            path.push(segment);
+        }
+        Ok(path)
+    }
+
+    fn ident(&mut self) -> Result<String, PathError> {
+        let start = self.pos;
+        match self.peek() {
+            Some(c) if c == '_' || c.is_alphabetic() => (),
+            _ => return self.error("expected an identifier"),
+        }
+        while let Some(c) = self.peek() {
+            if c == '_' || c.is_alphanumeric() {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        Ok(self.s[start..self.pos].to_owned())
+    }
+
+    fn number(&mut self) -> Result<u64, PathError> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_digit() {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        match self.s[start..self.pos].parse() {
+            Ok(n) => Ok(n),
+            Err(_) => {
+                self.pos = start;
+                self.error("expected a number")
+            },
+        }
+    }
+
+    fn key(&mut self) -> Result<Key, PathError> {
+        match self.peek() {
+            Some('"') => {
+                self.bump();
+                let mut s = String::new();
+                loop {
+                    match self.bump() {
+                        Some('"') => return Ok(Key::Str(s)),
+                        Some('\\') => s.push(self.escape()?),
+                        Some(c) => s.push(c),
+                        None => return self.error("unterminated string"),
+                    }
+                }
+            },
+            Some('\'') => {
+                self.bump();
+                let c = match self.bump() {
+                    Some('\\') => self.escape()?,
+                    Some(c) => c,
+                    None => return self.error("unterminated char"),
+                };
+                if !self.eat("'") {
+                    return self.error("expected `'`");
+                }
+                Ok(Key::Char(c))
+            },
+            Some('-') => {
+                let start = self.pos;
+                self.bump();
+                let n = self.number()?;
+                if n > i64::MAX as u64 + 1 {
+                    self.pos = start;
+                    return self.error("integer out of range");
+                }
+                Ok(Key::Int((n as i64).wrapping_neg()))
+            },
+            Some(c) if c.is_ascii_digit() => {
+                let start = self.pos;
+                let n = self.number()?;
+                if n > i64::MAX as u64 {
+                    self.pos = start;
+                    return self.error("integer out of range");
+                }
+                Ok(Key::Int(n as i64))
+            },
+            _ if self.eat("true") => Ok(Key::Bool(true)),
+            _ if self.eat("false") => Ok(Key::Bool(false)),
+            _ => self.error("expected a key"),
+        }
+    }
+
+    /// The escapes that `{:?}` produces for strings and chars.
+    fn escape(&mut self) -> Result<char, PathError> {
+        match self.bump() {
+            Some('n') => Ok('\n'),
+            Some('r') => Ok('\r'),
+            Some('t') => Ok('\t'),
+            Some('0') => Ok('\0'),
+            Some('\\') => Ok('\\'),
+            Some('"') => Ok('"'),
+            Some('\'') => Ok('\''),
+            Some('u') => {
+                if !self.eat("{") {
+                    return self.error("expected `{`");
+                }
+                let start = self.pos;
+                while self.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+                    self.bump();
+                }
+                let c = u32::from_str_radix(&self.s[start..self.pos], 16).ok()
+                    .and_then(::std::char::from_u32);
+                match c {
+                    Some(c) if self.eat("}") => Ok(c),
+                    _ => self.error("bad unicode escape"),
+                }
+            },
+            _ => self.error("unknown escape"),
+        }
+    }
+}
+
+/// Follows `path` from the value of type `type_id` at `ptr`, returning the
+/// type and location of whatever it points to.
+///
+/// # Safety
+///
+/// See `Walker::walk` for the requirements on `ptr`.
+pub unsafe fn resolve_ptr(map: &dyn ContourMap,
+                          type_id: TypeId,
+                          ptr: *const u8,
+                          path: &Path)
+    -> Result<(TypeId, *const u8), PathError>
+{
+    let mut type_id = type_id;
+    let mut ptr = ptr;
+    for (i, segment) in path.segments.iter().enumerate() {
+        let fail = |msg: String| PathError::Segment {
+            path: path.prefix(i + 1).to_string(),
+            msg,
+        };
+
+        let mut contour;
+        loop {
+            contour = match map.lookup(type_id) {
+                Some(contour) => contour,
+                None => return Err(fail(format!("no contour for {:?}", type_id))),
+            };
+            match contour {
+                Contour::Pointer { pointee, deref: Some(deref), .. } => {
+                    ptr = deref(ptr);
+                    type_id = pointee;
+                    if ptr.is_null() {
+                        return Err(fail("null pointer".to_owned()));
+                    }
+                },
+                Contour::Pointer { deref: None, .. } =>
+                    return Err(fail("raw pointers aren't followed".to_owned())),
+                _ => break,
+            }
+        }
+
+        let next = match (segment, &contour) {
+            (Segment::Field(name), Contour::Struct { fields, .. }) => fields.iter()
+                .find(|f| f.name == name)
+                .map(|f| (f.type_id, f.offset)),
+            (&Segment::Tuple(ix), Contour::Tuple { fields, .. }) => fields.iter()
+                .find(|f| f.ix == ix)
+                .map(|f| (f.type_id, f.offset)),
+            (&Segment::Field(_), &Contour::Enum { ref variants, tag, .. }) |
+            (&Segment::Tuple(_), &Contour::Enum { ref variants, tag, .. }) => {
+                let variant = &variants[tag(ptr)];
+                match (segment, &variant.fields) {
+                    (Segment::Field(name), VariantFields::Struct(fields)) => fields.iter()
+                        .find(|f| f.name == name)
+                        .map(|f| (f.type_id, f.offset)),
+                    (&Segment::Tuple(ix), VariantFields::Tuple(fields)) => fields.iter()
+                        .find(|f| f.ix == ix)
+                        .map(|f| (f.type_id, f.offset)),
+                    _ => None,
+                }
+            },
+            (Segment::Variant(name), &Contour::Enum { ref variants, tag, .. }) => {
+                let active = variants[tag(ptr)].name;
+                if active != name {
+                    return Err(fail(format!("active variant is {}", active)));
+                }
+                continue;
+            },
+            (Segment::Index(key), &Contour::Seq { element, len, item, .. }) => {
+                let n = len(ptr);
+                let ix = match *key {
+                    Key::Int(ix) if ix >= 0 => ix as usize,
+                    _ => return Err(fail(format!("{} isn't a valid index", key))),
+                };
+                if ix >= n {
+                    return Err(fail(format!("index {} out of bounds (len {})", ix, n)));
+                }
+                ptr = item(ptr, ix);
+                type_id = element;
+                continue;
+            },
+            (Segment::Index(key), &Contour::Map { key: key_type, value, entries, .. }) => {
+                let kind = match map.lookup(key_type) {
+                    Some(Contour::Primitive { variant, .. }) => variant,
+                    _ => return Err(fail("map keys aren't primitive".to_owned())),
+                };
+                let mut found = None;
+                entries(ptr, &mut |k, v| {
+                    if found.is_none() && key.matches(&kind.read(k)) {
+                        found = Some(v);
+                    }
+                });
+                match found {
+                    Some(v) => {
+                        ptr = v;
+                        type_id = value;
+                        continue;
+                    },
+                    None => return Err(fail(format!("no entry for key {}", key))),
+                }
+            },
+            _ => None,
+        };
+        match next {
+            Some((field_type, offset)) => {
+                ptr = ptr.add(offset);
+                type_id = field_type;
+            },
+            None => {
+                let msg = match *segment {
+                    Segment::Field(ref name) =>
+                        format!("{} has no field {}", contour.name(), name),
+                    Segment::Tuple(ix) => format!("{} has no field {}", contour.name(), ix),
+                    Segment::Index(_) => format!("{} can't be indexed", contour.name()),
+                    Segment::Variant(_) => format!("{} isn't an enum", contour.name()),
+                };
+                return Err(fail(msg));
+            },
+        }
+    }
+    Ok((type_id, ptr))
+}
+
+/// Follows `path` from `value`, checking that it ends up at a `U`.
+pub fn resolve<'a, U, T>(value: &'a T, path: &Path) -> Result<&'a U, PathError>
+    where U: Introspectable + 'static,
+          T: Introspectable,
+{
+    let registry = Registry::of::<T>();
+    let root = value as *const T as *const u8;
+    let (type_id, ptr) = unsafe {
+        resolve_ptr(&registry, T::contour().type_id(), root, path)?
+    };
+
+    if type_id != TypeId::of::<U>() {
+        let found = registry.lookup(type_id).map_or("?", |c| c.name());
+        return Err(PathError::Type {
+            path: path.to_string(),
+            expected: U::contour().name(),
+            found,
+        });
+    }
+    Ok(unsafe { &*(ptr as *const U) })
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::collections::{
+        BTreeMap,
+        HashMap,
+    };
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        StructField,
+        TupleField,
+        Variant,
+        VariantFields,
+    };
+    use super::*;
+
+    #[derive(Introspectable)]
+    enum State {
+        Idle,
+        Running { pid: u32 },
+        Failed(i32),
+    }
+
+    #[derive(Introspectable)]
+    struct Conns {
+        active: u32,
+        idle: u32,
+    }
+
+    #[derive(Introspectable)]
+    struct Pool {
+        conns: Conns,
+        state: State,
+    }
+
+    #[derive(Introspectable)]
+    struct Server {
+        pools: Vec<Box<Pool>>,
+        by_name: HashMap<String, u64>,
+        by_id: BTreeMap<i32, u8>,
+        pair: Pair,
+    }
+
+    #[derive(Introspectable)]
+    struct Pair(u8, char);
+
+    fn server() -> Server {
+        let mut by_name = HashMap::new();
+        by_name.insert("quote\"d".to_owned(), 10);
+        let mut by_id = BTreeMap::new();
+        by_id.insert(-3, 4);
+        let pool = |active, state| Box::new(Pool {
+            conns: Conns { active: active, idle: 0 },
+            state: state,
+        });
+        Server {
+            pools: vec![pool(1, State::Idle), pool(2, State::Running { pid: 99 })],
+            by_name: by_name,
+            by_id: by_id,
+            pair: Pair(5, 'x'),
+        }
+    }
+
+    fn path(s: &str) -> Path {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        for s in &["", "pools[1].conns.active", "state::Running.pid", "pair.0",
+                   "by_name[\"a\\\"b\\n\"]", "by_id[-3]", "m['\\'']", "m[true]"] {
+            assert_eq!(path(s).to_string(), *s);
+        }
+        assert_eq!(path("a[0]::B.1").segments(), &[
+            Segment::Field("a".to_owned()),
+            Segment::Index(Key::Int(0)),
+            Segment::Variant("B".to_owned()),
+            Segment::Tuple(1),
+        ]);
+        assert_eq!(Path::parse("a..b"),
+                   Err(PathError::Syntax { pos: 2, msg: "expected an identifier".to_owned() }));
+        assert_eq!(Path::parse("a[\"b]"),
+                   Err(PathError::Syntax { pos: 5, msg: "unterminated string".to_owned() }));
+        assert_eq!(Path::parse("a b").unwrap_err().to_string(),
+                   "syntax error at 1: expected `.`, `[` or `::`");
+    }
+
+    #[test]
+    fn test_resolve() {
+        let s = server();
+        assert_eq!(*resolve::<u32, _>(&s, &path("pools[0].conns.active")).unwrap(), 1);
+        assert_eq!(*resolve::<u32, _>(&s, &path("pools[1].state::Running.pid")).unwrap(), 99);
+        assert_eq!(*resolve::<u32, _>(&s, &path("pools[1].state.pid")).unwrap(), 99);
+        assert_eq!(*resolve::<u64, _>(&s, &path("by_name[\"quote\\\"d\"]")).unwrap(), 10);
+        assert_eq!(*resolve::<u8, _>(&s, &path("by_id[-3]")).unwrap(), 4);
+        assert_eq!(*resolve::<char, _>(&s, &path("pair.1")).unwrap(), 'x');
+        assert_eq!(resolve::<Conns, _>(&s, &path("pools[1].conns")).unwrap().active, 2);
+    }
+
+    #[test]
+    fn test_errors() {
+        let s = server();
+        let err = |p| resolve::<u32, _>(&s, &path(p)).unwrap_err().to_string();
+        assert_eq!(err("pools[2].conns"), "at `pools[2]`: index 2 out of bounds (len 2)");
+        assert_eq!(err("pools[0].state::Running.pid"),
+                   "at `pools[0].state::Running`: active variant is Idle");
+        assert_eq!(err("pools[0].conns.busy"), "at `pools[0].conns.busy`: Conns has no field busy");
+        assert_eq!(err("by_name[\"nope\"]"), "at `by_name[\"nope\"]`: no entry for key \"nope\"");
+        assert_eq!(err("pair[0]"), "at `pair[0]`: Pair can't be indexed");
+        assert_eq!(err("pools[0].conns"), "at `pools[0].conns`: expected u32, found Conns");
+    }
+}
@@ -0,0 +1,504 @@
+//! `Debug`-style rendering for anything `Introspectable`.
+
+use std::any::TypeId;
+use std::fmt;
+
+use visit::{
+    walk_pointer,
+    Visitor,
+    Walker,
+};
+use {
+    raw_address,
+    BitField,
+    Contour,
+    ContourMap,
+    Introspectable,
+    Primitive,
+    PrimitiveValue,
+    Registry,
+    StructField,
+    TupleField,
+    Variant,
+    VariantFields,
+};
+
+#[derive(Clone, Debug)]
+pub struct PrettyOptions {
+    /// Structs, enums, sequences and maps nested deeper than this are elided as `..`.
+    pub max_depth: usize,
+    /// Groups that don't fit on one line within this many columns are broken
+    /// up one item per line.
+    pub width: usize,
+    /// Whether to hide `#[contour(redact)]` fields.
+    pub redact: bool,
+}
+
+impl Default for PrettyOptions {
+    fn default() -> Self {
+        PrettyOptions {
+            max_depth: 16,
+            width: 80,
+            redact: true,
+        }
+    }
+}
+
+/// Renders `value` with the default options.
+pub fn pretty<T: Introspectable>(value: &T) -> String {
+    Pretty::new(value).to_string()
+}
+
+/// Renders the value of type `type_id` at `ptr`, which must already be
+/// charted into `map`.
+///
+/// # Safety
+///
+/// See `Walker::walk` for the requirements on `ptr`.
+pub unsafe fn pretty_ptr(map: &dyn ContourMap,
+                         type_id: TypeId,
+                         ptr: *const u8,
+                         options: &PrettyOptions)
+    -> String
+{
+    let mut builder = Builder { options, depth: 0, node: None };
+    Walker::new(map).walk(&mut builder, type_id, ptr);
+
+    let mut out = String::new();
+    builder.take().layout(0, 0, options.width, &mut out);
+    out
+}
+
+/// `Display` adapter, e.g. `println!("{}", Pretty::new(&state))`.
+pub struct Pretty<'a, T: 'a> {
+    value: &'a T,
+    options: PrettyOptions,
+}
+
+impl<'a, T: Introspectable> Pretty<'a, T> {
+    pub fn new(value: &'a T) -> Self {
+        Pretty::with_options(value, PrettyOptions::default())
+    }
+
+    pub fn with_options(value: &'a T, options: PrettyOptions) -> Self {
+        Pretty { value, options }
+    }
+}
+
+impl<'a, T: Introspectable> fmt::Display for Pretty<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let registry = Registry::of::<T>();
+        let type_id = T::contour().type_id();
+        let ptr = self.value as *const T as *const u8;
+        let s = unsafe { pretty_ptr(&registry, type_id, ptr, &self.options) };
+        f.write_str(&s)
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Delim {
+    Brace,
+    Paren,
+    Bracket,
+}
+
+enum Node {
+    Leaf(String),
+    Group {
+        head: &'static str,
+        delim: Delim,
+        items: Vec<(Option<String>, Node)>,
+    },
+}
+
+impl Node {
+    fn flat(&self, out: &mut String) {
+        match *self {
+            Node::Leaf(ref s) => out.push_str(s),
+            Node::Group { head, delim, ref items } => {
+                // Structs get padded braces, like `Debug` does; maps don't.
+                let pad = if head.is_empty() { "" } else { " " };
+                out.push_str(head);
+                match delim {
+                    Delim::Brace if items.is_empty() => {
+                        out.push_str(pad);
+                        out.push_str("{}");
+                    },
+                    Delim::Brace => {
+                        out.push_str(pad);
+                        out.push('{');
+                        out.push_str(pad);
+                    },
+                    Delim::Paren => out.push('('),
+                    Delim::Bracket => out.push('['),
+                }
+                for (i, (label, node)) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    if let Some(ref label) = *label {
+                        out.push_str(label);
+                        out.push_str(": ");
+                    }
+                    node.flat(out);
+                }
+                match delim {
+                    Delim::Brace if items.is_empty() => (),
+                    Delim::Brace => {
+                        out.push_str(pad);
+                        out.push('}');
+                    },
+                    Delim::Paren => out.push(')'),
+                    Delim::Bracket => out.push(']'),
+                }
+            },
+        }
+    }
+
+    /// `column` is where the cursor is when we start, and `indent` is the
+    /// indentation of the line we're on.
+    fn layout(&self, indent: usize, column: usize, width: usize, out: &mut String) {
+        let mut flat = String::new();
+        self.flat(&mut flat);
+        let (head, delim, items) = match *self {
+            Node::Group { head, delim, ref items } if !items.is_empty() &&
+                column + flat.len() > width => (head, delim, items),
+            _ => {
+                out.push_str(&flat);
+                return;
+            },
+        };
+
+        out.push_str(head);
+        if delim == Delim::Brace && !head.is_empty() {
+            out.push(' ');
+        }
+        out.push_str(match delim {
+            Delim::Brace => "{\n",
+            Delim::Paren => "(\n",
+            Delim::Bracket => "[\n",
+        });
+        for (label, node) in items {
+            let start = out.len();
+            for _ in 0..indent + 4 {
+                out.push(' ');
+            }
+            if let Some(ref label) = *label {
+                out.push_str(label);
+                out.push_str(": ");
+            }
+            let column = out.len() - start;
+            node.layout(indent + 4, column, width, out);
+            out.push_str(",\n");
+        }
+        for _ in 0..indent {
+            out.push(' ');
+        }
+        out.push(match delim {
+            Delim::Brace => '}',
+            Delim::Paren => ')',
+            Delim::Bracket => ']',
+        });
+    }
+}
+
+struct Builder<'a> {
+    options: &'a PrettyOptions,
+    depth: usize,
+    node: Option<Node>,
+}
+
+impl<'a> Builder<'a> {
+    fn take(&mut self) -> Node {
+        self.node.take().expect("Visitor didn't produce a node")
+    }
+
+    /// Renders a field, or an element with no `redacted` or `bits` of its own.
+    unsafe fn child(&mut self,
+                    cx: &mut Walker,
+                    redacted: bool,
+                    bits: &[BitField],
+                    type_id: TypeId,
+                    ptr: *const u8)
+        -> Node
+    {
+        if redacted && self.options.redact {
+            return Node::Leaf("<redacted>".to_owned());
+        }
+        if !bits.is_empty() {
+            if let Contour::Primitive { ref variant, atomic, .. } = cx.contour(type_id) {
+                if let Some(word) = word(&unsafe { variant.load(ptr, atomic) }) {
+                    return Node::Leaf(format!("{:#x} ({})", word, BitField::describe(bits, word)));
+                }
+            }
+        }
+        self.visit_value(cx, type_id, ptr);
+        self.take()
+    }
+
+    unsafe fn struct_items(&mut self, cx: &mut Walker, fields: &[StructField], ptr: *const u8)
+        -> Vec<(Option<String>, Node)>
+    {
+        fields.iter()
+            .map(|field| {
+                let subptr = unsafe { ptr.add(field.offset) };
+                let node = self.child(cx, field.redacted, &field.bits, field.type_id, subptr);
+                (Some(field.name.to_owned()), node)
+            })
+            .collect()
+    }
+
+    unsafe fn tuple_items(&mut self, cx: &mut Walker, fields: &[TupleField], ptr: *const u8)
+        -> Vec<(Option<String>, Node)>
+    {
+        fields.iter()
+            .map(|field| {
+                let subptr = unsafe { ptr.add(field.offset) };
+                (None, self.child(cx, field.redacted, &field.bits, field.type_id, subptr))
+            })
+            .collect()
+    }
+
+    /// Runs `f` one level deeper, or produces `..` if that's too deep.
+    fn nested<F: FnOnce(&mut Self) -> Node>(&mut self, f: F) {
+        if self.depth >= self.options.max_depth {
+            self.node = Some(Node::Leaf("..".to_owned()));
+            return;
+        }
+        self.depth += 1;
+        let node = f(self);
+        self.depth -= 1;
+        self.node = Some(node);
+    }
+}
+
+impl<'a> Visitor for Builder<'a> {
+    unsafe fn visit_struct(&mut self, cx: &mut Walker, contour: &Contour, ptr: *const u8) {
+        self.nested(|b| match *contour {
+            Contour::Struct { name, ref fields, .. } => Node::Group {
+                head: name,
+                delim: Delim::Brace,
+                items: b.struct_items(cx, fields, ptr),
+            },
+            Contour::Tuple { name, ref fields, .. } => Node::Group {
+                head: name,
+                delim: Delim::Paren,
+                items: b.tuple_items(cx, fields, ptr),
+            },
+            _ => Node::Leaf(contour.name().to_owned()),
+        })
+    }
+
+    unsafe fn visit_variant(&mut self, cx: &mut Walker, variant: &Variant, ptr: *const u8) {
+        self.nested(|b| match variant.fields {
+            VariantFields::Struct(ref fields) => Node::Group {
+                head: variant.name,
+                delim: Delim::Brace,
+                items: b.struct_items(cx, fields, ptr),
+            },
+            VariantFields::Tuple(ref fields) => Node::Group {
+                head: variant.name,
+                delim: Delim::Paren,
+                items: b.tuple_items(cx, fields, ptr),
+            },
+            VariantFields::Unit => Node::Leaf(variant.name.to_owned()),
+        })
+    }
+
+    unsafe fn visit_primitive(&mut self,
+                              _cx: &mut Walker,
+                              kind: &Primitive,
+                              atomic: bool,
+                              ptr: *const u8) {
+        let value = unsafe { kind.load(ptr, atomic) };
+        self.node = Some(Node::Leaf(value.to_string()));
+    }
+
+    unsafe fn visit_seq(&mut self, cx: &mut Walker, contour: &Contour, ptr: *const u8) {
+        self.nested(|b| match *contour {
+            Contour::Seq { element, len, item, .. } => {
+                let n = unsafe { len(ptr) };
+                let items = (0..n)
+                    .map(|ix| {
+                        let subptr = unsafe { item(ptr, ix) };
+                        (None, b.child(cx, false, &[], element, subptr))
+                    })
+                    .collect();
+                Node::Group { head: "", delim: Delim::Bracket, items }
+            },
+            _ => panic!("{} isn't a sequence", contour.name()),
+        })
+    }
+
+    unsafe fn visit_map(&mut self, cx: &mut Walker, contour: &Contour, ptr: *const u8) {
+        self.nested(|b| match *contour {
+            Contour::Map { name, key, value, entries, .. } => {
+                let mut items = vec![];
+                unsafe {
+                    entries(ptr, &mut |k, v| {
+                        let mut label = String::new();
+                        b.child(cx, false, &[], key, k).flat(&mut label);
+                        items.push((Some(label), b.child(cx, false, &[], value, v)));
+                    });
+                }
+                // Hash maps iterate in an arbitrary order, so sort them to keep
+                // the output stable.
+                if name == "HashMap" {
+                    items.sort_by(|a, b| a.0.cmp(&b.0));
+                }
+                Node::Group { head: "", delim: Delim::Brace, items }
+            },
+            _ => panic!("{} isn't a map", contour.name()),
+        })
+    }
+
+    /// Raw pointers aren't followed, so they show their address instead.
+    unsafe fn visit_pointer(&mut self, cx: &mut Walker, contour: &Contour, ptr: *const u8) {
+        if let Contour::Pointer { deref: None, .. } = *contour {
+            let address = raw_address(ptr);
+            if !address.is_null() {
+                self.node = Some(Node::Leaf(format!("{:?}", address)));
+                return;
+            }
+        }
+        walk_pointer(self, cx, contour, ptr);
+        if self.node.is_none() {
+            self.node = Some(Node::Leaf("null".to_owned()));
+        }
+    }
+
+    unsafe fn visit_cycle(&mut self, _cx: &mut Walker, _type_id: TypeId, _ptr: *const u8) {
+        self.node = Some(Node::Leaf("<cycle>".to_owned()));
+    }
+}
+
+/// The bits of an integer, without sign extension.
+fn word(value: &PrimitiveValue) -> Option<u64> {
+    Some(match *value {
+        PrimitiveValue::u8(v) => v as u64,
+        PrimitiveValue::u16(v) => v as u64,
+        PrimitiveValue::u32(v) => v as u64,
+        PrimitiveValue::u64(v) => v,
+        PrimitiveValue::usize(v) => v as u64,
+        PrimitiveValue::i8(v) => v as u8 as u64,
+        PrimitiveValue::i16(v) => v as u16 as u64,
+        PrimitiveValue::i32(v) => v as u32 as u64,
+        PrimitiveValue::i64(v) => v as u64,
+        PrimitiveValue::isize(v) => v as usize as u64,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use {
+        BitField,
+        Contour,
+        ContourMap,
+        Introspectable,
+        StructField,
+        TupleField,
+        Variant,
+        VariantFields,
+    };
+    use super::*;
+
+    #[derive(Introspectable)]
+    enum State {
+        Idle,
+        Running { pid: u32 },
+    }
+
+    #[derive(Introspectable)]
+    struct Conn(u16, bool);
+
+    #[derive(Introspectable)]
+    struct Pool {
+        name: String,
+        #[contour(redact)]
+        password: String,
+        conns: Vec<Conn>,
+        state: State,
+        #[contour(skip)]
+        _cache: ::std::collections::HashMap<u32, u32>,
+    }
+
+    #[derive(Introspectable)]
+    struct Header {
+        #[contour(bits(syn = 0, ack = 1, window(offset = 4, width = 4)))]
+        flags: u16,
+        len: u16,
+    }
+
+    fn pool() -> Pool {
+        Pool {
+            name: "primary".to_owned(),
+            password: "hunter2".to_owned(),
+            conns: vec![Conn(5432, true), Conn(5433, false)],
+            state: State::Running { pid: 31 },
+            _cache: ::std::collections::HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_flat() {
+        let options = PrettyOptions { width: 200, ..PrettyOptions::default() };
+        assert_eq!(
+            Pretty::with_options(&pool(), options).to_string(),
+            "Pool { name: \"primary\", password: <redacted>, \
+             conns: [Conn(5432, true), Conn(5433, false)], state: Running { pid: 31 } }"
+        );
+    }
+
+    #[test]
+    fn test_options() {
+        assert_eq!(pretty(&pool()), "\
+Pool {
+    name: \"primary\",
+    password: <redacted>,
+    conns: [Conn(5432, true), Conn(5433, false)],
+    state: Running { pid: 31 },
+}");
+
+        let options = PrettyOptions { max_depth: 2, width: 40, redact: false };
+        assert_eq!(Pretty::with_options(&pool(), options).to_string(), "\
+Pool {
+    name: \"primary\",
+    password: \"hunter2\",
+    conns: [.., ..],
+    state: Running { pid: 31 },
+}");
+    }
+
+    #[test]
+    fn test_bits() {
+        match Header::contour() {
+            Contour::Struct { ref fields, .. } => assert_eq!(fields[0].bits[2], BitField {
+                name: "window",
+                offset: 4,
+                width: 4,
+            }),
+            c => panic!("unexpected contour {:?}", c),
+        }
+        let header = Header { flags: 0x1a2, len: 20 };
+        assert_eq!(pretty(&header), "Header { flags: 0x1a2 (ack | window=10 | 0x100), len: 20 }");
+        let header = Header { flags: 0, len: 0 };
+        assert_eq!(pretty(&header), "Header { flags: 0x0 (0), len: 0 }");
+    }
+
+    #[derive(Introspectable)]
+    struct Link {
+        val: u8,
+        next: *const Link,
+    }
+
+    #[test]
+    fn test_raw_pointers() {
+        // Raw pointers are never followed, so a dangling one is harmless.
+        let link = Link { val: 1, next: 0x10 as *const Link };
+        assert_eq!(pretty(&link), "Link { val: 1, next: 0x10 }");
+        let link = Link { val: 1, next: ::std::ptr::null() };
+        assert_eq!(pretty(&link), "Link { val: 1, next: null }");
+    }
+}
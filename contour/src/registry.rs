@@ -0,0 +1,85 @@
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use {
+    Contour,
+    ContourMap,
+    Introspectable,
+    Method,
+    Methods,
+};
+
+/// A thread-safe `ContourMap` for callers that don't need to keep any state
+/// of their own next to the contours.
+pub struct Registry {
+    map: Mutex<HashMap<TypeId, Contour>>,
+    methods: Mutex<HashMap<TypeId, Vec<Method>>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry { map: Mutex::new(HashMap::new()), methods: Mutex::new(HashMap::new()) }
+    }
+
+    /// Creates a registry with `T` and all of its descendants charted.
+    pub fn of<T: Introspectable>() -> Self {
+        let registry = Registry::new();
+        T::chart(&registry);
+        registry
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// All registered contours, in no particular order.
+    pub fn contours(&self) -> Vec<Contour> {
+        self.map.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Charts `T` and records its introspected methods next to its contour.
+    pub fn register_methods<T: Introspectable + Methods>(&self) {
+        T::chart(self);
+        self.methods.lock().unwrap().insert(T::contour().type_id(), T::methods());
+    }
+
+    /// The methods recorded for `type_id`, if any.
+    pub fn methods(&self, type_id: TypeId) -> Vec<Method> {
+        self.methods.lock().unwrap().get(&type_id).cloned().unwrap_or_default()
+    }
+
+    pub fn method(&self, type_id: TypeId, name: &str) -> Option<Method> {
+        self.methods(type_id).into_iter().find(|m| m.name == name)
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+impl ContourMap for Registry {
+    fn register(&self, contour: Contour) -> bool {
+        let mut map = self.map.lock().unwrap();
+        let type_id = contour.type_id();
+        if let Some(current) = map.get(&type_id) {
+            if current == &contour {
+                return true;
+            } else {
+                panic!("Contour mismatch: {:?} vs. {:?}", current, contour);
+            }
+        }
+        map.insert(type_id, contour);
+        false
+    }
+
+    fn lookup(&self, type_id: TypeId) -> Option<Contour> {
+        self.map.lock().unwrap().get(&type_id).cloned()
+    }
+}
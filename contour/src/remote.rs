@@ -0,0 +1,494 @@
+//! Reading values out of another process's memory.
+//!
+//! The target publishes an `Anchor`, its exported schema and the address of
+//! a root value, somewhere the inspector can read it (like a file).  The
+//! inspector then reads the root through a `Memory`, such as
+//! `ProcessMemory` for a live process on Linux, and decodes it into a
+//! `Value` the same way `Snapshot::decode` would have.
+//!
+//! Schemas describe where fields are, but not the insides of standard
+//! library containers, so those are read assuming the target was built with
+//! the same toolchain as the inspector: `Vec` and `String` buffers are
+//! located the way they are in this build, and `Rc`/`Arc` targets by the
+//! layout of their reference-counted boxes.  Enums and maps are reported as
+//! unsupported, since their layouts aren't recorded anywhere.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::str;
+
+use path::{
+    Key,
+    Path,
+    Segment,
+};
+use schema::{
+    DecodeError,
+    Fields,
+    Kind,
+    Reader,
+    Schema,
+    Writer,
+};
+use value::{
+    FieldValues,
+    Value,
+};
+use {
+    Introspectable,
+    Primitive,
+    PrimitiveValue,
+};
+
+const MAGIC: &[u8] = b"CNTA";
+const VERSION: u8 = 1;
+
+/// Values deeper than this, or sequences longer than `MAX_LEN` or
+/// `MAX_BYTES`, are taken to be garbage rather than read.
+const MAX_DEPTH: usize = 1024;
+const MAX_LEN: usize = 1 << 24;
+const MAX_BYTES: usize = 1 << 28;
+
+/// Everything an inspector needs to find and decode a value in another
+/// process.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Anchor {
+    pub schema: Schema,
+    pub addr: usize,
+}
+
+impl Anchor {
+    /// The anchor for `value`, which must stay put for as long as anyone
+    /// might read it.
+    pub fn of<T: Introspectable>(value: &T) -> Self {
+        Anchor { schema: Schema::of::<T>(), addr: value as *const T as usize }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        Writer(&mut out).fixed(self.addr as u64, 8);
+        self.schema.encode(&mut out);
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(bytes);
+        if r.take(MAGIC.len()).ok() != Some(MAGIC) {
+            return Err(DecodeError { offset: 0, msg: "not an anchor".to_owned() });
+        }
+        if r.u8()? != VERSION {
+            return r.error("unsupported version");
+        }
+        let addr = r.fixed(8)? as usize;
+        let schema = Schema::decode(&mut r)?;
+        if !r.is_empty() {
+            return r.error("trailing bytes");
+        }
+        Ok(Anchor { schema, addr })
+    }
+}
+
+/// Somewhere to read another address space from.
+pub trait Memory {
+    /// Fills `buf` from `addr` onwards, failing if any of it is unreadable.
+    fn read(&self, addr: usize, buf: &mut [u8]) -> io::Result<()>;
+}
+
+/// The memory of a live process, through `/proc/<pid>/mem`.  Reading it
+/// needs the same permissions as attaching a debugger.
+#[cfg(target_os = "linux")]
+pub struct ProcessMemory {
+    file: ::std::fs::File,
+}
+
+#[cfg(target_os = "linux")]
+impl ProcessMemory {
+    pub fn open(pid: u32) -> io::Result<Self> {
+        let file = ::std::fs::File::open(format!("/proc/{}/mem", pid))?;
+        Ok(ProcessMemory { file })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Memory for ProcessMemory {
+    fn read(&self, addr: usize, buf: &mut [u8]) -> io::Result<()> {
+        use std::os::unix::fs::FileExt;
+        self.file.read_exact_at(buf, addr as u64)
+    }
+}
+
+#[derive(Debug)]
+pub enum RemoteError {
+    /// Memory at `addr` for the value at `path` couldn't be read.
+    Read { path: String, addr: usize, err: io::Error },
+    /// The value at `path` is of a kind that can't be read remotely.
+    Unsupported { path: String, what: String },
+    /// The memory at `path` doesn't hold a valid value of its type.
+    Invalid { path: String, msg: String },
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RemoteError::Read { ref path, addr, ref err } =>
+                write!(f, "at `{}`: couldn't read {:#x}: {}", path, addr, err),
+            RemoteError::Unsupported { ref path, ref what } =>
+                write!(f, "at `{}`: can't read {} remotely", path, what),
+            RemoteError::Invalid { ref path, ref msg } => write!(f, "at `{}`: {}", path, msg),
+        }
+    }
+}
+
+impl error::Error for RemoteError {}
+
+/// Reads and decodes the root value of `anchor` out of `mem`.
+pub fn read_anchor(mem: &dyn Memory, anchor: &Anchor) -> Result<Value, RemoteError> {
+    read_value(mem, &anchor.schema, anchor.schema.root, anchor.addr)
+}
+
+/// Reads and decodes the value of schema type `ty` at `addr` in `mem`.
+pub fn read_value(mem: &dyn Memory, schema: &Schema, ty: usize, addr: usize)
+    -> Result<Value, RemoteError>
+{
+    let mut reader = RemoteReader {
+        mem,
+        schema,
+        vec: vec_layout(),
+        path: Path::root(),
+        active: vec![],
+    };
+    let bytes = reader.fetch(addr, schema.types[ty].size)?;
+    reader.value(ty, addr, &bytes)
+}
+
+/// Which words of a `Vec` hold its buffer pointer and length.
+struct VecLayout {
+    ptr: usize,
+    len: usize,
+}
+
+fn vec_layout() -> VecLayout {
+    let mut probe: Vec<u8> = Vec::with_capacity(7);
+    probe.extend_from_slice(&[1, 2]);
+    assert_eq!(mem::size_of::<Vec<u8>>(), 3 * mem::size_of::<usize>());
+    let words: [usize; 3] = unsafe { mem::transmute_copy(&probe) };
+    let find = |w: usize| words.iter().position(|&x| x == w).expect("Unrecognized Vec layout");
+    VecLayout { ptr: find(probe.as_ptr() as usize), len: find(probe.len()) }
+}
+
+struct RemoteReader<'a> {
+    mem: &'a dyn Memory,
+    schema: &'a Schema,
+    vec: VecLayout,
+    path: Path,
+    /// Values on the path from the root, to spot pointers back up it.
+    active: Vec<(usize, usize)>,
+}
+
+impl<'a> RemoteReader<'a> {
+    fn invalid<T>(&self, msg: String) -> Result<T, RemoteError> {
+        Err(RemoteError::Invalid { path: self.path.to_string(), msg })
+    }
+
+    fn unsupported<T>(&self, what: &str) -> Result<T, RemoteError> {
+        Err(RemoteError::Unsupported { path: self.path.to_string(), what: what.to_owned() })
+    }
+
+    fn fetch(&self, addr: usize, len: usize) -> Result<Vec<u8>, RemoteError> {
+        if len > MAX_BYTES {
+            return self.invalid(format!("implausible size of {} bytes", len));
+        }
+        let mut buf = vec![0; len];
+        if len > 0 {
+            if addr == 0 {
+                return self.invalid("null pointer".to_owned());
+            }
+            self.mem.read(addr, &mut buf).map_err(|e| RemoteError::Read {
+                path: self.path.to_string(),
+                addr,
+                err: e,
+            })?;
+        }
+        Ok(buf)
+    }
+
+    fn word(&self, bytes: &[u8], ix: usize) -> Result<usize, RemoteError> {
+        let mut word = [0; mem::size_of::<usize>()];
+        let n = word.len();
+        if bytes.len() < (ix + 1) * n {
+            return self.invalid(format!("{} bytes is too small for its type", bytes.len()));
+        }
+        word.copy_from_slice(&bytes[ix * n..(ix + 1) * n]);
+        Ok(usize::from_ne_bytes(word))
+    }
+
+    /// `bytes` are the value's own `size` bytes, which live at `addr`.
+    fn value(&mut self, ty: usize, addr: usize, bytes: &[u8]) -> Result<Value, RemoteError> {
+        if self.active.len() >= MAX_DEPTH {
+            return self.invalid("nested too deeply".to_owned());
+        }
+        self.active.push((ty, addr));
+        let result = self.kind(ty, addr, bytes);
+        self.active.pop();
+        result
+    }
+
+    fn kind(&mut self, ty: usize, addr: usize, bytes: &[u8]) -> Result<Value, RemoteError> {
+        let schema = self.schema;
+        let desc = &schema.types[ty];
+        match desc.kind {
+            Kind::Struct(ref fields) => {
+                let fields = match *fields {
+                    Fields::Named(ref fields) => {
+                        let mut values = vec![];
+                        for f in fields {
+                            self.path.push(Segment::Field(f.name.clone()));
+                            let v = self.field(f.ty, addr, bytes, f.offset)?;
+                            self.path.pop();
+                            values.push((f.name.clone(), v));
+                        }
+                        FieldValues::Named(values)
+                    },
+                    Fields::Tuple(ref fields) => {
+                        let mut values = vec![];
+                        for f in fields {
+                            self.path.push(Segment::Tuple(f.name.parse().unwrap_or(0)));
+                            values.push(self.field(f.ty, addr, bytes, f.offset)?);
+                            self.path.pop();
+                        }
+                        FieldValues::Tuple(values)
+                    },
+                    Fields::Unit => FieldValues::Unit,
+                };
+                Ok(Value::Struct { name: desc.name.clone(), fields })
+            },
+            Kind::Enum(..) => self.unsupported(&format!("enum `{}`", desc.name)),
+            Kind::Map { .. } => self.unsupported(&format!("map `{}`", desc.name)),
+            Kind::Primitive(Primitive::String) => {
+                let buf = self.buffer(bytes, 1)?;
+                match String::from_utf8(buf) {
+                    Ok(s) => Ok(Value::Primitive(PrimitiveValue::String(s))),
+                    Err(_) => self.invalid("invalid UTF-8 in string".to_owned()),
+                }
+            },
+            Kind::Primitive(ref p) => self.primitive(p, bytes).map(Value::Primitive),
+            Kind::Seq { element } => {
+                let stride = schema.types[element].size;
+                let n = self.word(bytes, self.vec.len)?;
+                let buf = self.buffer(bytes, stride)?;
+                let start = self.word(bytes, self.vec.ptr)?;
+                let mut items = vec![];
+                for i in 0..n {
+                    self.path.push(Segment::Index(Key::Int(i as i64)));
+                    let item = &buf[i * stride..(i + 1) * stride];
+                    items.push(self.value(element, start + i * stride, item)?);
+                    self.path.pop();
+                }
+                Ok(Value::Seq(items))
+            },
+            Kind::Pointer { pointee, .. } => {
+                let target = self.word(bytes, 0)?;
+                if target == 0 {
+                    return Ok(Value::Pointer(None));
+                }
+                let target = match &desc.name[..] {
+                    "Box" | "*const" | "*mut" => target,
+                    // The count fields of `RcBox` and `ArcInner`.
+                    "Rc" | "Arc" => {
+                        let align = schema.types[pointee].align;
+                        let header = 2 * mem::size_of::<usize>();
+                        target + header.div_ceil(align) * align
+                    },
+                    _ => return self.unsupported(&format!("pointer `{}`", desc.name)),
+                };
+                if self.active.contains(&(pointee, target)) {
+                    return Ok(Value::Cycle);
+                }
+                let bytes = self.fetch(target, schema.types[pointee].size)?;
+                Ok(Value::Pointer(Some(Box::new(self.value(pointee, target, &bytes)?))))
+            },
+        }
+    }
+
+    fn field(&mut self, ty: usize, addr: usize, bytes: &[u8], offset: usize)
+        -> Result<Value, RemoteError>
+    {
+        let size = self.schema.types[ty].size;
+        if offset + size > bytes.len() {
+            return self.invalid(format!("field at offset {} overruns its parent", offset));
+        }
+        self.value(ty, addr + offset, &bytes[offset..offset + size])
+    }
+
+    /// The contents of the `Vec`-like buffer described by `bytes`.
+    fn buffer(&self, bytes: &[u8], stride: usize) -> Result<Vec<u8>, RemoteError> {
+        let n = self.word(bytes, self.vec.len)?;
+        if n > MAX_LEN {
+            return self.invalid(format!("implausible length {}", n));
+        }
+        let len = match n.checked_mul(stride) {
+            Some(len) if len <= MAX_BYTES => len,
+            _ => return self.invalid(format!("implausible size of {} elements of {} bytes",
+                                             n, stride)),
+        };
+        let addr = self.word(bytes, self.vec.ptr)?;
+        if addr.checked_add(len).is_none() {
+            return self.invalid(format!("buffer at {:#x} runs past the end of memory", addr));
+        }
+        self.fetch(addr, len)
+    }
+
+    fn primitive(&self, kind: &Primitive, bytes: &[u8]) -> Result<PrimitiveValue, RemoteError> {
+        let raw = match bytes.len() {
+            1 => bytes[0] as u64,
+            2 => u16::from_ne_bytes([bytes[0], bytes[1]]) as u64,
+            4 => u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64,
+            8 => {
+                let mut word = [0; 8];
+                word.copy_from_slice(bytes);
+                u64::from_ne_bytes(word)
+            },
+            n => return self.invalid(format!("{} bytes is the wrong size for `{:?}`", n, kind)),
+        };
+        Ok(match *kind {
+            Primitive::u8 => PrimitiveValue::u8(raw as u8),
+            Primitive::u16 => PrimitiveValue::u16(raw as u16),
+            Primitive::u32 => PrimitiveValue::u32(raw as u32),
+            Primitive::u64 => PrimitiveValue::u64(raw),
+            Primitive::usize => PrimitiveValue::usize(raw as usize),
+            Primitive::i8 => PrimitiveValue::i8(raw as i8),
+            Primitive::i16 => PrimitiveValue::i16(raw as i16),
+            Primitive::i32 => PrimitiveValue::i32(raw as i32),
+            Primitive::i64 => PrimitiveValue::i64(raw as i64),
+            Primitive::isize => PrimitiveValue::isize(raw as isize),
+            Primitive::f32 => PrimitiveValue::f32(f32::from_bits(raw as u32)),
+            Primitive::f64 => PrimitiveValue::f64(f64::from_bits(raw)),
+            Primitive::bool => match raw {
+                0 => PrimitiveValue::bool(false),
+                1 => PrimitiveValue::bool(true),
+                b => return self.invalid(format!("{} isn't a bool", b)),
+            },
+            Primitive::char => match ::std::char::from_u32(raw as u32) {
+                Some(c) => PrimitiveValue::char(c),
+                None => return self.invalid(format!("{:#x} isn't a char", raw)),
+            },
+            Primitive::String => unreachable!(),
+        })
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    #![allow(dead_code)]
+    use std::process;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        StructField,
+        TupleField,
+        Variant,
+        VariantFields,
+    };
+    use snapshot::{
+        snapshot,
+        Snapshot,
+    };
+    use super::*;
+
+    #[derive(Introspectable)]
+    struct Peer(u16, char);
+
+    #[derive(Introspectable)]
+    struct Daemon {
+        name: String,
+        uptime: f64,
+        healthy: bool,
+        peers: Vec<Peer>,
+        config: Box<Vec<String>>,
+        shared: Rc<i32>,
+        stats: Arc<u8>,
+        me: *const Daemon,
+    }
+
+    #[test]
+    fn test_read_self() {
+        let mut daemon = Daemon {
+            name: "indexer".to_owned(),
+            uptime: 12.5,
+            healthy: true,
+            peers: vec![Peer(7, 'a'), Peer(9, 'ß')],
+            config: Box::new(vec!["-v".to_owned(), "--fast".to_owned()]),
+            shared: Rc::new(-4),
+            stats: Arc::new(3),
+            me: ::std::ptr::null(),
+        };
+        daemon.me = &daemon;
+
+        let anchor = Anchor::decode(&Anchor::of(&daemon).encode()).unwrap();
+        let mem = ProcessMemory::open(process::id()).unwrap();
+        let value = read_anchor(&mem, &anchor).unwrap();
+        // Raw pointers are followed here, since a bad one is just a failed
+        // read, but a snapshot only records their address.
+        let mut expected = Snapshot::decode(&snapshot(&daemon)).unwrap().value;
+        if let Value::Struct { fields: FieldValues::Named(ref mut fields), .. } = expected {
+            fields.last_mut().unwrap().1 = Value::Cycle;
+        }
+        assert_eq!(value, expected);
+    }
+
+    #[derive(Introspectable)]
+    enum State {
+        Idle,
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let states = vec![State::Idle];
+        let mem = ProcessMemory::open(process::id()).unwrap();
+        let err = read_anchor(&mem, &Anchor::of(&states)).unwrap_err();
+        assert_eq!(err.to_string(), "at `[0]`: can't read enum `State` remotely");
+    }
+
+    #[derive(Introspectable)]
+    struct Wide(u64, u64, u64, u64, u64, u64, u64, u64);
+
+    /// Serves a copy of a `Vec`'s header, with the length and capacity
+    /// replaced.
+    struct Forged(Vec<u8>);
+
+    impl Memory for Forged {
+        fn read(&self, addr: usize, buf: &mut [u8]) -> io::Result<()> {
+            let bytes = self.0.get(addr - 1..addr - 1 + buf.len())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "unmapped"))?;
+            buf.copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_implausible_size() {
+        let wides = vec![Wide(0, 0, 0, 0, 0, 0, 0, 0)];
+        let mut header = vec![];
+        let words: [usize; 3] = unsafe { ::std::mem::transmute_copy(&wides) };
+        for &word in &words {
+            let word = if word == 1 { MAX_LEN } else { word };
+            header.extend_from_slice(&word.to_ne_bytes());
+        }
+        let anchor = Anchor::of(&wides);
+        let forged = Forged(header);
+        let err = read_value(&forged, &anchor.schema, anchor.schema.root, 1).unwrap_err();
+        assert_eq!(err.to_string(),
+                   format!("at ``: implausible size of {} elements of 64 bytes", MAX_LEN));
+
+        let mut schema = anchor.schema.clone();
+        schema.types[schema.root].size = 8;
+        let err = read_value(&forged, &schema, schema.root, 1).unwrap_err();
+        assert_eq!(err.to_string(), "at ``: 8 bytes is too small for its type");
+    }
+}
@@ -0,0 +1,59 @@
+//! Named values that `server` and `watch` look at from another thread.
+
+use std::any::TypeId;
+use std::sync::Mutex;
+
+use {
+    Introspectable,
+    Registry,
+};
+
+/// Calls back with a pointer to the value, and whether nothing else can
+/// touch it until the callback returns.
+type Access = Box<dyn Fn(&mut dyn FnMut(*mut u8, bool)) + Send + Sync>;
+
+pub struct Root {
+    pub name: String,
+    pub type_id: TypeId,
+    pub registry: Registry,
+    access: Access,
+}
+
+impl Root {
+    /// `value`, which the rest of the process may be using meanwhile.
+    pub fn new<T: Introspectable + Sync>(name: &str, value: &'static T) -> Self {
+        let addr = value as *const T as usize;
+        Root::with_access::<T>(name, Box::new(move |f| f(addr as *mut u8, false)))
+    }
+
+    /// The value inside `value`, locked for each access.
+    pub fn locked<T: Introspectable + Send>(name: &str, value: &'static Mutex<T>) -> Self {
+        Root::with_access::<T>(name, Box::new(move |f| {
+            let mut guard = value.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut *guard as *mut T as *mut u8, true)
+        }))
+    }
+
+    fn with_access<T: Introspectable>(name: &str, access: Access) -> Self {
+        Root {
+            name: name.to_owned(),
+            type_id: T::contour().type_id(),
+            registry: Registry::of::<T>(),
+            access,
+        }
+    }
+
+    /// Calls `f` with a pointer to the value, and whether nothing else can
+    /// touch it until `f` returns.
+    pub fn access(&self, f: &mut dyn FnMut(*mut u8, bool)) {
+        (self.access)(f)
+    }
+}
+
+/// Adds `root` to `roots`, whose names have to be unique.
+pub fn push(roots: &mut Vec<Root>, root: Root) {
+    if roots.iter().any(|r| r.name == root.name) {
+        panic!("Root `{}` registered twice", root.name);
+    }
+    roots.push(root);
+}
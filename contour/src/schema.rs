@@ -0,0 +1,537 @@
+//! Contours in a form that can leave the process.
+//!
+//! A `Contour` refers to other types by `TypeId`, which means nothing outside
+//! of the build that produced it.  A `Schema` is the set of contours reachable
+//! from a root type with those references replaced by indices into the
+//! schema, so it can be written out next to data and read back by another
+//! build, or by a different program entirely.
+
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::str;
+
+use {
+    Contour,
+    ContourMap,
+    Introspectable,
+    Primitive,
+    Registry,
+    StructField,
+    TupleField,
+    VariantFields,
+};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Schema {
+    pub types: Vec<TypeDesc>,
+    /// Index of the type the schema was exported for.
+    pub root: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TypeDesc {
+    pub name: String,
+    /// Empty for types that aren't derived.
+    pub module_path: String,
+    pub size: usize,
+    pub align: usize,
+    pub kind: Kind,
+}
+
+/// Type references are indices into `Schema::types`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Kind {
+    /// Structs, tuple structs and unit structs.
+    Struct(Fields),
+    Enum(Vec<VariantDesc>),
+    Primitive(Primitive),
+    Seq { element: usize },
+    Pointer { pointee: usize, owned: bool },
+    Map { key: usize, value: usize },
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Fields {
+    Named(Vec<FieldDesc>),
+    Tuple(Vec<FieldDesc>),
+    Unit,
+}
+
+impl Fields {
+    pub fn iter(&self) -> ::std::slice::Iter<'_, FieldDesc> {
+        match *self {
+            Fields::Named(ref fields) | Fields::Tuple(ref fields) => fields.iter(),
+            Fields::Unit => [].iter(),
+        }
+    }
+}
+
+/// Tuple fields are named by their index.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FieldDesc {
+    pub name: String,
+    pub ty: usize,
+    pub offset: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VariantDesc {
+    pub name: String,
+    pub fields: Fields,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DecodeError {
+    /// Byte offset into the input where decoding failed.
+    pub offset: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at byte {}: {}", self.offset, self.msg)
+    }
+}
+
+impl error::Error for DecodeError {}
+
+impl Schema {
+    /// Exports `T` and everything reachable from it.
+    pub fn of<T: Introspectable>() -> Self {
+        let registry = Registry::of::<T>();
+        Schema::export(&registry, T::contour().type_id())
+            .expect("chart didn't register all descendants")
+    }
+
+    /// Exports `root` and everything reachable from it, or returns `None` if
+    /// any of those haven't been charted into `map`.  Types are numbered in
+    /// breadth-first order, so the root is always index 0.
+    pub fn export(map: &dyn ContourMap, root: TypeId) -> Option<Self> {
+        let mut ids = HashMap::new();
+        let mut order = vec![root];
+        ids.insert(root, 0);
+
+        let mut types = vec![];
+        let mut next = 0;
+        while next < order.len() {
+            let contour = map.lookup(order[next])?;
+            next += 1;
+            let mut ix = |type_id: TypeId| {
+                let n = order.len();
+                *ids.entry(type_id).or_insert_with(|| {
+                    order.push(type_id);
+                    n
+                })
+            };
+            let (module_path, kind) = match contour {
+                Contour::Struct { module_path, ref fields, .. } =>
+                    (module_path, Kind::Struct(Fields::Named(struct_fields(fields, &mut ix)))),
+                Contour::Tuple { module_path, ref fields, .. } =>
+                    (module_path, Kind::Struct(Fields::Tuple(tuple_fields(fields, &mut ix)))),
+                Contour::Unit { module_path, .. } => (module_path, Kind::Struct(Fields::Unit)),
+                Contour::Enum { module_path, ref variants, .. } => {
+                    let variants = variants.iter()
+                        .map(|v| VariantDesc {
+                            name: v.name.to_owned(),
+                            fields: match v.fields {
+                                VariantFields::Struct(ref fields) =>
+                                    Fields::Named(struct_fields(fields, &mut ix)),
+                                VariantFields::Tuple(ref fields) =>
+                                    Fields::Tuple(tuple_fields(fields, &mut ix)),
+                                VariantFields::Unit => Fields::Unit,
+                            },
+                        })
+                        .collect();
+                    (module_path, Kind::Enum(variants))
+                },
+                Contour::Primitive { ref variant, .. } => ("", Kind::Primitive(variant.clone())),
+                Contour::Seq { element, .. } => ("", Kind::Seq { element: ix(element) }),
+                Contour::Pointer { pointee, heap_size, .. } =>
+                    ("", Kind::Pointer { pointee: ix(pointee), owned: heap_size.is_some() }),
+                Contour::Map { key, value, .. } =>
+                    ("", Kind::Map { key: ix(key), value: ix(value) }),
+            };
+            types.push(TypeDesc {
+                name: contour.name().to_owned(),
+                module_path: module_path.to_owned(),
+                size: contour.size(),
+                align: contour.align(),
+                kind,
+            });
+        }
+        Some(Schema { types, root: 0 })
+    }
+
+    pub fn root(&self) -> &TypeDesc {
+        &self.types[self.root]
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let mut w = Writer(out);
+        w.varint(self.types.len() as u64);
+        for ty in &self.types {
+            w.str(&ty.name);
+            w.str(&ty.module_path);
+            w.varint(ty.size as u64);
+            w.varint(ty.align as u64);
+            match ty.kind {
+                Kind::Struct(ref fields) => {
+                    w.u8(KIND_STRUCT);
+                    w.fields(fields);
+                },
+                Kind::Enum(ref variants) => {
+                    w.u8(KIND_ENUM);
+                    w.varint(variants.len() as u64);
+                    for variant in variants {
+                        w.str(&variant.name);
+                        w.fields(&variant.fields);
+                    }
+                },
+                Kind::Primitive(ref p) => {
+                    w.u8(KIND_PRIMITIVE);
+                    w.u8(primitive_code(p));
+                },
+                Kind::Seq { element } => {
+                    w.u8(KIND_SEQ);
+                    w.varint(element as u64);
+                },
+                Kind::Pointer { pointee, owned } => {
+                    w.u8(KIND_POINTER);
+                    w.varint(pointee as u64);
+                    w.u8(owned as u8);
+                },
+                Kind::Map { key, value } => {
+                    w.u8(KIND_MAP);
+                    w.varint(key as u64);
+                    w.varint(value as u64);
+                },
+            }
+        }
+        w.varint(self.root as u64);
+    }
+
+    /// Decodes a schema written by `encode` from the front of `r`, checking
+    /// that every type reference is in bounds and every layout is plausible.
+    pub fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        let n = r.varint()? as usize;
+        let mut types = vec![];
+        for _ in 0..n {
+            let name = r.str()?;
+            let module_path = r.str()?;
+            let size = r.varint()?;
+            if size > MAX_SIZE as u64 {
+                return r.error("implausible size");
+            }
+            let align = r.varint()?;
+            if !align.is_power_of_two() || align > MAX_SIZE as u64 {
+                return r.error("alignment isn't a power of two");
+            }
+            let kind = match r.u8()? {
+                KIND_STRUCT => Kind::Struct(r.fields(n)?),
+                KIND_ENUM => {
+                    let count = r.varint()?;
+                    let mut variants = vec![];
+                    for _ in 0..count {
+                        let name = r.str()?;
+                        variants.push(VariantDesc { name, fields: r.fields(n)? });
+                    }
+                    Kind::Enum(variants)
+                },
+                KIND_PRIMITIVE => match primitive_from_code(r.u8()?) {
+                    Some(p) => Kind::Primitive(p),
+                    None => return r.error("unknown primitive"),
+                },
+                KIND_SEQ => Kind::Seq { element: r.index(n)? },
+                KIND_POINTER => Kind::Pointer { pointee: r.index(n)?, owned: r.u8()? != 0 },
+                KIND_MAP => Kind::Map { key: r.index(n)?, value: r.index(n)? },
+                _ => return r.error("unknown kind"),
+            };
+            types.push(TypeDesc {
+                name,
+                module_path,
+                size: size as usize,
+                align: align as usize,
+                kind,
+            });
+        }
+        let root = r.index(n)?;
+        Ok(Schema { types, root })
+    }
+}
+
+fn struct_fields<F: FnMut(TypeId) -> usize>(fields: &[StructField], ix: &mut F) -> Vec<FieldDesc> {
+    fields.iter()
+        .map(|f| FieldDesc { name: f.name.to_owned(), ty: ix(f.type_id), offset: f.offset })
+        .collect()
+}
+
+fn tuple_fields<F: FnMut(TypeId) -> usize>(fields: &[TupleField], ix: &mut F) -> Vec<FieldDesc> {
+    fields.iter()
+        .map(|f| FieldDesc { name: f.ix.to_string(), ty: ix(f.type_id), offset: f.offset })
+        .collect()
+}
+
+/// Types bigger than this are taken to be garbage rather than decoded.
+const MAX_SIZE: usize = 1 << 28;
+
+const KIND_STRUCT: u8 = 0;
+const KIND_ENUM: u8 = 1;
+const KIND_PRIMITIVE: u8 = 2;
+const KIND_SEQ: u8 = 3;
+const KIND_POINTER: u8 = 4;
+const KIND_MAP: u8 = 5;
+
+const FIELDS_NAMED: u8 = 0;
+const FIELDS_TUPLE: u8 = 1;
+const FIELDS_UNIT: u8 = 2;
+
+const PRIMITIVES: [Primitive; 15] = [
+    Primitive::u8,
+    Primitive::u16,
+    Primitive::u32,
+    Primitive::u64,
+    Primitive::usize,
+    Primitive::i8,
+    Primitive::i16,
+    Primitive::i32,
+    Primitive::i64,
+    Primitive::f32,
+    Primitive::f64,
+    Primitive::isize,
+    Primitive::bool,
+    Primitive::char,
+    Primitive::String,
+];
+
+fn primitive_code(p: &Primitive) -> u8 {
+    PRIMITIVES.iter().position(|q| q == p).unwrap() as u8
+}
+
+fn primitive_from_code(code: u8) -> Option<Primitive> {
+    PRIMITIVES.get(code as usize).cloned()
+}
+
+/// Little-endian, with LEB128 for lengths and indices.
+pub struct Writer<'a>(pub &'a mut Vec<u8>);
+
+impl<'a> Writer<'a> {
+    pub fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    pub fn fixed(&mut self, v: u64, width: usize) {
+        for i in 0..width {
+            self.0.push((v >> (8 * i)) as u8);
+        }
+    }
+
+    pub fn varint(&mut self, mut v: u64) {
+        loop {
+            let b = (v & 0x7f) as u8;
+            v >>= 7;
+            if v == 0 {
+                self.0.push(b);
+                return;
+            }
+            self.0.push(b | 0x80);
+        }
+    }
+
+    pub fn bytes(&mut self, b: &[u8]) {
+        self.varint(b.len() as u64);
+        self.0.extend_from_slice(b);
+    }
+
+    pub fn str(&mut self, s: &str) {
+        self.bytes(s.as_bytes());
+    }
+
+    fn fields(&mut self, fields: &Fields) {
+        let (shape, fields) = match *fields {
+            Fields::Named(ref fields) => (FIELDS_NAMED, &fields[..]),
+            Fields::Tuple(ref fields) => (FIELDS_TUPLE, &fields[..]),
+            Fields::Unit => (FIELDS_UNIT, &[][..]),
+        };
+        self.u8(shape);
+        self.varint(fields.len() as u64);
+        for field in fields {
+            self.str(&field.name);
+            self.varint(field.ty as u64);
+            self.varint(field.offset as u64);
+        }
+    }
+}
+
+/// The inverse of `Writer`, over a borrowed buffer.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    pub fn error<T>(&self, msg: &str) -> Result<T, DecodeError> {
+        Err(DecodeError { offset: self.pos, msg: msg.to_owned() })
+    }
+
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.buf.len() - self.pos < n {
+            return self.error("unexpected end of input");
+        }
+        let out = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(out)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn fixed(&mut self, width: usize) -> Result<u64, DecodeError> {
+        let b = self.take(width)?;
+        Ok(b.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64))
+    }
+
+    pub fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut v = 0u64;
+        for shift in 0..10 {
+            let b = self.u8()?;
+            v |= ((b & 0x7f) as u64) << (7 * shift);
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        self.error("varint too long")
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let n = self.varint()?;
+        if n > (self.buf.len() - self.pos) as u64 {
+            return self.error("length past end of input");
+        }
+        self.take(n as usize)
+    }
+
+    pub fn str(&mut self) -> Result<String, DecodeError> {
+        let start = self.pos;
+        let b = self.bytes()?;
+        match str::from_utf8(b) {
+            Ok(s) => Ok(s.to_owned()),
+            Err(_) => Err(DecodeError { offset: start, msg: "invalid UTF-8".to_owned() }),
+        }
+    }
+
+    fn index(&mut self, n: usize) -> Result<usize, DecodeError> {
+        let ix = self.varint()?;
+        if ix >= n as u64 {
+            return self.error("type index out of range");
+        }
+        Ok(ix as usize)
+    }
+
+    fn fields(&mut self, n: usize) -> Result<Fields, DecodeError> {
+        let shape = self.u8()?;
+        let count = self.varint()?;
+        let mut fields = vec![];
+        for _ in 0..count {
+            let name = self.str()?;
+            let ty = self.index(n)?;
+            let offset = self.varint()? as usize;
+            fields.push(FieldDesc { name, ty, offset });
+        }
+        match shape {
+            FIELDS_NAMED => Ok(Fields::Named(fields)),
+            FIELDS_TUPLE => Ok(Fields::Tuple(fields)),
+            FIELDS_UNIT if fields.is_empty() => Ok(Fields::Unit),
+            _ => self.error("bad field list"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::collections::HashMap;
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        StructField,
+        TupleField,
+        Variant,
+        VariantFields,
+    };
+    use super::*;
+
+    #[derive(Introspectable)]
+    enum Shape {
+        Empty,
+        Point(i32, i32),
+        Named { label: String },
+    }
+
+    #[derive(Introspectable)]
+    struct Scene {
+        shapes: Vec<Shape>,
+        lookup: HashMap<String, Box<Scene>>,
+        parent: *const Scene,
+    }
+
+    #[test]
+    fn test_export() {
+        let schema = Schema::of::<Scene>();
+        assert_eq!(schema.root().name, "Scene");
+        assert_eq!(schema.types.iter().filter(|t| t.name == "Scene").count(), 1);
+        match schema.root().kind {
+            Kind::Struct(Fields::Named(ref fields)) => {
+                let names: Vec<_> = fields.iter().map(|f| &f.name[..]).collect();
+                assert_eq!(names, vec!["shapes", "lookup", "parent"]);
+                match schema.types[fields[2].ty].kind {
+                    Kind::Pointer { pointee, owned } => {
+                        assert_eq!(pointee, schema.root);
+                        assert!(!owned);
+                    },
+                    ref k => panic!("Wrong kind: {:?}", k),
+                }
+            },
+            ref k => panic!("Wrong kind: {:?}", k),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let schema = Schema::of::<Scene>();
+        let mut buf = vec![];
+        schema.encode(&mut buf);
+        let mut r = Reader::new(&buf);
+        assert_eq!(Schema::decode(&mut r).unwrap(), schema);
+        assert!(r.is_empty());
+
+        let err = Schema::decode(&mut Reader::new(&buf[..buf.len() - 1])).unwrap_err();
+        assert_eq!(err.msg, "unexpected end of input");
+
+        let mut schema = schema;
+        schema.types[schema.root].align = 0;
+        let mut buf = vec![];
+        schema.encode(&mut buf);
+        let err = Schema::decode(&mut Reader::new(&buf)).unwrap_err();
+        assert_eq!(err.msg, "alignment isn't a power of two");
+    }
+}
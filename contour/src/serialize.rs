@@ -0,0 +1,303 @@
+//! A bridge to serde, for types that are `Introspectable` but not `Serialize`.
+//!
+//! Values come out the way `#[derive(Serialize)]` would write them:
+//! single-field tuple structs and variants as newtypes, pointers as what they
+//! point to (or `None` if null), raw pointers as their address, and
+//! `usize`/`isize` widened to 64 bits.
+//! Redacted fields are skipped in structs and written as `()` in tuples.
+
+use std::any::TypeId;
+use std::cell::RefCell;
+
+use serde::ser::{
+    Error,
+    Serialize,
+    SerializeMap,
+    SerializeSeq,
+    SerializeStruct,
+    SerializeStructVariant,
+    SerializeTupleStruct,
+    SerializeTupleVariant,
+    Serializer,
+};
+
+use {
+    raw_address,
+    Contour,
+    ContourMap,
+    Introspectable,
+    PrimitiveValue,
+    Registry,
+    StructField,
+    VariantFields,
+};
+
+enum Charts<'a> {
+    Owned(Registry),
+    Borrowed(&'a dyn ContourMap),
+}
+
+/// Implements `Serialize` for any introspectable value.
+pub struct Serializable<'a> {
+    charts: Charts<'a>,
+    type_id: TypeId,
+    ptr: *const u8,
+    /// Values on the path from the root, to spot pointers back up it, which
+    /// can't be serialized.
+    active: RefCell<Vec<(TypeId, *const u8)>>,
+}
+
+impl<'a> Serializable<'a> {
+    pub fn new<T: Introspectable>(value: &'a T) -> Self {
+        Serializable {
+            charts: Charts::Owned(Registry::of::<T>()),
+            type_id: T::contour().type_id(),
+            ptr: value as *const T as *const u8,
+            active: RefCell::new(vec![]),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// See `Walker::walk` for the requirements on `ptr`, which must also stay
+    /// valid for `'a`.
+    pub unsafe fn from_ptr(map: &'a dyn ContourMap, type_id: TypeId, ptr: *const u8) -> Self {
+        Serializable {
+            charts: Charts::Borrowed(map),
+            type_id,
+            ptr,
+            active: RefCell::new(vec![]),
+        }
+    }
+
+    fn map(&self) -> &dyn ContourMap {
+        match self.charts {
+            Charts::Owned(ref registry) => registry,
+            Charts::Borrowed(map) => map,
+        }
+    }
+}
+
+impl<'a> Serialize for Serializable<'a> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        Node { root: self, type_id: self.type_id, ptr: self.ptr }.serialize(s)
+    }
+}
+
+struct Node<'r, 'a: 'r> {
+    root: &'r Serializable<'a>,
+    type_id: TypeId,
+    ptr: *const u8,
+}
+
+impl<'r, 'a> Node<'r, 'a> {
+    fn child(&self, type_id: TypeId, ptr: *const u8) -> Self {
+        Node { root: self.root, type_id, ptr }
+    }
+
+    fn at(&self, type_id: TypeId, offset: usize) -> Self {
+        self.child(type_id, unsafe { self.ptr.add(offset) })
+    }
+
+    unsafe fn contour<S: Serializer>(&self, contour: Contour, s: S) -> Result<S::Ok, S::Error> {
+        let ptr = self.ptr;
+        match contour {
+            Contour::Struct { name, ref fields, .. } => {
+                let mut st = s.serialize_struct(name, written(fields))?;
+                for field in fields {
+                    if field.redacted {
+                        st.skip_field(field.name)?;
+                    } else {
+                        st.serialize_field(field.name, &self.at(field.type_id, field.offset))?;
+                    }
+                }
+                st.end()
+            },
+            Contour::Tuple { name, ref fields, .. } => {
+                if fields.len() == 1 && !fields[0].redacted {
+                    let field = &fields[0];
+                    return s.serialize_newtype_struct(name, &self.at(field.type_id, field.offset));
+                }
+                let mut st = s.serialize_tuple_struct(name, fields.len())?;
+                for field in fields {
+                    if field.redacted {
+                        st.serialize_field(&())?;
+                    } else {
+                        st.serialize_field(&self.at(field.type_id, field.offset))?;
+                    }
+                }
+                st.end()
+            },
+            Contour::Unit { name, .. } => s.serialize_unit_struct(name),
+            Contour::Enum { name, ref variants, tag, .. } => {
+                let ix = tag(ptr);
+                let variant = &variants[ix];
+                match variant.fields {
+                    VariantFields::Struct(ref fields) => {
+                        let mut st = s.serialize_struct_variant(name, ix as u32, variant.name,
+                                                                written(fields))?;
+                        for field in fields {
+                            if field.redacted {
+                                st.skip_field(field.name)?;
+                            } else {
+                                st.serialize_field(field.name,
+                                                   &self.at(field.type_id, field.offset))?;
+                            }
+                        }
+                        st.end()
+                    },
+                    VariantFields::Tuple(ref fields) => {
+                        if fields.len() == 1 && !fields[0].redacted {
+                            let field = &fields[0];
+                            return s.serialize_newtype_variant(
+                                name, ix as u32, variant.name,
+                                &self.at(field.type_id, field.offset));
+                        }
+                        let mut st = s.serialize_tuple_variant(name, ix as u32, variant.name,
+                                                               fields.len())?;
+                        for field in fields {
+                            if field.redacted {
+                                st.serialize_field(&())?;
+                            } else {
+                                st.serialize_field(&self.at(field.type_id, field.offset))?;
+                            }
+                        }
+                        st.end()
+                    },
+                    VariantFields::Unit => s.serialize_unit_variant(name, ix as u32, variant.name),
+                }
+            },
+            Contour::Primitive { ref variant, atomic, .. } => match variant.load(ptr, atomic) {
+                PrimitiveValue::u8(v) => s.serialize_u8(v),
+                PrimitiveValue::u16(v) => s.serialize_u16(v),
+                PrimitiveValue::u32(v) => s.serialize_u32(v),
+                PrimitiveValue::u64(v) => s.serialize_u64(v),
+                PrimitiveValue::usize(v) => s.serialize_u64(v as u64),
+                PrimitiveValue::i8(v) => s.serialize_i8(v),
+                PrimitiveValue::i16(v) => s.serialize_i16(v),
+                PrimitiveValue::i32(v) => s.serialize_i32(v),
+                PrimitiveValue::i64(v) => s.serialize_i64(v),
+                PrimitiveValue::f32(v) => s.serialize_f32(v),
+                PrimitiveValue::f64(v) => s.serialize_f64(v),
+                PrimitiveValue::isize(v) => s.serialize_i64(v as i64),
+                PrimitiveValue::bool(v) => s.serialize_bool(v),
+                PrimitiveValue::char(v) => s.serialize_char(v),
+                PrimitiveValue::String(ref v) => s.serialize_str(v),
+            },
+            Contour::Seq { element, len, item, .. } => {
+                let n = len(ptr);
+                let mut seq = s.serialize_seq(Some(n))?;
+                for i in 0..n {
+                    seq.serialize_element(&self.child(element, item(ptr, i)))?;
+                }
+                seq.end()
+            },
+            Contour::Pointer { deref: None, .. } => {
+                let address = raw_address(ptr);
+                if address.is_null() {
+                    s.serialize_none()
+                } else {
+                    s.serialize_u64(address as u64)
+                }
+            },
+            Contour::Pointer { pointee, deref: Some(deref), .. } => {
+                let target = deref(ptr);
+                if target.is_null() {
+                    s.serialize_none()
+                } else if self.root.active.borrow().contains(&(pointee, target)) {
+                    Err(S::Error::custom(format!("cycle through a `{}`", contour.name())))
+                } else {
+                    self.child(pointee, target).serialize(s)
+                }
+            },
+            Contour::Map { key, value, len, entries, .. } => {
+                let mut pairs = vec![];
+                entries(ptr, &mut |k, v| pairs.push((k, v)));
+                let mut map = s.serialize_map(Some(len(ptr)))?;
+                for (k, v) in pairs {
+                    map.serialize_entry(&self.child(key, k), &self.child(value, v))?;
+                }
+                map.end()
+            },
+        }
+    }
+}
+
+/// How many of `fields` get written, which is what serde wants to be told,
+/// since redacted ones are skipped.
+fn written(fields: &[StructField]) -> usize {
+    fields.iter().filter(|f| !f.redacted).count()
+}
+
+impl<'r, 'a> Serialize for Node<'r, 'a> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let contour = match self.root.map().lookup(self.type_id) {
+            Some(contour) => contour,
+            None => return Err(S::Error::custom(format!("No contour for {:?}", self.type_id))),
+        };
+        self.root.active.borrow_mut().push((self.type_id, self.ptr));
+        let result = unsafe { self.contour(contour, s) };
+        self.root.active.borrow_mut().pop();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    extern crate serde_json;
+
+    use std::collections::BTreeMap;
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        StructField,
+        TupleField,
+        Variant,
+        VariantFields,
+    };
+    use super::*;
+
+    #[derive(Introspectable)]
+    struct Port(u16);
+
+    #[derive(Introspectable)]
+    enum Auth {
+        Anonymous,
+        Token(String),
+        Basic { user: String, #[contour(redact)] password: String },
+    }
+
+    #[derive(Introspectable)]
+    struct Listener {
+        port: Port,
+        auth: Vec<Auth>,
+        labels: BTreeMap<String, Box<i32>>,
+        next: *const Listener,
+    }
+
+    #[test]
+    fn test_json() {
+        let mut labels = BTreeMap::new();
+        labels.insert("zone".to_owned(), Box::new(-1));
+        let mut listener = Listener {
+            port: Port(8080),
+            auth: vec![
+                Auth::Anonymous,
+                Auth::Token("t0k".to_owned()),
+                Auth::Basic { user: "root".to_owned(), password: "hunter2".to_owned() },
+            ],
+            labels: labels,
+            next: ::std::ptr::null(),
+        };
+        assert_eq!(
+            serde_json::to_string(&Serializable::new(&listener)).unwrap(),
+            concat!(r#"{"port":8080,"auth":["Anonymous",{"Token":"t0k"},"#,
+                    r#"{"Basic":{"user":"root"}}],"labels":{"zone":-1},"next":null}"#));
+
+        listener.next = &listener;
+        let json = serde_json::to_string(&Serializable::new(&listener)).unwrap();
+        assert!(json.ends_with(&format!(r#""next":{}}}"#, &listener as *const _ as u64)));
+    }
+}
@@ -0,0 +1,490 @@
+//! A background thread answering JSON requests about a running process's
+//! values, for poking at a daemon the way py-contour pokes at a script.
+//!
+//! Each connection sends one request per line and gets one response per
+//! line, either `{"ok": ...}` or `{"error": "..."}`.  Requests are objects
+//! with an `op` of:
+//!
+//! - `roots`: the names and types of the registered roots;
+//! - `schema`, with a `root` and optional `path`: the `Schema` of the type
+//!   found there;
+//! - `read`, with a `root` and optional `path`: the value found there;
+//! - `set`, with a `root`, `path` and `value`: stores a primitive.
+//!
+//! Roots registered with `register` are shared with the rest of the
+//! process, so only the atomics in them can be set.  Reading them is fine,
+//! since atomics are read with atomic loads and nothing else in a `Sync`
+//! value the process can see is charted as changing.  Roots registered with
+//! `register_mutex` are locked for each request, and any primitive in them
+//! can be set unless the path goes through an `Rc`, `Arc` or raw pointer.
+
+use std::any::TypeId;
+use std::io::{
+    self,
+    BufRead,
+    BufReader,
+    Read,
+    Write,
+};
+use std::net::{
+    SocketAddr,
+    TcpListener,
+    ToSocketAddrs,
+};
+#[cfg(unix)] use std::os::unix::net::UnixListener;
+use std::sync::atomic::{
+    self,
+    Ordering,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::thread;
+
+use json::{
+    self,
+    Json,
+};
+use path::{
+    resolve_ptr,
+    Path,
+};
+use root::{
+    self,
+    Root,
+};
+use snapshot::{
+    snapshot_ptr,
+    Snapshot,
+};
+use {
+    Contour,
+    ContourMap,
+    Introspectable,
+    Primitive,
+    PrimitiveValue,
+    Schema,
+};
+
+/// The roots to serve.  Register them all, then start listening with
+/// `spawn_unix` or `spawn_tcp`.
+pub struct Server {
+    roots: Vec<Root>,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Server { roots: vec![] }
+    }
+
+    /// Serves `value`, which the rest of the process may be using meanwhile.
+    pub fn register<T: Introspectable + Sync>(&mut self, name: &str, value: &'static T)
+        -> &mut Self
+    {
+        root::push(&mut self.roots, Root::new(name, value));
+        self
+    }
+
+    /// Serves the value inside `value`, holding the lock for each request.
+    pub fn register_mutex<T: Introspectable + Send>(&mut self, name: &str, value: &'static Mutex<T>)
+        -> &mut Self
+    {
+        root::push(&mut self.roots, Root::locked(name, value));
+        self
+    }
+
+    /// Listens on a Unix domain socket at `path`, which mustn't exist yet.
+    #[cfg(unix)]
+    pub fn spawn_unix<P: AsRef<::std::path::Path>>(self, path: P) -> io::Result<()> {
+        let listener = UnixListener::bind(path)?;
+        let server = Arc::new(self);
+        thread::Builder::new().name("contour-server".to_owned()).spawn(move || {
+            for stream in listener.incoming().filter_map(Result::ok) {
+                let server = server.clone();
+                thread::spawn(move || server.serve(stream));
+            }
+        })?;
+        Ok(())
+    }
+
+    /// Listens on `addr`, which has to be a loopback address, returning the
+    /// address actually bound in case `addr` left the port up to the OS.
+    pub fn spawn_tcp<A: ToSocketAddrs>(self, addr: A) -> io::Result<SocketAddr> {
+        let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+        if addrs.is_empty() || addrs.iter().any(|addr| !addr.ip().is_loopback()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "only loopback addresses can be served"));
+        }
+        let listener = TcpListener::bind(&addrs[..])?;
+        let local = listener.local_addr()?;
+        let server = Arc::new(self);
+        thread::Builder::new().name("contour-server".to_owned()).spawn(move || {
+            for stream in listener.incoming().filter_map(Result::ok) {
+                let server = server.clone();
+                thread::spawn(move || server.serve(stream));
+            }
+        })?;
+        Ok(local)
+    }
+
+    fn serve<S>(&self, stream: S) where for<'a> &'a S: Read + Write {
+        let mut reader = BufReader::new(&stream);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {},
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut response = self.handle(line.trim());
+            response.push('\n');
+            if (&stream).write_all(response.as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Answers a single request, as a connection does for each line.
+    pub fn handle(&self, request: &str) -> String {
+        let mut out = String::new();
+        match self.answer(request) {
+            Ok(body) => {
+                out.push_str("{\"ok\":");
+                out.push_str(&body);
+            },
+            Err(msg) => {
+                out.push_str("{\"error\":");
+                json::string(&msg, &mut out);
+            },
+        }
+        out.push('}');
+        out
+    }
+
+    fn answer(&self, request: &str) -> Result<String, String> {
+        let request = json::parse(request)?;
+        let op = request.get("op").and_then(Json::as_str).ok_or("missing `op`")?;
+        let mut out = String::new();
+        if op == "roots" {
+            out.push('[');
+            for (i, root) in self.roots.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str("{\"name\":");
+                json::string(&root.name, &mut out);
+                out.push_str(",\"type\":");
+                let contour = root.registry.lookup(root.type_id).expect("root wasn't charted");
+                json::string(contour.name(), &mut out);
+                out.push('}');
+            }
+            out.push(']');
+            return Ok(out);
+        }
+        if op != "schema" && op != "read" && op != "set" {
+            return Err(format!("unknown op `{}`", op));
+        }
+
+        let name = request.get("root").and_then(Json::as_str).ok_or("missing `root`")?;
+        let root = self.roots.iter()
+            .find(|root| root.name == name)
+            .ok_or_else(|| format!("no root named `{}`", name))?;
+        let path = match request.get("path") {
+            Some(path) => path.as_str()
+                .ok_or("`path` should be a string")?
+                .parse::<Path>()
+                .map_err(|e| e.to_string())?,
+            None => Path::root(),
+        };
+        match op {
+            "schema" => root.with(&path, |type_id, _, _| {
+                let schema = Schema::export(&root.registry, type_id).expect("root wasn't charted");
+                json::schema(&schema, &mut out);
+                Ok(())
+            })?,
+            "read" => root.with(&path, |type_id, ptr, _| {
+                let bytes = unsafe { snapshot_ptr(&root.registry, type_id, ptr) };
+                let snapshot = Snapshot::decode(&bytes).expect("snapshot didn't round-trip");
+                json::value(&snapshot.value, &mut out);
+                Ok(())
+            })?,
+            "set" => {
+                let value = request.get("value").ok_or("missing `value`")?;
+                root.with(&path, |type_id, ptr, exclusive| unsafe {
+                    let (kind, atomic) = match root.registry.lookup(type_id) {
+                        Some(Contour::Primitive { variant, atomic, .. }) => (variant, atomic),
+                        Some(contour) => return Err(format!("`{}` isn't a primitive",
+                                                            contour.name())),
+                        None => panic!("No contour for {:?}", type_id),
+                    };
+                    let value = primitive(&kind, value)?;
+                    if atomic {
+                        store_atomic(ptr, value);
+                        Ok(())
+                    } else if exclusive {
+                        write(ptr, value);
+                        Ok(())
+                    } else {
+                        Err(format!("`{}` may be shared, so only atomics can be set there", path))
+                    }
+                })?;
+                out.push_str("null");
+            },
+            _ => unreachable!(),
+        }
+        Ok(out)
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server::new()
+    }
+}
+
+impl Root {
+    /// Calls `f` with whatever `path` leads to, past any pointers it ends
+    /// at, and whether it's safe to write to with plain stores.
+    fn with<F>(&self, path: &Path, f: F) -> Result<(), String>
+        where F: FnOnce(TypeId, *mut u8, bool) -> Result<(), String>
+    {
+        let mut f = Some(f);
+        let mut result = Ok(());
+        self.access(&mut |ptr, exclusive| unsafe {
+            let f = f.take().expect("access called back twice");
+            result = self.shared(ptr, path).and_then(|shared| {
+                let (type_id, target) = resolve_ptr(&self.registry, self.type_id, ptr, path)
+                    .map_err(|e| e.to_string())?;
+                let (type_id, target, _) = self.follow(type_id, target)?;
+                f(type_id, target as *mut u8, exclusive && !shared)
+            });
+        });
+        result
+    }
+
+    /// Whether getting to the end of `path` goes through a pointer that
+    /// something else might hold too.  Only `Box`es are known not to be.
+    unsafe fn shared(&self, ptr: *const u8, path: &Path) -> Result<bool, String> {
+        let mut prefix = Path::root();
+        let mut segments = path.segments().iter();
+        loop {
+            let (type_id, target) = resolve_ptr(&self.registry, self.type_id, ptr, &prefix)
+                .map_err(|e| e.to_string())?;
+            if self.follow(type_id, target)?.2 {
+                return Ok(true);
+            }
+            match segments.next() {
+                Some(segment) => prefix.push(segment.clone()),
+                None => return Ok(false),
+            }
+        }
+    }
+
+    /// Dereferences pointers until it gets to something else, noting
+    /// whether any of them weren't `Box`es.
+    unsafe fn follow(&self, mut type_id: TypeId, mut ptr: *const u8)
+        -> Result<(TypeId, *const u8, bool), String>
+    {
+        let mut shared = false;
+        while let Some(Contour::Pointer { name, pointee, deref, .. }) =
+            self.registry.lookup(type_id)
+        {
+            let deref = match deref {
+                Some(deref) => deref,
+                None => return Err("raw pointers aren't followed".to_owned()),
+            };
+            shared |= name != "Box";
+            ptr = deref(ptr);
+            type_id = pointee;
+            if ptr.is_null() {
+                return Err("null pointer".to_owned());
+            }
+        }
+        Ok((type_id, ptr, shared))
+    }
+}
+
+/// Reads a `kind` out of `json`.
+fn primitive(kind: &Primitive, json: &Json) -> Result<PrimitiveValue, String> {
+    macro_rules! number {
+        ($t:ident) => {
+            match *json {
+                Json::Number(ref n) => n.parse().map(PrimitiveValue::$t)
+                    .map_err(|_| format!("{} isn't a valid {}", n, stringify!($t))),
+                _ => Err(format!("expected a {}", stringify!($t))),
+            }
+        };
+    }
+    match *kind {
+        Primitive::u8 => number!(u8),
+        Primitive::u16 => number!(u16),
+        Primitive::u32 => number!(u32),
+        Primitive::u64 => number!(u64),
+        Primitive::usize => number!(usize),
+        Primitive::i8 => number!(i8),
+        Primitive::i16 => number!(i16),
+        Primitive::i32 => number!(i32),
+        Primitive::i64 => number!(i64),
+        Primitive::isize => number!(isize),
+        Primitive::f32 => number!(f32),
+        Primitive::f64 => number!(f64),
+        Primitive::bool => match *json {
+            Json::Bool(b) => Ok(PrimitiveValue::bool(b)),
+            _ => Err("expected a bool".to_owned()),
+        },
+        Primitive::char => {
+            let mut chars = json.as_str().map(str::chars);
+            match (chars.as_mut().and_then(Iterator::next), chars.as_mut().map(|c| c.count())) {
+                (Some(c), Some(0)) => Ok(PrimitiveValue::char(c)),
+                _ => Err("expected a one-character string".to_owned()),
+            }
+        },
+        Primitive::String => match *json {
+            Json::String(ref s) => Ok(PrimitiveValue::String(s.clone())),
+            _ => Err("expected a string".to_owned()),
+        },
+    }
+}
+
+/// Stores `value` in the atomic version of its type at `ptr`.
+unsafe fn store_atomic(ptr: *mut u8, value: PrimitiveValue) {
+    macro_rules! atomics {
+        ($($t:ident($v:ident)),*) => {
+            match value {
+                $(PrimitiveValue::$v(v) =>
+                    (*(ptr as *const atomic::$t)).store(v, Ordering::SeqCst),)*
+                value => panic!("No atomic version of {:?}", value),
+            }
+        };
+    }
+    atomics!(AtomicU8(u8), AtomicU16(u16), AtomicU32(u32), AtomicU64(u64), AtomicUsize(usize),
+             AtomicI8(i8), AtomicI16(i16), AtomicI32(i32), AtomicI64(i64), AtomicIsize(isize),
+             AtomicBool(bool))
+}
+
+/// Overwrites the primitive at `ptr`, dropping the old value.
+unsafe fn write(ptr: *mut u8, value: PrimitiveValue) {
+    match value {
+        PrimitiveValue::u8(v) => *ptr = v,
+        PrimitiveValue::u16(v) => *(ptr as *mut u16) = v,
+        PrimitiveValue::u32(v) => *(ptr as *mut u32) = v,
+        PrimitiveValue::u64(v) => *(ptr as *mut u64) = v,
+        PrimitiveValue::usize(v) => *(ptr as *mut usize) = v,
+        PrimitiveValue::i8(v) => *(ptr as *mut i8) = v,
+        PrimitiveValue::i16(v) => *(ptr as *mut i16) = v,
+        PrimitiveValue::i32(v) => *(ptr as *mut i32) = v,
+        PrimitiveValue::i64(v) => *(ptr as *mut i64) = v,
+        PrimitiveValue::isize(v) => *(ptr as *mut isize) = v,
+        PrimitiveValue::f32(v) => *(ptr as *mut f32) = v,
+        PrimitiveValue::f64(v) => *(ptr as *mut f64) = v,
+        PrimitiveValue::bool(v) => *(ptr as *mut bool) = v,
+        PrimitiveValue::char(v) => *(ptr as *mut char) = v,
+        PrimitiveValue::String(v) => *(ptr as *mut String) = v,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::sync::atomic::AtomicU64;
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        StructField,
+    };
+    use super::*;
+
+    #[derive(Introspectable)]
+    struct Stats {
+        requests: AtomicU64,
+        errors: u64,
+    }
+
+    #[derive(Introspectable)]
+    struct Limits {
+        max_conns: u32,
+        name: String,
+        backlog: Box<u16>,
+        shared: Arc<u8>,
+    }
+
+    fn server() -> (Server, &'static Stats, &'static Mutex<Limits>) {
+        let stats = Box::leak(Box::new(Stats { requests: AtomicU64::new(5), errors: 1 }));
+        let limits = Box::leak(Box::new(Mutex::new(Limits {
+            max_conns: 10,
+            name: "edge".to_owned(),
+            backlog: Box::new(64),
+            shared: Arc::new(1),
+        })));
+        let mut server = Server::new();
+        server.register("stats", stats).register_mutex("limits", limits);
+        (server, stats, limits)
+    }
+
+    #[test]
+    fn test_requests() {
+        let (server, stats, limits) = server();
+        assert_eq!(server.handle(r#"{"op": "roots"}"#),
+                   r#"{"ok":[{"name":"stats","type":"Stats"},{"name":"limits","type":"Limits"}]}"#);
+        assert_eq!(server.handle(r#"{"op": "read", "root": "stats"}"#),
+                   r#"{"ok":{"requests":5,"errors":1}}"#);
+        assert!(server.handle(r#"{"op": "schema", "root": "limits", "path": "backlog"}"#)
+                .starts_with(r#"{"ok":{"root":0,"types":[{"name":"u16","#));
+
+        let set = |root: &str, path: &str, value: &str| server.handle(&format!(
+            r#"{{"op": "set", "root": "{}", "path": "{}", "value": {}}}"#, root, path, value));
+        assert_eq!(set("stats", "requests", "9"), r#"{"ok":null}"#);
+        assert_eq!(stats.requests.load(Ordering::SeqCst), 9);
+        assert_eq!(set("stats", "errors", "0"),
+                   r#"{"error":"`errors` may be shared, so only atomics can be set there"}"#);
+        assert_eq!(set("limits", "name", r#""core é""#), r#"{"ok":null}"#);
+        assert_eq!(set("limits", "backlog", "128"), r#"{"ok":null}"#);
+        assert_eq!(set("limits", "shared", "2"),
+                   r#"{"error":"`shared` may be shared, so only atomics can be set there"}"#);
+        assert_eq!(set("limits", "max_conns", "-1"), r#"{"error":"-1 isn't a valid u32"}"#);
+        assert_eq!(server.handle(r#"{"op": "read", "root": "limits", "path": "name"}"#),
+                   "{\"ok\":\"core \u{e9}\"}");
+        assert_eq!(*limits.lock().unwrap().backlog, 128);
+
+        let busy = thread::spawn(move || for _ in 0..1000 {
+            stats.requests.fetch_add(1, Ordering::Relaxed);
+        });
+        for _ in 0..10 {
+            assert!(server.handle(r#"{"op": "read", "root": "stats", "path": "requests"}"#)
+                    .starts_with(r#"{"ok":"#));
+        }
+        busy.join().unwrap();
+        assert_eq!(server.handle(r#"{"op": "read", "root": "stats", "path": "requests"}"#),
+                   r#"{"ok":1009}"#);
+
+        assert_eq!(server.handle(r#"{"op": "read", "root": "nope"}"#),
+                   r#"{"error":"no root named `nope`"}"#);
+        assert_eq!(server.handle("{\"op\": "), r#"{"error":"at byte 7: expected a value"}"#);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        use std::os::unix::net::UnixStream;
+
+        let path = ::std::env::temp_dir().join(format!("contour-{}.sock", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+        let (server, _, _) = server();
+        server.spawn_unix(&path).unwrap();
+        let stream = UnixStream::connect(&path).unwrap();
+        (&stream).write_all(b"{\"op\":\"read\",\"root\":\"limits\",\"path\":\"max_conns\"}\n\n\
+                              {\"op\":\"frob\"}\n").unwrap();
+        let mut lines = BufReader::new(&stream).lines();
+        assert_eq!(lines.next().unwrap().unwrap(), r#"{"ok":10}"#);
+        assert_eq!(lines.next().unwrap().unwrap(), r#"{"error":"unknown op `frob`"}"#);
+        let _ = ::std::fs::remove_file(&path);
+
+        let err = Server::new().spawn_tcp("0.0.0.0:0").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
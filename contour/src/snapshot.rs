@@ -0,0 +1,499 @@
+//! A self-describing binary format for introspected values.
+//!
+//! A snapshot is the value's exported `Schema` followed by the value itself,
+//! laid out by that schema rather than by memory.  Decoding only needs the
+//! schema, so a snapshot can be read back as a `Value` by a build that
+//! doesn't have (or has since changed) the types that wrote it.
+//!
+//! The layout is a 4-byte magic, a version byte, the schema, then the value:
+//!
+//! * structs and variant fields: each field in schema order;
+//! * enums: the variant's index into the schema's variants, then its fields;
+//! * integers, floats and chars: fixed-width little-endian, with `usize` and
+//!   `isize` widened to 8 bytes;
+//! * bools: one byte;
+//! * strings: a length, then UTF-8 bytes;
+//! * sequences and maps: a length, then each element or key-value pair;
+//! * pointers: 0 for null, 1 followed by the target, or 2 for a pointer back
+//!   to one of the value's ancestors, which isn't written again;
+//! * raw pointers: 0 for null, or 3 followed by the 8-byte address, since
+//!   they aren't followed.
+//!
+//! Lengths, indices and variant numbers are unsigned LEB128.
+
+use std::any::TypeId;
+
+use schema::{
+    DecodeError,
+    Fields,
+    Kind,
+    Reader,
+    Schema,
+    Writer,
+};
+use value::{
+    FieldValues,
+    Value,
+};
+use {
+    raw_address,
+    Contour,
+    ContourMap,
+    Introspectable,
+    Primitive,
+    PrimitiveValue,
+    Registry,
+    StructField,
+    TupleField,
+    VariantFields,
+};
+
+const MAGIC: &[u8] = b"CNTR";
+const VERSION: u8 = 1;
+
+const POINTER_NULL: u8 = 0;
+const POINTER_VALUE: u8 = 1;
+const POINTER_CYCLE: u8 = 2;
+const POINTER_ADDRESS: u8 = 3;
+
+/// Deeper values than this are rejected when decoding, rather than risking
+/// the stack on a malicious or corrupt snapshot.
+const MAX_DEPTH: usize = 1024;
+
+/// Elements that encode to nothing, like unit structs, don't use up any
+/// input, so their count across the whole snapshot is capped at this
+/// instead.
+const MAX_EMPTY_ITEMS: u64 = 1 << 16;
+
+pub fn snapshot<T: Introspectable>(value: &T) -> Vec<u8> {
+    let registry = Registry::of::<T>();
+    let type_id = T::contour().type_id();
+    unsafe { snapshot_ptr(&registry, type_id, value as *const T as *const u8) }
+}
+
+/// Panics if anything reachable from `type_id` hasn't been charted into
+/// `map`.
+///
+/// # Safety
+///
+/// See `Walker::walk` for the requirements on `ptr`.
+pub unsafe fn snapshot_ptr(map: &dyn ContourMap, type_id: TypeId, ptr: *const u8) -> Vec<u8> {
+    let schema = Schema::export(map, type_id)
+        .unwrap_or_else(|| panic!("No contour for something reachable from {:?}", type_id));
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    schema.encode(&mut out);
+
+    let mut encoder = Encoder { map, out, active: vec![] };
+    encoder.value(type_id, ptr);
+    encoder.out
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub schema: Schema,
+    pub value: Value,
+}
+
+impl Snapshot {
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(bytes);
+        if r.take(MAGIC.len()).ok() != Some(MAGIC) {
+            return Err(DecodeError { offset: 0, msg: "not a snapshot".to_owned() });
+        }
+        if r.u8()? != VERSION {
+            return r.error("unsupported version");
+        }
+        let schema = Schema::decode(&mut r)?;
+        let value = Decoder {
+            schema: &schema,
+            r: &mut r,
+            depth: 0,
+            empty: vec![None; schema.types.len()],
+            empty_items: MAX_EMPTY_ITEMS,
+        }.value(schema.root)?;
+        if !r.is_empty() {
+            return r.error("trailing bytes");
+        }
+        Ok(Snapshot { schema, value })
+    }
+}
+
+struct Encoder<'a> {
+    map: &'a dyn ContourMap,
+    out: Vec<u8>,
+    /// Values on the path from the root, to spot pointers back up it.
+    active: Vec<(TypeId, *const u8)>,
+}
+
+impl<'a> Encoder<'a> {
+    fn w(&mut self) -> Writer<'_> {
+        Writer(&mut self.out)
+    }
+
+    unsafe fn value(&mut self, type_id: TypeId, ptr: *const u8) {
+        let contour = self.map.lookup(type_id).unwrap();
+        self.active.push((type_id, ptr));
+        match contour {
+            Contour::Struct { ref fields, .. } => self.struct_fields(fields, ptr),
+            Contour::Tuple { ref fields, .. } => self.tuple_fields(fields, ptr),
+            Contour::Unit { .. } => {},
+            Contour::Enum { ref variants, tag, .. } => {
+                let ix = tag(ptr);
+                self.w().varint(ix as u64);
+                match variants[ix].fields {
+                    VariantFields::Struct(ref fields) => self.struct_fields(fields, ptr),
+                    VariantFields::Tuple(ref fields) => self.tuple_fields(fields, ptr),
+                    VariantFields::Unit => {},
+                }
+            },
+            Contour::Primitive { ref variant, atomic, .. } =>
+                primitive(&mut self.w(), &variant.load(ptr, atomic)),
+            Contour::Seq { element, len, item, .. } => {
+                let n = len(ptr);
+                self.w().varint(n as u64);
+                for i in 0..n {
+                    self.value(element, item(ptr, i));
+                }
+            },
+            Contour::Pointer { deref: None, .. } => {
+                let address = raw_address(ptr);
+                if address.is_null() {
+                    self.w().u8(POINTER_NULL);
+                } else {
+                    self.w().u8(POINTER_ADDRESS);
+                    self.w().fixed(address as u64, 8);
+                }
+            },
+            Contour::Pointer { pointee, deref: Some(deref), .. } => {
+                let target = deref(ptr);
+                if target.is_null() {
+                    self.w().u8(POINTER_NULL);
+                } else if self.active.contains(&(pointee, target)) {
+                    self.w().u8(POINTER_CYCLE);
+                } else {
+                    self.w().u8(POINTER_VALUE);
+                    self.value(pointee, target);
+                }
+            },
+            Contour::Map { key, value, len, entries, .. } => {
+                self.w().varint(len(ptr) as u64);
+                entries(ptr, &mut |k, v| {
+                    self.value(key, k);
+                    self.value(value, v);
+                });
+            },
+        }
+        self.active.pop();
+    }
+
+    unsafe fn struct_fields(&mut self, fields: &[StructField], ptr: *const u8) {
+        for field in fields {
+            self.value(field.type_id, ptr.add(field.offset));
+        }
+    }
+
+    unsafe fn tuple_fields(&mut self, fields: &[TupleField], ptr: *const u8) {
+        for field in fields {
+            self.value(field.type_id, ptr.add(field.offset));
+        }
+    }
+}
+
+fn primitive(w: &mut Writer, value: &PrimitiveValue) {
+    match *value {
+        PrimitiveValue::u8(v) => w.u8(v),
+        PrimitiveValue::u16(v) => w.fixed(v as u64, 2),
+        PrimitiveValue::u32(v) => w.fixed(v as u64, 4),
+        PrimitiveValue::u64(v) => w.fixed(v, 8),
+        PrimitiveValue::usize(v) => w.fixed(v as u64, 8),
+        PrimitiveValue::i8(v) => w.u8(v as u8),
+        PrimitiveValue::i16(v) => w.fixed(v as u16 as u64, 2),
+        PrimitiveValue::i32(v) => w.fixed(v as u32 as u64, 4),
+        PrimitiveValue::i64(v) => w.fixed(v as u64, 8),
+        PrimitiveValue::f32(v) => w.fixed(v.to_bits() as u64, 4),
+        PrimitiveValue::f64(v) => w.fixed(v.to_bits(), 8),
+        PrimitiveValue::isize(v) => w.fixed(v as i64 as u64, 8),
+        PrimitiveValue::bool(v) => w.u8(v as u8),
+        PrimitiveValue::char(v) => w.fixed(v as u64, 4),
+        PrimitiveValue::String(ref s) => w.str(s),
+    }
+}
+
+struct Decoder<'a, 'b: 'a> {
+    schema: &'a Schema,
+    r: &'a mut Reader<'b>,
+    depth: usize,
+    /// Which types are known to encode to nothing.
+    empty: Vec<Option<bool>>,
+    /// How many more empty elements there's room for.
+    empty_items: u64,
+}
+
+impl<'a, 'b> Decoder<'a, 'b> {
+    fn value(&mut self, ty: usize) -> Result<Value, DecodeError> {
+        if self.depth == MAX_DEPTH {
+            return self.r.error("nested too deeply");
+        }
+        self.depth += 1;
+        let schema = self.schema;
+        let desc = &schema.types[ty];
+        let value = match desc.kind {
+            Kind::Struct(ref fields) => Value::Struct {
+                name: desc.name.clone(),
+                fields: self.fields(fields)?,
+            },
+            Kind::Enum(ref variants) => {
+                let ix = self.r.varint()?;
+                let variant = match variants.get(ix as usize) {
+                    Some(variant) if ix < variants.len() as u64 => variant,
+                    _ => return self.r.error("variant index out of range"),
+                };
+                Value::Enum {
+                    name: desc.name.clone(),
+                    variant: variant.name.clone(),
+                    fields: self.fields(&variant.fields)?,
+                }
+            },
+            Kind::Primitive(ref p) => Value::Primitive(self.primitive(p)?),
+            Kind::Seq { element } => {
+                let empty = self.is_empty(element);
+                let n = self.count(empty)?;
+                let mut items = vec![];
+                for _ in 0..n {
+                    items.push(self.value(element)?);
+                }
+                Value::Seq(items)
+            },
+            Kind::Pointer { pointee, .. } => match self.r.u8()? {
+                POINTER_NULL => Value::Pointer(None),
+                POINTER_VALUE => Value::Pointer(Some(Box::new(self.value(pointee)?))),
+                POINTER_CYCLE => Value::Cycle,
+                POINTER_ADDRESS => Value::Address(self.r.fixed(8)?),
+                _ => return self.r.error("bad pointer tag"),
+            },
+            Kind::Map { key, value } => {
+                let empty = self.is_empty(key) && self.is_empty(value);
+                let n = self.count(empty)?;
+                let mut entries = vec![];
+                for _ in 0..n {
+                    let k = self.value(key)?;
+                    entries.push((k, self.value(value)?));
+                }
+                Value::Map(entries)
+            },
+        };
+        self.depth -= 1;
+        Ok(value)
+    }
+
+    /// Reads the length of a sequence or map, which can't be longer than
+    /// the rest of the input unless its elements are `empty`.
+    fn count(&mut self, empty: bool) -> Result<u64, DecodeError> {
+        let n = self.r.varint()?;
+        if empty {
+            if n > self.empty_items {
+                return self.r.error("too many empty elements");
+            }
+            self.empty_items -= n;
+        }
+        if !empty && n > self.r.remaining() as u64 {
+            return self.r.error("length past end of input");
+        }
+        Ok(n)
+    }
+
+    /// Whether values of type `ty` encode to no bytes at all.  Only structs
+    /// can, and a struct that contains itself is never considered empty.
+    fn is_empty(&mut self, ty: usize) -> bool {
+        if let Some(empty) = self.empty[ty] {
+            return empty;
+        }
+        self.empty[ty] = Some(false);
+        let schema = self.schema;
+        let empty = match schema.types[ty].kind {
+            Kind::Struct(ref fields) => fields.iter().all(|f| self.is_empty(f.ty)),
+            _ => false,
+        };
+        self.empty[ty] = Some(empty);
+        empty
+    }
+
+    fn fields(&mut self, fields: &Fields) -> Result<FieldValues, DecodeError> {
+        Ok(match *fields {
+            Fields::Named(ref fields) => {
+                let mut values = vec![];
+                for field in fields {
+                    values.push((field.name.clone(), self.value(field.ty)?));
+                }
+                FieldValues::Named(values)
+            },
+            Fields::Tuple(ref fields) => {
+                let mut values = vec![];
+                for field in fields {
+                    values.push(self.value(field.ty)?);
+                }
+                FieldValues::Tuple(values)
+            },
+            Fields::Unit => FieldValues::Unit,
+        })
+    }
+
+    fn primitive(&mut self, kind: &Primitive) -> Result<PrimitiveValue, DecodeError> {
+        let r = &mut *self.r;
+        Ok(match *kind {
+            Primitive::u8 => PrimitiveValue::u8(r.u8()?),
+            Primitive::u16 => PrimitiveValue::u16(r.fixed(2)? as u16),
+            Primitive::u32 => PrimitiveValue::u32(r.fixed(4)? as u32),
+            Primitive::u64 => PrimitiveValue::u64(r.fixed(8)?),
+            Primitive::usize => PrimitiveValue::usize(r.fixed(8)? as usize),
+            Primitive::i8 => PrimitiveValue::i8(r.u8()? as i8),
+            Primitive::i16 => PrimitiveValue::i16(r.fixed(2)? as u16 as i16),
+            Primitive::i32 => PrimitiveValue::i32(r.fixed(4)? as u32 as i32),
+            Primitive::i64 => PrimitiveValue::i64(r.fixed(8)? as i64),
+            Primitive::f32 => PrimitiveValue::f32(f32::from_bits(r.fixed(4)? as u32)),
+            Primitive::f64 => PrimitiveValue::f64(f64::from_bits(r.fixed(8)?)),
+            Primitive::isize => PrimitiveValue::isize(r.fixed(8)? as i64 as isize),
+            Primitive::bool => match r.u8()? {
+                0 => PrimitiveValue::bool(false),
+                1 => PrimitiveValue::bool(true),
+                _ => return r.error("bad bool"),
+            },
+            Primitive::char => match ::std::char::from_u32(r.fixed(4)? as u32) {
+                Some(c) => PrimitiveValue::char(c),
+                None => return r.error("bad char"),
+            },
+            Primitive::String => PrimitiveValue::String(r.str()?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::collections::HashMap;
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        PrimitiveValue,
+        StructField,
+        TupleField,
+        Variant,
+        VariantFields,
+    };
+    use super::*;
+
+    #[derive(Introspectable)]
+    enum Status {
+        Idle,
+        Busy(u32, char),
+        Failed { code: i32 },
+    }
+
+    #[derive(Introspectable)]
+    struct Job {
+        id: i64,
+        ratio: f64,
+        done: bool,
+        status: Vec<Status>,
+        tags: HashMap<String, u16>,
+        owner: Box<String>,
+        parent: *const Job,
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut tags = HashMap::new();
+        tags.insert("prio".to_owned(), 7);
+        let mut job = Job {
+            id: -3,
+            ratio: 0.25,
+            done: true,
+            status: vec![
+                Status::Idle,
+                Status::Busy(9, 'λ'),
+                Status::Failed { code: 28 },
+            ],
+            tags: tags,
+            owner: Box::new("ops".to_owned()),
+            parent: ::std::ptr::null(),
+        };
+        job.parent = &job;
+
+        let snap = Snapshot::decode(&snapshot(&job)).unwrap();
+        assert_eq!(snap.schema, Schema::of::<Job>());
+        let v = snap.value;
+        assert_eq!(v.name(), Some("Job"));
+        assert_eq!(v.field("id"), Some(&Value::Primitive(PrimitiveValue::i64(-3))));
+        assert_eq!(v.field("ratio"), Some(&Value::Primitive(PrimitiveValue::f64(0.25))));
+        assert_eq!(v.field("parent"), Some(&Value::Address(&job as *const Job as u64)));
+        assert_eq!(v.field("owner"), Some(&Value::Pointer(Some(Box::new(
+            Value::Primitive(PrimitiveValue::String("ops".to_owned())))))));
+        assert_eq!(v.field("tags"), Some(&Value::Map(vec![
+            (Value::Primitive(PrimitiveValue::String("prio".to_owned())),
+             Value::Primitive(PrimitiveValue::u16(7))),
+        ])));
+        match v.field("status") {
+            Some(&Value::Seq(ref items)) => {
+                assert_eq!(items.len(), 3);
+                assert_eq!(items[1], Value::Enum {
+                    name: "Status".to_owned(),
+                    variant: "Busy".to_owned(),
+                    fields: FieldValues::Tuple(vec![
+                        Value::Primitive(PrimitiveValue::u32(9)),
+                        Value::Primitive(PrimitiveValue::char('λ')),
+                    ]),
+                });
+                assert_eq!(items[2].field("code"),
+                           Some(&Value::Primitive(PrimitiveValue::i32(28))));
+            },
+            ref v => panic!("Wrong value: {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let bytes = snapshot(&vec![Status::Busy(1, 'x')]);
+        assert_eq!(Snapshot::decode(&bytes[1..]).unwrap_err().msg, "not a snapshot");
+        assert_eq!(Snapshot::decode(&bytes[..bytes.len() - 1]).unwrap_err().msg,
+                   "unexpected end of input");
+
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert_eq!(Snapshot::decode(&extra).unwrap_err().msg, "trailing bytes");
+
+        // The u32 and char take up the last eight bytes, after the variant index.
+        let mut bad = bytes.clone();
+        let n = bad.len();
+        bad[n - 9] = 7;
+        assert_eq!(Snapshot::decode(&bad).unwrap_err().msg, "variant index out of range");
+
+        let mut long = bytes.clone();
+        long[n - 10] = 100;
+        assert_eq!(Snapshot::decode(&long).unwrap_err().msg, "length past end of input");
+    }
+
+    #[derive(Introspectable)]
+    struct Marker;
+
+    #[test]
+    fn test_empty_elements() {
+        let mut bytes = snapshot(&vec![Marker, Marker]);
+        assert_eq!(Snapshot::decode(&bytes).unwrap().value, Value::Seq(vec![
+            Value::Struct { name: "Marker".to_owned(), fields: FieldValues::Unit },
+            Value::Struct { name: "Marker".to_owned(), fields: FieldValues::Unit },
+        ]));
+
+        // Without a cap, this would take forever to decode.
+        bytes.pop();
+        bytes.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]);
+        assert_eq!(Snapshot::decode(&bytes).unwrap_err().msg, "too many empty elements");
+
+        // Nor can the cap be had again by each of several sequences.
+        let nested: Vec<Vec<Marker>> = (0..2).map(|_| (0..40000).map(|_| Marker).collect())
+            .collect();
+        assert_eq!(Snapshot::decode(&snapshot(&nested)).unwrap_err().msg,
+                   "too many empty elements");
+    }
+}
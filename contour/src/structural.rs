@@ -0,0 +1,458 @@
+//! Equality and hashing driven by contours, for tools that only have a
+//! `TypeId` and a pointer.
+//!
+//! Both walk values field by field, so padding is never looked at.  Floats
+//! compare the way `==` does, and hash so that `0.0` and `-0.0` agree.  For
+//! derived types, `dyn_eq` agrees with `PartialEq`, and `dyn_hash` feeds the
+//! hasher what `Hash` would, with one exception: `HashMap`s, which have no
+//! `Hash` of their own, hash independently of iteration order.  Fields left
+//! out of a contour are left out here too, so the two can disagree for types
+//! whose structs or variants are `skipped`.
+
+use std::any::TypeId;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{
+    Hash,
+    Hasher,
+};
+
+use {
+    raw_address,
+    Contour,
+    ContourMap,
+    Introspectable,
+    PrimitiveValue,
+    Registry,
+    StructField,
+    TupleField,
+    Variant,
+    VariantFields,
+};
+
+pub fn dyn_eq<T: Introspectable>(a: &T, b: &T) -> bool {
+    let registry = Registry::of::<T>();
+    let (a, b) = (a as *const T as *const u8, b as *const T as *const u8);
+    unsafe { dyn_eq_ptr(&registry, T::contour().type_id(), a, b) }
+}
+
+/// # Safety
+///
+/// See `Walker::walk` for the requirements on `a` and `b`.
+pub unsafe fn dyn_eq_ptr(map: &dyn ContourMap, type_id: TypeId, a: *const u8, b: *const u8)
+    -> bool
+{
+    Cx { map, active: vec![] }.eq(type_id, a, b)
+}
+
+pub fn dyn_hash<T: Introspectable, H: Hasher>(value: &T, state: &mut H) {
+    let registry = Registry::of::<T>();
+    let ptr = value as *const T as *const u8;
+    unsafe { dyn_hash_ptr(&registry, T::contour().type_id(), ptr, state) }
+}
+
+/// # Safety
+///
+/// See `Walker::walk` for the requirements on `ptr`.
+pub unsafe fn dyn_hash_ptr(map: &dyn ContourMap,
+                           type_id: TypeId,
+                           ptr: *const u8,
+                           state: &mut dyn Hasher) {
+    Cx { map, active: vec![] }.hash(type_id, ptr, state)
+}
+
+struct Cx<'a> {
+    map: &'a dyn ContourMap,
+    /// Pointer targets being compared or hashed further up, so cycles end.
+    active: Vec<(TypeId, *const u8, *const u8)>,
+}
+
+impl<'a> Cx<'a> {
+    fn contour(&self, type_id: TypeId) -> Contour {
+        match self.map.lookup(type_id) {
+            Some(contour) => contour,
+            None => panic!("No contour for {:?}", type_id),
+        }
+    }
+
+    unsafe fn eq(&mut self, type_id: TypeId, a: *const u8, b: *const u8) -> bool {
+        match self.contour(type_id) {
+            Contour::Struct { ref fields, .. } => self.struct_eq(fields, a, b),
+            Contour::Tuple { ref fields, .. } => self.tuple_eq(fields, a, b),
+            Contour::Unit { .. } => true,
+            Contour::Enum { ref variants, tag, .. } => {
+                let ix = tag(a);
+                ix == tag(b) && self.variant_eq(&variants[ix], a, b)
+            },
+            Contour::Primitive { ref variant, atomic, .. } =>
+                variant.load(a, atomic) == variant.load(b, atomic),
+            Contour::Seq { element, len, item, .. } => {
+                let n = len(a);
+                n == len(b) && (0..n).all(|ix| self.eq(element, item(a, ix), item(b, ix)))
+            },
+            Contour::Pointer { deref: None, .. } => raw_address(a) == raw_address(b),
+            Contour::Pointer { pointee, deref: Some(deref), .. } => {
+                let (x, y) = (deref(a), deref(b));
+                if x.is_null() || y.is_null() {
+                    return x == y;
+                }
+                // Whatever's being compared further up will decide.
+                if self.active.contains(&(pointee, x, y)) {
+                    return true;
+                }
+                self.active.push((pointee, x, y));
+                let eq = self.eq(pointee, x, y);
+                self.active.pop();
+                eq
+            },
+            Contour::Map { name, key, value, len, entries, .. } => {
+                if len(a) != len(b) {
+                    return false;
+                }
+                let (xs, ys) = (collect(entries, a), collect(entries, b));
+                // Equal `BTreeMap`s iterate in the same order.
+                if name == "BTreeMap" {
+                    return xs.iter().zip(&ys).all(|(&(kx, vx), &(ky, vy))| {
+                        self.eq(key, kx, ky) && self.eq(value, vx, vy)
+                    });
+                }
+                xs.iter().all(|&(kx, vx)| {
+                    match ys.iter().find(|&&(ky, _)| self.eq(key, kx, ky)) {
+                        Some(&(_, vy)) => self.eq(value, vx, vy),
+                        None => false,
+                    }
+                })
+            },
+        }
+    }
+
+    unsafe fn struct_eq(&mut self, fields: &[StructField], a: *const u8, b: *const u8) -> bool {
+        fields.iter().all(|f| {
+            let offset = f.offset as isize;
+            self.eq(f.type_id, a.offset(offset), b.offset(offset))
+        })
+    }
+
+    unsafe fn tuple_eq(&mut self, fields: &[TupleField], a: *const u8, b: *const u8) -> bool {
+        fields.iter().all(|f| {
+            let offset = f.offset as isize;
+            self.eq(f.type_id, a.offset(offset), b.offset(offset))
+        })
+    }
+
+    unsafe fn variant_eq(&mut self, variant: &Variant, a: *const u8, b: *const u8) -> bool {
+        match variant.fields {
+            VariantFields::Struct(ref fields) => self.struct_eq(fields, a, b),
+            VariantFields::Tuple(ref fields) => self.tuple_eq(fields, a, b),
+            VariantFields::Unit => true,
+        }
+    }
+
+    unsafe fn hash(&mut self, type_id: TypeId, ptr: *const u8, mut state: &mut dyn Hasher) {
+        match self.contour(type_id) {
+            Contour::Struct { ref fields, .. } => for f in fields {
+                self.hash(f.type_id, ptr.add(f.offset), state);
+            },
+            Contour::Tuple { ref fields, .. } => for f in fields {
+                self.hash(f.type_id, ptr.add(f.offset), state);
+            },
+            Contour::Unit { .. } => {},
+            Contour::Enum { ref variants, tag, ref repr, .. } => {
+                let ix = tag(ptr);
+                // Derived `Hash` only writes the discriminant when there's a
+                // choice of variant, and writes it in the `repr` type.
+                if variants.len() > 1 {
+                    let d = variants[ix].discriminant;
+                    match repr.int {
+                        Some("u8") => (d as u8).hash(&mut state),
+                        Some("u16") => (d as u16).hash(&mut state),
+                        Some("u32") => (d as u32).hash(&mut state),
+                        Some("u64") => (d as u64).hash(&mut state),
+                        Some("u128") => (d as u128).hash(&mut state),
+                        Some("usize") => (d as usize).hash(&mut state),
+                        Some("i8") => (d as i8).hash(&mut state),
+                        Some("i16") => (d as i16).hash(&mut state),
+                        Some("i32") => (d as i32).hash(&mut state),
+                        Some("i64") => (d as i64).hash(&mut state),
+                        Some("i128") => d.hash(&mut state),
+                        _ => (d as isize).hash(&mut state),
+                    }
+                }
+                match variants[ix].fields {
+                    VariantFields::Struct(ref fields) => for f in fields {
+                        self.hash(f.type_id, ptr.add(f.offset), state);
+                    },
+                    VariantFields::Tuple(ref fields) => for f in fields {
+                        self.hash(f.type_id, ptr.add(f.offset), state);
+                    },
+                    VariantFields::Unit => {},
+                }
+            },
+            Contour::Primitive { ref variant, atomic, .. } => match variant.load(ptr, atomic) {
+                PrimitiveValue::u8(v) => v.hash(&mut state),
+                PrimitiveValue::u16(v) => v.hash(&mut state),
+                PrimitiveValue::u32(v) => v.hash(&mut state),
+                PrimitiveValue::u64(v) => v.hash(&mut state),
+                PrimitiveValue::usize(v) => v.hash(&mut state),
+                PrimitiveValue::i8(v) => v.hash(&mut state),
+                PrimitiveValue::i16(v) => v.hash(&mut state),
+                PrimitiveValue::i32(v) => v.hash(&mut state),
+                PrimitiveValue::i64(v) => v.hash(&mut state),
+                PrimitiveValue::isize(v) => v.hash(&mut state),
+                PrimitiveValue::f32(v) => float_bits(v as f64).hash(&mut state),
+                PrimitiveValue::f64(v) => float_bits(v).hash(&mut state),
+                PrimitiveValue::bool(v) => v.hash(&mut state),
+                PrimitiveValue::char(v) => v.hash(&mut state),
+                PrimitiveValue::String(ref v) => v.hash(&mut state),
+            },
+            Contour::Seq { element, len, item, .. } => {
+                let n = len(ptr);
+                n.hash(&mut state);
+                // `Hash` writes a slice of integers all at once, which
+                // hashers that don't just stream bytes tell apart from one
+                // write per element.
+                match self.int_bytes(element, (0..n).map(|ix| item(ptr, ix))) {
+                    Some(bytes) => state.write(&bytes),
+                    None => for ix in 0..n {
+                        self.hash(element, item(ptr, ix), state);
+                    },
+                }
+            },
+            Contour::Pointer { deref: None, .. } => (raw_address(ptr) as usize).hash(&mut state),
+            Contour::Pointer { pointee, deref: Some(deref), .. } => {
+                let target = deref(ptr);
+                if target.is_null() {
+                    (target as usize).hash(&mut state);
+                } else if !self.active.contains(&(pointee, target, target)) {
+                    self.active.push((pointee, target, target));
+                    self.hash(pointee, target, state);
+                    self.active.pop();
+                }
+            },
+            Contour::Map { name, key, value, len, entries, .. } => {
+                len(ptr).hash(&mut state);
+                if name == "BTreeMap" {
+                    for (k, v) in collect(entries, ptr) {
+                        self.hash(key, k, state);
+                        self.hash(value, v, state);
+                    }
+                } else {
+                    // Equal maps can iterate in different orders, so entries
+                    // are hashed separately and summed.
+                    let mut sum = 0u64;
+                    for (k, v) in collect(entries, ptr) {
+                        let mut entry = DefaultHasher::new();
+                        self.hash(key, k, &mut entry);
+                        self.hash(value, v, &mut entry);
+                        sum = sum.wrapping_add(entry.finish());
+                    }
+                    sum.hash(&mut state);
+                }
+            },
+        }
+    }
+
+    /// The bytes of `items` laid end to end, if they're integers.
+    unsafe fn int_bytes<I: Iterator<Item = *const u8>>(&self, type_id: TypeId, items: I)
+        -> Option<Vec<u8>>
+    {
+        let variant = match self.contour(type_id) {
+            Contour::Primitive { variant, atomic: false, .. } => variant,
+            _ => return None,
+        };
+        let mut bytes = vec![];
+        for ptr in items {
+            match variant.load(ptr, false) {
+                PrimitiveValue::u8(v) => bytes.push(v),
+                PrimitiveValue::u16(v) => bytes.extend_from_slice(&v.to_ne_bytes()),
+                PrimitiveValue::u32(v) => bytes.extend_from_slice(&v.to_ne_bytes()),
+                PrimitiveValue::u64(v) => bytes.extend_from_slice(&v.to_ne_bytes()),
+                PrimitiveValue::usize(v) => bytes.extend_from_slice(&v.to_ne_bytes()),
+                PrimitiveValue::i8(v) => bytes.extend_from_slice(&v.to_ne_bytes()),
+                PrimitiveValue::i16(v) => bytes.extend_from_slice(&v.to_ne_bytes()),
+                PrimitiveValue::i32(v) => bytes.extend_from_slice(&v.to_ne_bytes()),
+                PrimitiveValue::i64(v) => bytes.extend_from_slice(&v.to_ne_bytes()),
+                PrimitiveValue::isize(v) => bytes.extend_from_slice(&v.to_ne_bytes()),
+                _ => return None,
+            }
+        }
+        Some(bytes)
+    }
+}
+
+/// `==` treats the zeros as equal, so they hash alike.  NaNs never compare
+/// equal, but hash alike anyway so hashing stays deterministic.
+fn float_bits(v: f64) -> u64 {
+    if v == 0.0 {
+        0
+    } else if v.is_nan() {
+        f64::NAN.to_bits()
+    } else {
+        v.to_bits()
+    }
+}
+
+unsafe fn collect(entries: unsafe fn(*const u8, &mut dyn FnMut(*const u8, *const u8)),
+                  ptr: *const u8)
+    -> Vec<(*const u8, *const u8)>
+{
+    let mut out = vec![];
+    entries(ptr, &mut |k, v| out.push((k, v)));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::collections::{
+        BTreeMap,
+        HashMap,
+    };
+    use std::mem;
+    use std::rc::Rc;
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        StructField,
+        TupleField,
+        Variant,
+        VariantFields,
+    };
+    use super::*;
+
+    #[derive(Clone, Debug, Hash, Introspectable, PartialEq)]
+    enum Shape {
+        Dot,
+        Line(u32, i32),
+        Named { label: String, tags: Vec<char> },
+    }
+
+    #[derive(Clone, Debug, Hash, Introspectable, PartialEq)]
+    #[repr(u8)]
+    enum Opcode {
+        Nop = 0x90,
+        Jmp(i32) = 0xe9,
+        Call(u32),
+    }
+
+    #[derive(Clone, Debug, Hash, Introspectable, PartialEq)]
+    enum Single {
+        Only(u8),
+    }
+
+    #[derive(Clone, Debug, Hash, Introspectable, PartialEq)]
+    struct Scene {
+        id: u64,
+        visible: bool,
+        shapes: Vec<Shape>,
+        origin: Box<i16>,
+        title: Rc<String>,
+        index: BTreeMap<u8, Shape>,
+        single: Single,
+        ops: Vec<Opcode>,
+        ports: Vec<u16>,
+    }
+
+    #[derive(Introspectable, PartialEq)]
+    struct Sample {
+        small: u8,
+        value: f64,
+    }
+
+    #[derive(Introspectable, PartialEq)]
+    struct Reading {
+        sample: Sample,
+        counts: HashMap<String, u32>,
+    }
+
+    fn std_hash<T: Hash>(value: &T) -> u64 {
+        let mut state = DefaultHasher::new();
+        value.hash(&mut state);
+        state.finish()
+    }
+
+    /// Records each write, to tell one write of several bytes from several
+    /// writes of one.
+    #[derive(Default)]
+    struct Writes(Vec<Vec<u8>>);
+
+    impl Hasher for Writes {
+        fn write(&mut self, bytes: &[u8]) {
+            self.0.push(bytes.to_vec());
+        }
+
+        fn finish(&self) -> u64 {
+            0
+        }
+    }
+
+    fn hash<T: Introspectable>(value: &T) -> u64 {
+        let mut state = DefaultHasher::new();
+        dyn_hash(value, &mut state);
+        state.finish()
+    }
+
+    #[test]
+    fn test_agrees_with_derived() {
+        let mut index = BTreeMap::new();
+        index.insert(2, Shape::Line(4, -5));
+        index.insert(9, Shape::Named { label: "hub".to_owned(), tags: vec!['x', 'y'] });
+        let scene = Scene {
+            id: 77,
+            visible: true,
+            shapes: vec![Shape::Dot, Shape::Line(1, 2)],
+            origin: Box::new(-3),
+            title: Rc::new("main".to_owned()),
+            index: index,
+            single: Single::Only(6),
+            ops: vec![Opcode::Nop, Opcode::Jmp(-2), Opcode::Call(7)],
+            ports: vec![80, 443],
+        };
+        assert_eq!(hash(&scene), std_hash(&scene));
+        let (mut ours, mut std) = (Writes::default(), Writes::default());
+        dyn_hash(&scene, &mut ours);
+        scene.hash(&mut std);
+        assert_eq!(ours.0, std.0);
+        match Opcode::contour() {
+            Contour::Enum { ref variants, .. } => {
+                let discriminants: Vec<_> = variants.iter().map(|v| v.discriminant).collect();
+                assert_eq!(discriminants, vec![0x90, 0xe9, 0xea]);
+            },
+            c => panic!("unexpected contour {:?}", c),
+        }
+
+        let mut other = scene.clone();
+        assert!(dyn_eq(&scene, &other));
+        other.index.insert(2, Shape::Line(4, 5));
+        assert_eq!(dyn_eq(&scene, &other), scene == other);
+        assert_eq!(hash(&other), std_hash(&other));
+        other = scene.clone();
+        other.title = Rc::new("side".to_owned());
+        assert!(!dyn_eq(&scene, &other));
+        assert_ne!(hash(&scene), hash(&other));
+    }
+
+    #[test]
+    fn test_floats_maps_and_padding() {
+        let reading = |value: f64, keys: &[&str]| {
+            // Start from junk, so only the fields and not the padding match.
+            let mut sample: Sample = unsafe { mem::transmute([0xa5u8; 16]) };
+            sample.small = 1;
+            sample.value = value;
+            let mut counts = HashMap::new();
+            for k in keys {
+                counts.insert(k.to_string(), k.len() as u32);
+            }
+            Reading { sample: sample, counts: counts }
+        };
+        let keys = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        let backwards = ["h", "f", "g", "d", "e", "b", "c", "a"];
+        let (a, b) = (reading(0.0, &keys), reading(-0.0, &backwards));
+        assert!(dyn_eq(&a, &b) && a == b);
+        assert_eq!(hash(&a), hash(&b));
+
+        let (a, b) = (reading(f64::NAN, &keys), reading(f64::NAN, &keys));
+        assert!(!dyn_eq(&a, &b) && a != b);
+        assert!(!dyn_eq(&reading(1.0, &keys), &reading(1.0, &keys[1..])));
+    }
+}
@@ -0,0 +1,656 @@
+//! Checking that contours describe their types correctly, by generating
+//! values and comparing what the contour reads out of them with what the
+//! type's own code sees, field by field.
+//!
+//! The type's side comes from `Direct`, which `#[derive(Direct)]` implements
+//! by reaching each field directly and matching on the real variant, so if a
+//! contour has a wrong offset, field type or `tag`, the two disagree at that
+//! field.  Each contour's layout is checked too: fields have to be aligned,
+//! fit inside their parent and not overlap.  `#[contour(skip)]` fields are
+//! left out on both sides, so they simply aren't compared.
+
+use std::any::TypeId;
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+use std::hash::Hash;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use path::{
+    Key,
+    Path,
+    Segment,
+};
+use value::{
+    FieldValues,
+    Value,
+};
+use {
+    raw_address,
+    Contour,
+    ContourMap,
+    Introspectable,
+    PrimitiveValue,
+    Registry,
+    StructField,
+    TupleField,
+    VariantFields,
+};
+
+/// Types that can make up values of themselves.
+pub trait Arbitrary: Sized {
+    fn arbitrary(g: &mut Gen) -> Self;
+}
+
+/// A deterministic source of randomness.  `size` bounds the length of
+/// strings and containers.
+pub struct Gen {
+    state: u64,
+    pub size: usize,
+}
+
+impl Gen {
+    pub fn new(seed: u64) -> Self {
+        Gen { state: seed ^ 0x9e37_79b9_7f4a_7c15, size: 8 }
+    }
+
+    /// splitmix64.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0..n`, which mustn't be 0.  Draws past the last whole
+    /// multiple of `n` are thrown away, since they'd favour small results.
+    pub fn below(&mut self, n: u64) -> u64 {
+        let zone = u64::MAX - u64::MAX % n;
+        loop {
+            let x = self.next_u64();
+            if x < zone {
+                return x % n;
+            }
+        }
+    }
+
+    pub fn gen<T: Arbitrary>(&mut self) -> T {
+        T::arbitrary(self)
+    }
+
+    fn len(&mut self) -> usize {
+        self.below(self.size as u64 + 1) as usize
+    }
+}
+
+macro_rules! int_arbitrary {
+    ($($t:ident)*) => {
+        $(impl Arbitrary for $t {
+            /// Extremes show up often, since they're where bugs live.
+            fn arbitrary(g: &mut Gen) -> Self {
+                match g.below(8) {
+                    0 => 0,
+                    1 => $t::MIN,
+                    2 => $t::MAX,
+                    _ => g.next_u64() as $t,
+                }
+            }
+        })*
+    };
+}
+int_arbitrary!(u8 u16 u32 u64 usize i8 i16 i32 i64 isize);
+
+macro_rules! float_arbitrary {
+    ($($t:ident)*) => {
+        $(impl Arbitrary for $t {
+            fn arbitrary(g: &mut Gen) -> Self {
+                match g.below(8) {
+                    0 => 0.0,
+                    1 => -0.0,
+                    2 => $t::NAN,
+                    3 => $t::INFINITY,
+                    _ => (g.next_u64() as i64 as $t) / (g.below(1000) + 1) as $t,
+                }
+            }
+        })*
+    };
+}
+float_arbitrary!(f32 f64);
+
+impl Arbitrary for bool {
+    fn arbitrary(g: &mut Gen) -> Self {
+        g.below(2) == 1
+    }
+}
+
+impl Arbitrary for char {
+    /// Mostly printable ASCII, with the odd escape and multi-byte char.
+    fn arbitrary(g: &mut Gen) -> Self {
+        loop {
+            let n = match g.below(8) {
+                0 => g.below(0x20) as u32,
+                1 => g.below(0x11_0000) as u32,
+                _ => 0x20 + g.below(0x5f) as u32,
+            };
+            if let Some(c) = ::std::char::from_u32(n) {
+                return c;
+            }
+        }
+    }
+}
+
+impl Arbitrary for String {
+    fn arbitrary(g: &mut Gen) -> Self {
+        (0..g.len()).map(|_| g.gen::<char>()).collect()
+    }
+}
+
+impl<T: Arbitrary> Arbitrary for Vec<T> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        (0..g.len()).map(|_| g.gen()).collect()
+    }
+}
+
+impl<T: Arbitrary> Arbitrary for Box<T> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Box::new(g.gen())
+    }
+}
+
+impl<T: Arbitrary> Arbitrary for Rc<T> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Rc::new(g.gen())
+    }
+}
+
+impl<T: Arbitrary> Arbitrary for Arc<T> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Arc::new(g.gen())
+    }
+}
+
+impl<K: Arbitrary + Ord, V: Arbitrary> Arbitrary for BTreeMap<K, V> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        (0..g.len()).map(|_| (g.gen(), g.gen())).collect()
+    }
+}
+
+impl<K: Arbitrary + Eq + Hash, V: Arbitrary> Arbitrary for HashMap<K, V> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        (0..g.len()).map(|_| (g.gen(), g.gen())).collect()
+    }
+}
+
+/// Values as the type's own code sees them, for comparing with what its
+/// contour reads.  `#[derive(Direct)]` implements this for structs and
+/// enums, leaving out `#[contour(skip)]` fields like the contour does.
+pub trait Direct {
+    fn direct(&self) -> Value;
+}
+
+macro_rules! primitive_direct {
+    ($($t:ident)*) => {
+        $(impl Direct for $t {
+            fn direct(&self) -> Value {
+                Value::Primitive(PrimitiveValue::$t(*self))
+            }
+        })*
+    };
+}
+primitive_direct!(u8 u16 u32 u64 usize i8 i16 i32 i64 f32 f64 isize bool char);
+
+impl Direct for String {
+    fn direct(&self) -> Value {
+        Value::Primitive(PrimitiveValue::String(self.clone()))
+    }
+}
+
+macro_rules! atomic_direct {
+    ($($t:ident: $n:ident),*) => {
+        $(impl Direct for ::std::sync::atomic::$t {
+            fn direct(&self) -> Value {
+                Value::Primitive(PrimitiveValue::$n(self.load(Ordering::Relaxed)))
+            }
+        })*
+    };
+}
+atomic_direct!(AtomicU8: u8, AtomicU16: u16, AtomicU32: u32, AtomicU64: u64,
+               AtomicUsize: usize, AtomicI8: i8, AtomicI16: i16, AtomicI32: i32,
+               AtomicI64: i64, AtomicIsize: isize, AtomicBool: bool);
+
+impl<T: Direct> Direct for Vec<T> {
+    fn direct(&self) -> Value {
+        Value::Seq(self.iter().map(Direct::direct).collect())
+    }
+}
+
+macro_rules! pointer_direct {
+    ($($t:ident)*) => {
+        $(impl<T: Direct> Direct for $t<T> {
+            fn direct(&self) -> Value {
+                Value::Pointer(Some(Box::new((**self).direct())))
+            }
+        })*
+    };
+}
+pointer_direct!(Box Rc Arc);
+
+impl<T> Direct for *const T {
+    fn direct(&self) -> Value {
+        address(*self as *const u8)
+    }
+}
+
+impl<T> Direct for *mut T {
+    fn direct(&self) -> Value {
+        address(*self as *const u8)
+    }
+}
+
+impl<K: Direct, V: Direct, S> Direct for HashMap<K, V, S> {
+    fn direct(&self) -> Value {
+        Value::Map(self.iter().map(|(k, v)| (k.direct(), v.direct())).collect())
+    }
+}
+
+impl<K: Direct, V: Direct> Direct for BTreeMap<K, V> {
+    fn direct(&self) -> Value {
+        Value::Map(self.iter().map(|(k, v)| (k.direct(), v.direct())).collect())
+    }
+}
+
+/// Checks `cases` arbitrary values of `T`, panicking at the first problem.
+pub fn check<T: Introspectable + Arbitrary + Direct>(cases: usize) {
+    check_with(cases, |g| g.gen::<T>())
+}
+
+/// Like `check`, with values from `gen`.  Failures name the seed, so a case
+/// can be reproduced with `Gen::new`.
+pub fn check_with<T, F>(cases: usize, mut gen: F)
+    where T: Introspectable + Direct,
+          F: FnMut(&mut Gen) -> T,
+{
+    for seed in 0..cases as u64 {
+        let value = gen(&mut Gen::new(seed));
+        if let Err(msg) = check_value(&value) {
+            panic!("Contour of `{}` is wrong for seed {}: {}", T::contour().name(), seed, msg);
+        }
+    }
+}
+
+/// Checks the layout of every contour reachable from `T`, and that reading
+/// `value` through them agrees with direct access, field by field.
+pub fn check_value<T: Introspectable + Direct>(value: &T) -> Result<(), String> {
+    let registry = Registry::of::<T>();
+    for contour in registry.contours() {
+        check_layout(&registry, &contour)?;
+    }
+    let seen = unsafe { read(&registry, T::contour().type_id(), value as *const T as *const u8) };
+    compare(&seen, &value.direct(), &mut Path::root())
+}
+
+/// A field's name, type and offset.
+type FieldLayout = (String, TypeId, usize);
+
+fn check_layout(map: &Registry, contour: &Contour) -> Result<(), String> {
+    let groups: Vec<(String, Vec<FieldLayout>)> = match *contour {
+        Contour::Struct { name, ref fields, .. } =>
+            vec![(name.to_owned(), fields.iter().map(named).collect())],
+        Contour::Tuple { name, ref fields, .. } =>
+            vec![(name.to_owned(), fields.iter().map(unnamed).collect())],
+        Contour::Enum { name, ref variants, .. } => variants.iter()
+            .map(|v| {
+                let fields = match v.fields {
+                    VariantFields::Struct(ref fields) => fields.iter().map(named)
+                        .collect(),
+                    VariantFields::Tuple(ref fields) => fields.iter().map(unnamed)
+                        .collect(),
+                    VariantFields::Unit => vec![],
+                };
+                (format!("{}::{}", name, v.name), fields)
+            })
+            .collect(),
+        _ => return Ok(()),
+    };
+    for (owner, fields) in groups {
+        let mut spans = vec![];
+        for (name, type_id, offset) in fields {
+            let inner = map.lookup(type_id).expect("chart didn't register all descendants");
+            if offset % inner.align() != 0 {
+                return Err(format!("`{}.{}` at offset {} isn't aligned to {}",
+                                   owner, name, offset, inner.align()));
+            }
+            if offset + inner.size() > contour.size() {
+                return Err(format!("`{}.{}` at offset {} runs past the end of `{}`",
+                                   owner, name, offset, contour.name()));
+            }
+            if inner.size() > 0 {
+                spans.push((offset, offset + inner.size(), name));
+            }
+        }
+        spans.sort();
+        for pair in spans.windows(2) {
+            if pair[0].1 > pair[1].0 {
+                return Err(format!("`{}.{}` and `{}.{}` overlap",
+                                   owner, pair[0].2, owner, pair[1].2));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn named(f: &StructField) -> FieldLayout {
+    (f.name.to_owned(), f.type_id, f.offset)
+}
+
+fn unnamed(f: &TupleField) -> FieldLayout {
+    (f.ix.to_string(), f.type_id, f.offset)
+}
+
+/// Reads the value at `ptr` through its contour.  Generated values don't
+/// have cycles, so pointers are always followed.
+unsafe fn read(map: &Registry, type_id: TypeId, ptr: *const u8) -> Value {
+    let contour = map.lookup(type_id).expect("chart didn't register all descendants");
+    match contour {
+        Contour::Struct { name, ref fields, .. } =>
+            Value::Struct { name: name.to_owned(), fields: read_named(map, fields, ptr) },
+        Contour::Tuple { name, ref fields, .. } =>
+            Value::Struct { name: name.to_owned(), fields: read_tuple(map, fields, ptr) },
+        Contour::Unit { name, .. } =>
+            Value::Struct { name: name.to_owned(), fields: FieldValues::Unit },
+        Contour::Enum { name, ref variants, tag, .. } => {
+            let variant = &variants[tag(ptr)];
+            let fields = match variant.fields {
+                VariantFields::Struct(ref fields) => read_named(map, fields, ptr),
+                VariantFields::Tuple(ref fields) => read_tuple(map, fields, ptr),
+                VariantFields::Unit => FieldValues::Unit,
+            };
+            Value::Enum { name: name.to_owned(), variant: variant.name.to_owned(), fields }
+        },
+        Contour::Primitive { ref variant, atomic, .. } =>
+            Value::Primitive(variant.load(ptr, atomic)),
+        Contour::Seq { element, len, item, .. } =>
+            Value::Seq((0..len(ptr)).map(|ix| read(map, element, item(ptr, ix))).collect()),
+        Contour::Pointer { deref: None, .. } => address(raw_address(ptr)),
+        Contour::Pointer { pointee, deref: Some(deref), .. } => {
+            let target = deref(ptr);
+            if target.is_null() {
+                Value::Pointer(None)
+            } else {
+                Value::Pointer(Some(Box::new(read(map, pointee, target))))
+            }
+        },
+        Contour::Map { key, value, entries, .. } => {
+            let mut out = vec![];
+            entries(ptr, &mut |k, v| out.push((read(map, key, k), read(map, value, v))));
+            Value::Map(out)
+        },
+    }
+}
+
+unsafe fn read_named(map: &Registry, fields: &[StructField], ptr: *const u8) -> FieldValues {
+    FieldValues::Named(fields.iter()
+        .map(|f| (f.name.to_owned(), read(map, f.type_id, ptr.add(f.offset))))
+        .collect())
+}
+
+unsafe fn read_tuple(map: &Registry, fields: &[TupleField], ptr: *const u8) -> FieldValues {
+    FieldValues::Tuple(fields.iter().map(|f| read(map, f.type_id, ptr.add(f.offset))).collect())
+}
+
+/// Raw pointers aren't followed, the same as in snapshots.
+fn address(ptr: *const u8) -> Value {
+    if ptr.is_null() {
+        Value::Pointer(None)
+    } else {
+        Value::Address(ptr as u64)
+    }
+}
+
+/// Compares what the contour read with what direct access gives, stopping at
+/// the first difference.  Floats are compared by their bits, so that NaNs
+/// match themselves.
+fn compare(seen: &Value, direct: &Value, path: &mut Path) -> Result<(), String> {
+    let same = match (seen, direct) {
+        (Value::Struct { name: a, fields: x },
+         Value::Struct { name: b, fields: y }) if a == b =>
+            return compare_fields(x, y, path),
+        (Value::Enum { name: a, variant: va, fields: x },
+         Value::Enum { name: b, variant: vb, fields: y }) if a == b && va == vb => {
+            path.push(Segment::Variant(va.clone()));
+            return compare_fields(x, y, path);
+        },
+        (Value::Primitive(a), Value::Primitive(b)) => same_primitive(a, b),
+        (Value::Seq(x), Value::Seq(y)) if x.len() == y.len() => {
+            for (i, (x, y)) in x.iter().zip(y).enumerate() {
+                path.push(Segment::Index(Key::Int(i as i64)));
+                compare(x, y, path)?;
+                path.pop();
+            }
+            return Ok(());
+        },
+        // Both sides iterate the same container, so entries come in the same
+        // order.
+        (Value::Map(x), Value::Map(y)) if x.len() == y.len() => {
+            for (i, ((kx, vx), (ky, vy))) in x.iter().zip(y).enumerate() {
+                let key = ky.as_primitive().and_then(Key::from_value);
+                path.push(Segment::Index(key.unwrap_or(Key::Int(i as i64))));
+                compare(kx, ky, path)?;
+                compare(vx, vy, path)?;
+                path.pop();
+            }
+            return Ok(());
+        },
+        (&Value::Pointer(Some(ref x)), &Value::Pointer(Some(ref y))) => return compare(x, y, path),
+        _ => seen == direct,
+    };
+    if same {
+        Ok(())
+    } else {
+        Err(mismatch(path, &describe(seen), &describe(direct)))
+    }
+}
+
+fn compare_fields(seen: &FieldValues, direct: &FieldValues, path: &mut Path)
+    -> Result<(), String>
+{
+    if shape(seen) != shape(direct) {
+        return Err(mismatch(path, &shape(seen), &shape(direct)));
+    }
+    match (seen, direct) {
+        (FieldValues::Named(x), FieldValues::Named(y)) => {
+            for ((name, x), (_, y)) in x.iter().zip(y) {
+                path.push(Segment::Field(name.clone()));
+                compare(x, y, path)?;
+                path.pop();
+            }
+        },
+        (FieldValues::Tuple(x), FieldValues::Tuple(y)) => {
+            for (i, (x, y)) in x.iter().zip(y).enumerate() {
+                path.push(Segment::Tuple(i));
+                compare(x, y, path)?;
+                path.pop();
+            }
+        },
+        _ => {},
+    }
+    Ok(())
+}
+
+fn same_primitive(a: &PrimitiveValue, b: &PrimitiveValue) -> bool {
+    match (a, b) {
+        (&PrimitiveValue::f32(x), &PrimitiveValue::f32(y)) => x.to_bits() == y.to_bits(),
+        (&PrimitiveValue::f64(x), &PrimitiveValue::f64(y)) => x.to_bits() == y.to_bits(),
+        _ => a == b,
+    }
+}
+
+fn mismatch(path: &Path, seen: &str, direct: &str) -> String {
+    let at = if path.is_empty() { ".".to_owned() } else { path.to_string() };
+    format!("at `{}`: contour reads {}, direct access gives {}", at, seen, direct)
+}
+
+/// What `value` is, without going into what's inside it.
+fn describe(value: &Value) -> String {
+    match *value {
+        Value::Struct { ref name, .. } => format!("a `{}`", name),
+        Value::Enum { ref name, ref variant, .. } => format!("`{}::{}`", name, variant),
+        Value::Primitive(ref v) => v.to_string(),
+        Value::Seq(ref items) => format!("{} elements", items.len()),
+        Value::Map(ref entries) => format!("{} entries", entries.len()),
+        Value::Pointer(None) => "a null pointer".to_owned(),
+        Value::Pointer(Some(_)) => "a pointer".to_owned(),
+        Value::Address(address) => format!("address {:#x}", address),
+        Value::Cycle => "a cycle".to_owned(),
+    }
+}
+
+/// The names of the fields, or how many there are if they're unnamed.
+fn shape(fields: &FieldValues) -> String {
+    match *fields {
+        FieldValues::Named(ref fields) => {
+            let names: Vec<_> = fields.iter().map(|f| format!("`{}`", f.0)).collect();
+            format!("fields {}", names.join(", "))
+        },
+        FieldValues::Tuple(ref fields) => format!("{} fields", fields.len()),
+        FieldValues::Unit => "no fields".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::cell::Cell;
+
+    use {
+        Contour,
+        ContourMap,
+        Hooks,
+        Introspectable,
+        Repr,
+        StructField,
+        TupleField,
+        Variant,
+        VariantFields,
+    };
+    use super::*;
+
+    #[derive(Direct, Introspectable)]
+    #[repr(u8)]
+    enum Op {
+        Nop,
+        Push(i64, char),
+        Call { target: Box<String>, args: Vec<u16>, tail: bool },
+    }
+
+    #[derive(Direct, Introspectable)]
+    struct Empty;
+
+    #[derive(Direct, Introspectable)]
+    #[repr(C)]
+    struct Frame<T: Introspectable + Direct + 'static> {
+        flag: bool,
+        value: T,
+        ops: Vec<Op>,
+        locals: BTreeMap<u8, Rc<f32>>,
+        globals: HashMap<String, Arc<Empty>>,
+        parent: *const u8,
+        #[contour(skip)]
+        hits: Cell<u32>,
+    }
+
+    impl Arbitrary for Op {
+        fn arbitrary(g: &mut Gen) -> Self {
+            match g.below(3) {
+                0 => Op::Nop,
+                1 => Op::Push(g.gen(), g.gen()),
+                _ => Op::Call { target: g.gen(), args: g.gen(), tail: g.gen() },
+            }
+        }
+    }
+
+    impl Arbitrary for Empty {
+        fn arbitrary(_: &mut Gen) -> Self {
+            Empty
+        }
+    }
+
+    impl<T: Arbitrary + Introspectable + Direct> Arbitrary for Frame<T> {
+        fn arbitrary(g: &mut Gen) -> Self {
+            Frame {
+                flag: g.gen(),
+                value: g.gen(),
+                ops: g.gen(),
+                locals: g.gen(),
+                globals: g.gen(),
+                parent: g.next_u64() as usize as *const u8,
+                hits: Cell::new(g.gen()),
+            }
+        }
+    }
+
+    /// Charted by hand with `a` and `b` swapped, like a broken derive might.
+    /// Both are `u32`s, so reads through the swapped offsets stay in bounds.
+    struct Swapped {
+        a: u32,
+        b: u32,
+    }
+
+    impl Direct for Swapped {
+        fn direct(&self) -> Value {
+            let fields = vec![("a".to_owned(), self.a.direct()), ("b".to_owned(), self.b.direct())];
+            Value::Struct { name: "Swapped".to_owned(), fields: FieldValues::Named(fields) }
+        }
+    }
+
+    unsafe impl Introspectable for Swapped {
+        fn contour() -> Contour {
+            let field = |name, offset| StructField {
+                name: name,
+                type_id: TypeId::of::<u32>(),
+                offset: offset,
+                redacted: false,
+                constraints: vec![],
+                bits: vec![],
+            };
+            let s = Swapped { a: 0, b: 0 };
+            let offset = |p: &u32| p as *const u32 as usize - &s as *const Swapped as usize;
+            Contour::Struct {
+                name: "Swapped",
+                module_path: module_path!(),
+                size: ::std::mem::size_of::<Swapped>(),
+                align: ::std::mem::align_of::<Swapped>(),
+                type_id: TypeId::of::<Swapped>(),
+                fields: vec![field("a", offset(&s.b)), field("b", offset(&s.a))],
+                skipped: false,
+                repr: Repr::default(),
+                hooks: Hooks::default(),
+            }
+        }
+
+        fn chart<CM: ContourMap>(map: &CM) {
+            if !map.register(Self::contour()) {
+                u32::chart(map);
+            }
+        }
+    }
+
+    #[test]
+    fn test_derived() {
+        check::<Op>(200);
+        check::<Frame<u64>>(100);
+        check::<Frame<Vec<Frame<i8>>>>(50);
+        check_with(20, |g| (0..g.below(4)).map(|_| g.gen::<String>()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_detects_mistakes() {
+        assert_eq!(check_value(&Swapped { a: 1, b: 2 }).unwrap_err(),
+                   "at `a`: contour reads 2, direct access gives 1");
+        assert!(check_value(&Swapped { a: 3, b: 3 }).is_ok());
+    }
+}
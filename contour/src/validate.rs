@@ -0,0 +1,367 @@
+//! Checking values against the constraints declared on their fields.
+
+use std::any::TypeId;
+use std::cmp::Ordering;
+#[cfg(feature = "regex")] use std::cell::RefCell;
+#[cfg(feature = "regex")] use std::collections::HashMap;
+use std::fmt;
+
+#[cfg(feature = "regex")] use regex::Regex;
+
+use path::{
+    Key,
+    Path,
+    Segment,
+};
+use visit::{
+    walk_value,
+    Visitor,
+    Walker,
+};
+use {
+    Bound,
+    Constraint,
+    Contour,
+    ContourMap,
+    Introspectable,
+    PrimitiveValue,
+    Registry,
+    Variant,
+    VariantFields,
+};
+
+/// A field whose value breaks one of its constraints.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Violation {
+    pub path: Path,
+    pub msg: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at `{}`: {}", self.path, self.msg)
+    }
+}
+
+/// Checks every constrained field reachable from `value`, returning all of
+/// the violations in the order they were found.
+pub fn validate<T: Introspectable>(value: &T) -> Vec<Violation> {
+    let registry = Registry::of::<T>();
+    unsafe { validate_ptr(&registry, T::contour().type_id(), value as *const T as *const u8) }
+}
+
+/// # Safety
+///
+/// See `Walker::walk` for the requirements on `ptr`.
+pub unsafe fn validate_ptr(map: &dyn ContourMap, type_id: TypeId, ptr: *const u8)
+    -> Vec<Violation>
+{
+    let mut validator = Validator { path: Path::root(), violations: vec![] };
+    Walker::new(map).walk(&mut validator, type_id, ptr);
+    validator.violations
+}
+
+#[cfg(feature = "regex")]
+thread_local! {
+    /// Patterns compiled so far, anchored at both ends.
+    static PATTERNS: RefCell<HashMap<&'static str, Result<Regex, String>>> =
+        RefCell::new(HashMap::new());
+}
+
+struct Validator {
+    path: Path,
+    violations: Vec<Violation>,
+}
+
+impl Validator {
+    unsafe fn field(&mut self,
+                    cx: &mut Walker,
+                    segment: Segment,
+                    constraints: &[Constraint],
+                    type_id: TypeId,
+                    ptr: *const u8) {
+        self.path.push(segment);
+        for constraint in constraints {
+            if let Err(msg) = unsafe { check(cx, constraint, type_id, ptr) } {
+                self.violations.push(Violation { path: self.path.clone(), msg });
+            }
+        }
+        walk_value(self, cx, type_id, ptr);
+        self.path.pop();
+    }
+}
+
+impl Visitor for Validator {
+    unsafe fn visit_struct(&mut self, cx: &mut Walker, contour: &Contour, ptr: *const u8) {
+        match *contour {
+            Contour::Struct { ref fields, .. } => for f in fields {
+                let subptr = unsafe { ptr.add(f.offset) };
+                let segment = Segment::Field(f.name.to_owned());
+                self.field(cx, segment, &f.constraints, f.type_id, subptr);
+            },
+            Contour::Tuple { ref fields, .. } => for f in fields {
+                let subptr = unsafe { ptr.add(f.offset) };
+                self.field(cx, Segment::Tuple(f.ix), &f.constraints, f.type_id, subptr);
+            },
+            _ => {},
+        }
+    }
+
+    unsafe fn visit_variant(&mut self, cx: &mut Walker, variant: &Variant, ptr: *const u8) {
+        self.path.push(Segment::Variant(variant.name.to_owned()));
+        match variant.fields {
+            VariantFields::Struct(ref fields) => for f in fields {
+                let subptr = unsafe { ptr.add(f.offset) };
+                let segment = Segment::Field(f.name.to_owned());
+                self.field(cx, segment, &f.constraints, f.type_id, subptr);
+            },
+            VariantFields::Tuple(ref fields) => for f in fields {
+                let subptr = unsafe { ptr.add(f.offset) };
+                self.field(cx, Segment::Tuple(f.ix), &f.constraints, f.type_id, subptr);
+            },
+            VariantFields::Unit => {},
+        }
+        self.path.pop();
+    }
+
+    unsafe fn visit_element(&mut self,
+                            cx: &mut Walker,
+                            ix: usize,
+                            type_id: TypeId,
+                            ptr: *const u8) {
+        self.path.push(Segment::Index(Key::Int(ix as i64)));
+        walk_value(self, cx, type_id, ptr);
+        self.path.pop();
+    }
+
+    /// Entries are addressed by key where the key is a primitive, and by
+    /// position otherwise.  Keys themselves aren't checked.
+    unsafe fn visit_map(&mut self, cx: &mut Walker, contour: &Contour, ptr: *const u8) {
+        if let Contour::Map { key, value, entries, .. } = *contour {
+            let key_contour = cx.contour(key);
+            let mut i = 0;
+            unsafe {
+                entries(ptr, &mut |k, v| {
+                    let segment = match key_contour {
+                        Contour::Primitive { ref variant, .. } => Key::from_value(&variant.read(k)),
+                        _ => None,
+                    };
+                    self.path.push(Segment::Index(segment.unwrap_or(Key::Int(i))));
+                    walk_value(self, cx, value, v);
+                    self.path.pop();
+                    i += 1;
+                });
+            }
+        }
+    }
+}
+
+/// Checks one constraint on the value at `ptr`, looking through pointers.
+unsafe fn check(cx: &Walker, constraint: &Constraint, type_id: TypeId, ptr: *const u8)
+    -> Result<(), String>
+{
+    let contour = cx.contour(type_id);
+    let name = contour.name();
+    match (constraint, contour) {
+        (_, Contour::Pointer { deref: None, .. }) =>
+            Err("is a raw pointer, which isn't followed".to_owned()),
+        (_, Contour::Pointer { pointee, deref: Some(deref), .. }) => {
+            let target = deref(ptr);
+            if target.is_null() {
+                return Err("is null".to_owned());
+            }
+            check(cx, constraint, pointee, target)
+        },
+        (&Constraint::Range { min, max }, Contour::Primitive { ref variant, atomic, .. }) => {
+            let value = variant.load(ptr, atomic);
+            let n = match number(&value) {
+                Some(n) => n,
+                None => return Err(format!("`range` doesn't apply to `{}`", name)),
+            };
+            if let Some(min) = min {
+                if below(n, min) {
+                    return Err(format!("is {}, expected at least {}", value, show(min)));
+                }
+            }
+            if let Some(max) = max {
+                if below(max, n) {
+                    return Err(format!("is {}, expected at most {}", value, show(max)));
+                }
+            }
+            Ok(())
+        },
+        (&Constraint::NonEmpty, contour) => {
+            let len = match contour {
+                Contour::Primitive { ref variant, atomic, .. } => match variant.load(ptr, atomic) {
+                    PrimitiveValue::String(ref s) => s.len(),
+                    _ => return Err(format!("`non_empty` doesn't apply to `{}`", name)),
+                },
+                Contour::Seq { len, .. } | Contour::Map { len, .. } => len(ptr),
+                _ => return Err(format!("`non_empty` doesn't apply to `{}`", name)),
+            };
+            if len == 0 { Err("is empty".to_owned()) } else { Ok(()) }
+        },
+        (&Constraint::Regex(pattern), Contour::Primitive { ref variant, atomic, .. }) => {
+            let s = match variant.load(ptr, atomic) {
+                PrimitiveValue::String(s) => s,
+                _ => return Err(format!("`regex` doesn't apply to `{}`", name)),
+            };
+            regex(pattern, &s)
+        },
+        (&Constraint::Range { .. }, _) => Err(format!("`range` doesn't apply to `{}`", name)),
+        (&Constraint::Regex(..), _) => Err(format!("`regex` doesn't apply to `{}`", name)),
+    }
+}
+
+#[cfg(feature = "regex")]
+fn regex(pattern: &'static str, s: &str) -> Result<(), String> {
+    PATTERNS.with(|patterns| {
+        let mut patterns = patterns.borrow_mut();
+        let re = patterns.entry(pattern).or_insert_with(|| {
+            Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| e.to_string())
+        });
+        match *re {
+            Ok(ref re) if re.is_match(s) => Ok(()),
+            Ok(_) => Err(format!("{:?} doesn't match `{}`", s, pattern)),
+            Err(ref e) => Err(format!("invalid pattern `{}`: {}", pattern, e)),
+        }
+    })
+}
+
+#[cfg(not(feature = "regex"))]
+fn regex(_pattern: &'static str, _s: &str) -> Result<(), String> {
+    Err("checking `regex` needs contour's `regex` feature".to_owned())
+}
+
+fn number(value: &PrimitiveValue) -> Option<Bound> {
+    Some(match *value {
+        PrimitiveValue::u8(v) => Bound::Int(v as i128),
+        PrimitiveValue::u16(v) => Bound::Int(v as i128),
+        PrimitiveValue::u32(v) => Bound::Int(v as i128),
+        PrimitiveValue::u64(v) => Bound::Int(v as i128),
+        PrimitiveValue::usize(v) => Bound::Int(v as i128),
+        PrimitiveValue::i8(v) => Bound::Int(v as i128),
+        PrimitiveValue::i16(v) => Bound::Int(v as i128),
+        PrimitiveValue::i32(v) => Bound::Int(v as i128),
+        PrimitiveValue::i64(v) => Bound::Int(v as i128),
+        PrimitiveValue::isize(v) => Bound::Int(v as i128),
+        PrimitiveValue::f32(v) => Bound::Float(v as f64),
+        PrimitiveValue::f64(v) => Bound::Float(v),
+        _ => return None,
+    })
+}
+
+/// Whether `a < b`.  NaNs are never in range.
+fn below(a: Bound, b: Bound) -> bool {
+    match (a, b) {
+        (Bound::Int(a), Bound::Int(b)) => a < b,
+        (a, b) => float(a).partial_cmp(&float(b)).is_none_or(|o| o == Ordering::Less),
+    }
+}
+
+fn float(bound: Bound) -> f64 {
+    match bound {
+        Bound::Int(n) => n as f64,
+        Bound::Float(f) => f,
+    }
+}
+
+fn show(bound: Bound) -> String {
+    match bound {
+        Bound::Int(n) => n.to_string(),
+        Bound::Float(f) => f.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::collections::BTreeMap;
+    use {
+        Bound,
+        Constraint,
+        Contour,
+        ContourMap,
+        Introspectable,
+        StructField,
+        TupleField,
+        Variant,
+        VariantFields,
+    };
+    use super::*;
+
+    #[derive(Introspectable)]
+    struct Port(#[contour(range(min = 1, max = 65535))] u32);
+
+    #[derive(Introspectable)]
+    enum Upstream {
+        Local,
+        Remote {
+            #[contour(regex = "[a-z0-9.-]+")]
+            host: String,
+            port: Port,
+        },
+    }
+
+    #[derive(Introspectable)]
+    struct Listener {
+        #[contour(non_empty)]
+        name: Box<String>,
+        #[contour(range(min = "-1.5", max = 1.5))]
+        weight: f64,
+        #[contour(non_empty)]
+        upstreams: Vec<Upstream>,
+        timeouts: BTreeMap<String, Port>,
+    }
+
+    #[test]
+    fn test_contour() {
+        match Port::contour() {
+            Contour::Tuple { ref fields, .. } => assert_eq!(fields[0].constraints, vec![
+                Constraint::Range { min: Some(Bound::Int(1)), max: Some(Bound::Int(65535)) },
+            ]),
+            c => panic!("unexpected contour {:?}", c),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut timeouts = BTreeMap::new();
+        timeouts.insert("read".to_owned(), Port(30));
+        let mut listener = Listener {
+            name: Box::new("edge".to_owned()),
+            weight: 1.0,
+            upstreams: vec![
+                Upstream::Local,
+                Upstream::Remote { host: "db-1.internal".to_owned(), port: Port(5432) },
+            ],
+            timeouts: timeouts,
+        };
+        let violations: Vec<_> = validate(&listener).iter().map(|v| v.to_string()).collect();
+        if cfg!(feature = "regex") {
+            assert_eq!(violations, Vec::<String>::new());
+        } else {
+            assert_eq!(violations, vec![
+                "at `upstreams[1]::Remote.host`: checking `regex` needs contour's `regex` feature",
+            ]);
+        }
+
+        listener.name = Box::new(String::new());
+        listener.weight = -2.0;
+        listener.upstreams[1] = Upstream::Remote { host: "DB_1".to_owned(), port: Port(0) };
+        listener.timeouts.insert("write".to_owned(), Port(70000));
+        let violations: Vec<_> = validate(&listener).iter().map(|v| v.to_string()).collect();
+        let host = if cfg!(feature = "regex") {
+            "at `upstreams[1]::Remote.host`: \"DB_1\" doesn't match `[a-z0-9.-]+`"
+        } else {
+            "at `upstreams[1]::Remote.host`: checking `regex` needs contour's `regex` feature"
+        };
+        assert_eq!(violations, vec![
+            "at `name`: is empty",
+            "at `weight`: is -2.0, expected at least -1.5",
+            host,
+            "at `upstreams[1]::Remote.port.0`: is 0, expected at least 1",
+            "at `timeouts[\"write\"].0`: is 70000, expected at most 65535",
+        ]);
+    }
+}
@@ -0,0 +1,85 @@
+//! Values detached from the types that produced them.
+//!
+//! A `Value` holds the same information as a live value walked through its
+//! contour, but owns all of it and names things by string, so it can outlive
+//! the process, or come from a build whose types this one doesn't have.
+
+use PrimitiveValue;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    /// Structs, tuple structs and unit structs.
+    Struct { name: String, fields: FieldValues },
+    Enum { name: String, variant: String, fields: FieldValues },
+    Primitive(PrimitiveValue),
+    Seq(Vec<Value>),
+    /// Entries in the order the container iterated them in.
+    Map(Vec<(Value, Value)>),
+    /// `None` for null pointers.
+    Pointer(Option<Box<Value>>),
+    /// A non-null raw pointer, which isn't followed.
+    Address(u64),
+    /// A pointer back to one of this value's own ancestors.
+    Cycle,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValues {
+    Named(Vec<(String, Value)>),
+    Tuple(Vec<Value>),
+    Unit,
+}
+
+impl FieldValues {
+    pub fn len(&self) -> usize {
+        match *self {
+            FieldValues::Named(ref fields) => fields.len(),
+            FieldValues::Tuple(ref fields) => fields.len(),
+            FieldValues::Unit => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Looks up a named field, or a tuple field by its index.
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        match *self {
+            FieldValues::Named(ref fields) =>
+                fields.iter().find(|f| f.0 == name).map(|f| &f.1),
+            FieldValues::Tuple(ref fields) =>
+                name.parse::<usize>().ok().and_then(|ix| fields.get(ix)),
+            FieldValues::Unit => None,
+        }
+    }
+}
+
+impl Value {
+    /// The type name for structs and enums.
+    pub fn name(&self) -> Option<&str> {
+        match *self {
+            Value::Struct { ref name, .. } | Value::Enum { ref name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    /// The fields of a struct or of an enum's active variant.
+    pub fn fields(&self) -> Option<&FieldValues> {
+        match *self {
+            Value::Struct { ref fields, .. } | Value::Enum { ref fields, .. } => Some(fields),
+            _ => None,
+        }
+    }
+
+    pub fn field(&self, name: &str) -> Option<&Value> {
+        self.fields().and_then(|fields| fields.get(name))
+    }
+
+    pub fn as_primitive(&self) -> Option<&PrimitiveValue> {
+        match *self {
+            Value::Primitive(ref v) => Some(v),
+            _ => None,
+        }
+    }
+}
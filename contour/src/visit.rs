@@ -0,0 +1,499 @@
+//! Walking live values through their contours.
+//!
+//! This follows `syn::visit`: each `visit_*` method's default implementation
+//! calls the matching `walk_*` function, which visits the node's children.
+//! Overriding a method lets a visitor do its own traversal, call `walk_*` to
+//! get the default one, or skip the subtree entirely by doing nothing.
+
+use std::any::TypeId;
+use std::fmt;
+
+use {
+    raw_address,
+    Contour,
+    ContourMap,
+    Introspectable,
+    Primitive,
+    Registry,
+    Variant,
+    VariantFields,
+};
+
+/// How a struct, tuple struct or enum variant refers to one of its fields.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum FieldName {
+    Named(&'static str),
+    Index(usize),
+}
+
+impl fmt::Display for FieldName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FieldName::Named(name) => write!(f, "{}", name),
+            FieldName::Index(ix) => write!(f, "{}", ix),
+        }
+    }
+}
+
+/// Traversal state shared by all of the `walk_*` functions: where to find
+/// contours, whether to follow raw pointers and which pointer targets we're
+/// currently underneath.
+pub struct Walker<'a> {
+    map: &'a dyn ContourMap,
+    follow_raw: bool,
+    active: Vec<(TypeId, *const u8)>,
+}
+
+impl<'a> Walker<'a> {
+    pub fn new(map: &'a dyn ContourMap) -> Self {
+        Walker { map, follow_raw: false, active: vec![] }
+    }
+
+    /// Has `walk_pointer` follow raw pointers as well as owning ones.  Only
+    /// `Walker::walk` can take advantage of this, since it's on its caller to
+    /// say those pointers are valid.
+    pub fn follow_raw(mut self) -> Self {
+        self.follow_raw = true;
+        self
+    }
+
+    pub fn map(&self) -> &'a dyn ContourMap {
+        self.map
+    }
+
+    /// Panics if `type_id` hasn't been charted, since that means whoever
+    /// handed us the value forgot to call `chart` first.
+    pub fn contour(&self, type_id: TypeId) -> Contour {
+        match self.map.lookup(type_id) {
+            Some(contour) => contour,
+            None => panic!("No contour for {:?}", type_id),
+        }
+    }
+
+    /// Walks the value of type `type_id` living at `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a live value of that type.  If the walker follows
+    /// raw pointers, every raw pointer reachable from it must either be null
+    /// or point to a live value too.
+    pub unsafe fn walk<V: Visitor>(&mut self, visitor: &mut V, type_id: TypeId, ptr: *const u8) {
+        self.active.push((type_id, ptr));
+        visitor.visit_value(self, type_id, ptr);
+        self.active.pop();
+    }
+}
+
+/// Walks `value` after charting its type into a scratch registry.
+pub fn walk<T: Introspectable, V: Visitor>(value: &T, visitor: &mut V) {
+    let registry = Registry::of::<T>();
+    let mut walker = Walker::new(&registry);
+    let type_id = T::contour().type_id();
+    unsafe { walker.walk(visitor, type_id, value as *const T as *const u8) }
+}
+
+/// Every `ptr` passed to a `Visitor` points at a live value described by the
+/// accompanying contour or `TypeId`, and stays valid for the duration of the
+/// call.  The methods and `walk_*` functions are `unsafe` because they
+/// dereference it: calling one with any other pointer is undefined behavior.
+pub trait Visitor: Sized {
+    /// # Safety
+    ///
+    /// `ptr` must point to a live value of type `type_id`.
+    unsafe fn visit_value(&mut self, cx: &mut Walker, type_id: TypeId, ptr: *const u8) {
+        walk_value(self, cx, type_id, ptr)
+    }
+
+    /// Called for structs, tuple structs and unit structs.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a live value that `contour` describes.
+    unsafe fn visit_struct(&mut self, cx: &mut Walker, contour: &Contour, ptr: *const u8) {
+        walk_struct(self, cx, contour, ptr)
+    }
+
+    /// Called for fields of both structs and enum variants.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a live value of type `type_id`.
+    unsafe fn visit_field(&mut self,
+                          cx: &mut Walker,
+                          _name: FieldName,
+                          type_id: TypeId,
+                          ptr: *const u8) {
+        walk_value(self, cx, type_id, ptr)
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must point to a live value that `contour` describes.
+    unsafe fn visit_enum(&mut self, cx: &mut Walker, contour: &Contour, ptr: *const u8) {
+        walk_enum(self, cx, contour, ptr)
+    }
+
+    /// Called with the enum's currently active variant.  `ptr` points at the
+    /// enum itself, since variant field offsets are relative to it.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a live enum whose active variant is `variant`.
+    unsafe fn visit_variant(&mut self, cx: &mut Walker, variant: &Variant, ptr: *const u8) {
+        walk_variant(self, cx, variant, ptr)
+    }
+
+    /// `atomic` is set for the `std::sync::atomic` types; see
+    /// `Primitive::load`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a live primitive of kind `kind`, or its atomic
+    /// version if `atomic` is set.
+    unsafe fn visit_primitive(&mut self,
+                              _cx: &mut Walker,
+                              _kind: &Primitive,
+                              _atomic: bool,
+                              _ptr: *const u8) {
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must point to a live value that `contour` describes.
+    unsafe fn visit_seq(&mut self, cx: &mut Walker, contour: &Contour, ptr: *const u8) {
+        walk_seq(self, cx, contour, ptr)
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must point to a live value of type `type_id`.
+    unsafe fn visit_element(&mut self,
+                            cx: &mut Walker,
+                            _ix: usize,
+                            type_id: TypeId,
+                            ptr: *const u8) {
+        walk_value(self, cx, type_id, ptr)
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must point to a live value that `contour` describes.
+    unsafe fn visit_pointer(&mut self, cx: &mut Walker, contour: &Contour, ptr: *const u8) {
+        walk_pointer(self, cx, contour, ptr)
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must point to a live value that `contour` describes.
+    unsafe fn visit_map(&mut self, cx: &mut Walker, contour: &Contour, ptr: *const u8) {
+        walk_map(self, cx, contour, ptr)
+    }
+
+    /// # Safety
+    ///
+    /// `key` and `value` must point to live values of types `key_type` and
+    /// `value_type`.
+    unsafe fn visit_entry(&mut self,
+                          cx: &mut Walker,
+                          key_type: TypeId,
+                          key: *const u8,
+                          value_type: TypeId,
+                          value: *const u8) {
+        walk_entry(self, cx, key_type, key, value_type, value)
+    }
+
+    /// Called instead of descending into a pointer target that's already
+    /// being walked further up.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a live value of type `type_id`.
+    unsafe fn visit_cycle(&mut self, _cx: &mut Walker, _type_id: TypeId, _ptr: *const u8) {}
+}
+
+/// # Safety
+///
+/// `ptr` must point to a live value of type `type_id`.
+pub unsafe fn walk_value<V: Visitor>(visitor: &mut V,
+                                     cx: &mut Walker,
+                                     type_id: TypeId,
+                                     ptr: *const u8) {
+    let contour = cx.contour(type_id);
+    match contour {
+        Contour::Struct {..} | Contour::Tuple {..} | Contour::Unit {..} =>
+            visitor.visit_struct(cx, &contour, ptr),
+        Contour::Enum {..} => visitor.visit_enum(cx, &contour, ptr),
+        Contour::Primitive { ref variant, atomic, .. } =>
+            visitor.visit_primitive(cx, variant, atomic, ptr),
+        Contour::Seq {..} => visitor.visit_seq(cx, &contour, ptr),
+        Contour::Pointer {..} => visitor.visit_pointer(cx, &contour, ptr),
+        Contour::Map {..} => visitor.visit_map(cx, &contour, ptr),
+    }
+}
+
+/// # Safety
+///
+/// `ptr` must point to a live value that `contour` describes.
+pub unsafe fn walk_struct<V: Visitor>(visitor: &mut V,
+                                      cx: &mut Walker,
+                                      contour: &Contour,
+                                      ptr: *const u8) {
+    match *contour {
+        Contour::Struct { ref fields, .. } => for field in fields {
+            let subptr = ptr.add(field.offset);
+            visitor.visit_field(cx, FieldName::Named(field.name), field.type_id, subptr);
+        },
+        Contour::Tuple { ref fields, .. } => for field in fields {
+            let subptr = ptr.add(field.offset);
+            visitor.visit_field(cx, FieldName::Index(field.ix), field.type_id, subptr);
+        },
+        Contour::Unit {..} => (),
+        _ => panic!("{} isn't a struct", contour.name()),
+    }
+}
+
+/// # Safety
+///
+/// `ptr` must point to a live value that `contour` describes.
+pub unsafe fn walk_enum<V: Visitor>(visitor: &mut V,
+                                    cx: &mut Walker,
+                                    contour: &Contour,
+                                    ptr: *const u8) {
+    match *contour {
+        Contour::Enum { ref variants, tag, .. } => {
+            let ix = tag(ptr);
+            visitor.visit_variant(cx, &variants[ix], ptr);
+        },
+        _ => panic!("{} isn't an enum", contour.name()),
+    }
+}
+
+/// # Safety
+///
+/// `ptr` must point to a live enum whose active variant is `variant`.
+pub unsafe fn walk_variant<V: Visitor>(visitor: &mut V,
+                                       cx: &mut Walker,
+                                       variant: &Variant,
+                                       ptr: *const u8) {
+    match variant.fields {
+        VariantFields::Struct(ref fields) => for field in fields {
+            let subptr = ptr.add(field.offset);
+            visitor.visit_field(cx, FieldName::Named(field.name), field.type_id, subptr);
+        },
+        VariantFields::Tuple(ref fields) => for field in fields {
+            let subptr = ptr.add(field.offset);
+            visitor.visit_field(cx, FieldName::Index(field.ix), field.type_id, subptr);
+        },
+        VariantFields::Unit => (),
+    }
+}
+
+/// # Safety
+///
+/// `ptr` must point to a live value that `contour` describes.
+pub unsafe fn walk_seq<V: Visitor>(visitor: &mut V,
+                                   cx: &mut Walker,
+                                   contour: &Contour,
+                                   ptr: *const u8) {
+    match *contour {
+        Contour::Seq { element, len, item, .. } => {
+            for ix in 0..len(ptr) {
+                visitor.visit_element(cx, ix, element, item(ptr, ix));
+            }
+        },
+        _ => panic!("{} isn't a sequence", contour.name()),
+    }
+}
+
+/// # Safety
+///
+/// `ptr` must point to a live value that `contour` describes.
+pub unsafe fn walk_pointer<V: Visitor>(visitor: &mut V,
+                                       cx: &mut Walker,
+                                       contour: &Contour,
+                                       ptr: *const u8) {
+    match *contour {
+        Contour::Pointer { pointee, deref, .. } => {
+            let target = match deref {
+                Some(deref) => deref(ptr),
+                None if cx.follow_raw => raw_address(ptr),
+                None => return,
+            };
+            if target.is_null() {
+                return;
+            }
+            if cx.active.contains(&(pointee, target)) {
+                visitor.visit_cycle(cx, pointee, target);
+                return;
+            }
+            cx.active.push((pointee, target));
+            visitor.visit_value(cx, pointee, target);
+            cx.active.pop();
+        },
+        _ => panic!("{} isn't a pointer", contour.name()),
+    }
+}
+
+/// # Safety
+///
+/// `ptr` must point to a live value that `contour` describes.
+pub unsafe fn walk_map<V: Visitor>(visitor: &mut V,
+                                   cx: &mut Walker,
+                                   contour: &Contour,
+                                   ptr: *const u8) {
+    match *contour {
+        Contour::Map { key, value, entries, .. } => {
+            entries(ptr, &mut |k, v| visitor.visit_entry(cx, key, k, value, v));
+        },
+        _ => panic!("{} isn't a map", contour.name()),
+    }
+}
+
+/// # Safety
+///
+/// `key` and `value` must point to live values of types `key_type` and
+/// `value_type`.
+pub unsafe fn walk_entry<V: Visitor>(visitor: &mut V,
+                                     cx: &mut Walker,
+                                     key_type: TypeId,
+                                     key: *const u8,
+                                     value_type: TypeId,
+                                     value: *const u8) {
+    visitor.visit_value(cx, key_type, key);
+    visitor.visit_value(cx, value_type, value);
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::any::TypeId;
+    use std::ptr;
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        Primitive,
+        StructField,
+        TupleField,
+        Variant,
+        VariantFields,
+    };
+    use super::*;
+
+    /// Records the path to every primitive it passes.
+    struct Paths {
+        stack: Vec<String>,
+        seen: Vec<String>,
+        cycles: usize,
+    }
+
+    impl Paths {
+        fn new() -> Self {
+            Paths { stack: vec![], seen: vec![], cycles: 0 }
+        }
+    }
+
+    impl Visitor for Paths {
+        unsafe fn visit_field(&mut self,
+                              cx: &mut Walker,
+                              name: FieldName,
+                              type_id: TypeId,
+                              ptr: *const u8) {
+            self.stack.push(format!("{}", name));
+            walk_value(self, cx, type_id, ptr);
+            self.stack.pop();
+        }
+
+        unsafe fn visit_variant(&mut self, cx: &mut Walker, variant: &Variant, ptr: *const u8) {
+            self.stack.push(format!("::{}", variant.name));
+            walk_variant(self, cx, variant, ptr);
+            self.stack.pop();
+        }
+
+        unsafe fn visit_element(&mut self,
+                                cx: &mut Walker,
+                                ix: usize,
+                                type_id: TypeId,
+                                ptr: *const u8) {
+            self.stack.push(format!("[{}]", ix));
+            walk_value(self, cx, type_id, ptr);
+            self.stack.pop();
+        }
+
+        unsafe fn visit_primitive(&mut self,
+                                  _cx: &mut Walker,
+                                  _kind: &Primitive,
+                                  _atomic: bool,
+                                  _ptr: *const u8) {
+            self.seen.push(self.stack.join("."));
+        }
+
+        unsafe fn visit_cycle(&mut self, _cx: &mut Walker, _type_id: TypeId, _ptr: *const u8) {
+            self.cycles += 1;
+        }
+    }
+
+    #[derive(Introspectable)]
+    enum State {
+        Idle,
+        Running { pid: u32 },
+    }
+
+    #[derive(Introspectable)]
+    struct Pair(u8, bool);
+
+    #[derive(Introspectable)]
+    struct Server {
+        name: String,
+        state: State,
+        pools: Vec<Pair>,
+        boxed: Box<u64>,
+    }
+
+    #[test]
+    fn test_walk() {
+        let server = Server {
+            name: "db".to_owned(),
+            state: State::Running { pid: 7 },
+            pools: vec![Pair(1, true), Pair(2, false)],
+            boxed: Box::new(3),
+        };
+        let mut paths = Paths::new();
+        walk(&server, &mut paths);
+        assert_eq!(paths.seen, vec![
+            "name",
+            "state.::Running.pid",
+            "pools.[0].0",
+            "pools.[0].1",
+            "pools.[1].0",
+            "pools.[1].1",
+            "boxed",
+        ]);
+        assert_eq!(paths.cycles, 0);
+    }
+
+    #[derive(Introspectable)]
+    struct Node {
+        val: u32,
+        next: *const Node,
+    }
+
+    #[test]
+    fn test_cycle() {
+        let mut a = Node { val: 1, next: ptr::null() };
+        let b = Node { val: 2, next: &a };
+        a.next = &b;
+
+        let mut paths = Paths::new();
+        walk(&a, &mut paths);
+        assert_eq!(paths.seen, vec!["val"]);
+        assert_eq!(paths.cycles, 0);
+
+        let registry = Registry::of::<Node>();
+        let mut walker = Walker::new(&registry).follow_raw();
+        let mut paths = Paths::new();
+        unsafe { walker.walk(&mut paths, TypeId::of::<Node>(), &a as *const _ as *const u8) };
+        assert_eq!(paths.seen, vec!["val", "next.val"]);
+        assert_eq!(paths.cycles, 1);
+    }
+}
@@ -0,0 +1,481 @@
+//! Tracking how values change over time.
+//!
+//! A `Watcher` snapshots its roots into a ring buffer of generations, each
+//! recording which paths changed since the one before.  The roots are
+//! treated as the fields of one struct, so paths start with a root's name,
+//! as in `conn.state`.  Roots are registered the same way as for `server`.
+
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::sync::{
+    Arc,
+    Mutex,
+    Weak,
+};
+use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+};
+
+use path::{
+    Key,
+    Path,
+    Segment,
+};
+use root::{
+    self,
+    Root,
+};
+use snapshot::{
+    snapshot_ptr,
+    Snapshot,
+};
+use value::{
+    FieldValues,
+    Value,
+};
+use Introspectable;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Generation {
+    /// Counts up from 0 with each snapshot.
+    pub number: u64,
+    pub taken: Instant,
+    /// A struct with a field per root.
+    pub value: Value,
+    /// Paths that differ from the previous generation.
+    pub changed: Vec<Path>,
+}
+
+/// What `Watcher::wait_until` saw at `path` before giving up, if anything.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WaitError {
+    pub path: Path,
+    pub last: Option<Value>,
+}
+
+impl fmt::Display for WaitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.last {
+            Some(_) => write!(f, "timed out waiting on `{}`", self.path),
+            None => write!(f, "timed out waiting for `{}` to exist", self.path),
+        }
+    }
+}
+
+impl error::Error for WaitError {}
+
+pub struct Watcher {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    roots: Mutex<Vec<Root>>,
+    generations: Mutex<VecDeque<Generation>>,
+    /// Held for each poll, so that polls don't overlap.
+    polling: Mutex<()>,
+    capacity: usize,
+    interval: Duration,
+}
+
+impl Watcher {
+    /// Keeps the last `capacity` generations, and snapshots every
+    /// `interval` when spawned or waiting.
+    pub fn new(capacity: usize, interval: Duration) -> Self {
+        assert!(capacity > 0, "Watcher needs room for at least one generation");
+        Watcher {
+            inner: Arc::new(Inner {
+                roots: Mutex::new(vec![]),
+                generations: Mutex::new(VecDeque::with_capacity(capacity)),
+                polling: Mutex::new(()),
+                capacity,
+                interval,
+            }),
+        }
+    }
+
+    /// Watches `value`, which the rest of the process may be using meanwhile.
+    pub fn register<T: Introspectable + Sync>(&mut self, name: &str, value: &'static T)
+        -> &mut Self
+    {
+        root::push(&mut self.inner.roots.lock().unwrap(), Root::new(name, value));
+        self
+    }
+
+    /// Watches the value inside `value`, holding the lock for each snapshot.
+    pub fn register_mutex<T: Introspectable + Send>(&mut self, name: &str, value: &'static Mutex<T>)
+        -> &mut Self
+    {
+        root::push(&mut self.inner.roots.lock().unwrap(), Root::locked(name, value));
+        self
+    }
+
+    /// Snapshots every root now, returning the new generation's number.
+    pub fn poll(&self) -> u64 {
+        self.inner.poll()
+    }
+
+    /// Polls on a background thread until the watcher is dropped.
+    pub fn spawn(&self) {
+        let inner: Weak<Inner> = Arc::downgrade(&self.inner);
+        let interval = self.inner.interval;
+        thread::Builder::new()
+            .name("contour-watcher".to_owned())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                match inner.upgrade() {
+                    Some(inner) => inner.poll(),
+                    None => return,
+                };
+            })
+            .expect("couldn't spawn watcher thread");
+    }
+
+    /// The generations still in the buffer, oldest first.
+    pub fn generations(&self) -> Vec<Generation> {
+        self.inner.generations.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn latest(&self) -> Option<Generation> {
+        self.inner.generations.lock().unwrap().back().cloned()
+    }
+
+    /// The paths that differ between generations `from` and `to`, or `None`
+    /// if either has left the buffer.
+    pub fn changes(&self, from: u64, to: u64) -> Option<Vec<Path>> {
+        let generations = self.inner.generations.lock().unwrap();
+        let find = |n| generations.iter().find(|g| g.number == n);
+        let (a, b) = (find(from)?, find(to)?);
+        let mut changed = vec![];
+        compare(&a.value, &b.value, &mut Path::root(), &mut changed);
+        Some(changed)
+    }
+
+    /// Snapshots until the value at `path` satisfies `pred`, returning it.
+    /// The path doesn't have to exist yet.
+    pub fn wait_until<F>(&self, path: &Path, timeout: Duration, pred: F) -> Result<Value, WaitError>
+        where F: Fn(&Value) -> bool
+    {
+        let start = Instant::now();
+        loop {
+            self.poll();
+            let generations = self.inner.generations.lock().unwrap();
+            let latest = generations.back().expect("poll didn't add a generation");
+            let found = lookup(&latest.value, path.segments()).cloned();
+            drop(generations);
+
+            if let Some(ref value) = found {
+                if pred(value) {
+                    return Ok(value.clone());
+                }
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Err(WaitError { path: path.clone(), last: found });
+            }
+            thread::sleep(::std::cmp::min(self.inner.interval, timeout - elapsed));
+        }
+    }
+}
+
+impl Inner {
+    /// Polls one at a time, so that generations are numbered in the order
+    /// they were captured.  The buffer is only locked once the roots have
+    /// been captured, so that a thread holding a root's lock can still read
+    /// it.
+    fn poll(&self) -> u64 {
+        let _polling = self.polling.lock().unwrap();
+        let fields = self.roots.lock().unwrap().iter()
+            .map(|root| (root.name.clone(), capture(root)))
+            .collect();
+        let value = Value::Struct { name: String::new(), fields: FieldValues::Named(fields) };
+
+        let mut generations = self.generations.lock().unwrap();
+        let mut changed = vec![];
+        let number = match generations.back() {
+            Some(previous) => {
+                compare(&previous.value, &value, &mut Path::root(), &mut changed);
+                previous.number + 1
+            },
+            None => 0,
+        };
+        if generations.len() == self.capacity {
+            generations.pop_front();
+        }
+        generations.push_back(Generation {
+            number,
+            taken: Instant::now(),
+            value,
+            changed,
+        });
+        number
+    }
+}
+
+fn capture(root: &Root) -> Value {
+    let mut bytes = vec![];
+    root.access(&mut |ptr, _| {
+        bytes = unsafe { snapshot_ptr(&root.registry, root.type_id, ptr) };
+    });
+    Snapshot::decode(&bytes).expect("snapshot didn't round-trip").value
+}
+
+/// Collects the paths where `a` and `b` differ: primitives that changed,
+/// enums that switched variants, sequences and maps that changed length,
+/// and map entries that came or went.
+fn compare(a: &Value, b: &Value, path: &mut Path, out: &mut Vec<Path>) {
+    match (a, b) {
+        (Value::Struct { fields: x, .. }, Value::Struct { fields: y, .. }) =>
+            compare_fields(x, y, path, out),
+        (Value::Enum { variant: vx, fields: x, .. },
+         Value::Enum { variant: vy, fields: y, .. }) if vx == vy => {
+            path.push(Segment::Variant(vx.clone()));
+            compare_fields(x, y, path, out);
+            path.pop();
+        },
+        (Value::Seq(x), Value::Seq(y)) => {
+            if x.len() != y.len() {
+                out.push(path.clone());
+            }
+            for (i, (x, y)) in x.iter().zip(y).enumerate() {
+                path.push(Segment::Index(Key::Int(i as i64)));
+                compare(x, y, path, out);
+                path.pop();
+            }
+        },
+        (Value::Map(x), Value::Map(y)) => {
+            if x.len() != y.len() {
+                out.push(path.clone());
+            }
+            for (i, (k, vx)) in x.iter().enumerate() {
+                path.push(key_segment(k, i));
+                match y.iter().find(|e| e.0 == *k) {
+                    Some((_, vy)) => compare(vx, vy, path, out),
+                    None => out.push(path.clone()),
+                }
+                path.pop();
+            }
+            for (i, (k, _)) in y.iter().enumerate() {
+                if !x.iter().any(|e| e.0 == *k) {
+                    out.push(path.child(key_segment(k, i)));
+                }
+            }
+        },
+        (&Value::Pointer(Some(ref x)), &Value::Pointer(Some(ref y))) => compare(x, y, path, out),
+        _ => if a != b {
+            out.push(path.clone());
+        },
+    }
+}
+
+fn compare_fields(a: &FieldValues, b: &FieldValues, path: &mut Path, out: &mut Vec<Path>) {
+    match (a, b) {
+        (FieldValues::Named(x), FieldValues::Named(y)) if x.len() == y.len() => {
+            for ((name, x), (_, y)) in x.iter().zip(y) {
+                path.push(Segment::Field(name.clone()));
+                compare(x, y, path, out);
+                path.pop();
+            }
+        },
+        (FieldValues::Tuple(x), FieldValues::Tuple(y)) if x.len() == y.len() => {
+            for (i, (x, y)) in x.iter().zip(y).enumerate() {
+                path.push(Segment::Tuple(i));
+                compare(x, y, path, out);
+                path.pop();
+            }
+        },
+        _ => if a != b {
+            out.push(path.clone());
+        },
+    }
+}
+
+/// Entries are addressed by key where the key is a primitive, and by
+/// position otherwise.
+fn key_segment(key: &Value, position: usize) -> Segment {
+    let key = key.as_primitive().and_then(Key::from_value);
+    Segment::Index(key.unwrap_or(Key::Int(position as i64)))
+}
+
+/// Follows `segments` through a snapshot, looking through pointers.  A
+/// variant segment only matches the active variant, but can be left out.
+fn lookup<'a>(value: &'a Value, segments: &[Segment]) -> Option<&'a Value> {
+    if let Value::Pointer(ref target) = *value {
+        return lookup(target.as_ref()?, segments);
+    }
+    let (segment, rest) = match segments.split_first() {
+        Some(split) => split,
+        None => return Some(value),
+    };
+    let fields = match *value {
+        Value::Struct { ref fields, .. } => fields,
+        Value::Enum { ref variant, ref fields, .. } => match *segment {
+            Segment::Variant(ref name) => {
+                return if name == variant { lookup(value, rest) } else { None };
+            },
+            _ => fields,
+        },
+        Value::Seq(ref items) => return match *segment {
+            Segment::Index(Key::Int(ix)) if ix >= 0 => lookup(items.get(ix as usize)?, rest),
+            _ => None,
+        },
+        Value::Map(ref entries) => return match *segment {
+            Segment::Index(ref key) => entries.iter()
+                .find(|e| e.0.as_primitive().is_some_and(|k| key.matches(k)))
+                .and_then(|e| lookup(&e.1, rest)),
+            _ => None,
+        },
+        _ => return None,
+    };
+    let next = match (segment, fields) {
+        (Segment::Field(name), FieldValues::Named(fields)) =>
+            fields.iter().find(|f| f.0 == *name).map(|f| &f.1),
+        (&Segment::Tuple(ix), FieldValues::Tuple(fields)) => fields.get(ix),
+        _ => None,
+    };
+    lookup(next?, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(dead_code)]
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{
+        AtomicU64,
+        Ordering,
+    };
+    use {
+        Contour,
+        ContourMap,
+        Introspectable,
+        PrimitiveValue,
+        StructField,
+        Variant,
+        VariantFields,
+    };
+    use super::*;
+
+    #[derive(Introspectable)]
+    enum State {
+        Connecting,
+        Open { sent: u32 },
+        Closed,
+    }
+
+    #[derive(Introspectable)]
+    struct Conn {
+        state: State,
+        peers: BTreeMap<String, u32>,
+        retries: u8,
+    }
+
+    fn watched() -> (Watcher, &'static Mutex<Conn>, &'static AtomicU64) {
+        let conn = Box::leak(Box::new(Mutex::new(Conn {
+            state: State::Connecting,
+            peers: BTreeMap::new(),
+            retries: 0,
+        })));
+        let hits = Box::leak(Box::new(AtomicU64::new(0)));
+        let mut watcher = Watcher::new(3, Duration::from_millis(1));
+        watcher.register_mutex("conn", conn).register("hits", hits);
+        (watcher, conn, hits)
+    }
+
+    fn paths(paths: &[Path]) -> Vec<String> {
+        paths.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn test_generations() {
+        let (watcher, conn, hits) = watched();
+        let first = watcher.poll();
+        {
+            let mut conn = conn.lock().unwrap();
+            conn.state = State::Open { sent: 1 };
+            conn.peers.insert("a".to_owned(), 7);
+        }
+        hits.fetch_add(1, Ordering::SeqCst);
+        watcher.poll();
+        assert_eq!(paths(&watcher.latest().unwrap().changed),
+                   vec!["conn.state", "conn.peers", "conn.peers[\"a\"]", "hits"]);
+
+        conn.lock().unwrap().state = State::Open { sent: 2 };
+        let third = watcher.poll();
+        assert_eq!(paths(&watcher.latest().unwrap().changed), vec!["conn.state::Open.sent"]);
+        assert_eq!(watcher.changes(first, third).unwrap().len(), 4);
+
+        watcher.poll();
+        let numbers: Vec<_> = watcher.generations().iter().map(|g| g.number).collect();
+        assert_eq!(numbers, vec![1, 2, 3]);
+        assert_eq!(watcher.changes(first, third), None);
+        assert!(watcher.latest().unwrap().changed.is_empty());
+    }
+
+    #[test]
+    fn test_overlapping_polls() {
+        let (watcher, _, hits) = watched();
+        let watcher = Arc::new(watcher);
+        let threads: Vec<_> = (0..4).map(|_| {
+            let watcher = watcher.clone();
+            thread::spawn(move || for _ in 0..20 {
+                hits.fetch_add(1, Ordering::SeqCst);
+                watcher.poll();
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let generations = watcher.generations();
+        for pair in generations.windows(2) {
+            assert_eq!(pair[1].number, pair[0].number + 1);
+            assert!(pair[1].taken >= pair[0].taken);
+            let hits = |g: &Generation| lookup(&g.value, &[Segment::Field("hits".to_owned())])
+                .cloned();
+            match (hits(&pair[0]), hits(&pair[1])) {
+                (Some(Value::Primitive(PrimitiveValue::u64(a))),
+                 Some(Value::Primitive(PrimitiveValue::u64(b)))) => assert!(a <= b),
+                other => panic!("unexpected values {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_while_locked() {
+        let (watcher, conn, _) = watched();
+        watcher.poll();
+        watcher.spawn();
+        let guard = conn.lock().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(watcher.latest().unwrap().number, 0);
+        assert_eq!(watcher.generations().len(), 1);
+        drop(guard);
+    }
+
+    #[test]
+    fn test_wait_until() {
+        let (watcher, conn, _) = watched();
+        watcher.spawn();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            conn.lock().unwrap().state = State::Closed;
+        });
+        let closed = |v: &Value| match *v {
+            Value::Enum { ref variant, .. } => variant == "Closed",
+            _ => false,
+        };
+        let path = "conn.state".parse().unwrap();
+        assert!(watcher.wait_until(&path, Duration::from_secs(10), closed).is_ok());
+
+        let path = "conn.retries".parse().unwrap();
+        let err = watcher.wait_until(&path, Duration::from_millis(5), |v| {
+            *v == Value::Primitive(PrimitiveValue::u8(9))
+        }).unwrap_err();
+        assert_eq!(err.last, Some(Value::Primitive(PrimitiveValue::u8(0))));
+        let path = "conn.peers[\"b\"]".parse().unwrap();
+        let err = watcher.wait_until(&path, Duration::from_millis(5), |_| true).unwrap_err();
+        assert_eq!(err.to_string(), "timed out waiting for `conn.peers[\"b\"]` to exist");
+        assert!(watcher.latest().unwrap().number > 2);
+    }
+}
@@ -15,6 +15,7 @@ use contour::{
     Contour,
     ContourMap,
     Primitive,
+    PrimitiveValue,
 };
 use cpython::{
     Python,
@@ -90,6 +91,25 @@ impl PythonManager {
                 obj.into_object()
             },
 
+            // Other threads may be updating atomics, so they're loaded
+            // atomically rather than copied.
+            Contour::Primitive { ref variant, atomic: true, .. } =>
+                match unsafe { variant.load(ptr, true) } {
+                    PrimitiveValue::u8(v) => v.to_py_object(py).into_object(),
+                    PrimitiveValue::u16(v) => v.to_py_object(py).into_object(),
+                    PrimitiveValue::u32(v) => v.to_py_object(py).into_object(),
+                    PrimitiveValue::u64(v) => v.to_py_object(py).into_object(),
+                    PrimitiveValue::usize(v) => v.to_py_object(py).into_object(),
+                    PrimitiveValue::i8(v) => v.to_py_object(py).into_object(),
+                    PrimitiveValue::i16(v) => v.to_py_object(py).into_object(),
+                    PrimitiveValue::i32(v) => v.to_py_object(py).into_object(),
+                    PrimitiveValue::i64(v) => v.to_py_object(py).into_object(),
+                    PrimitiveValue::isize(v) => v.to_py_object(py).into_object(),
+                    PrimitiveValue::bool(v) => v.to_py_object(py).into_object(),
+                    // Only integers and `bool`s come in atomic types.
+                    _ => unreachable!("atomic {:?}", variant),
+                },
+
             Contour::Primitive { variant: Primitive::u8, .. } =>
                 to_py_object::<u8>(py, ptr),
             Contour::Primitive { variant: Primitive::u16, .. } =>
@@ -148,6 +168,10 @@ impl ContourMap for PythonManager {
         inner.map.insert(type_id, contour);
         false
     }
+
+    fn lookup(&self, type_id: TypeId) -> Option<Contour> {
+        self.inner.lock().unwrap().map.get(&type_id).cloned()
+    }
 }
 
 struct Inner {